@@ -0,0 +1,241 @@
+// Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! End-to-end benchmarks for aggregation jobs. Each iteration runs an aggregation job from the
+//! Leader's `AggregationJobInitReq` to the Helper's final `AggregationJobResp`, including HPKE
+//! decryption of the input shares by both Aggregators. Sharding the reports is not included.
+//!
+//! Before benchmarking each configuration, the memory used by the messages and aggregation job
+//! state is printed. Run with
+//!
+//! ```text
+//! cargo bench -p daphne --features test-utils --bench aggregation
+//! ```
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use daphne::{
+    hpke::HpkeKemId,
+    messages::Report,
+    testing::AggregationJobTest,
+    vdaf::{MasticWeight, MasticWeightConfig, Prio3Config, VdafConfig},
+    DapAggregationParam, DapHelperAggregationJobTransition, DapLeaderAggregationJobTransition,
+    DapMeasurement, DapVersion,
+};
+use deepsize::DeepSizeOf;
+use futures::executor::block_on;
+use prio::{
+    codec::{Encode, ParameterizedEncode},
+    idpf::IdpfInput,
+    vdaf::poplar1::Poplar1AggregationParam,
+};
+
+const REPORT_COUNTS: [usize; 3] = [100, 1_000, 10_000];
+
+/// The VDAFs to benchmark, along with a valid measurement and aggregation parameter for each.
+fn vdafs() -> Vec<(VdafConfig, DapMeasurement, DapAggregationParam)> {
+    vec![
+        (
+            VdafConfig::Prio3(Prio3Config::Count),
+            DapMeasurement::U64(1),
+            DapAggregationParam::Empty,
+        ),
+        (
+            VdafConfig::Prio3(Prio3Config::Sum { bits: 32 }),
+            DapMeasurement::U64(1337),
+            DapAggregationParam::Empty,
+        ),
+        (
+            VdafConfig::Prio3(Prio3Config::Histogram {
+                length: 100,
+                chunk_length: 10,
+            }),
+            DapMeasurement::U64(42),
+            DapAggregationParam::Empty,
+        ),
+        (
+            VdafConfig::Prio3(Prio3Config::SumVec {
+                bits: 1,
+                length: 100,
+                chunk_length: 10,
+            }),
+            DapMeasurement::U128Vec(vec![1; 100]),
+            DapAggregationParam::Empty,
+        ),
+        (
+            VdafConfig::Prio3(Prio3Config::SumVecField64MultiproofHmacSha256Aes128 {
+                bits: 1,
+                length: 100,
+                chunk_length: 10,
+                num_proofs: 2,
+            }),
+            DapMeasurement::U64Vec(vec![1; 100]),
+            DapAggregationParam::Empty,
+        ),
+        (
+            VdafConfig::Prio2 { dimension: 100 },
+            DapMeasurement::U32Vec(vec![1; 100]),
+            DapAggregationParam::Empty,
+        ),
+        (
+            VdafConfig::Mastic {
+                input_size: 4,
+                weight_config: MasticWeightConfig::Count,
+            },
+            DapMeasurement::Mastic {
+                input: b"cool".to_vec(),
+                weight: MasticWeight::Bool(true),
+            },
+            DapAggregationParam::Mastic(
+                Poplar1AggregationParam::try_from_prefixes(vec![IdpfInput::from_bytes(b"cool")])
+                    .unwrap(),
+            ),
+        ),
+    ]
+}
+
+/// Memory used at each step of an aggregation job: the encoded length of each message and the
+/// size in memory of each Aggregator's state.
+#[derive(Default)]
+struct MemoryUse(Vec<(&'static str, usize)>);
+
+impl MemoryUse {
+    fn record(&mut self, what: &'static str, size: usize) {
+        self.0.push((what, size));
+    }
+}
+
+/// Run an aggregation job for the reports and return the number of reports aggregated by the
+/// Leader. If `mem` is provided, then record the memory used along the way.
+fn aggregate(
+    t: &AggregationJobTest,
+    agg_param: &DapAggregationParam,
+    reports: Vec<Report>,
+    mut mem: Option<&mut MemoryUse>,
+) -> u64 {
+    let version = DapVersion::default();
+    if let Some(mem) = mem.as_mut() {
+        mem.record("reports", reports.deep_size_of());
+    }
+
+    let DapLeaderAggregationJobTransition::Continued(mut leader_state, agg_job_init_req) =
+        block_on(t.produce_agg_job_init_req(agg_param, reports))
+    else {
+        panic!("unexpected transition");
+    };
+    if let Some(mem) = mem.as_mut() {
+        mem.record(
+            "AggregationJobInitReq",
+            agg_job_init_req
+                .get_encoded_with_param(&version)
+                .unwrap()
+                .len(),
+        );
+        mem.record("Leader state", leader_state.deep_size_of());
+    }
+
+    let mut helper_transition = block_on(t.handle_agg_job_init_req(agg_job_init_req));
+    let leader_agg_span = loop {
+        match helper_transition {
+            DapHelperAggregationJobTransition::Continued(helper_state, agg_job_resp) => {
+                if let Some(mem) = mem.as_mut() {
+                    mem.record(
+                        "AggregationJobResp",
+                        agg_job_resp.get_encoded().unwrap().len(),
+                    );
+                    mem.record("Helper state", helper_state.deep_size_of());
+                }
+                match t.handle_agg_job_resp(leader_state, agg_job_resp) {
+                    DapLeaderAggregationJobTransition::Continued(next_state, agg_job_cont_req) => {
+                        leader_state = next_state;
+                        helper_transition =
+                            t.handle_agg_job_cont_req(&helper_state, &agg_job_cont_req);
+                    }
+                    DapLeaderAggregationJobTransition::Uncommitted(
+                        uncommitted,
+                        agg_job_cont_req,
+                    ) => {
+                        if let Some(mem) = mem.as_mut() {
+                            mem.record(
+                                "AggregationJobContinueReq",
+                                agg_job_cont_req
+                                    .get_encoded_with_param(&version)
+                                    .unwrap()
+                                    .len(),
+                            );
+                        }
+                        let DapHelperAggregationJobTransition::Finished(_, agg_job_resp) =
+                            t.handle_agg_job_cont_req(&helper_state, &agg_job_cont_req)
+                        else {
+                            panic!("unexpected transition");
+                        };
+                        if let Some(mem) = mem.as_mut() {
+                            mem.record(
+                                "AggregationJobResp",
+                                agg_job_resp.get_encoded().unwrap().len(),
+                            );
+                        }
+                        break t.handle_final_agg_job_resp(uncommitted, agg_job_resp);
+                    }
+                    DapLeaderAggregationJobTransition::Finished(..) => {
+                        panic!("unexpected transition")
+                    }
+                }
+            }
+            DapHelperAggregationJobTransition::Finished(_, agg_job_resp) => {
+                if let Some(mem) = mem.as_mut() {
+                    mem.record(
+                        "AggregationJobResp",
+                        agg_job_resp.get_encoded().unwrap().len(),
+                    );
+                }
+                let DapLeaderAggregationJobTransition::Finished(leader_agg_span) =
+                    t.handle_agg_job_resp(leader_state, agg_job_resp)
+                else {
+                    panic!("unexpected transition");
+                };
+                break leader_agg_span;
+            }
+        }
+    };
+
+    leader_agg_span
+        .iter()
+        .map(|(_bucket, (agg_share, _report_ids))| agg_share.report_count)
+        .sum()
+}
+
+fn agg_job(c: &mut Criterion) {
+    for (vdaf, measurement, agg_param) in vdafs() {
+        let t = AggregationJobTest::new(&vdaf, HpkeKemId::X25519HkdfSha256, DapVersion::default());
+        let mut group = c.benchmark_group(format!("agg_job/{vdaf}"));
+        group.sample_size(10);
+        for report_count in REPORT_COUNTS {
+            let reports = t.produce_reports(vec![measurement.clone(); report_count]);
+
+            let mut mem = MemoryUse::default();
+            let aggregated = aggregate(&t, &agg_param, reports.clone(), Some(&mut mem));
+            assert_eq!(aggregated, u64::try_from(report_count).unwrap());
+            println!("agg_job/{vdaf}/{report_count}: memory use:");
+            for (what, size) in mem.0 {
+                println!("    {what}: {size} bytes");
+            }
+
+            group.throughput(Throughput::Elements(report_count.try_into().unwrap()));
+            group.bench_with_input(
+                BenchmarkId::from_parameter(report_count),
+                &reports,
+                |b, reports| {
+                    b.iter_batched(
+                        || reports.clone(),
+                        |reports| aggregate(&t, &agg_param, reports, None),
+                        BatchSize::LargeInput,
+                    );
+                },
+            );
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, agg_job);
+criterion_main!(benches);
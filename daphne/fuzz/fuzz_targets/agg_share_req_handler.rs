@@ -0,0 +1,9 @@
+// Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+#![no_main]
+
+use daphne_fuzz::helper::AggregateShareReqInput;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|input: AggregateShareReqInput| input.run());
@@ -0,0 +1,9 @@
+// Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+#![no_main]
+
+use daphne::messages::AggregateShare;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| daphne_fuzz::round_trip::<AggregateShare>(data));
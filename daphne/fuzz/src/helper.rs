@@ -0,0 +1,594 @@
+// Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Structure-aware inputs for the Helper's request handlers.
+//!
+//! Each input describes how to derive a request from a valid one, which is produced by a Leader
+//! for a [`HelperFixture`]. Deriving requests from valid ones lets the fuzzer get past HPKE
+//! decryption and VDAF preparation, which random bytes almost never do.
+
+use std::sync::OnceLock;
+
+use arbitrary::Arbitrary;
+use daphne::{
+    auth::BearerToken,
+    constants::DapMediaType,
+    hpke::{HpkeKemId, HpkeReceiverConfig},
+    messages::{
+        AggregateShare, AggregateShareReq, AggregationJobContinueReq, AggregationJobId,
+        AggregationJobInitReq, AggregationJobResp, BatchId, BatchSelector, Draft02AggregationJobId,
+        Interval, PartialBatchSelector, PrepareInit, ReportId, TaskId, Time,
+    },
+    roles::helper,
+    testing::{InMemoryAggregator, MockClock},
+    vdaf::{Prio3Config, VdafConfig},
+    DapAggregationParam, DapError, DapGlobalConfig, DapLeaderAggregationJobTransition,
+    DapMeasurement, DapQueryConfig, DapRequest, DapResource, DapResponse, DapTaskConfig,
+    DapVersion, MetaAggregationJobId,
+};
+use futures::executor::block_on;
+use prio::codec::{Decode, ParameterizedEncode};
+use rand::prelude::*;
+use url::Url;
+
+/// Number of reports in the aggregation job of a [`HelperFixture`].
+const NUM_REPORTS: usize = 4;
+
+/// A task for which the Helper is configured, along with a valid aggregation job for it.
+pub struct HelperFixture {
+    version: DapVersion,
+    now: Time,
+    pub task_id: TaskId,
+    pub task_config: DapTaskConfig,
+    global_config: DapGlobalConfig,
+    hpke_receiver_config_list: Vec<HpkeReceiverConfig>,
+    leader_token: BearerToken,
+    taskprov_vdaf_verify_key_init: [u8; 32],
+
+    /// The aggregation job, as sent by the Leader.
+    pub agg_job_id: MetaAggregationJobId,
+    pub agg_job_init_req: AggregationJobInitReq,
+    pub agg_job_cont_req: Option<AggregationJobContinueReq>,
+
+    /// The batch into which the reports are aggregated, and the Leader's view of it.
+    pub batch_interval: Interval,
+    pub report_count: u64,
+    pub checksum: [u8; 32],
+}
+
+impl HelperFixture {
+    /// The fixture for the given version. The fixture is created the first time it's needed.
+    pub fn get(version: DapVersion) -> &'static Self {
+        static DRAFT02: OnceLock<HelperFixture> = OnceLock::new();
+        static DRAFT09: OnceLock<HelperFixture> = OnceLock::new();
+        static LATEST: OnceLock<HelperFixture> = OnceLock::new();
+        match version {
+            DapVersion::Draft02 => &DRAFT02,
+            DapVersion::Draft09 => &DRAFT09,
+            DapVersion::Latest => &LATEST,
+        }
+        .get_or_init(|| block_on(Self::new(version)))
+    }
+
+    async fn new(version: DapVersion) -> Self {
+        let mut rng = thread_rng();
+        let now = 1_700_000_000;
+        let global_config = DapGlobalConfig {
+            max_batch_duration: 604_800,
+            min_batch_interval_start: 604_800,
+            max_batch_interval_end: 604_800,
+            supported_hpke_kems: vec![HpkeKemId::X25519HkdfSha256],
+            allow_taskprov: false,
+            helper_async_agg_jobs: false,
+            agg_job_policy: Default::default(),
+            retry_policy: Default::default(),
+        };
+        let vdaf = VdafConfig::Prio3(Prio3Config::Count);
+        let collector_hpke_config = HpkeReceiverConfig::gen(rng.gen(), HpkeKemId::X25519HkdfSha256)
+            .unwrap()
+            .config;
+        let task_id = TaskId(rng.gen());
+        let task_config = DapTaskConfig {
+            version,
+            leader_url: Url::parse("https://leader.example.com/").unwrap(),
+            helper_url: Url::parse("https://helper.example.com/").unwrap(),
+            time_precision: 3600,
+            expiration: now + 604_800,
+            min_batch_size: 1,
+            query: DapQueryConfig::TimeInterval,
+            max_batch_query_count: 1,
+            vdaf,
+            vdaf_verify_key: vdaf.gen_verify_key().unwrap(),
+            collector_hpke_config,
+            method: Default::default(),
+        };
+        let agg_job_id = match version {
+            DapVersion::Draft02 => {
+                MetaAggregationJobId::Draft02(Draft02AggregationJobId(rng.gen()))
+            }
+            DapVersion::Draft09 | DapVersion::Latest => {
+                MetaAggregationJobId::Draft09(AggregationJobId(rng.gen()))
+            }
+        };
+        let start = task_config.quantized_time_lower_bound(now);
+
+        let mut fixture = Self {
+            version,
+            now,
+            task_id,
+            task_config,
+            hpke_receiver_config_list: global_config
+                .gen_hpke_receiver_config_list(rng.gen())
+                .unwrap(),
+            global_config,
+            leader_token: BearerToken::from("leader_token"),
+            taskprov_vdaf_verify_key_init: rng.gen(),
+            agg_job_id,
+            agg_job_init_req: AggregationJobInitReq {
+                draft02_task_id: None,
+                draft02_agg_job_id: None,
+                agg_param: Vec::new(),
+                part_batch_sel: PartialBatchSelector::TimeInterval,
+                prep_inits: Vec::new(),
+            },
+            agg_job_cont_req: None,
+            batch_interval: Interval {
+                start,
+                duration: 3600,
+            },
+            report_count: 0,
+            checksum: [0; 32],
+        };
+
+        // Have a Leader produce the aggregation job and run it against a Helper.
+        let leader = InMemoryAggregator::new_leader(
+            [(fixture.task_id, fixture.task_config.clone())],
+            fixture
+                .global_config
+                .gen_hpke_receiver_config_list(rng.gen())
+                .unwrap(),
+            fixture.global_config.clone(),
+            fixture.leader_token.clone(),
+            None,
+            fixture.task_config.collector_hpke_config.clone(),
+            &prometheus::Registry::new(),
+            fixture.taskprov_vdaf_verify_key_init,
+            fixture.leader_token.clone(),
+            None,
+            None,
+        );
+        let helper = fixture.new_helper();
+        let client_hpke_config_list = [
+            leader.hpke_receiver_config_list[0].config.clone(),
+            helper.hpke_receiver_config_list[0].config.clone(),
+        ];
+        let reports = (0..NUM_REPORTS)
+            .map(|i| {
+                fixture
+                    .task_config
+                    .vdaf
+                    .produce_report(
+                        &client_hpke_config_list,
+                        now,
+                        &fixture.task_id,
+                        DapMeasurement::U64((i % 2) as u64),
+                        version,
+                    )
+                    .unwrap()
+            })
+            .collect();
+        let DapLeaderAggregationJobTransition::Continued(leader_state, agg_job_init_req) = fixture
+            .task_config
+            .produce_agg_job_init_req(
+                &leader,
+                &leader,
+                &leader.report_extension_handlers,
+                &fixture.task_id,
+                &fixture.agg_job_id,
+                &PartialBatchSelector::TimeInterval,
+                &DapAggregationParam::Empty,
+                &reports,
+                &leader.metrics,
+            )
+            .await
+            .unwrap()
+        else {
+            panic!("unexpected transition");
+        };
+        fixture.agg_job_init_req = agg_job_init_req;
+
+        let agg_job_resp = fixture.send_agg_job_init_req(&helper).await;
+        let agg_span = match fixture
+            .task_config
+            .handle_agg_job_resp(
+                &fixture.task_id,
+                &fixture.agg_job_id,
+                leader_state,
+                agg_job_resp,
+                &leader.metrics,
+            )
+            .unwrap()
+        {
+            DapLeaderAggregationJobTransition::Finished(agg_span) => agg_span,
+            DapLeaderAggregationJobTransition::Uncommitted(uncommitted, agg_job_cont_req) => {
+                fixture.agg_job_cont_req = Some(agg_job_cont_req);
+                let agg_job_resp = fixture.send_agg_job_cont_req(&helper).await.unwrap();
+                fixture
+                    .task_config
+                    .handle_final_agg_job_resp(
+                        &fixture.task_id,
+                        uncommitted,
+                        agg_job_resp,
+                        &leader.metrics,
+                    )
+                    .unwrap()
+            }
+            DapLeaderAggregationJobTransition::Continued(..) => panic!("unexpected transition"),
+        };
+        let (_bucket, (agg_share, _reports)) = agg_span.into_iter().next().unwrap();
+        fixture.report_count = agg_share.report_count;
+        fixture.checksum = agg_share.checksum;
+        fixture
+    }
+
+    pub fn version(&self) -> DapVersion {
+        self.version
+    }
+
+    /// A Helper that has not yet seen any requests for the task.
+    pub fn new_helper(&self) -> InMemoryAggregator {
+        InMemoryAggregator::new_helper(
+            [(self.task_id, self.task_config.clone())],
+            self.hpke_receiver_config_list.clone(),
+            self.global_config.clone(),
+            self.leader_token.clone(),
+            self.task_config.collector_hpke_config.clone(),
+            &prometheus::Registry::new(),
+            self.taskprov_vdaf_verify_key_init,
+            self.leader_token.clone(),
+        )
+        .with_clock(MockClock::new(self.now))
+    }
+
+    /// A request from the Leader for the task.
+    pub fn leader_req(
+        &self,
+        media_type: DapMediaType,
+        resource: DapResource,
+        payload: Vec<u8>,
+    ) -> DapRequest<BearerToken> {
+        DapRequest {
+            version: self.version,
+            media_type: Some(media_type),
+            task_id: Some(self.task_id),
+            resource,
+            payload,
+            sender_auth: Some(self.leader_token.clone()),
+            taskprov: None,
+        }
+    }
+
+    /// Run the aggregation job on the Helper.
+    pub async fn aggregate(&self, helper: &InMemoryAggregator) {
+        self.send_agg_job_init_req(helper).await;
+        if self.agg_job_cont_req.is_some() {
+            self.send_agg_job_cont_req(helper).await;
+        }
+    }
+
+    /// The Leader's request for the aggregate share of the batch.
+    pub fn agg_share_req(&self) -> AggregateShareReq {
+        AggregateShareReq {
+            draft02_task_id: self.task_id.for_request_payload(&self.version),
+            batch_sel: BatchSelector::TimeInterval {
+                batch_interval: self.batch_interval.clone(),
+            },
+            agg_param: Vec::new(),
+            report_count: self.report_count,
+            checksum: self.checksum,
+        }
+    }
+
+    /// Send the `AggregationJobInitReq` to the Helper. Panics if the Helper rejects it.
+    pub async fn send_agg_job_init_req(&self, helper: &InMemoryAggregator) -> AggregationJobResp {
+        let req = self.leader_req(
+            DapMediaType::AggregationJobInitReq,
+            resource_for(&self.agg_job_id),
+            self.agg_job_init_req
+                .get_encoded_with_param(&self.version)
+                .unwrap(),
+        );
+        let resp = helper::handle_agg_job_req(helper, &req).await.unwrap();
+        AggregationJobResp::get_decoded(&resp.payload).unwrap()
+    }
+
+    async fn send_agg_job_cont_req(
+        &self,
+        helper: &InMemoryAggregator,
+    ) -> Option<AggregationJobResp> {
+        let agg_job_cont_req = self.agg_job_cont_req.as_ref()?;
+        let req = self.leader_req(
+            DapMediaType::AggregationJobContinueReq,
+            resource_for(&self.agg_job_id),
+            agg_job_cont_req
+                .get_encoded_with_param(&self.version)
+                .unwrap(),
+        );
+        let resp = helper::handle_agg_job_req(helper, &req).await.unwrap();
+        Some(AggregationJobResp::get_decoded(&resp.payload).unwrap())
+    }
+}
+
+/// The resource of a request for the aggregation job.
+fn resource_for(agg_job_id: &MetaAggregationJobId) -> DapResource {
+    match agg_job_id {
+        // In draft02, the aggregation job ID is carried by the payload.
+        MetaAggregationJobId::Draft02(..) => DapResource::Undefined,
+        MetaAggregationJobId::Draft09(agg_job_id) => DapResource::AggregationJob(*agg_job_id),
+    }
+}
+
+/// Panic if the Helper failed for a reason other than rejecting the request.
+fn check_not_fatal(result: &Result<DapResponse, DapError>) {
+    if let Err(DapError::Fatal(e)) = result {
+        panic!("Helper failed to handle request: {e}");
+    }
+}
+
+#[derive(Arbitrary, Clone, Copy, Debug)]
+pub enum Version {
+    Draft02,
+    Draft09,
+    Latest,
+}
+
+impl From<Version> for DapVersion {
+    fn from(version: Version) -> Self {
+        match version {
+            Version::Draft02 => Self::Draft02,
+            Version::Draft09 => Self::Draft09,
+            Version::Latest => Self::Latest,
+        }
+    }
+}
+
+/// An `AggregationJobInitReq` derived from the fixture's.
+#[derive(Arbitrary, Debug)]
+pub struct AggregationJobInitReqInput {
+    pub version: Version,
+
+    /// Replace the aggregation job ID.
+    pub agg_job_id: Option<[u8; 32]>,
+
+    /// Replace the aggregation parameter.
+    pub agg_param: Option<Vec<u8>>,
+
+    /// Replace the partial batch selector with one for a fixed-size batch.
+    pub batch_id: Option<[u8; 32]>,
+
+    /// The reports to aggregate, each taken from the fixture.
+    pub prep_inits: Vec<PrepareInitInput>,
+
+    /// Send the request twice.
+    pub replay: bool,
+}
+
+#[derive(Arbitrary, Debug)]
+pub struct PrepareInitInput {
+    /// Index of the report in the fixture's aggregation job.
+    pub index: u8,
+    pub mutation: Option<PrepareInitMutation>,
+}
+
+#[derive(Arbitrary, Debug)]
+pub enum PrepareInitMutation {
+    ReportId([u8; 16]),
+    Time(Time),
+    PublicShare(Vec<u8>),
+    HpkeConfigId(u8),
+    EncapsulatedKey(Vec<u8>),
+    Payload(Vec<u8>),
+    FlipPayloadBit(u16),
+    LeaderPrepShare(Option<Vec<u8>>),
+}
+
+impl PrepareInitMutation {
+    fn apply(self, prep_init: &mut PrepareInit) {
+        let report_share = &mut prep_init.report_share;
+        let ciphertext = &mut report_share.encrypted_input_share;
+        match self {
+            Self::ReportId(id) => report_share.report_metadata.id = ReportId(id),
+            Self::Time(time) => report_share.report_metadata.time = time,
+            Self::PublicShare(public_share) => report_share.public_share = public_share,
+            Self::HpkeConfigId(config_id) => ciphertext.config_id = config_id,
+            Self::EncapsulatedKey(enc) => ciphertext.enc = enc,
+            Self::Payload(payload) => ciphertext.payload = payload,
+            Self::FlipPayloadBit(bit) => {
+                let bit = usize::from(bit) % (ciphertext.payload.len() * 8);
+                ciphertext.payload[bit / 8] ^= 1 << (bit % 8);
+            }
+            Self::LeaderPrepShare(payload) => prep_init.draft09_payload = payload,
+        }
+    }
+}
+
+impl AggregationJobInitReqInput {
+    /// Send the request to a new Helper. Check that the Helper does not fail and that, if it
+    /// accepts the request, then it responds with a transition for each report.
+    pub fn run(self) {
+        let version = self.version.into();
+        let fixture = HelperFixture::get(version);
+        block_on(self.run_with(fixture));
+    }
+
+    async fn run_with(self, fixture: &HelperFixture) {
+        let version = fixture.version;
+        let helper = fixture.new_helper();
+
+        let agg_job_id = match (self.agg_job_id, version) {
+            (None, _) => fixture.agg_job_id,
+            (Some(id), DapVersion::Draft02) => {
+                MetaAggregationJobId::Draft02(Draft02AggregationJobId(id))
+            }
+            (Some(id), DapVersion::Draft09 | DapVersion::Latest) => {
+                MetaAggregationJobId::Draft09(AggregationJobId(id[..16].try_into().unwrap()))
+            }
+        };
+
+        let mut agg_job_init_req = fixture.agg_job_init_req.clone();
+        if let MetaAggregationJobId::Draft02(id) = agg_job_id {
+            agg_job_init_req.draft02_agg_job_id = Some(id);
+        }
+        if let Some(agg_param) = self.agg_param {
+            agg_job_init_req.agg_param = agg_param;
+        }
+        if let Some(batch_id) = self.batch_id {
+            agg_job_init_req.part_batch_sel = PartialBatchSelector::FixedSizeByBatchId {
+                batch_id: BatchId(batch_id),
+            };
+        }
+        agg_job_init_req.prep_inits = self
+            .prep_inits
+            .into_iter()
+            .map(|input| {
+                let mut prep_init = fixture.agg_job_init_req.prep_inits
+                    [usize::from(input.index) % NUM_REPORTS]
+                    .clone();
+                if let Some(mutation) = input.mutation {
+                    mutation.apply(&mut prep_init);
+                }
+                prep_init
+            })
+            .collect();
+
+        let report_ids = agg_job_init_req
+            .prep_inits
+            .iter()
+            .map(|prep_init| prep_init.report_share.report_metadata.id)
+            .collect::<Vec<_>>();
+        let Ok(payload) = agg_job_init_req.get_encoded_with_param(&version) else {
+            return;
+        };
+        let req = fixture.leader_req(
+            DapMediaType::AggregationJobInitReq,
+            resource_for(&agg_job_id),
+            payload,
+        );
+
+        for _ in 0..=u8::from(self.replay) {
+            let result = helper::handle_agg_job_req(&helper, &req).await;
+            check_not_fatal(&result);
+            if let Ok(resp) = result {
+                let agg_job_resp = AggregationJobResp::get_decoded(&resp.payload)
+                    .expect("failed to decode AggregationJobResp");
+                assert_eq!(
+                    agg_job_resp
+                        .transitions
+                        .iter()
+                        .map(|transition| transition.report_id)
+                        .collect::<Vec<_>>(),
+                    report_ids,
+                    "AggregationJobResp does not cover the reports in the request"
+                );
+            }
+        }
+    }
+}
+
+/// An `AggregateShareReq` for the fixture's task.
+#[derive(Arbitrary, Debug)]
+pub struct AggregateShareReqInput {
+    pub version: Version,
+
+    /// Run the fixture's aggregation job before requesting the aggregate share.
+    pub aggregate: bool,
+
+    pub batch_sel: BatchSelectorInput,
+    pub agg_param: Vec<u8>,
+
+    /// Replace the report count claimed by the Leader.
+    pub report_count: Option<u64>,
+
+    /// Replace the checksum claimed by the Leader.
+    pub checksum: Option<[u8; 32]>,
+
+    /// Send the request twice.
+    pub replay: bool,
+}
+
+#[derive(Arbitrary, Debug)]
+pub enum BatchSelectorInput {
+    /// The batch into which the fixture's reports are aggregated.
+    Fixture,
+    TimeInterval {
+        start: Time,
+        duration: u64,
+    },
+    FixedSize([u8; 32]),
+}
+
+impl AggregateShareReqInput {
+    /// Send the request to a new Helper. Check that the Helper does not fail and that, if it
+    /// accepts the request, then the report count and checksum match the Leader's.
+    pub fn run(self) {
+        let version = self.version.into();
+        let fixture = HelperFixture::get(version);
+        block_on(self.run_with(fixture));
+    }
+
+    async fn run_with(self, fixture: &HelperFixture) {
+        let version = fixture.version;
+        let helper = fixture.new_helper();
+        if self.aggregate {
+            fixture.aggregate(&helper).await;
+        }
+
+        let mut agg_share_req = fixture.agg_share_req();
+        match self.batch_sel {
+            BatchSelectorInput::Fixture => (),
+            BatchSelectorInput::TimeInterval { start, duration } => {
+                agg_share_req.batch_sel = BatchSelector::TimeInterval {
+                    batch_interval: Interval { start, duration },
+                };
+            }
+            BatchSelectorInput::FixedSize(batch_id) => {
+                agg_share_req.batch_sel = BatchSelector::FixedSizeByBatchId {
+                    batch_id: BatchId(batch_id),
+                };
+            }
+        }
+        agg_share_req.agg_param = self.agg_param;
+        if let Some(report_count) = self.report_count {
+            agg_share_req.report_count = report_count;
+        }
+        if let Some(checksum) = self.checksum {
+            agg_share_req.checksum = checksum;
+        }
+        let Ok(payload) = agg_share_req.get_encoded_with_param(&version) else {
+            return;
+        };
+        let req = fixture.leader_req(
+            DapMediaType::AggregateShareReq,
+            DapResource::Undefined,
+            payload,
+        );
+
+        for _ in 0..=u8::from(self.replay) {
+            let result = helper::handle_agg_share_req(&helper, &req).await;
+            check_not_fatal(&result);
+            if let Ok(resp) = result {
+                AggregateShare::get_decoded(&resp.payload)
+                    .expect("failed to decode AggregateShare");
+                assert!(
+                    self.aggregate,
+                    "Helper produced an aggregate share for an empty batch"
+                );
+                assert_eq!(
+                    (agg_share_req.report_count, agg_share_req.checksum),
+                    (fixture.report_count, fixture.checksum),
+                    "Helper accepted an AggregateShareReq that does not match its aggregate share"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,342 @@
+// Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Code shared by the fuzz targets.
+
+pub mod helper;
+
+use daphne::DapVersion;
+use prio::codec::{Decode, Encode, ParameterizedDecode, ParameterizedEncode};
+
+/// The DAP version selected by the first byte of the input.
+pub fn version_from_byte(b: u8) -> DapVersion {
+    match b % 3 {
+        0 => DapVersion::Draft02,
+        1 => DapVersion::Draft09,
+        _ => DapVersion::Latest,
+    }
+}
+
+/// Decode a message. If decoding succeeds, then check that encoding the message yields the input.
+pub fn round_trip<T: Decode + Encode>(data: &[u8]) {
+    let Ok(msg) = T::get_decoded(data) else {
+        return;
+    };
+    let encoded = msg.get_encoded().expect("failed to encode decoded message");
+    assert_eq!(data, encoded, "decoded message does not round-trip");
+}
+
+/// Like [`round_trip`], except that the first byte of the input selects the DAP version with which
+/// the rest of the input is decoded.
+pub fn round_trip_with_version<T>(data: &[u8])
+where
+    T: ParameterizedDecode<DapVersion> + ParameterizedEncode<DapVersion>,
+{
+    let Some((first, data)) = data.split_first() else {
+        return;
+    };
+    let version = version_from_byte(*first);
+    let Ok(msg) = T::get_decoded_with_param(&version, data) else {
+        return;
+    };
+    let encoded = msg
+        .get_encoded_with_param(&version)
+        .expect("failed to encode decoded message");
+    assert_eq!(
+        data, encoded,
+        "decoded message does not round-trip ({version})"
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, path::PathBuf};
+
+    use daphne::{
+        constants::DapMediaType,
+        hpke::HpkeConfig,
+        messages::{
+            taskprov, AggregateShare, AggregateShareReq, AggregationJobContinueReq,
+            AggregationJobInitReq, AggregationJobResp, BatchId, BatchSelector, Collection,
+            CollectionReq, Extension, HpkeConfigList, PartialBatchSelector, PlaintextInputShare,
+            PrepareInit, Query, Report, ReportMetadata, ReportShare, Transition,
+        },
+        roles::helper,
+        DapResource, DapVersion,
+    };
+    use futures::executor::block_on;
+    use prio::codec::{Decode, Encode, ParameterizedDecode, ParameterizedEncode};
+
+    use super::{round_trip, round_trip_with_version, version_from_byte};
+    use crate::helper::HelperFixture;
+
+    const VERSIONS: [DapVersion; 3] =
+        [DapVersion::Draft02, DapVersion::Draft09, DapVersion::Latest];
+
+    fn seeds_dir() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("seeds")
+    }
+
+    /// Prefix the encoding of a message with the byte that selects the version.
+    fn with_version(version: DapVersion, msg: &impl ParameterizedEncode<DapVersion>) -> Vec<u8> {
+        let first = (0..3).find(|b| version_from_byte(*b) == version).unwrap();
+        let mut data = vec![first];
+        data.extend(msg.get_encoded_with_param(&version).unwrap());
+        data
+    }
+
+    /// A valid encoding of each message, named by the target that decodes it.
+    async fn seeds(fixture: &HelperFixture) -> Vec<(&'static str, Vec<u8>)> {
+        let version = fixture.version();
+        let agg_job_init_req = &fixture.agg_job_init_req;
+        let prep_init = &agg_job_init_req.prep_inits[0];
+        let report_share = &prep_init.report_share;
+        let report = Report {
+            draft02_task_id: fixture.task_id.for_request_payload(&version),
+            report_metadata: report_share.report_metadata.clone(),
+            public_share: report_share.public_share.clone(),
+            encrypted_input_shares: [
+                report_share.encrypted_input_share.clone(),
+                report_share.encrypted_input_share.clone(),
+            ],
+        };
+        let extension = Extension::Taskprov {
+            draft02_payload: (version == DapVersion::Draft02).then(|| b"task config".to_vec()),
+        };
+        let agg_job_cont_req =
+            fixture
+                .agg_job_cont_req
+                .clone()
+                .unwrap_or_else(|| AggregationJobContinueReq {
+                    draft02_task_id: None,
+                    draft02_agg_job_id: None,
+                    round: Some(1),
+                    transitions: Vec::new(),
+                });
+
+        let agg_job_resp = fixture
+            .send_agg_job_init_req(&fixture.new_helper())
+            .await
+            .get_encoded()
+            .unwrap();
+        let transition = AggregationJobResp::get_decoded(&agg_job_resp)
+            .unwrap()
+            .transitions
+            .remove(0);
+
+        let helper = fixture.new_helper();
+        fixture.aggregate(&helper).await;
+        let agg_share_req = fixture.agg_share_req();
+        let agg_share = helper::handle_agg_share_req(
+            &helper,
+            &fixture.leader_req(
+                DapMediaType::AggregateShareReq,
+                DapResource::Undefined,
+                agg_share_req.get_encoded_with_param(&version).unwrap(),
+            ),
+        )
+        .await
+        .unwrap()
+        .payload;
+        let encrypted_agg_share = AggregateShare::get_decoded(&agg_share)
+            .unwrap()
+            .encrypted_agg_share;
+
+        let query = Query::TimeInterval {
+            batch_interval: fixture.batch_interval.clone(),
+        };
+        let collection = Collection {
+            part_batch_sel: PartialBatchSelector::TimeInterval,
+            report_count: fixture.report_count,
+            draft09_interval: (version != DapVersion::Draft02)
+                .then(|| fixture.batch_interval.clone()),
+            encrypted_agg_shares: [encrypted_agg_share.clone(), encrypted_agg_share],
+        };
+        let hpke_config: HpkeConfig = fixture.new_helper().hpke_receiver_config_list[0]
+            .config
+            .clone();
+        let taskprov_config = taskprov::TaskConfig {
+            task_info: b"fuzz".to_vec(),
+            leader_url: taskprov::UrlBytes {
+                bytes: fixture.task_config.leader_url.to_string().into_bytes(),
+            },
+            helper_url: taskprov::UrlBytes {
+                bytes: fixture.task_config.helper_url.to_string().into_bytes(),
+            },
+            query_config: taskprov::QueryConfig {
+                time_precision: fixture.task_config.time_precision,
+                max_batch_query_count: 1,
+                min_batch_size: 1,
+                var: taskprov::QueryConfigVar::FixedSize { max_batch_size: 2 },
+            },
+            task_expiration: fixture.task_config.expiration,
+            vdaf_config: taskprov::VdafConfig {
+                dp_config: taskprov::DpConfig::None,
+                var: taskprov::VdafTypeVar::Prio2 { dimension: 10 },
+            },
+        };
+
+        vec![
+            ("decode_report", with_version(version, &report)),
+            (
+                "decode_report_metadata",
+                with_version(version, &report_share.report_metadata),
+            ),
+            ("decode_report_share", with_version(version, report_share)),
+            (
+                "decode_plaintext_input_share",
+                with_version(
+                    version,
+                    &PlaintextInputShare {
+                        extensions: if version == DapVersion::Draft02 {
+                            Vec::new()
+                        } else {
+                            vec![extension.clone()]
+                        },
+                        payload: b"input share".to_vec(),
+                    },
+                ),
+            ),
+            ("decode_extension", with_version(version, &extension)),
+            (
+                "decode_agg_job_init_req",
+                with_version(version, agg_job_init_req),
+            ),
+            ("decode_prepare_init", with_version(version, prep_init)),
+            (
+                "decode_agg_job_cont_req",
+                with_version(version, &agg_job_cont_req),
+            ),
+            ("decode_agg_job_resp", agg_job_resp),
+            ("decode_transition", transition.get_encoded().unwrap()),
+            (
+                "decode_agg_share_req",
+                with_version(version, &agg_share_req),
+            ),
+            ("decode_agg_share", agg_share),
+            (
+                "decode_collection_req",
+                with_version(
+                    version,
+                    &CollectionReq {
+                        draft02_task_id: fixture.task_id.for_request_payload(&version),
+                        query: query.clone(),
+                        agg_param: Vec::new(),
+                    },
+                ),
+            ),
+            ("decode_collection", with_version(version, &collection)),
+            ("decode_query", with_version(version, &query)),
+            (
+                "decode_batch_selector",
+                BatchSelector::TimeInterval {
+                    batch_interval: fixture.batch_interval.clone(),
+                }
+                .get_encoded()
+                .unwrap(),
+            ),
+            (
+                "decode_partial_batch_selector",
+                PartialBatchSelector::FixedSizeByBatchId {
+                    batch_id: BatchId([1; 32]),
+                }
+                .get_encoded()
+                .unwrap(),
+            ),
+            ("decode_hpke_config", hpke_config.get_encoded().unwrap()),
+            (
+                "decode_hpke_config_list",
+                HpkeConfigList {
+                    hpke_configs: vec![hpke_config],
+                }
+                .get_encoded()
+                .unwrap(),
+            ),
+            (
+                "decode_taskprov_task_config",
+                with_version(version, &taskprov_config),
+            ),
+        ]
+    }
+
+    /// Decode the seed with the target's decoder, then run the target on it.
+    fn check_seed(target: &str, data: &[u8]) {
+        fn check<T: Decode + Encode>(data: &[u8]) {
+            T::get_decoded(data).expect("failed to decode seed");
+            round_trip::<T>(data);
+        }
+
+        fn check_with_version<T>(data: &[u8])
+        where
+            T: ParameterizedDecode<DapVersion> + ParameterizedEncode<DapVersion>,
+        {
+            let (first, rest) = data.split_first().expect("seed is empty");
+            T::get_decoded_with_param(&version_from_byte(*first), rest)
+                .expect("failed to decode seed");
+            round_trip_with_version::<T>(data);
+        }
+
+        match target {
+            "decode_report" => check_with_version::<Report>(data),
+            "decode_report_metadata" => check_with_version::<ReportMetadata>(data),
+            "decode_report_share" => check_with_version::<ReportShare>(data),
+            "decode_plaintext_input_share" => check_with_version::<PlaintextInputShare>(data),
+            "decode_extension" => check_with_version::<Extension>(data),
+            "decode_agg_job_init_req" => check_with_version::<AggregationJobInitReq>(data),
+            "decode_prepare_init" => check_with_version::<PrepareInit>(data),
+            "decode_agg_job_cont_req" => check_with_version::<AggregationJobContinueReq>(data),
+            "decode_agg_job_resp" => check::<AggregationJobResp>(data),
+            "decode_transition" => check::<Transition>(data),
+            "decode_agg_share_req" => check_with_version::<AggregateShareReq>(data),
+            "decode_agg_share" => check::<AggregateShare>(data),
+            "decode_collection_req" => check_with_version::<CollectionReq>(data),
+            "decode_collection" => check_with_version::<Collection>(data),
+            "decode_query" => check_with_version::<Query>(data),
+            "decode_batch_selector" => check::<BatchSelector>(data),
+            "decode_partial_batch_selector" => check::<PartialBatchSelector>(data),
+            "decode_hpke_config" => check::<HpkeConfig>(data),
+            "decode_hpke_config_list" => check::<HpkeConfigList>(data),
+            "decode_taskprov_task_config" => check_with_version::<taskprov::TaskConfig>(data),
+            _ => panic!("unknown target {target}"),
+        }
+    }
+
+    #[test]
+    fn generated_seeds_are_valid() {
+        for version in VERSIONS {
+            for (target, data) in block_on(seeds(HelperFixture::get(version))) {
+                check_seed(target, &data);
+            }
+        }
+    }
+
+    #[test]
+    fn committed_seeds_are_valid() {
+        for entry in fs::read_dir(seeds_dir()).unwrap() {
+            let dir = entry.unwrap().path();
+            let target = dir.file_name().unwrap().to_str().unwrap().to_string();
+            for seed in fs::read_dir(&dir).unwrap() {
+                let seed = seed.unwrap().path();
+                println!("checking {}", seed.display());
+                check_seed(&target, &fs::read(seed).unwrap());
+            }
+        }
+    }
+
+    /// Regenerate the seed corpus, e.g., after a change to the wire format:
+    ///
+    /// ```text
+    /// cargo test generate_seeds -- --ignored
+    /// ```
+    #[test]
+    #[ignore = "overwrites the seed corpus"]
+    fn generate_seeds() {
+        for version in VERSIONS {
+            for (target, data) in block_on(seeds(HelperFixture::get(version))) {
+                let dir = seeds_dir().join(target);
+                fs::create_dir_all(&dir).unwrap();
+                fs::write(dir.join(version.to_string()), data).unwrap();
+            }
+        }
+    }
+}
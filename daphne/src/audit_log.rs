@@ -1,14 +1,37 @@
 // Copyright (c) 2023 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
-use crate::{messages::TaskId, DapTaskConfig};
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::mpsc,
+    thread,
+    time::SystemTime,
+};
 
+use serde::Serialize;
+use tracing::error;
+
+use crate::{
+    messages::{BatchSelector, CollectionJobId, ReportId, TaskId},
+    vdaf::VdafConfig,
+    DapTaskConfig, DapVersion,
+};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum AggregationJobAuditAction {
     Init,
     Continue,
 }
 
+/// Sink for records of security-relevant actions taken by an Aggregator.
+///
+/// Task deletion is out of scope: the Aggregator has no code path that removes a task. Tasks are
+/// removed out of band by the deployment, which is responsible for auditing that itself.
 pub trait AuditLog {
+    /// Called by the Helper after it initializes or continues an aggregation job.
     fn on_aggregation_job(
         &self,
         host: &str,
@@ -17,6 +40,41 @@ pub trait AuditLog {
         report_count: u64,
         action: AggregationJobAuditAction,
     );
+
+    /// Called by the Leader after it accepts a report from a Client.
+    fn on_upload(
+        &self,
+        host: &str,
+        task_id: &TaskId,
+        task_config: &DapTaskConfig,
+        report_id: &ReportId,
+    );
+
+    /// Called by the Leader after it initializes a collection job. The collection job ID is not
+    /// known in draft02, since it is chosen by the Leader after this point.
+    fn on_collection_job(
+        &self,
+        host: &str,
+        task_id: &TaskId,
+        task_config: &DapTaskConfig,
+        coll_job_id: Option<&CollectionJobId>,
+        batch_sel: &BatchSelector,
+    );
+
+    /// Called by the Leader when it requests an aggregate share from the Helper and by the Helper
+    /// when it responds to such a request.
+    fn on_aggregate_share(
+        &self,
+        host: &str,
+        task_id: &TaskId,
+        task_config: &DapTaskConfig,
+        batch_sel: &BatchSelector,
+        report_count: u64,
+        checksum: &[u8; 32],
+    );
+
+    /// Called after a task is configured via the taskprov extension.
+    fn on_taskprov_provision(&self, host: &str, task_id: &TaskId, task_config: &DapTaskConfig);
 }
 
 /// Default implementation of the trait, which is a no-op.
@@ -32,4 +90,303 @@ impl AuditLog for NoopAuditLog {
         _action: AggregationJobAuditAction,
     ) {
     }
+
+    fn on_upload(
+        &self,
+        _host: &str,
+        _task_id: &TaskId,
+        _task_config: &DapTaskConfig,
+        _report_id: &ReportId,
+    ) {
+    }
+
+    fn on_collection_job(
+        &self,
+        _host: &str,
+        _task_id: &TaskId,
+        _task_config: &DapTaskConfig,
+        _coll_job_id: Option<&CollectionJobId>,
+        _batch_sel: &BatchSelector,
+    ) {
+    }
+
+    fn on_aggregate_share(
+        &self,
+        _host: &str,
+        _task_id: &TaskId,
+        _task_config: &DapTaskConfig,
+        _batch_sel: &BatchSelector,
+        _report_count: u64,
+        _checksum: &[u8; 32],
+    ) {
+    }
+
+    fn on_taskprov_provision(&self, _host: &str, _task_id: &TaskId, _task_config: &DapTaskConfig) {}
+}
+
+/// A single line of the JSON-lines audit log.
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    /// Number of seconds since the beginning of UNIX time.
+    time: u64,
+    host: &'a str,
+    task_id: &'a TaskId,
+    version: DapVersion,
+    vdaf: &'a VdafConfig,
+    #[serde(flatten)]
+    event: AuditEvent<'a>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum AuditEvent<'a> {
+    AggregationJob {
+        action: AggregationJobAuditAction,
+        report_count: u64,
+    },
+    Upload {
+        report_id: &'a ReportId,
+    },
+    CollectionJob {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        coll_job_id: Option<&'a CollectionJobId>,
+        batch_sel: &'a BatchSelector,
+    },
+    AggregateShare {
+        batch_sel: &'a BatchSelector,
+        report_count: u64,
+        checksum: String,
+    },
+    TaskprovProvision,
+}
+
+/// Audit log that appends each event as a JSON object on its own line.
+///
+/// Records are handed off to a dedicated thread that writes them, so that logging an event never
+/// blocks the caller on I/O. The writer flushes whenever it has caught up with the records sent
+/// so far. Failures to write are logged, but otherwise ignored, so that the audit log never causes
+/// a request to fail.
+pub struct JsonLinesAuditLog<W: Write + Send + 'static = File> {
+    records: mpsc::Sender<Vec<u8>>,
+    writer: thread::JoinHandle<W>,
+}
+
+impl JsonLinesAuditLog<File> {
+    /// Open the file at `path` for appending, creating it if it does not exist.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::new(file))
+    }
+}
+
+impl<W: Write + Send + 'static> JsonLinesAuditLog<W> {
+    /// Write the audit log to `out`.
+    pub fn new(mut out: W) -> Self {
+        let (records, received) = mpsc::channel::<Vec<u8>>();
+        let writer = thread::Builder::new()
+            .name("audit-log".into())
+            .spawn(move || {
+                while let Ok(line) = received.recv() {
+                    let result = std::iter::once(line)
+                        .chain(received.try_iter())
+                        .try_for_each(|line| out.write_all(&line))
+                        .and_then(|()| out.flush());
+                    if let Err(e) = result {
+                        error!("audit log: failed to write record: {e}");
+                    }
+                }
+                out
+            })
+            .expect("failed to spawn audit log writer");
+        Self { records, writer }
+    }
+
+    /// Wait for every record to be written, then return the underlying writer.
+    pub fn into_inner(self) -> W {
+        let Self { records, writer } = self;
+        drop(records);
+        writer
+            .join()
+            .unwrap_or_else(|e| std::panic::resume_unwind(e))
+    }
+
+    fn write(
+        &self,
+        host: &str,
+        task_id: &TaskId,
+        task_config: &DapTaskConfig,
+        event: AuditEvent<'_>,
+    ) {
+        let record = AuditRecord {
+            time: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            host,
+            task_id,
+            version: task_config.version,
+            vdaf: &task_config.vdaf,
+            event,
+        };
+
+        let mut line = match serde_json::to_vec(&record) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("audit log: failed to encode record: {e}");
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        if self.records.send(line).is_err() {
+            error!("audit log: failed to write record: writer has stopped");
+        }
+    }
+}
+
+impl<W: Write + Send + 'static> AuditLog for JsonLinesAuditLog<W> {
+    fn on_aggregation_job(
+        &self,
+        host: &str,
+        task_id: &TaskId,
+        task_config: &DapTaskConfig,
+        report_count: u64,
+        action: AggregationJobAuditAction,
+    ) {
+        self.write(
+            host,
+            task_id,
+            task_config,
+            AuditEvent::AggregationJob {
+                action,
+                report_count,
+            },
+        );
+    }
+
+    fn on_upload(
+        &self,
+        host: &str,
+        task_id: &TaskId,
+        task_config: &DapTaskConfig,
+        report_id: &ReportId,
+    ) {
+        self.write(host, task_id, task_config, AuditEvent::Upload { report_id });
+    }
+
+    fn on_collection_job(
+        &self,
+        host: &str,
+        task_id: &TaskId,
+        task_config: &DapTaskConfig,
+        coll_job_id: Option<&CollectionJobId>,
+        batch_sel: &BatchSelector,
+    ) {
+        self.write(
+            host,
+            task_id,
+            task_config,
+            AuditEvent::CollectionJob {
+                coll_job_id,
+                batch_sel,
+            },
+        );
+    }
+
+    fn on_aggregate_share(
+        &self,
+        host: &str,
+        task_id: &TaskId,
+        task_config: &DapTaskConfig,
+        batch_sel: &BatchSelector,
+        report_count: u64,
+        checksum: &[u8; 32],
+    ) {
+        self.write(
+            host,
+            task_id,
+            task_config,
+            AuditEvent::AggregateShare {
+                batch_sel,
+                report_count,
+                checksum: hex::encode(checksum),
+            },
+        );
+    }
+
+    fn on_taskprov_provision(&self, host: &str, task_id: &TaskId, task_config: &DapTaskConfig) {
+        self.write(host, task_id, task_config, AuditEvent::TaskprovProvision);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AggregationJobAuditAction, AuditLog, JsonLinesAuditLog};
+    use crate::{
+        hpke::{HpkeKemId, HpkeReceiverConfig},
+        messages::{BatchId, BatchSelector},
+        DapQueryConfig, DapTaskParameters, DapVersion,
+    };
+    use serde_json::Value;
+
+    #[test]
+    fn json_lines() {
+        let batch_id = BatchId([2; 32]);
+        let collector_hpke_config = HpkeReceiverConfig::gen(1, HpkeKemId::X25519HkdfSha256)
+            .unwrap()
+            .config;
+        let (task_config, task_id, _, _) = DapTaskParameters {
+            version: DapVersion::Draft09,
+            query: DapQueryConfig::FixedSize {
+                max_batch_size: None,
+            },
+            ..Default::default()
+        }
+        .to_config_with_taskprov(b"cool task".to_vec(), 0, &[0; 32], &collector_hpke_config)
+        .unwrap();
+        let audit_log = JsonLinesAuditLog::new(Vec::new());
+
+        audit_log.on_aggregation_job(
+            "helper.example.com",
+            &task_id,
+            &task_config,
+            10,
+            AggregationJobAuditAction::Init,
+        );
+        audit_log.on_aggregate_share(
+            "helper.example.com",
+            &task_id,
+            &task_config,
+            &BatchSelector::FixedSizeByBatchId { batch_id },
+            10,
+            &[0xab; 32],
+        );
+        audit_log.on_taskprov_provision("helper.example.com", &task_id, &task_config);
+
+        let out = String::from_utf8(audit_log.into_inner()).unwrap();
+        let records = out
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(records.len(), 3);
+
+        assert_eq!(records[0]["event"], "aggregation_job");
+        assert_eq!(records[0]["host"], "helper.example.com");
+        assert_eq!(
+            records[0]["task_id"],
+            serde_json::to_value(task_id).unwrap()
+        );
+        assert_eq!(records[0]["action"], "init");
+        assert_eq!(records[0]["report_count"], 10);
+
+        assert_eq!(records[1]["event"], "aggregate_share");
+        assert_eq!(
+            records[1]["batch_sel"],
+            serde_json::to_value(BatchSelector::FixedSizeByBatchId { batch_id }).unwrap()
+        );
+        assert_eq!(records[1]["checksum"], hex::encode([0xab; 32]));
+
+        assert_eq!(records[2]["event"], "taskprov_provision");
+        assert_eq!(records[2]["version"], "v09");
+    }
 }
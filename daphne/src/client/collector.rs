@@ -0,0 +1,659 @@
+// Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Collection jobs run on behalf of the Collector.
+
+use prio::codec::{Encode, ParameterizedDecode, ParameterizedEncode};
+use rand::prelude::*;
+use url::Url;
+
+use crate::{
+    auth::BearerToken,
+    constants::DapMediaType,
+    fatal_error,
+    hpke::HpkeDecrypter,
+    messages::{
+        Base64Encode, BatchSelector, Collection, CollectionJobId, CollectionReq, Duration,
+        Interval, PartialBatchSelector, Query, TaskId,
+    },
+    DapAggregateResult, DapAggregationParam, DapError, DapRetryPolicy, DapVersion, VdafConfig,
+};
+
+use super::{
+    http::{DapHttpClient, DapHttpMethod, DapHttpRequest},
+    send_with_retries,
+};
+
+/// Collector configuration.
+#[derive(Clone, Debug)]
+pub struct DapCollectorConfig {
+    /// Policy for retrying requests that fail due to a transient error.
+    pub retry_policy: DapRetryPolicy,
+
+    /// Policy for polling a collection job until it completes. Each poll counts as an attempt.
+    /// If the Leader responds with a "retry-after" header, then its value is used instead of the
+    /// backoff.
+    pub poll_policy: DapRetryPolicy,
+}
+
+impl Default for DapCollectorConfig {
+    fn default() -> Self {
+        Self {
+            retry_policy: DapRetryPolicy::default(),
+            poll_policy: DapRetryPolicy {
+                max_attempts: 60,
+                initial_backoff: 1,
+                max_backoff: 60,
+            },
+        }
+    }
+}
+
+/// The parameters of a task needed by the Collector.
+#[derive(Clone, Debug)]
+pub struct DapCollectorTask {
+    pub task_id: TaskId,
+    pub version: DapVersion,
+
+    /// Base URL of the Leader, including the version, e.g., `https://leader.example.com/v09/`.
+    pub leader_url: Url,
+
+    pub vdaf: VdafConfig,
+
+    /// Token used to authorize requests to the Leader, if any.
+    pub collector_auth_token: Option<BearerToken>,
+}
+
+/// A collection job started by the Collector.
+#[derive(Clone, Debug)]
+pub struct DapCollectorJob {
+    /// URI at which the collection job is polled.
+    pub uri: Url,
+    pub query: Query,
+    pub agg_param: DapAggregationParam,
+}
+
+/// The result of a collection job.
+#[derive(Debug)]
+pub struct DapCollection {
+    /// The batch that was collected.
+    pub batch_sel: BatchSelector,
+
+    /// Number of reports in the batch.
+    pub report_count: u64,
+
+    /// Smallest interval containing the timestamps of the reports in the batch. draft02 Leaders
+    /// don't report the interval, so for draft02 this is the batch interval of time-interval
+    /// queries and not set for fixed-size queries.
+    pub interval: Option<Interval>,
+
+    pub result: DapAggregateResult,
+}
+
+enum CollectionJobStatus {
+    Done(Collection),
+    Pending { retry_after: Option<Duration> },
+}
+
+/// Client for running collection jobs.
+pub struct DapCollector<H> {
+    http: H,
+    config: DapCollectorConfig,
+}
+
+impl<H: DapHttpClient> DapCollector<H> {
+    pub fn new(http: H, config: DapCollectorConfig) -> Self {
+        Self { http, config }
+    }
+
+    /// Collect the aggregate result for the query: start a collection job, then poll it until it
+    /// completes.
+    pub async fn collect(
+        &self,
+        task: &DapCollectorTask,
+        decrypter: &impl HpkeDecrypter,
+        query: Query,
+        agg_param: DapAggregationParam,
+    ) -> Result<DapCollection, DapError> {
+        let job = self.start_collection_job(task, query, agg_param).await?;
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let retry_after = match self.poll_once(task, &job).await? {
+                CollectionJobStatus::Done(collection) => {
+                    return consume_collection(task, &job, decrypter, collection).await;
+                }
+                CollectionJobStatus::Pending { retry_after } => retry_after,
+            };
+            if attempts >= self.config.poll_policy.max_attempts {
+                return Err(DapError::Transient(format!(
+                    "collection job {} did not complete after {attempts} polls",
+                    job.uri
+                )));
+            }
+            self.http
+                .sleep(retry_after.unwrap_or_else(|| self.config.poll_policy.backoff(attempts)))
+                .await;
+        }
+    }
+
+    /// Start a collection job. The job is polled with [`Self::poll_collection_job`].
+    pub async fn start_collection_job(
+        &self,
+        task: &DapCollectorTask,
+        query: Query,
+        agg_param: DapAggregationParam,
+    ) -> Result<DapCollectorJob, DapError> {
+        let (method, path) = match task.version {
+            DapVersion::Draft02 => (DapHttpMethod::Post, "collect".to_string()),
+            DapVersion::Draft09 | DapVersion::Latest => {
+                let coll_job_id = CollectionJobId(thread_rng().gen());
+                (
+                    DapHttpMethod::Put,
+                    format!(
+                        "tasks/{}/collection_jobs/{}",
+                        task.task_id.to_base64url(),
+                        coll_job_id.to_base64url()
+                    ),
+                )
+            }
+        };
+        let url = task
+            .leader_url
+            .join(&path)
+            .map_err(|e| fatal_error!(err = ?e, "failed to construct collect URL"))?;
+
+        let coll_job_req = CollectionReq {
+            draft02_task_id: (task.version == DapVersion::Draft02).then_some(task.task_id),
+            query: query.clone(),
+            agg_param: agg_param.get_encoded().map_err(DapError::encoding)?,
+        };
+        let body = coll_job_req
+            .get_encoded_with_param(&task.version)
+            .map_err(DapError::encoding)?;
+        let resp = send_with_retries(
+            &self.http,
+            &self.config.retry_policy,
+            request(task, method, url.clone(), body)?,
+        )
+        .await?;
+
+        let uri = match task.version {
+            // The Leader redirects us to the URI at which the collection job is polled.
+            DapVersion::Draft02 => {
+                let location = resp.header("location").ok_or_else(|| {
+                    fatal_error!(err = "collect response is missing the location header")
+                })?;
+                url.join(location).map_err(
+                    |e| fatal_error!(err = ?e, %location, "Leader responded with invalid URI"),
+                )?
+            }
+            DapVersion::Draft09 | DapVersion::Latest => url,
+        };
+
+        Ok(DapCollectorJob {
+            uri,
+            query,
+            agg_param,
+        })
+    }
+
+    /// Poll a collection job once. Returns `None` if the job has not yet completed.
+    pub async fn poll_collection_job(
+        &self,
+        task: &DapCollectorTask,
+        job: &DapCollectorJob,
+        decrypter: &impl HpkeDecrypter,
+    ) -> Result<Option<DapCollection>, DapError> {
+        match self.poll_once(task, job).await? {
+            CollectionJobStatus::Done(collection) => {
+                consume_collection(task, job, decrypter, collection)
+                    .await
+                    .map(Some)
+            }
+            CollectionJobStatus::Pending { .. } => Ok(None),
+        }
+    }
+
+    async fn poll_once(
+        &self,
+        task: &DapCollectorTask,
+        job: &DapCollectorJob,
+    ) -> Result<CollectionJobStatus, DapError> {
+        let method = match task.version {
+            DapVersion::Draft02 => DapHttpMethod::Get,
+            DapVersion::Draft09 | DapVersion::Latest => DapHttpMethod::Post,
+        };
+        let resp = send_with_retries(
+            &self.http,
+            &self.config.retry_policy,
+            request(task, method, job.uri.clone(), Vec::new())?,
+        )
+        .await?;
+
+        match resp.status {
+            200 => Ok(CollectionJobStatus::Done(
+                Collection::get_decoded_with_param(&task.version, &resp.body)
+                    .map_err(DapError::encoding)?,
+            )),
+            202 => Ok(CollectionJobStatus::Pending {
+                retry_after: resp
+                    .header("retry-after")
+                    .and_then(|retry_after| retry_after.trim().parse().ok()),
+            }),
+            status => Err(fatal_error!(
+                err = "unexpected response to collection job poll",
+                status
+            )),
+        }
+    }
+}
+
+/// Decrypt and unshard the aggregate shares in the Leader's response.
+async fn consume_collection(
+    task: &DapCollectorTask,
+    job: &DapCollectorJob,
+    decrypter: &impl HpkeDecrypter,
+    collection: Collection,
+) -> Result<DapCollection, DapError> {
+    let batch_sel = match (job.query.clone(), collection.part_batch_sel) {
+        (Query::TimeInterval { batch_interval }, PartialBatchSelector::TimeInterval) => {
+            BatchSelector::TimeInterval { batch_interval }
+        }
+        (
+            Query::FixedSizeByBatchId { batch_id },
+            PartialBatchSelector::FixedSizeByBatchId {
+                batch_id: collected_batch_id,
+            },
+        ) if batch_id == collected_batch_id => BatchSelector::FixedSizeByBatchId { batch_id },
+        (Query::FixedSizeCurrentBatch, PartialBatchSelector::FixedSizeByBatchId { batch_id }) => {
+            BatchSelector::FixedSizeByBatchId { batch_id }
+        }
+        (query, part_batch_sel) => {
+            return Err(fatal_error!(
+                err = "collection does not match the query",
+                ?query,
+                %part_batch_sel
+            ))
+        }
+    };
+
+    let result = task
+        .vdaf
+        .consume_encrypted_agg_shares(
+            decrypter,
+            &task.task_id,
+            &batch_sel,
+            collection.report_count,
+            &job.agg_param,
+            collection.encrypted_agg_shares.into(),
+            task.version,
+        )
+        .await?;
+
+    let interval = match (collection.draft09_interval, &batch_sel) {
+        (Some(interval), _) => Some(interval),
+        (None, BatchSelector::TimeInterval { batch_interval }) => Some(batch_interval.clone()),
+        (None, BatchSelector::FixedSizeByBatchId { .. }) => None,
+    };
+
+    Ok(DapCollection {
+        batch_sel,
+        report_count: collection.report_count,
+        interval,
+        result,
+    })
+}
+
+fn request(
+    task: &DapCollectorTask,
+    method: DapHttpMethod,
+    url: Url,
+    body: Vec<u8>,
+) -> Result<DapHttpRequest, DapError> {
+    let content_type = DapMediaType::CollectReq
+        .as_str_for_version(task.version)
+        .ok_or_else(
+            || fatal_error!(err = "collect request has no media type", version = ?task.version),
+        )?;
+    let mut headers = vec![("content-type", content_type.to_string())];
+    if let Some(ref token) = task.collector_auth_token {
+        headers.push(("dap-auth-token", token.as_str().to_string()));
+    }
+    Ok(DapHttpRequest {
+        method,
+        url,
+        headers,
+        body,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use assert_matches::assert_matches;
+    use prio::{
+        codec::{ParameterizedDecode, ParameterizedEncode},
+        field::Field64,
+        vdaf::{AggregateShare, OutputShare},
+    };
+    use url::Url;
+
+    use super::{DapCollector, DapCollectorConfig, DapCollectorJob, DapCollectorTask};
+    use crate::{
+        async_test_version, async_test_versions,
+        client::{
+            http::{DapHttpMethod, DapHttpResponse},
+            test::{ok, MockHttpClient},
+        },
+        hpke::HpkeKemId,
+        messages::{
+            encode_base64url, BatchId, BatchSelector, Collection, CollectionReq, Interval, Query,
+        },
+        testing::AggregationJobTest,
+        vdaf::{Prio3Config, VdafAggregateShare, VdafConfig},
+        DapAggregateResult, DapAggregateShare, DapAggregationParam, DapError, DapRetryPolicy,
+        DapVersion,
+    };
+
+    const TEST_VDAF: &VdafConfig = &VdafConfig::Prio3(Prio3Config::Count);
+
+    struct Test {
+        t: AggregationJobTest,
+        task: DapCollectorTask,
+    }
+
+    impl Test {
+        fn new(version: DapVersion) -> Self {
+            let t = AggregationJobTest::new(TEST_VDAF, HpkeKemId::X25519HkdfSha256, version);
+            let task = DapCollectorTask {
+                task_id: t.task_id,
+                version,
+                leader_url: Url::parse("https://leader.example.com/v09/").unwrap(),
+                vdaf: *TEST_VDAF,
+                collector_auth_token: Some("collector token".into()),
+            };
+            Self { t, task }
+        }
+
+        fn collection_resp(
+            &self,
+            batch_sel: &BatchSelector,
+            draft09_interval: Interval,
+        ) -> DapHttpResponse {
+            let agg_share = |value| DapAggregateShare {
+                report_count: 50,
+                min_time: 1_637_359_200,
+                max_time: 1_637_359_200,
+                checksum: [0; 32],
+                data: Some(VdafAggregateShare::Field64(AggregateShare::from(
+                    OutputShare::from(vec![Field64::from(value)]),
+                ))),
+            };
+            let collection = Collection {
+                part_batch_sel: batch_sel.clone().into(),
+                report_count: 50,
+                draft09_interval: (self.task.version != DapVersion::Draft02)
+                    .then_some(draft09_interval),
+                encrypted_agg_shares: [
+                    self.t.produce_leader_encrypted_agg_share(
+                        batch_sel,
+                        &DapAggregationParam::Empty,
+                        &agg_share(23),
+                    ),
+                    self.t.produce_helper_encrypted_agg_share(
+                        batch_sel,
+                        &DapAggregationParam::Empty,
+                        &agg_share(9),
+                    ),
+                ],
+            };
+            DapHttpResponse {
+                status: 200,
+                headers: Vec::new(),
+                body: collection
+                    .get_encoded_with_param(&self.task.version)
+                    .unwrap(),
+            }
+        }
+
+        fn push_coll_job_created(&self, http: &MockHttpClient) {
+            http.push(match self.task.version {
+                DapVersion::Draft02 => DapHttpResponse {
+                    status: 303,
+                    headers: vec![(
+                        "location".into(),
+                        "https://leader.example.com/v09/collect/task/abc/req/def".into(),
+                    )],
+                    body: Vec::new(),
+                },
+                DapVersion::Draft09 | DapVersion::Latest => DapHttpResponse {
+                    status: 201,
+                    ..Default::default()
+                },
+            });
+        }
+    }
+
+    fn pending(retry_after: Option<&str>) -> DapHttpResponse {
+        DapHttpResponse {
+            status: 202,
+            headers: retry_after
+                .map(|retry_after| ("retry-after".into(), retry_after.into()))
+                .into_iter()
+                .collect(),
+            body: Vec::new(),
+        }
+    }
+
+    async fn collect_time_interval(version: DapVersion) {
+        let t = Test::new(version);
+        let batch_interval = Interval {
+            start: 1_637_359_200,
+            duration: 7200,
+        };
+        let batch_sel = BatchSelector::TimeInterval {
+            batch_interval: batch_interval.clone(),
+        };
+        let http = MockHttpClient::default();
+        t.push_coll_job_created(&http);
+        http.push(pending(None));
+        http.push(pending(Some("30")));
+        http.push(t.collection_resp(
+            &batch_sel,
+            Interval {
+                start: 1_637_359_200,
+                duration: 3600,
+            },
+        ));
+
+        let collector = DapCollector::new(http, DapCollectorConfig::default());
+        let collection = collector
+            .collect(
+                &t.task,
+                &t.t.collector_hpke_receiver_config,
+                batch_sel.clone().into(),
+                DapAggregationParam::Empty,
+            )
+            .await
+            .unwrap();
+        assert_eq!(collection.batch_sel, batch_sel);
+        assert_eq!(collection.report_count, 50);
+        assert_eq!(collection.result, DapAggregateResult::U64(32));
+        match version {
+            DapVersion::Draft02 => assert_eq!(collection.interval, Some(batch_interval)),
+            DapVersion::Draft09 | DapVersion::Latest => assert_eq!(
+                collection.interval,
+                Some(Interval {
+                    start: 1_637_359_200,
+                    duration: 3600,
+                })
+            ),
+        }
+
+        // The first poll backs off, the second honors the Leader's "retry-after".
+        assert_eq!(*collector.http.sleeps.lock().unwrap(), [1, 30]);
+
+        let reqs = collector.http.requests();
+        assert_eq!(reqs.len(), 4);
+        for req in &reqs {
+            assert!(req
+                .headers
+                .contains(&("dap-auth-token", "collector token".to_string())));
+        }
+        let coll_job_req = CollectionReq::get_decoded_with_param(&version, &reqs[0].body).unwrap();
+        assert_eq!(coll_job_req.query, Query::from(batch_sel));
+        match version {
+            DapVersion::Draft02 => {
+                assert_eq!(reqs[0].method, DapHttpMethod::Post);
+                assert_eq!(reqs[0].url.path(), "/v09/collect");
+                assert_eq!(coll_job_req.draft02_task_id, Some(t.task.task_id));
+                for req in &reqs[1..] {
+                    assert_eq!(req.method, DapHttpMethod::Get);
+                    assert_eq!(req.url.path(), "/v09/collect/task/abc/req/def");
+                }
+            }
+            DapVersion::Draft09 | DapVersion::Latest => {
+                assert_eq!(reqs[0].method, DapHttpMethod::Put);
+                assert!(reqs[0].url.path().starts_with(&format!(
+                    "/v09/tasks/{}/collection_jobs/",
+                    encode_base64url(t.task.task_id.0)
+                )));
+                for req in &reqs[1..] {
+                    assert_eq!(req.method, DapHttpMethod::Post);
+                    assert_eq!(req.url, reqs[0].url);
+                }
+            }
+        }
+    }
+
+    async fn collect_fixed_size_current_batch(version: DapVersion) {
+        let t = Test::new(version);
+        let batch_sel = BatchSelector::FixedSizeByBatchId {
+            batch_id: BatchId([7; 32]),
+        };
+        let http = MockHttpClient::default();
+        t.push_coll_job_created(&http);
+        let interval = Interval {
+            start: 1_637_359_200,
+            duration: 3600,
+        };
+        http.push(t.collection_resp(&batch_sel, interval.clone()));
+
+        let collector = DapCollector::new(http, DapCollectorConfig::default());
+        let collection = collector
+            .collect(
+                &t.task,
+                &t.t.collector_hpke_receiver_config,
+                Query::FixedSizeCurrentBatch,
+                DapAggregationParam::Empty,
+            )
+            .await
+            .unwrap();
+        assert_eq!(collection.batch_sel, batch_sel);
+        // draft02 Leaders don't report the interval, and it can't be derived from a fixed-size
+        // batch.
+        let expected_interval = (version != DapVersion::Draft02).then_some(interval);
+        assert_eq!(collection.interval, expected_interval);
+        assert_eq!(collection.result, DapAggregateResult::U64(32));
+    }
+
+    async fn collect_mismatched_batch(version: DapVersion) {
+        let t = Test::new(version);
+        let http = MockHttpClient::default();
+        t.push_coll_job_created(&http);
+        http.push(t.collection_resp(
+            &BatchSelector::FixedSizeByBatchId {
+                batch_id: BatchId([7; 32]),
+            },
+            Interval {
+                start: 1_637_359_200,
+                duration: 3600,
+            },
+        ));
+
+        let collector = DapCollector::new(http, DapCollectorConfig::default());
+        assert_matches!(
+            collector
+                .collect(
+                    &t.task,
+                    &t.t.collector_hpke_receiver_config,
+                    Query::FixedSizeByBatchId {
+                        batch_id: BatchId([8; 32]),
+                    },
+                    DapAggregationParam::Empty,
+                )
+                .await,
+            Err(DapError::Fatal(..))
+        );
+    }
+
+    async fn collect_gives_up_after_max_polls(version: DapVersion) {
+        let t = Test::new(version);
+        let query = Query::TimeInterval {
+            batch_interval: Interval {
+                start: 1_637_359_200,
+                duration: 7200,
+            },
+        };
+        let http = MockHttpClient::default();
+        t.push_coll_job_created(&http);
+        for _ in 0..3 {
+            http.push(pending(None));
+        }
+
+        let collector = DapCollector::new(
+            http,
+            DapCollectorConfig {
+                poll_policy: DapRetryPolicy {
+                    max_attempts: 3,
+                    initial_backoff: 2,
+                    max_backoff: 3,
+                },
+                ..Default::default()
+            },
+        );
+        assert_matches!(
+            collector
+                .collect(
+                    &t.task,
+                    &t.t.collector_hpke_receiver_config,
+                    query.clone(),
+                    DapAggregationParam::Empty,
+                )
+                .await,
+            Err(DapError::Transient(..))
+        );
+        assert_eq!(*collector.http.sleeps.lock().unwrap(), [2, 3]);
+
+        // The job can also be polled one request at a time.
+        let job = DapCollectorJob {
+            uri: collector.http.requests()[0].url.clone(),
+            query,
+            agg_param: DapAggregationParam::Empty,
+        };
+        collector.http.push(pending(None));
+        assert!(collector
+            .poll_collection_job(&t.task, &job, &t.t.collector_hpke_receiver_config)
+            .await
+            .unwrap()
+            .is_none());
+        // A malformed collection is rejected.
+        collector.http.push(ok());
+        assert_matches!(
+            collector
+                .poll_collection_job(&t.task, &job, &t.t.collector_hpke_receiver_config)
+                .await,
+            Err(DapError::Fatal(..))
+        );
+    }
+
+    async_test_versions! {
+        collect_time_interval,
+        collect_mismatched_batch,
+        collect_gives_up_after_max_polls
+    }
+
+    // draft02 doesn't support querying the current batch.
+    async_test_version! { collect_fixed_size_current_batch, Draft09 }
+    async_test_version! { collect_fixed_size_current_batch, Latest }
+}
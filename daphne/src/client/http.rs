@@ -0,0 +1,104 @@
+// Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! HTTP transport for clients of a DAP deployment.
+
+use async_trait::async_trait;
+use url::Url;
+
+use crate::{
+    error::aborts::ProblemDetails,
+    fatal_error,
+    messages::{Duration, Time},
+    DapError,
+};
+
+/// HTTP request method.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DapHttpMethod {
+    Get,
+    Post,
+    Put,
+    Delete,
+}
+
+/// An HTTP request sent by a client.
+#[derive(Clone, Debug)]
+pub struct DapHttpRequest {
+    pub method: DapHttpMethod,
+    pub url: Url,
+
+    /// Request headers. Header names are lower case.
+    pub headers: Vec<(&'static str, String)>,
+
+    pub body: Vec<u8>,
+}
+
+/// An HTTP response received by a client.
+#[derive(Clone, Debug, Default)]
+pub struct DapHttpResponse {
+    pub status: u16,
+
+    /// Response headers. Header names are lower case.
+    pub headers: Vec<(String, String)>,
+
+    pub body: Vec<u8>,
+}
+
+impl DapHttpResponse {
+    /// Return the value of the first header with the given (lower case) name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _value)| header == name)
+            .map(|(_header, value)| value.as_str())
+    }
+}
+
+/// The HTTP stack and clock used by a client of a DAP deployment.
+#[async_trait]
+pub trait DapHttpClient: Sync {
+    /// Send an HTTP request. The status of the response is not checked. Failures that may not
+    /// recur if the request is retried, e.g., timeouts, should be reported as
+    /// [`DapError::Transient`].
+    async fn send(&self, req: DapHttpRequest) -> Result<DapHttpResponse, DapError>;
+
+    /// Return the number of seconds since the UNIX epoch.
+    fn get_current_time(&self) -> Time;
+
+    /// Wait for the given number of seconds, e.g., before retrying a request.
+    async fn sleep(&self, duration: Duration);
+}
+
+/// Send an HTTP request and check that the response indicates success. Error responses are mapped
+/// to [`DapError`] the same way the Leader treats error responses from the Helper.
+pub(crate) async fn send_checked(
+    http: &impl DapHttpClient,
+    req: DapHttpRequest,
+) -> Result<DapHttpResponse, DapError> {
+    let url = req.url.clone();
+    let resp = http.send(req).await?;
+    match resp.status {
+        // draft02: The Leader responds to a collect request with "303 See Other". The caller is
+        // expected to follow the redirect.
+        200..=299 | 303 => Ok(resp),
+
+        // The peer is temporarily unable to handle the request.
+        408 | 429 | 500.. => Err(DapError::Transient(format!(
+            "request to {url} failed with status {}",
+            resp.status
+        ))),
+
+        400 if resp.header("content-type") == Some("application/problem+json") => {
+            let problem: ProblemDetails = serde_json::from_slice(&resp.body)
+                .map_err(|e| fatal_error!(err = ?e, "failed to parse problem details"))?;
+            Err(DapError::from_problem_details(problem))
+        }
+
+        status => Err(fatal_error!(
+            err = "request aborted by peer",
+            %url,
+            status
+        )),
+    }
+}
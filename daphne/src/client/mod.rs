@@ -0,0 +1,716 @@
+// Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Clients of a DAP deployment.
+//!
+//! [`DapClient`] uploads reports to the Leader. It takes care of fetching the Aggregators' HPKE
+//! configs (and caching them), rounding the report timestamp to the task's time precision, and
+//! retrying uploads that fail due to a transient error.
+//!
+//! [`collector::DapCollector`] runs collection jobs on behalf of the Collector.
+
+pub mod collector;
+pub mod http;
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
+
+use prio::codec::{CodecError, Decode, ParameterizedEncode};
+use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_ASN1};
+use url::Url;
+
+use crate::{
+    constants::DapMediaType,
+    error::DapAbort,
+    fatal_error,
+    hpke::HpkeConfig,
+    messages::{
+        decode_base64url_vec, Base64Encode, Duration, Extension, HpkeConfigList, TaskId, Time,
+    },
+    DapError, DapMeasurement, DapRetryPolicy, DapVersion, VdafConfig,
+};
+
+use self::http::{send_checked, DapHttpClient, DapHttpMethod, DapHttpRequest, DapHttpResponse};
+
+/// HTTP header used by Aggregators to sign their HPKE configs.
+pub(crate) const HPKE_CONFIG_SIGNATURE_HEADER: &str = "x-hpke-config-signature";
+
+/// Client configuration.
+#[derive(Clone, Debug)]
+pub struct DapClientConfig {
+    /// If set, then the HPKE configs served by the Aggregators must be signed by this key. The
+    /// key is an ECDSA P-256 public key, SEC1-encoded.
+    pub hpke_config_verification_key: Option<Vec<u8>>,
+
+    /// Amount of time (in seconds) for which an HPKE config is cached if the response doesn't
+    /// indicate how long it may be cached for.
+    pub default_hpke_config_ttl: Duration,
+
+    /// Policy for retrying requests that fail due to a transient error.
+    pub retry_policy: DapRetryPolicy,
+}
+
+impl Default for DapClientConfig {
+    fn default() -> Self {
+        Self {
+            hpke_config_verification_key: None,
+            default_hpke_config_ttl: 3600,
+            retry_policy: DapRetryPolicy::default(),
+        }
+    }
+}
+
+/// The parameters of a task needed by the Client.
+#[derive(Clone, Debug)]
+pub struct DapClientTask {
+    pub task_id: TaskId,
+    pub version: DapVersion,
+
+    /// Base URL of the Leader, including the version, e.g., `https://leader.example.com/v09/`.
+    pub leader_url: Url,
+
+    /// Base URL of the Helper, including the version.
+    pub helper_url: Url,
+
+    pub vdaf: VdafConfig,
+
+    /// Report timestamps are rounded down to a multiple of this value.
+    pub time_precision: Duration,
+}
+
+struct CachedHpkeConfig {
+    config: HpkeConfig,
+    expires_at: Time,
+}
+
+/// Client for uploading reports to a DAP deployment.
+pub struct DapClient<H> {
+    http: H,
+    config: DapClientConfig,
+    hpke_config_cache: Mutex<HashMap<(Url, TaskId), CachedHpkeConfig>>,
+}
+
+impl<H: DapHttpClient> DapClient<H> {
+    pub fn new(http: H, config: DapClientConfig) -> Self {
+        Self {
+            http,
+            config,
+            hpke_config_cache: Mutex::default(),
+        }
+    }
+
+    /// Get the HPKE config the Aggregator at `base_url` uses for the given task. The config is
+    /// fetched from the Aggregator unless there is an unexpired copy in the cache.
+    pub async fn get_hpke_config(
+        &self,
+        base_url: &Url,
+        task_id: &TaskId,
+        version: DapVersion,
+    ) -> Result<HpkeConfig, DapError> {
+        let now = self.http.get_current_time();
+        let key = (base_url.clone(), *task_id);
+        if let Some(cached) = self.cache().get(&key) {
+            if cached.expires_at > now {
+                return Ok(cached.config.clone());
+            }
+        }
+
+        let mut url = base_url
+            .join("hpke_config")
+            .map_err(|e| fatal_error!(err = ?e, "failed to construct hpke_config URL"))?;
+        url.query_pairs_mut()
+            .append_pair("task_id", &task_id.to_base64url());
+        let resp = self
+            .send_with_retries(DapHttpRequest {
+                method: DapHttpMethod::Get,
+                url,
+                headers: Vec::new(),
+                body: Vec::new(),
+            })
+            .await?;
+
+        if let Some(ref verification_key) = self.config.hpke_config_verification_key {
+            verify_hpke_config_signature(verification_key, &resp)?;
+        }
+
+        let config = match version {
+            DapVersion::Draft02 => HpkeConfig::get_decoded(&resp.body),
+            DapVersion::Draft09 | DapVersion::Latest => HpkeConfigList::get_decoded(&resp.body)
+                .map(|list| list.hpke_configs)
+                .and_then(|configs| {
+                    configs
+                        .into_iter()
+                        .next()
+                        .ok_or(CodecError::UnexpectedValue)
+                }),
+        }
+        .map_err(DapError::encoding)?;
+
+        if let Some(ttl) = hpke_config_ttl(&resp, self.config.default_hpke_config_ttl) {
+            self.cache().insert(
+                key,
+                CachedHpkeConfig {
+                    config: config.clone(),
+                    expires_at: now.saturating_add(ttl),
+                },
+            );
+        }
+        Ok(config)
+    }
+
+    /// Generate a report for the measurement and upload it to the Leader.
+    pub async fn upload(
+        &self,
+        task: &DapClientTask,
+        measurement: DapMeasurement,
+    ) -> Result<(), DapError> {
+        self.upload_with_extensions(task, measurement, Vec::new())
+            .await
+    }
+
+    /// Generate a report for the measurement with the given extensions and upload it to the
+    /// Leader.
+    ///
+    /// If the Leader rejects the report, then the cached HPKE configs for the task are discarded,
+    /// since the most likely cause is that an Aggregator has rotated its config. The report is
+    /// re-encrypted with fresh configs and uploaded once more.
+    pub async fn upload_with_extensions(
+        &self,
+        task: &DapClientTask,
+        measurement: DapMeasurement,
+        extensions: Vec<Extension>,
+    ) -> Result<(), DapError> {
+        let res = self
+            .try_upload(task, measurement.clone(), extensions.clone())
+            .await;
+        if let Err(DapError::PeerAbort {
+            abort: DapAbort::ReportRejected { .. },
+            ..
+        }) = res
+        {
+            if self.invalidate_hpke_configs(task) {
+                return self.try_upload(task, measurement, extensions).await;
+            }
+        }
+        res
+    }
+
+    async fn try_upload(
+        &self,
+        task: &DapClientTask,
+        measurement: DapMeasurement,
+        extensions: Vec<Extension>,
+    ) -> Result<(), DapError> {
+        let (leader_hpke_config, helper_hpke_config) = futures::try_join!(
+            self.get_hpke_config(&task.leader_url, &task.task_id, task.version),
+            self.get_hpke_config(&task.helper_url, &task.task_id, task.version),
+        )?;
+
+        let now = self.http.get_current_time();
+        let time = now - (now % task.time_precision);
+        let report = task.vdaf.produce_report_with_extensions(
+            &[leader_hpke_config, helper_hpke_config],
+            time,
+            &task.task_id,
+            measurement,
+            extensions,
+            task.version,
+        )?;
+
+        let (method, path) = match task.version {
+            DapVersion::Draft02 => (DapHttpMethod::Post, "upload".to_string()),
+            DapVersion::Draft09 | DapVersion::Latest => (
+                DapHttpMethod::Put,
+                format!("tasks/{}/reports", task.task_id.to_base64url()),
+            ),
+        };
+        let url = task
+            .leader_url
+            .join(&path)
+            .map_err(|e| fatal_error!(err = ?e, "failed to construct upload URL"))?;
+        let content_type = DapMediaType::Report
+            .as_str_for_version(task.version)
+            .ok_or_else(
+                || fatal_error!(err = "report has no media type", version = ?task.version),
+            )?;
+        let body = report
+            .get_encoded_with_param(&task.version)
+            .map_err(DapError::encoding)?;
+
+        self.send_with_retries(DapHttpRequest {
+            method,
+            url,
+            headers: vec![("content-type", content_type.to_string())],
+            body,
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Remove the task's HPKE configs from the cache. Returns `true` if any were removed.
+    fn invalidate_hpke_configs(&self, task: &DapClientTask) -> bool {
+        let mut cache = self.cache();
+        let leader = cache.remove(&(task.leader_url.clone(), task.task_id));
+        let helper = cache.remove(&(task.helper_url.clone(), task.task_id));
+        leader.is_some() || helper.is_some()
+    }
+
+    /// Send a request, retrying it according to the retry policy if it fails due to a transient
+    /// error.
+    pub(crate) async fn send_with_retries(
+        &self,
+        req: DapHttpRequest,
+    ) -> Result<DapHttpResponse, DapError> {
+        send_with_retries(&self.http, &self.config.retry_policy, req).await
+    }
+
+    fn cache(&self) -> MutexGuard<'_, HashMap<(Url, TaskId), CachedHpkeConfig>> {
+        self.hpke_config_cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Send a request, retrying it according to `retry_policy` if it fails due to a transient error.
+pub(crate) async fn send_with_retries(
+    http: &impl DapHttpClient,
+    retry_policy: &DapRetryPolicy,
+    req: DapHttpRequest,
+) -> Result<DapHttpResponse, DapError> {
+    let mut failed_attempts = 0;
+    loop {
+        match send_checked(http, req.clone()).await {
+            Err(DapError::Transient(reason)) if failed_attempts + 1 < retry_policy.max_attempts => {
+                failed_attempts += 1;
+                tracing::debug!(failed_attempts, %reason, "retrying request");
+                http.sleep(retry_policy.backoff(failed_attempts)).await;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Check the signature the Aggregator attached to its HPKE config.
+fn verify_hpke_config_signature(
+    verification_key: &[u8],
+    resp: &DapHttpResponse,
+) -> Result<(), DapError> {
+    let signature = resp
+        .header(HPKE_CONFIG_SIGNATURE_HEADER)
+        .ok_or_else(|| fatal_error!(err = "aggregator did not sign its HPKE config"))?;
+    let signature = decode_base64url_vec(signature)
+        .ok_or_else(|| fatal_error!(err = "failed to decode HPKE config signature"))?;
+    UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, verification_key)
+        .verify(&resp.body, &signature)
+        .map_err(|_| fatal_error!(err = "HPKE config signature not verified"))
+}
+
+/// Determine how long the HPKE config in the response may be cached for, honoring the
+/// `cache-control` header. Returns `None` if it may not be cached.
+fn hpke_config_ttl(resp: &DapHttpResponse, default_ttl: Duration) -> Option<Duration> {
+    let Some(cache_control) = resp.header("cache-control") else {
+        return Some(default_ttl);
+    };
+    let mut ttl = default_ttl;
+    for directive in cache_control.split(',').map(str::trim) {
+        let (name, value) = directive.split_once('=').unwrap_or((directive, ""));
+        match name.to_ascii_lowercase().as_str() {
+            "no-store" | "no-cache" => return None,
+            "max-age" => ttl = value.trim_matches('"').parse().ok()?,
+            _ => (),
+        }
+    }
+    (ttl > 0).then_some(ttl)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::VecDeque,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Mutex,
+        },
+    };
+
+    use assert_matches::assert_matches;
+    use async_trait::async_trait;
+    use prio::codec::{Encode, ParameterizedDecode};
+    use rand::prelude::*;
+    use ring::{
+        rand::SystemRandom,
+        signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING},
+    };
+    use url::Url;
+
+    use super::{
+        http::{DapHttpClient, DapHttpMethod, DapHttpRequest, DapHttpResponse},
+        DapClient, DapClientConfig, DapClientTask, HPKE_CONFIG_SIGNATURE_HEADER,
+    };
+    use crate::{
+        async_test_versions,
+        error::DapAbort,
+        hpke::{HpkeKemId, HpkeReceiverConfig},
+        messages::{encode_base64url, Duration, HpkeConfigList, Report, TaskId, Time},
+        vdaf::{Prio3Config, VdafConfig},
+        DapError, DapMeasurement, DapRetryPolicy, DapVersion,
+    };
+
+    /// HTTP client that replays canned responses and records the requests it was sent.
+    #[derive(Default)]
+    pub(crate) struct MockHttpClient {
+        pub(crate) now: AtomicU64,
+        pub(crate) responses: Mutex<VecDeque<DapHttpResponse>>,
+        pub(crate) requests: Mutex<Vec<DapHttpRequest>>,
+        pub(crate) sleeps: Mutex<Vec<Duration>>,
+    }
+
+    impl MockHttpClient {
+        pub(crate) fn push(&self, resp: DapHttpResponse) {
+            self.responses.lock().unwrap().push_back(resp);
+        }
+
+        pub(crate) fn requests(&self) -> Vec<DapHttpRequest> {
+            self.requests.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl DapHttpClient for MockHttpClient {
+        async fn send(&self, req: DapHttpRequest) -> Result<DapHttpResponse, DapError> {
+            self.requests.lock().unwrap().push(req);
+            Ok(self
+                .responses
+                .lock()
+                .unwrap()
+                .pop_front()
+                .expect("unexpected request"))
+        }
+
+        fn get_current_time(&self) -> Time {
+            self.now.load(Ordering::Relaxed)
+        }
+
+        async fn sleep(&self, duration: Duration) {
+            self.sleeps.lock().unwrap().push(duration);
+            self.now.fetch_add(duration, Ordering::Relaxed);
+        }
+    }
+
+    struct Test {
+        task: DapClientTask,
+        leader_hpke_receiver_config: HpkeReceiverConfig,
+        helper_hpke_receiver_config: HpkeReceiverConfig,
+    }
+
+    impl Test {
+        fn new(version: DapVersion) -> Self {
+            let mut rng = thread_rng();
+            Self {
+                task: DapClientTask {
+                    task_id: TaskId(rng.gen()),
+                    version,
+                    leader_url: Url::parse("https://leader.example.com/v09/").unwrap(),
+                    helper_url: Url::parse("https://helper.example.com/v09/").unwrap(),
+                    vdaf: VdafConfig::Prio3(Prio3Config::Count),
+                    time_precision: 3600,
+                },
+                leader_hpke_receiver_config: HpkeReceiverConfig::gen(
+                    1,
+                    HpkeKemId::X25519HkdfSha256,
+                )
+                .unwrap(),
+                helper_hpke_receiver_config: HpkeReceiverConfig::gen(
+                    2,
+                    HpkeKemId::X25519HkdfSha256,
+                )
+                .unwrap(),
+            }
+        }
+
+        fn hpke_config_resp(&self, receiver: &HpkeReceiverConfig) -> DapHttpResponse {
+            let body = match self.task.version {
+                DapVersion::Draft02 => receiver.config.get_encoded().unwrap(),
+                DapVersion::Draft09 | DapVersion::Latest => HpkeConfigList {
+                    hpke_configs: vec![receiver.config.clone()],
+                }
+                .get_encoded()
+                .unwrap(),
+            };
+            DapHttpResponse {
+                status: 200,
+                headers: Vec::new(),
+                body,
+            }
+        }
+
+        fn push_hpke_configs(&self, http: &MockHttpClient) {
+            http.push(self.hpke_config_resp(&self.leader_hpke_receiver_config));
+            http.push(self.hpke_config_resp(&self.helper_hpke_receiver_config));
+        }
+    }
+
+    pub(crate) fn ok() -> DapHttpResponse {
+        DapHttpResponse {
+            status: 200,
+            ..Default::default()
+        }
+    }
+
+    async fn upload(version: DapVersion) {
+        let t = Test::new(version);
+        let http = MockHttpClient::default();
+        http.now.store(1_700_001_234, Ordering::Relaxed);
+        t.push_hpke_configs(&http);
+        http.push(ok());
+
+        let client = DapClient::new(http, DapClientConfig::default());
+        client
+            .upload(&t.task, DapMeasurement::U64(1))
+            .await
+            .unwrap();
+
+        let reqs = client.http.requests();
+        assert_eq!(reqs.len(), 3);
+        assert_eq!(reqs[0].method, DapHttpMethod::Get);
+        assert_eq!(
+            reqs[0].url.as_str(),
+            format!(
+                "https://leader.example.com/v09/hpke_config?task_id={}",
+                encode_base64url(t.task.task_id.0)
+            )
+        );
+
+        let upload_req = &reqs[2];
+        match version {
+            DapVersion::Draft02 => {
+                assert_eq!(upload_req.method, DapHttpMethod::Post);
+                assert_eq!(upload_req.url.path(), "/v09/upload");
+            }
+            DapVersion::Draft09 | DapVersion::Latest => {
+                assert_eq!(upload_req.method, DapHttpMethod::Put);
+                assert_eq!(
+                    upload_req.url.path(),
+                    format!("/v09/tasks/{}/reports", encode_base64url(t.task.task_id.0))
+                );
+            }
+        }
+
+        // The report timestamp is rounded down to the time precision.
+        let report = Report::get_decoded_with_param(&version, &upload_req.body).unwrap();
+        assert_eq!(report.report_metadata.time, 1_699_999_200);
+        assert_eq!(
+            report.encrypted_input_shares[0].config_id,
+            t.leader_hpke_receiver_config.config.id
+        );
+        assert_eq!(
+            report.encrypted_input_shares[1].config_id,
+            t.helper_hpke_receiver_config.config.id
+        );
+    }
+
+    async fn upload_caches_hpke_configs(version: DapVersion) {
+        let t = Test::new(version);
+        let http = MockHttpClient::default();
+        t.push_hpke_configs(&http);
+        http.push(ok());
+        http.push(ok());
+
+        let client = DapClient::new(
+            http,
+            DapClientConfig {
+                default_hpke_config_ttl: 60,
+                ..Default::default()
+            },
+        );
+        client
+            .upload(&t.task, DapMeasurement::U64(1))
+            .await
+            .unwrap();
+        client
+            .upload(&t.task, DapMeasurement::U64(0))
+            .await
+            .unwrap();
+        assert_eq!(client.http.requests().len(), 4);
+
+        // Once the configs expire they are fetched again.
+        client.http.now.fetch_add(60, Ordering::Relaxed);
+        t.push_hpke_configs(&client.http);
+        client.http.push(ok());
+        client
+            .upload(&t.task, DapMeasurement::U64(1))
+            .await
+            .unwrap();
+        assert_eq!(client.http.requests().len(), 7);
+    }
+
+    async fn upload_honors_cache_control(version: DapVersion) {
+        let t = Test::new(version);
+        let http = MockHttpClient::default();
+        for _ in 0..2 {
+            let mut resp = t.hpke_config_resp(&t.leader_hpke_receiver_config);
+            resp.headers
+                .push(("cache-control".into(), "no-store".into()));
+            http.push(resp);
+            let mut resp = t.hpke_config_resp(&t.helper_hpke_receiver_config);
+            resp.headers
+                .push(("cache-control".into(), "public, max-age=600".into()));
+            http.push(resp);
+            http.push(ok());
+        }
+        // Drop the Helper's config from the second round of responses, since it is cached.
+        http.responses.lock().unwrap().remove(4);
+
+        let client = DapClient::new(http, DapClientConfig::default());
+        for _ in 0..2 {
+            client
+                .upload(&t.task, DapMeasurement::U64(1))
+                .await
+                .unwrap();
+        }
+        let urls = client
+            .http
+            .requests()
+            .into_iter()
+            .map(|req| req.url.host_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            urls,
+            [
+                "leader.example.com",
+                "helper.example.com",
+                "leader.example.com",
+                "leader.example.com",
+                "leader.example.com",
+            ]
+        );
+    }
+
+    async fn upload_retries_transient_errors(version: DapVersion) {
+        let t = Test::new(version);
+        let http = MockHttpClient::default();
+        t.push_hpke_configs(&http);
+        for status in [503, 429] {
+            http.push(DapHttpResponse {
+                status,
+                ..Default::default()
+            });
+        }
+        http.push(ok());
+
+        let client = DapClient::new(
+            http,
+            DapClientConfig {
+                retry_policy: DapRetryPolicy {
+                    max_attempts: 3,
+                    initial_backoff: 1,
+                    max_backoff: 10,
+                },
+                ..Default::default()
+            },
+        );
+        client
+            .upload(&t.task, DapMeasurement::U64(1))
+            .await
+            .unwrap();
+        assert_eq!(client.http.requests().len(), 5);
+        assert_eq!(*client.http.sleeps.lock().unwrap(), [1, 2]);
+
+        // Give up once the retry policy is exhausted.
+        for _ in 0..3 {
+            client.http.push(DapHttpResponse {
+                status: 500,
+                ..Default::default()
+            });
+        }
+        assert_matches!(
+            client.upload(&t.task, DapMeasurement::U64(1)).await,
+            Err(DapError::Transient(..))
+        );
+    }
+
+    async fn upload_refreshes_hpke_configs_when_rejected(version: DapVersion) {
+        let t = Test::new(version);
+        let http = MockHttpClient::default();
+        t.push_hpke_configs(&http);
+        http.push(ok());
+        http.push(DapHttpResponse {
+            status: 400,
+            headers: vec![("content-type".into(), "application/problem+json".into())],
+            body: serde_json::to_vec(
+                &DapAbort::ReportRejected {
+                    detail: "unknown HPKE config".into(),
+                }
+                .into_problem_details(),
+            )
+            .unwrap(),
+        });
+        t.push_hpke_configs(&http);
+        http.push(ok());
+
+        let client = DapClient::new(http, DapClientConfig::default());
+        for _ in 0..2 {
+            client
+                .upload(&t.task, DapMeasurement::U64(1))
+                .await
+                .unwrap();
+        }
+        assert_eq!(client.http.requests().len(), 7);
+    }
+
+    async fn get_hpke_config_verifies_signature(version: DapVersion) {
+        let t = Test::new(version);
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        let key_pair =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng)
+                .unwrap();
+
+        let mut signed = t.hpke_config_resp(&t.leader_hpke_receiver_config);
+        let signature = key_pair.sign(&rng, &signed.body).unwrap();
+        signed.headers.push((
+            HPKE_CONFIG_SIGNATURE_HEADER.into(),
+            encode_base64url(signature.as_ref()),
+        ));
+        let mut forged = t.hpke_config_resp(&t.helper_hpke_receiver_config);
+        forged.headers = signed.headers.clone();
+        let unsigned = t.hpke_config_resp(&t.helper_hpke_receiver_config);
+
+        let http = MockHttpClient::default();
+        http.push(signed);
+        http.push(forged);
+        http.push(unsigned);
+        let client = DapClient::new(
+            http,
+            DapClientConfig {
+                hpke_config_verification_key: Some(key_pair.public_key().as_ref().to_vec()),
+                ..Default::default()
+            },
+        );
+
+        let config = client
+            .get_hpke_config(&t.task.leader_url, &t.task.task_id, version)
+            .await
+            .unwrap();
+        assert_eq!(config, t.leader_hpke_receiver_config.config);
+        for _ in 0..2 {
+            assert_matches!(
+                client
+                    .get_hpke_config(&t.task.helper_url, &t.task.task_id, version)
+                    .await,
+                Err(DapError::Fatal(..))
+            );
+        }
+    }
+
+    async_test_versions! {
+        upload,
+        upload_caches_hpke_configs,
+        upload_honors_cache_control,
+        upload_retries_transient_errors,
+        upload_refreshes_hpke_configs_when_rejected,
+        get_hpke_config_verifies_signature
+    }
+}
@@ -23,6 +23,12 @@ const MEDIA_TYPE_COLLECT_REQ: &str = "application/dap-collect-req";
 const MEDIA_TYPE_HPKE_CONFIG_LIST: &str = "application/dap-hpke-config-list";
 const MEDIA_TYPE_REPORT: &str = "application/dap-report";
 
+/// HTTP header with which the Helper indicates that it is still processing an aggregation job. The
+/// header is set to [`DAP_AGG_JOB_STATUS_PROCESSING`] in the response to an aggregation job that the
+/// Helper accepted for asynchronous processing, or that is still being processed when polled.
+pub const DAP_AGG_JOB_STATUS_HEADER: &str = "dap-aggregation-job-status";
+pub const DAP_AGG_JOB_STATUS_PROCESSING: &str = "processing";
+
 /// Media type for each DAP request. This is included in the "content-type" HTTP header.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum DapMediaType {
@@ -18,7 +18,7 @@ use super::FatalDapError;
 // The display implementation of this error is used for metrics, as such, it can't be changed to
 // include field values
 /// DAP aborts.
-#[derive(Debug, thiserror::Error)]
+#[derive(Debug, PartialEq, Eq, thiserror::Error)]
 pub enum DapAbort {
     /// Bad request. Sent in response to an HTTP request that couldn't be handled preoprly.
     #[error("bad request")]
@@ -37,6 +37,11 @@ pub enum DapAbort {
     #[error("batchOverlap")]
     BatchOverlap { detail: String, task_id: TaskId },
 
+    /// Batch queried too many times. Sent in response to a CollectReq or AggregateShareReq for a
+    /// batch that has already been collected the maximum number of times allowed by the task.
+    #[error("batchQueriedTooManyTimes")]
+    BatchQueriedTooManyTimes { detail: String, task_id: TaskId },
+
     /// Invalid batch size (either too small or too large). Sent in response to a CollectReq or
     /// AggregateShareReq.
     #[error("invalidBatchSize")]
@@ -106,8 +111,9 @@ pub enum DapAbort {
 }
 
 impl DapAbort {
-    /// Construct a problem details JSON object for this abort. `url` is the URL to which the
-    /// request was targeted and `task_id` is the associated `TaskID`.
+    /// Construct a problem details JSON object for this abort. The `instance` member is not set,
+    /// since the abort doesn't know which request caused it; see
+    /// [`ProblemDetails::with_instance`].
     pub fn into_problem_details(self) -> ProblemDetails {
         let (title, typ) = self.title_and_type();
         let (task_id, detail, agg_job_id_base64url) = match self {
@@ -115,6 +121,7 @@ impl DapAbort {
             | Self::InvalidTask { detail, task_id }
             | Self::BatchMismatch { detail, task_id }
             | Self::BatchOverlap { detail, task_id }
+            | Self::BatchQueriedTooManyTimes { detail, task_id }
             | Self::InvalidBatchSize { detail, task_id }
             | Self::QueryMismatch { detail, task_id }
             | Self::UnauthorizedRequest { detail, task_id } => (Some(task_id), Some(detail), None),
@@ -148,11 +155,89 @@ impl DapAbort {
             title: title.to_string(),
             task_id: task_id.map(|id| id.to_base64url()),
             agg_job_id: agg_job_id_base64url,
-            instance: None, // TODO interop: Implement as specified.
+            instance: None,
             detail,
         }
     }
 
+    /// Map a problem details document sent by a peer back to the abort that produced it. This is
+    /// the inverse of [`into_problem_details`](Self::into_problem_details).
+    ///
+    /// The document is expected to be the body of a response with status 400: A document without
+    /// a type is interpreted as a bad request. Returns `None` if the type is not recognized or if a
+    /// field required by the abort (e.g., the task ID) is missing or malformed.
+    pub fn from_problem_details(problem: &ProblemDetails) -> Option<Self> {
+        let Some(typ) = &problem.typ else {
+            return Some(Self::BadRequest(
+                problem
+                    .detail
+                    .clone()
+                    .unwrap_or_else(|| problem.title.clone()),
+            ));
+        };
+
+        let detail = || problem.detail.clone().unwrap_or_default();
+        let task_id = || {
+            problem
+                .task_id
+                .as_ref()
+                .and_then(TaskId::try_from_base64url)
+        };
+        let abort = match typ.strip_prefix("urn:ietf:params:ppm:dap:error:")? {
+            "batchInvalid" => Self::BatchInvalid {
+                detail: detail(),
+                task_id: task_id()?,
+            },
+            "batchMismatch" => Self::BatchMismatch {
+                detail: detail(),
+                task_id: task_id()?,
+            },
+            "batchOverlap" => Self::BatchOverlap {
+                detail: detail(),
+                task_id: task_id()?,
+            },
+            "batchQueriedTooManyTimes" => Self::BatchQueriedTooManyTimes {
+                detail: detail(),
+                task_id: task_id()?,
+            },
+            "invalidBatchSize" => Self::InvalidBatchSize {
+                detail: detail(),
+                task_id: task_id()?,
+            },
+            "invalidTask" => Self::InvalidTask {
+                detail: detail(),
+                task_id: task_id()?,
+            },
+            "missingTaskID" => Self::MissingTaskId,
+            "queryMismatch" => Self::QueryMismatch {
+                detail: detail(),
+                task_id: task_id()?,
+            },
+            "reportRejected" => Self::ReportRejected { detail: detail() },
+            "reportTooLate" => Self::ReportTooLate,
+            "roundMismatch" => Self::RoundMismatch {
+                detail: detail(),
+                task_id: task_id()?,
+                agg_job_id_base64url: problem.agg_job_id.clone()?,
+            },
+            "unauthorizedRequest" => Self::UnauthorizedRequest {
+                detail: detail(),
+                task_id: task_id()?,
+            },
+            "unrecognizedAggregationJob" => Self::UnrecognizedAggregationJob {
+                task_id: task_id()?,
+                agg_job_id_base64url: problem.agg_job_id.clone()?,
+            },
+            "invalidMessage" => Self::InvalidMessage {
+                detail: detail(),
+                task_id: task_id(),
+            },
+            "unrecognizedTask" => Self::UnrecognizedTask,
+            _ => return None,
+        };
+        Some(abort)
+    }
+
     /// Abort due to unexpected value for HTTP content-type header.
     pub fn content_type<S>(req: &DapRequest<S>, expected: DapMediaType) -> Self {
         let want_str = expected
@@ -184,9 +269,13 @@ impl DapAbort {
     }
 
     #[inline]
-    pub(crate) fn batch_overlap(task_id: &TaskId, batch_sel: impl std::fmt::Display) -> Self {
-        Self::BatchOverlap {
-            detail: format!("The batch indicated by the request: {batch_sel}"),
+    pub(crate) fn batch_queried_too_many_times(
+        task_id: &TaskId,
+        batch_sel: impl std::fmt::Display,
+        max_batch_query_count: u64,
+    ) -> Self {
+        Self::BatchQueriedTooManyTimes {
+            detail: format!("The batch indicated by the request ({batch_sel}) has already been collected the maximum number of times ({max_batch_query_count})."),
             task_id: *task_id,
         }
     }
@@ -239,6 +328,10 @@ impl DapAbort {
                 "The selected batch overlaps with a previous batch",
                 Some(self.to_string()),
             ),
+            Self::BatchQueriedTooManyTimes { .. } => (
+                "The selected batch was queried too many times",
+                Some(self.to_string()),
+            ),
             Self::InvalidBatchSize { .. } => ("Batch size is invalid", Some(self.to_string())),
             Self::InvalidTask { .. } => ("Opted out of Taskprov task", Some(self.to_string())),
             Self::QueryMismatch { .. } => {
@@ -295,7 +388,8 @@ impl DapAbort {
 }
 
 /// A problem details document compatible with RFC 7807.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
 pub struct ProblemDetails {
     pub title: String,
 
@@ -317,3 +411,187 @@ pub struct ProblemDetails {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub detail: Option<String>,
 }
+
+impl ProblemDetails {
+    /// Set the `instance` member to the path of the request that caused the problem.
+    #[must_use]
+    pub fn with_instance(self, instance: impl Into<String>) -> Self {
+        Self {
+            instance: Some(instance.into()),
+            ..self
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DapAbort, ProblemDetails};
+    use crate::{
+        messages::{Base64Encode, TaskId},
+        DapError,
+    };
+
+    fn aborts() -> Vec<DapAbort> {
+        let task_id = TaskId([17; 32]);
+        let detail = || "some detail".to_string();
+        let agg_job_id_base64url = || TaskId([23; 32]).to_base64url();
+        vec![
+            DapAbort::BadRequest(detail()),
+            DapAbort::BatchInvalid {
+                detail: detail(),
+                task_id,
+            },
+            DapAbort::BatchMismatch {
+                detail: detail(),
+                task_id,
+            },
+            DapAbort::BatchOverlap {
+                detail: detail(),
+                task_id,
+            },
+            DapAbort::BatchQueriedTooManyTimes {
+                detail: detail(),
+                task_id,
+            },
+            DapAbort::InvalidBatchSize {
+                detail: detail(),
+                task_id,
+            },
+            DapAbort::InvalidTask {
+                detail: detail(),
+                task_id,
+            },
+            DapAbort::MissingTaskId,
+            DapAbort::QueryMismatch {
+                detail: detail(),
+                task_id,
+            },
+            DapAbort::ReportRejected { detail: detail() },
+            DapAbort::ReportTooLate,
+            DapAbort::RoundMismatch {
+                detail: detail(),
+                task_id,
+                agg_job_id_base64url: agg_job_id_base64url(),
+            },
+            DapAbort::UnauthorizedRequest {
+                detail: detail(),
+                task_id,
+            },
+            DapAbort::UnrecognizedAggregationJob {
+                task_id,
+                agg_job_id_base64url: agg_job_id_base64url(),
+            },
+            DapAbort::InvalidMessage {
+                detail: detail(),
+                task_id: Some(task_id),
+            },
+            DapAbort::InvalidMessage {
+                detail: detail(),
+                task_id: None,
+            },
+            DapAbort::UnrecognizedTask,
+        ]
+    }
+
+    #[test]
+    fn problem_details_round_trip() {
+        for (abort, want) in aborts().into_iter().zip(aborts()) {
+            let json = serde_json::to_string(&abort.into_problem_details()).unwrap();
+            let problem: ProblemDetails = serde_json::from_str(&json).unwrap();
+            assert_eq!(
+                DapAbort::from_problem_details(&problem),
+                Some(want),
+                "{json}"
+            );
+        }
+    }
+
+    #[test]
+    fn problem_details_round_trip_with_instance() {
+        let task_id = TaskId([17; 32]);
+        let instance = format!(
+            "/v09/tasks/{}/aggregation_jobs/abcd",
+            task_id.to_base64url()
+        );
+        let problem = DapAbort::UnrecognizedTask
+            .into_problem_details()
+            .with_instance(&instance);
+        let json = serde_json::to_string(&problem).unwrap();
+        let problem: ProblemDetails = serde_json::from_str(&json).unwrap();
+        assert_eq!(problem.instance.as_ref(), Some(&instance), "{json}");
+
+        let DapError::PeerAbort {
+            abort,
+            instance: got,
+        } = DapError::from_problem_details(problem)
+        else {
+            panic!("expected a peer abort");
+        };
+        assert_eq!(abort, DapAbort::UnrecognizedTask);
+        assert_eq!(got, Some(instance));
+    }
+
+    #[test]
+    fn problem_details_unrecognized() {
+        let problem = |typ: &str, task_id: Option<&str>| ProblemDetails {
+            title: "title".into(),
+            typ: Some(typ.into()),
+            task_id: task_id.map(Into::into),
+            agg_job_id: None,
+            instance: None,
+            detail: None,
+        };
+        let task_id = TaskId([17; 32]).to_base64url();
+
+        assert_eq!(
+            DapAbort::from_problem_details(&problem(
+                "urn:ietf:params:ppm:dap:error:batchMismatch",
+                Some(&task_id)
+            )),
+            Some(DapAbort::BatchMismatch {
+                detail: String::new(),
+                task_id: TaskId([17; 32]),
+            })
+        );
+
+        // Unknown type.
+        assert_eq!(
+            DapAbort::from_problem_details(&problem(
+                "urn:ietf:params:ppm:dap:error:somethingElse",
+                Some(&task_id)
+            )),
+            None
+        );
+
+        // Type outside of the DAP namespace.
+        assert_eq!(
+            DapAbort::from_problem_details(&problem("about:blank", Some(&task_id))),
+            None
+        );
+
+        // Missing or malformed task ID.
+        assert_eq!(
+            DapAbort::from_problem_details(&problem(
+                "urn:ietf:params:ppm:dap:error:batchMismatch",
+                None
+            )),
+            None
+        );
+        assert_eq!(
+            DapAbort::from_problem_details(&problem(
+                "urn:ietf:params:ppm:dap:error:batchMismatch",
+                Some("not a task ID")
+            )),
+            None
+        );
+
+        // Missing aggregation job ID.
+        assert_eq!(
+            DapAbort::from_problem_details(&problem(
+                "urn:ietf:params:ppm:dap:error:unrecognizedAggregationJob",
+                Some(&task_id)
+            )),
+            None
+        );
+    }
+}
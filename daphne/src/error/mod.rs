@@ -5,7 +5,7 @@ pub mod aborts;
 
 use std::fmt::{Debug, Display};
 
-use crate::{messages::TransitionFailure, vdaf::VdafError};
+use crate::{fatal_error, messages::TransitionFailure, vdaf::VdafError};
 pub use aborts::DapAbort;
 use prio::codec::CodecError;
 
@@ -29,6 +29,20 @@ pub enum DapError {
     /// certain conditions, trigger an abort.
     #[error("transition error: {0}")]
     Transition(#[from] TransitionFailure),
+
+    /// Transient failure to communicate with the peer, e.g., the peer is temporarily unavailable
+    /// or the request timed out. The request may succeed if retried.
+    #[error("transient error: {0}")]
+    Transient(String),
+
+    /// The peer aborted a request that we sent it, as indicated by the problem details document
+    /// in its response. `instance` is the endpoint to which the request was targeted, if the peer
+    /// indicated it.
+    #[error("peer abort: {abort}")]
+    PeerAbort {
+        abort: DapAbort,
+        instance: Option<String>,
+    },
 }
 
 impl DapError {
@@ -47,6 +61,29 @@ impl DapError {
         }
     }
 
+    /// Construct an error from the problem details document with which the peer responded to a
+    /// request. The result is a [`DapError::PeerAbort`] if the document indicates an abort we
+    /// recognize and a fatal error otherwise.
+    pub fn from_problem_details(problem: ProblemDetails) -> Self {
+        if let Some(abort) = DapAbort::from_problem_details(&problem) {
+            Self::PeerAbort {
+                abort,
+                instance: problem.instance,
+            }
+        } else {
+            fatal_error!(
+                err = "peer responded with unrecognized problem details",
+                ?problem
+            )
+        }
+    }
+
+    /// Indicates if the error is transient, i.e., if the operation that caused it may succeed if
+    /// retried.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Self::Transient(..))
+    }
+
     /// Construct a fatal encoding error.
     pub fn encoding(e: CodecError) -> DapError {
         DapError::Fatal(FatalDapError(format!(
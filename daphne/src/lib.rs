@@ -42,6 +42,7 @@
 
 pub mod audit_log;
 pub mod auth;
+pub mod client;
 pub mod constants;
 pub mod error;
 pub mod hpke;
@@ -55,10 +56,10 @@ pub mod testing;
 pub mod vdaf;
 
 use crate::{
-    error::DapAbort,
+    error::{aborts::ProblemDetails, DapAbort},
     hpke::HpkeReceiverConfig,
     messages::{
-        AggregationJobId, BatchId, BatchSelector, Collection, CollectionJobId,
+        AggregationJobId, AggregationJobResp, BatchId, BatchSelector, Collection, CollectionJobId,
         Draft02AggregationJobId, Duration, Interval, PartialBatchSelector, ReportId, TaskId, Time,
     },
     vdaf::{
@@ -73,7 +74,10 @@ use messages::{encode_base64url, Base64Encode};
 #[cfg(any(test, feature = "test-utils"))]
 use prio::vdaf::poplar1::Poplar1AggregationParam;
 use prio::{
-    codec::{CodecError, Decode, Encode, ParameterizedDecode, ParameterizedEncode},
+    codec::{
+        decode_u32_items, encode_u32_items, CodecError, Decode, Encode, ParameterizedDecode,
+        ParameterizedEncode,
+    },
     vdaf::Aggregatable as AggregatableTrait,
 };
 use rand::prelude::*;
@@ -82,6 +86,7 @@ use std::{
     cmp::{max, min},
     collections::{HashMap, HashSet},
     fmt::Debug,
+    io::Read,
     str::FromStr,
 };
 use url::Url;
@@ -164,6 +169,98 @@ pub struct DapGlobalConfig {
     /// draft-wang-ppm-dap-taskprov: Indicates if the taskprov extension is enabled.
     #[serde(default)]
     pub allow_taskprov: bool,
+
+    /// Helper: Indicates if aggregation jobs are processed asynchronously. If set, the Helper
+    /// responds to `AggregationJobInitReq` before doing any work and the Leader polls for the
+    /// `AggregationJobResp`. This is not supported for draft02.
+    #[serde(default)]
+    pub helper_async_agg_jobs: bool,
+
+    /// Leader: Policy for sizing and scheduling aggregation jobs.
+    #[serde(default)]
+    pub agg_job_policy: DapAggregationJobPolicy,
+
+    /// Leader: Policy for retrying aggregation and collection jobs that fail due to a transient
+    /// error.
+    #[serde(default)]
+    pub retry_policy: DapRetryPolicy,
+}
+
+/// Leader: Policy for sizing and scheduling aggregation jobs.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+#[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
+pub struct DapAggregationJobPolicy {
+    /// Minimum number of reports in an aggregation job. Reports are not aggregated until there are
+    /// at least this many, unless they are needed for a collection job or the oldest of them has
+    /// waited `max_report_age`.
+    pub min_reports: usize,
+
+    /// Maximum number of reports in an aggregation job.
+    pub max_reports: usize,
+
+    /// Maximum amount of time (in seconds) to wait for `min_reports` reports before starting an
+    /// aggregation job with fewer reports. This only applies when `eager_aggregation` is set.
+    pub max_report_age: Duration,
+
+    /// Start aggregating reports as they are uploaded rather than when a collection job is
+    /// initialized. This only applies to VDAFs that don't take an aggregation parameter.
+    pub eager_aggregation: bool,
+
+    /// Maximum number of aggregation jobs for the same task that are run concurrently. If not
+    /// set, then there is no limit.
+    pub max_concurrent_agg_jobs_per_task: Option<usize>,
+}
+
+/// Leader: Policy for retrying work items that fail due to a transient error, e.g., the Helper is
+/// temporarily unavailable. Work items that fail due to any other error, or that run out of
+/// attempts, are dead-lettered.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(default)]
+#[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
+pub struct DapRetryPolicy {
+    /// Maximum number of times a work item is attempted.
+    pub max_attempts: u32,
+
+    /// Amount of time (in seconds) to wait before the first retry. The wait time doubles with
+    /// each subsequent attempt. Random jitter of up to half the wait time is added.
+    pub initial_backoff: Duration,
+
+    /// Maximum amount of time (in seconds) to wait before a retry, not including jitter.
+    pub max_backoff: Duration,
+}
+
+impl Default for DapRetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: 1,
+            max_backoff: 300,
+        }
+    }
+}
+
+impl DapRetryPolicy {
+    /// Compute the amount of time to wait before retrying a work item that has failed the given
+    /// number of times, not including jitter.
+    pub(crate) fn backoff(&self, failed_attempts: u32) -> Duration {
+        let exp = failed_attempts.saturating_sub(1).min(32);
+        self.initial_backoff
+            .saturating_mul(1 << exp)
+            .min(self.max_backoff)
+    }
+}
+
+impl Default for DapAggregationJobPolicy {
+    fn default() -> Self {
+        Self {
+            min_reports: 1,
+            max_reports: 1000,
+            max_report_age: 300,
+            eager_aggregation: false,
+            max_concurrent_agg_jobs_per_task: None,
+        }
+    }
 }
 
 impl DapGlobalConfig {
@@ -239,6 +336,14 @@ impl DapQueryConfig {
             )
         )
     }
+
+    /// The maximum number of reports in a batch, if any.
+    pub fn max_batch_size(&self) -> Option<u64> {
+        match self {
+            Self::TimeInterval => None,
+            Self::FixedSize { max_batch_size } => *max_batch_size,
+        }
+    }
 }
 
 impl std::fmt::Display for DapQueryConfig {
@@ -256,13 +361,23 @@ impl std::fmt::Display for DapQueryConfig {
 /// queries, the bucket to which a report is assigned is determined by truncating its timestamp by
 /// the task's `time_precision` parameter; for fixed-size queries, the span consists of a single
 /// bucket, which is the batch determined by the batch ID (i.e., the partial batch selector).
-#[derive(Debug, Clone, Eq, Hash, PartialEq)]
+#[derive(Debug, Clone, Eq, Hash, PartialEq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
 #[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
 pub enum DapBatchBucket {
     FixedSize { batch_id: BatchId },
     TimeInterval { batch_window: Time },
 }
 
+impl From<DapBatchBucket> for PartialBatchSelector {
+    fn from(bucket: DapBatchBucket) -> Self {
+        match bucket {
+            DapBatchBucket::FixedSize { batch_id } => Self::FixedSizeByBatchId { batch_id },
+            DapBatchBucket::TimeInterval { .. } => Self::TimeInterval,
+        }
+    }
+}
+
 /// A set of values related to reports in the same bucket.
 #[derive(Debug)]
 pub struct DapAggregateSpan<T> {
@@ -278,6 +393,48 @@ impl std::fmt::Display for DapBatchBucket {
     }
 }
 
+/// The query by which a batch is collected. Marking a batch as collected is idempotent for a given
+/// query, so that a retried request doesn't count against the batch's query limit twice.
+#[derive(Debug, Clone, Eq, Hash, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
+pub enum DapCollectionQuery {
+    /// The Leader's collection job.
+    CollectionJob(CollectionJobId),
+
+    /// The SHA-256 digest of the AggregateShareReq received by the Helper. An identical request
+    /// is indistinguishable from a retry, and so is counted once.
+    AggregateShareReq(#[serde(with = "hex")] [u8; 32]),
+}
+
+impl DapCollectionQuery {
+    /// Identify the query by the AggregateShareReq it was received in.
+    pub fn for_agg_share_req(
+        agg_share_req: &messages::AggregateShareReq,
+        version: DapVersion,
+    ) -> Result<Self, DapError> {
+        let encoded = agg_share_req
+            .get_encoded_with_param(&version)
+            .map_err(DapError::encoding)?;
+        let mut digest = [0; 32];
+        digest.copy_from_slice(ring::digest::digest(&ring::digest::SHA256, &encoded).as_ref());
+        Ok(Self::AggregateShareReq(digest))
+    }
+}
+
+impl std::fmt::Display for DapCollectionQuery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::CollectionJob(coll_job_id) => {
+                write!(f, "collection_job/{}", coll_job_id.to_hex())
+            }
+            Self::AggregateShareReq(digest) => {
+                write!(f, "aggregate_share_req/{}", hex::encode(digest))
+            }
+        }
+    }
+}
+
 // We can't derive default because it will require T to be Default, which we don't need.
 impl<T> Default for DapAggregateSpan<T> {
     fn default() -> Self {
@@ -454,6 +611,9 @@ pub struct DapTaskParameters {
     /// The query configuration for this task.
     pub query: DapQueryConfig,
 
+    /// The number of times each batch may be collected.
+    pub max_batch_query_count: u64,
+
     /// The VDAF configuration for this task.
     pub vdaf: VdafConfig,
 }
@@ -480,7 +640,7 @@ impl DapTaskParameters {
             },
             query_config: messages::taskprov::QueryConfig {
                 time_precision: self.time_precision,
-                max_batch_query_count: 1,
+                max_batch_query_count: self.max_batch_query_count.try_into().unwrap(),
                 min_batch_size: self.min_batch_size.try_into().unwrap(),
                 var: (&self.query).try_into()?,
             },
@@ -535,6 +695,7 @@ impl Default for DapTaskParameters {
             lifetime: 86400 * 14, // two weeks
             min_batch_size: 10,
             query: DapQueryConfig::TimeInterval,
+            max_batch_query_count: 1,
             vdaf: VdafConfig::Prio2 { dimension: 10 },
         }
     }
@@ -552,6 +713,7 @@ pub struct DapTaskConfig {
     pub time_precision: Duration,
     pub min_batch_size: u64,
     pub query: DapQueryConfig,
+    pub max_batch_query_count: u64,
     pub vdaf: VdafConfig,
 
     /// The time at which the task expires.
@@ -576,6 +738,9 @@ struct ShadowDapTaskConfig {
     time_precision: Duration,
     min_batch_size: u64,
     query: DapQueryConfig,
+    // Tasks configured before batches could be collected more than once allow a single query.
+    #[serde(default = "default_max_batch_query_count")]
+    max_batch_query_count: u64,
     vdaf: VdafConfig,
     expiration: Time,
     vdaf_verify_key: VdafVerifyKey,
@@ -589,6 +754,10 @@ struct ShadowDapTaskConfig {
     deprecated_taskprov: bool,
 }
 
+fn default_max_batch_query_count() -> u64 {
+    1
+}
+
 impl From<ShadowDapTaskConfig> for DapTaskConfig {
     fn from(shadow: ShadowDapTaskConfig) -> Self {
         Self {
@@ -598,6 +767,7 @@ impl From<ShadowDapTaskConfig> for DapTaskConfig {
             time_precision: shadow.time_precision,
             min_batch_size: shadow.min_batch_size,
             query: shadow.query,
+            max_batch_query_count: shadow.max_batch_query_count,
             vdaf: shadow.vdaf,
             expiration: shadow.expiration,
             vdaf_verify_key: shadow.vdaf_verify_key,
@@ -624,6 +794,7 @@ impl deepsize::DeepSizeOf for DapTaskConfig {
             + self.expiration.deep_size_of_children(context)
             + self.min_batch_size.deep_size_of_children(context)
             + self.query.deep_size_of_children(context)
+            + self.max_batch_query_count.deep_size_of_children(context)
             + self.vdaf.deep_size_of_children(context)
             + self.vdaf_verify_key.deep_size_of_children(context)
             + self.collector_hpke_config.deep_size_of_children(context)
@@ -887,6 +1058,23 @@ pub(crate) struct AggregationJobReportState {
 pub struct DapAggregationJobState {
     pub(crate) seq: Vec<AggregationJobReportState>,
     part_batch_sel: PartialBatchSelector,
+    /// The last round of the aggregation job that was completed.
+    pub(crate) round: u16,
+    /// Helper: The response for the last round. This is sent again if the Leader retries the
+    /// round.
+    pub(crate) last_resp: Option<AggregationJobResp>,
+    /// Helper: The encoded `AggregationJobInitReq` of an aggregation job that has been accepted
+    /// but not yet processed.
+    pub(crate) pending_init_req: Option<Vec<u8>>,
+    /// Helper: The SHA-256 hash of the `AggregationJobInitReq` that initialized the aggregation
+    /// job. This is used to recognize a retried request.
+    pub(crate) init_req_hash: Option<[u8; 32]>,
+    /// Helper: The time until which a worker holds the pending aggregation job. Once it has
+    /// passed, the aggregation job may be processed by another worker.
+    pub(crate) processing_lease: Option<Time>,
+    /// Helper: The reason the aggregation job failed. This is sent to the Leader in response to
+    /// any subsequent request for the aggregation job.
+    pub(crate) failure: Option<Box<ProblemDetails>>,
 }
 
 /// Leader state during an aggregation job in which it has computed the output shares but is
@@ -897,8 +1085,139 @@ pub struct DapAggregationJobUncommitted {
     part_batch_sel: PartialBatchSelector,
 }
 
+// The encoding of `DapAggregationJobState` used to not include the round or the last response.
+// When either is set, the state is prefixed with this byte, which is not a valid query type.
+const AGG_JOB_STATE_MULTI_ROUND: u8 = 0xff;
+
+// Flags indicating which of the optional fields of `DapAggregationJobState` are encoded.
+const AGG_JOB_STATE_FLAG_LAST_RESP: u8 = 0x01;
+const AGG_JOB_STATE_FLAG_PENDING_INIT_REQ: u8 = 0x02;
+const AGG_JOB_STATE_FLAG_INIT_REQ_HASH: u8 = 0x04;
+const AGG_JOB_STATE_FLAG_PROCESSING_LEASE: u8 = 0x08;
+const AGG_JOB_STATE_FLAG_FAILURE: u8 = 0x10;
+
+impl DapAggregationJobState {
+    /// Helper: The state of an aggregation job that has been accepted for asynchronous
+    /// processing. The worker that accepted it holds it until `processing_lease`.
+    pub(crate) fn pending(
+        part_batch_sel: PartialBatchSelector,
+        init_req_data: Vec<u8>,
+        init_req_hash: [u8; 32],
+        processing_lease: Time,
+    ) -> Self {
+        Self {
+            seq: Vec::new(),
+            part_batch_sel,
+            round: 0,
+            last_resp: None,
+            pending_init_req: Some(init_req_data),
+            init_req_hash: Some(init_req_hash),
+            processing_lease: Some(processing_lease),
+            failure: None,
+        }
+    }
+
+    /// Helper: The state of an aggregation job whose output shares are being aggregated. This
+    /// claims the aggregation job until `processing_lease` so that a concurrent or retried request
+    /// doesn't aggregate them again; it is replaced by the final state once the response is known.
+    pub(crate) fn processing(
+        part_batch_sel: PartialBatchSelector,
+        init_req_hash: [u8; 32],
+        processing_lease: Time,
+    ) -> Self {
+        Self {
+            seq: Vec::new(),
+            part_batch_sel,
+            round: 0,
+            last_resp: None,
+            pending_init_req: None,
+            init_req_hash: Some(init_req_hash),
+            processing_lease: Some(processing_lease),
+            failure: None,
+        }
+    }
+
+    /// Helper: The state of an aggregation job that failed.
+    pub(crate) fn failed(
+        part_batch_sel: PartialBatchSelector,
+        init_req_hash: [u8; 32],
+        failure: ProblemDetails,
+    ) -> Self {
+        Self {
+            seq: Vec::new(),
+            part_batch_sel,
+            round: 0,
+            last_resp: None,
+            pending_init_req: None,
+            init_req_hash: Some(init_req_hash),
+            processing_lease: None,
+            failure: Some(Box::new(failure)),
+        }
+    }
+
+    /// Helper: Returns `true` if a worker holds the pending or processing aggregation job at time
+    /// `now`.
+    pub(crate) fn is_held(&self, now: Time) -> bool {
+        self.processing_lease.is_some_and(|lease| now < lease)
+    }
+
+    /// Helper: Returns `true` if the aggregation job has been accepted but the response to the
+    /// `AggregationJobInitReq` is not yet known.
+    pub(crate) fn is_processing(&self) -> bool {
+        self.pending_init_req.is_some()
+            || (self.round == 0
+                && self.last_resp.is_none()
+                && self.init_req_hash.is_some()
+                && self.failure.is_none())
+    }
+}
+
 impl Encode for DapAggregationJobState {
     fn encode(&self, bytes: &mut Vec<u8>) -> Result<(), CodecError> {
+        if self.round > 0
+            || self.last_resp.is_some()
+            || self.pending_init_req.is_some()
+            || self.init_req_hash.is_some()
+            || self.processing_lease.is_some()
+            || self.failure.is_some()
+        {
+            AGG_JOB_STATE_MULTI_ROUND.encode(bytes)?;
+            self.round.encode(bytes)?;
+            let mut flags = 0;
+            if self.last_resp.is_some() {
+                flags |= AGG_JOB_STATE_FLAG_LAST_RESP;
+            }
+            if self.pending_init_req.is_some() {
+                flags |= AGG_JOB_STATE_FLAG_PENDING_INIT_REQ;
+            }
+            if self.init_req_hash.is_some() {
+                flags |= AGG_JOB_STATE_FLAG_INIT_REQ_HASH;
+            }
+            if self.processing_lease.is_some() {
+                flags |= AGG_JOB_STATE_FLAG_PROCESSING_LEASE;
+            }
+            if self.failure.is_some() {
+                flags |= AGG_JOB_STATE_FLAG_FAILURE;
+            }
+            flags.encode(bytes)?;
+            if let Some(last_resp) = &self.last_resp {
+                last_resp.encode(bytes)?;
+            }
+            if let Some(init_req_data) = &self.pending_init_req {
+                encode_u32_items(bytes, &(), init_req_data)?;
+            }
+            if let Some(init_req_hash) = &self.init_req_hash {
+                bytes.extend_from_slice(init_req_hash);
+            }
+            if let Some(processing_lease) = &self.processing_lease {
+                processing_lease.encode(bytes)?;
+            }
+            if let Some(failure) = &self.failure {
+                let failure =
+                    serde_json::to_vec(failure).map_err(|e| CodecError::Other(Box::new(e)))?;
+                encode_u32_items(bytes, &(), &failure)?;
+            }
+        }
         self.part_batch_sel.encode(bytes)?;
         for report_state in &self.seq {
             if report_state.draft02_prep_share.is_some() {
@@ -924,6 +1243,56 @@ impl DapAggregationJobState {
     /// Decode the Helper state from a byte string.
     pub fn get_decoded(vdaf_config: &VdafConfig, data: &[u8]) -> Result<Self, DapError> {
         let mut r = std::io::Cursor::new(data);
+        let mut round = 0;
+        let mut last_resp = None;
+        let mut pending_init_req = None;
+        let mut init_req_hash = None;
+        let mut processing_lease = None;
+        let mut failure = None;
+        if data.first() == Some(&AGG_JOB_STATE_MULTI_ROUND) {
+            r.set_position(1);
+            round = u16::decode(&mut r).map_err(|e| DapAbort::from_codec_error(e, None))?;
+            let flags = u8::decode(&mut r).map_err(|e| DapAbort::from_codec_error(e, None))?;
+            if flags
+                & !(AGG_JOB_STATE_FLAG_LAST_RESP
+                    | AGG_JOB_STATE_FLAG_PENDING_INIT_REQ
+                    | AGG_JOB_STATE_FLAG_INIT_REQ_HASH
+                    | AGG_JOB_STATE_FLAG_PROCESSING_LEASE
+                    | AGG_JOB_STATE_FLAG_FAILURE)
+                != 0
+            {
+                return Err(DapAbort::from_codec_error(CodecError::UnexpectedValue, None).into());
+            }
+            if flags & AGG_JOB_STATE_FLAG_LAST_RESP != 0 {
+                last_resp = Some(
+                    AggregationJobResp::decode(&mut r)
+                        .map_err(|e| DapAbort::from_codec_error(e, None))?,
+                );
+            }
+            if flags & AGG_JOB_STATE_FLAG_PENDING_INIT_REQ != 0 {
+                pending_init_req = Some(
+                    decode_u32_items(&(), &mut r)
+                        .map_err(|e| DapAbort::from_codec_error(e, None))?,
+                );
+            }
+            if flags & AGG_JOB_STATE_FLAG_INIT_REQ_HASH != 0 {
+                let mut hash = [0; 32];
+                r.read_exact(&mut hash)
+                    .map_err(|e| DapAbort::from_codec_error(CodecError::Io(e), None))?;
+                init_req_hash = Some(hash);
+            }
+            if flags & AGG_JOB_STATE_FLAG_PROCESSING_LEASE != 0 {
+                processing_lease =
+                    Some(Time::decode(&mut r).map_err(|e| DapAbort::from_codec_error(e, None))?);
+            }
+            if flags & AGG_JOB_STATE_FLAG_FAILURE != 0 {
+                let data: Vec<u8> = decode_u32_items(&(), &mut r)
+                    .map_err(|e| DapAbort::from_codec_error(e, None))?;
+                failure = Some(Box::new(serde_json::from_slice(&data).map_err(|e| {
+                    DapAbort::from_codec_error(CodecError::Other(Box::new(e)), None)
+                })?));
+            }
+        }
         let part_batch_sel = PartialBatchSelector::decode(&mut r)
             .map_err(|e| DapAbort::from_codec_error(e, None))?;
         let mut seq = vec![];
@@ -944,6 +1313,12 @@ impl DapAggregationJobState {
         Ok(Self {
             part_batch_sel,
             seq,
+            round,
+            last_resp,
+            pending_init_req,
+            init_req_hash,
+            processing_lease,
+            failure,
         })
     }
 }
@@ -1084,7 +1459,7 @@ pub enum DapSender {
 }
 
 /// Types of resources associated with DAP tasks.
-#[derive(Debug, Default, PartialEq, Eq)]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub enum DapResource {
     /// Aggregation job resource.
     AggregationJob(AggregationJobId),
@@ -1112,7 +1487,7 @@ pub enum DapResource {
 }
 
 /// DAP request.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct DapRequest<S> {
     /// Protocol version indicated by the request.
     pub version: DapVersion,
@@ -1193,11 +1568,27 @@ impl<S> DapRequest<S> {
     }
 }
 
+/// Status of a request that may be handled asynchronously.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DapResponseStatus {
+    /// The request has been handled and the payload is the result.
+    #[default]
+    Done,
+
+    /// Helper: The aggregation job has been accepted but is still being processed. The payload is
+    /// empty and the status is conveyed by the [`DAP_AGG_JOB_STATUS_HEADER`] header; the Leader is
+    /// expected to poll for the result.
+    ///
+    /// [`DAP_AGG_JOB_STATUS_HEADER`]: crate::constants::DAP_AGG_JOB_STATUS_HEADER
+    Processing,
+}
+
 /// DAP response.
 #[derive(Debug)]
 pub struct DapResponse {
     pub version: DapVersion,
     pub media_type: DapMediaType,
+    pub status: DapResponseStatus,
     pub payload: Vec<u8>,
 }
 
@@ -1224,6 +1615,12 @@ pub struct DapLeaderProcessTelemetry {
 
     /// The number of reports processed.
     pub reports_processed: u64,
+
+    /// The number of work items that failed and were put back in the queue to be retried.
+    pub work_items_retried: u64,
+
+    /// The number of work items that failed and were dead-lettered.
+    pub work_items_dead_lettered: u64,
 }
 
 /// draft02 compatibility: A logical aggregation job ID. In the latest draft, this is a 32-byte
@@ -0,0 +1,352 @@
+// Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Zero-copy decoding of aggregation job initialization requests.
+//!
+//! [`AggregationJobInitReq`] owns every public share and encrypted input share, which means
+//! decoding a request with many reports roughly doubles the memory used by the request body. The
+//! types in this module instead borrow from the encoded request. The request is still decoded
+//! in one go, so the encoded request must be buffered in full before decoding starts.
+
+use std::io::{self, Cursor};
+
+use prio::codec::{CodecError, Decode, ParameterizedDecode};
+
+use super::{
+    AggregationJobInitReq, Draft02AggregationJobId, HpkeCiphertext, PartialBatchSelector,
+    PrepareInit, ReportMetadata, ReportShare, TaskId,
+};
+use crate::DapVersion;
+
+/// An [`HpkeCiphertext`] that borrows its encapsulated key and payload.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HpkeCiphertextRef<'a> {
+    pub config_id: u8,
+    pub enc: &'a [u8],
+    pub payload: &'a [u8],
+}
+
+impl<'a> HpkeCiphertextRef<'a> {
+    fn decode(bytes: &mut Cursor<&'a [u8]>) -> Result<Self, CodecError> {
+        Ok(Self {
+            config_id: u8::decode(bytes)?,
+            enc: decode_u16_bytes_ref(bytes)?,
+            payload: decode_u32_bytes_ref(bytes)?,
+        })
+    }
+
+    /// Copy the borrowed fields into an [`HpkeCiphertext`].
+    pub fn into_owned(self) -> HpkeCiphertext {
+        HpkeCiphertext {
+            config_id: self.config_id,
+            enc: self.enc.to_vec(),
+            payload: self.payload.to_vec(),
+        }
+    }
+}
+
+impl<'a> From<&'a HpkeCiphertext> for HpkeCiphertextRef<'a> {
+    fn from(ciphertext: &'a HpkeCiphertext) -> Self {
+        Self {
+            config_id: ciphertext.config_id,
+            enc: &ciphertext.enc,
+            payload: &ciphertext.payload,
+        }
+    }
+}
+
+/// A [`ReportShare`] that borrows its public share and encrypted input share.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReportShareRef<'a> {
+    pub report_metadata: ReportMetadata,
+    pub public_share: &'a [u8],
+    pub encrypted_input_share: HpkeCiphertextRef<'a>,
+}
+
+impl<'a> ReportShareRef<'a> {
+    fn decode_with_param(
+        version: DapVersion,
+        bytes: &mut Cursor<&'a [u8]>,
+    ) -> Result<Self, CodecError> {
+        Ok(Self {
+            report_metadata: ReportMetadata::decode_with_param(&version, bytes)?,
+            public_share: decode_u32_bytes_ref(bytes)?,
+            encrypted_input_share: HpkeCiphertextRef::decode(bytes)?,
+        })
+    }
+
+    /// Copy the borrowed fields into a [`ReportShare`].
+    pub fn into_owned(self) -> ReportShare {
+        ReportShare {
+            report_metadata: self.report_metadata,
+            public_share: self.public_share.to_vec(),
+            encrypted_input_share: self.encrypted_input_share.into_owned(),
+        }
+    }
+}
+
+impl<'a> From<&'a ReportShare> for ReportShareRef<'a> {
+    fn from(report_share: &'a ReportShare) -> Self {
+        Self {
+            report_metadata: report_share.report_metadata.clone(),
+            public_share: &report_share.public_share,
+            encrypted_input_share: (&report_share.encrypted_input_share).into(),
+        }
+    }
+}
+
+/// A [`PrepareInit`] that borrows from the encoded request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PrepareInitRef<'a> {
+    pub report_share: ReportShareRef<'a>,
+    pub draft09_payload: Option<&'a [u8]>,
+}
+
+impl<'a> PrepareInitRef<'a> {
+    pub fn decode_with_param(
+        version: &DapVersion,
+        bytes: &mut Cursor<&'a [u8]>,
+    ) -> Result<Self, CodecError> {
+        let report_share = ReportShareRef::decode_with_param(*version, bytes)?;
+        let draft09_payload = match version {
+            DapVersion::Draft02 => None,
+            DapVersion::Draft09 | DapVersion::Latest => Some(decode_u32_bytes_ref(bytes)?),
+        };
+
+        Ok(Self {
+            report_share,
+            draft09_payload,
+        })
+    }
+
+    /// Copy the borrowed fields into a [`PrepareInit`].
+    pub fn into_owned(self) -> PrepareInit {
+        PrepareInit {
+            report_share: self.report_share.into_owned(),
+            draft09_payload: self.draft09_payload.map(<[u8]>::to_vec),
+        }
+    }
+}
+
+impl<'a> From<&'a PrepareInit> for PrepareInitRef<'a> {
+    fn from(prep_init: &'a PrepareInit) -> Self {
+        Self {
+            report_share: (&prep_init.report_share).into(),
+            draft09_payload: prep_init.draft09_payload.as_deref(),
+        }
+    }
+}
+
+/// An [`AggregationJobInitReq`] that borrows from the encoded request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AggregationJobInitReqRef<'a> {
+    pub draft02_task_id: Option<TaskId>, // Set in draft02
+    pub draft02_agg_job_id: Option<Draft02AggregationJobId>, // Set in draft02
+    pub agg_param: &'a [u8],
+    pub part_batch_sel: PartialBatchSelector,
+    pub prep_inits: Vec<PrepareInitRef<'a>>,
+}
+
+impl<'a> AggregationJobInitReqRef<'a> {
+    pub fn decode_with_param(
+        version: &DapVersion,
+        bytes: &mut Cursor<&'a [u8]>,
+    ) -> Result<Self, CodecError> {
+        let AggregationJobInitReqHeaderRef {
+            draft02_task_id,
+            draft02_agg_job_id,
+            agg_param,
+            part_batch_sel,
+        } = decode_agg_job_init_req_header(*version, bytes)?;
+
+        // Cribbed from `decode_u32_items()` from libprio.
+        let len = usize::try_from(u32::decode(bytes)?).unwrap();
+        let start = usize::try_from(bytes.position()).unwrap();
+        let end = start
+            .checked_add(len)
+            .ok_or(CodecError::LengthPrefixTooBig(len))?;
+        let encoded: &'a [u8] = bytes.get_ref();
+        let mut inner = Cursor::new(
+            encoded
+                .get(start..end)
+                .ok_or(CodecError::LengthPrefixTooBig(len))?,
+        );
+        let mut prep_inits = Vec::new();
+        while usize::try_from(inner.position()).unwrap() < len {
+            prep_inits.push(PrepareInitRef::decode_with_param(version, &mut inner)?);
+        }
+        bytes.set_position(end.try_into().unwrap());
+
+        Ok(Self {
+            draft02_task_id,
+            draft02_agg_job_id,
+            agg_param,
+            part_batch_sel,
+            prep_inits,
+        })
+    }
+
+    /// Decode the request from `bytes`, failing if any bytes are left over.
+    pub fn get_decoded_with_param(
+        version: &DapVersion,
+        bytes: &'a [u8],
+    ) -> Result<Self, CodecError> {
+        let mut r = Cursor::new(bytes);
+        let decoded = Self::decode_with_param(version, &mut r)?;
+        let num_bytes_left_over = bytes.len() - usize::try_from(r.position()).unwrap();
+        if num_bytes_left_over > 0 {
+            return Err(CodecError::BytesLeftOver(num_bytes_left_over));
+        }
+        Ok(decoded)
+    }
+
+    /// Copy the borrowed fields into an [`AggregationJobInitReq`].
+    pub fn into_owned(self) -> AggregationJobInitReq {
+        AggregationJobInitReq {
+            draft02_task_id: self.draft02_task_id,
+            draft02_agg_job_id: self.draft02_agg_job_id,
+            agg_param: self.agg_param.to_vec(),
+            part_batch_sel: self.part_batch_sel,
+            prep_inits: self
+                .prep_inits
+                .into_iter()
+                .map(PrepareInitRef::into_owned)
+                .collect(),
+        }
+    }
+}
+
+impl<'a> From<&'a AggregationJobInitReq> for AggregationJobInitReqRef<'a> {
+    fn from(agg_job_init_req: &'a AggregationJobInitReq) -> Self {
+        Self {
+            draft02_task_id: agg_job_init_req.draft02_task_id,
+            draft02_agg_job_id: agg_job_init_req.draft02_agg_job_id,
+            agg_param: &agg_job_init_req.agg_param,
+            part_batch_sel: agg_job_init_req.part_batch_sel.clone(),
+            prep_inits: agg_job_init_req.prep_inits.iter().map(Into::into).collect(),
+        }
+    }
+}
+
+/// The fields of an [`AggregationJobInitReq`] that precede the sequence of [`PrepareInit`]s.
+struct AggregationJobInitReqHeaderRef<'a> {
+    draft02_task_id: Option<TaskId>,
+    draft02_agg_job_id: Option<Draft02AggregationJobId>,
+    agg_param: &'a [u8],
+    part_batch_sel: PartialBatchSelector,
+}
+
+fn decode_agg_job_init_req_header<'a>(
+    version: DapVersion,
+    bytes: &mut Cursor<&'a [u8]>,
+) -> Result<AggregationJobInitReqHeaderRef<'a>, CodecError> {
+    let (draft02_task_id, draft02_agg_job_id, agg_param) = match version {
+        DapVersion::Draft02 => (
+            Some(TaskId::decode(bytes)?),
+            Some(Draft02AggregationJobId::decode(bytes)?),
+            decode_u16_bytes_ref(bytes)?,
+        ),
+        DapVersion::Draft09 | DapVersion::Latest => (None, None, decode_u32_bytes_ref(bytes)?),
+    };
+    Ok(AggregationJobInitReqHeaderRef {
+        draft02_task_id,
+        draft02_agg_job_id,
+        agg_param,
+        part_batch_sel: PartialBatchSelector::decode(bytes)?,
+    })
+}
+
+fn decode_bytes_ref<'a>(bytes: &mut Cursor<&'a [u8]>, len: usize) -> Result<&'a [u8], CodecError> {
+    let encoded: &'a [u8] = bytes.get_ref();
+    let start = usize::try_from(bytes.position()).unwrap();
+    let out = start
+        .checked_add(len)
+        .and_then(|end| encoded.get(start..end))
+        .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+    bytes.set_position((start + len).try_into().unwrap());
+    Ok(out)
+}
+
+fn decode_u16_bytes_ref<'a>(bytes: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], CodecError> {
+    let len = usize::from(u16::decode(bytes)?);
+    decode_bytes_ref(bytes, len)
+}
+
+fn decode_u32_bytes_ref<'a>(bytes: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], CodecError> {
+    let len = usize::try_from(u32::decode(bytes)?).unwrap();
+    decode_bytes_ref(bytes, len)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::{
+        messages::{BatchId, Extension, ReportId},
+        test_versions,
+    };
+    use prio::codec::ParameterizedEncode;
+
+    fn agg_job_init_req(version: DapVersion) -> AggregationJobInitReq {
+        let (draft02_task_id, draft02_agg_job_id) = match version {
+            DapVersion::Draft02 => (
+                Some(TaskId([23; 32])),
+                Some(Draft02AggregationJobId([1; 32])),
+            ),
+            DapVersion::Draft09 | DapVersion::Latest => (None, None),
+        };
+        AggregationJobInitReq {
+            draft02_task_id,
+            draft02_agg_job_id,
+            agg_param: b"this is an aggregation parameter".to_vec(),
+            part_batch_sel: PartialBatchSelector::FixedSizeByBatchId {
+                batch_id: BatchId([0; 32]),
+            },
+            prep_inits: (0..10)
+                .map(|i| PrepareInit {
+                    report_share: ReportShare {
+                        report_metadata: ReportMetadata {
+                            id: ReportId([i; 16]),
+                            time: 1_637_364_244,
+                            draft02_extensions: match version {
+                                DapVersion::Draft02 => Some(vec![Extension::NotImplemented {
+                                    typ: 0xffff,
+                                    payload: vec![i; usize::from(i)],
+                                }]),
+                                DapVersion::Draft09 | DapVersion::Latest => None,
+                            },
+                        },
+                        public_share: vec![i; 3 * usize::from(i)],
+                        encrypted_input_share: HpkeCiphertext {
+                            config_id: i,
+                            enc: b"encapsulated key".to_vec(),
+                            payload: vec![i; 100],
+                        },
+                    },
+                    draft09_payload: match version {
+                        DapVersion::Draft02 => None,
+                        DapVersion::Draft09 | DapVersion::Latest => Some(vec![i; 17]),
+                    },
+                })
+                .collect(),
+        }
+    }
+
+    fn read_agg_job_init_req_ref(version: DapVersion) {
+        let want = agg_job_init_req(version);
+        let encoded = want.get_encoded_with_param(&version).unwrap();
+
+        let got = AggregationJobInitReqRef::get_decoded_with_param(&version, &encoded).unwrap();
+        assert_eq!(got, AggregationJobInitReqRef::from(&want));
+        assert_eq!(got.into_owned(), want);
+
+        let mut with_left_over = encoded;
+        with_left_over.push(0);
+        assert!(matches!(
+            AggregationJobInitReqRef::get_decoded_with_param(&version, &with_left_over),
+            Err(CodecError::BytesLeftOver(1))
+        ));
+    }
+
+    test_versions! {read_agg_job_init_req_ref}
+}
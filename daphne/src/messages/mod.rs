@@ -3,7 +3,10 @@
 
 //! Messages in the DAP protocol.
 
+pub mod borrowed;
 pub mod taskprov;
+#[cfg(test)]
+mod test_vectors;
 
 use crate::{
     hpke::{HpkeAeadId, HpkeConfig, HpkeKdfId, HpkeKemId},
@@ -31,7 +34,7 @@ const FIXED_SIZE_QUERY_TYPE_BY_BATCH_ID: u8 = 0x00;
 const FIXED_SIZE_QUERY_TYPE_CURRENT_BATCH: u8 = 0x01;
 
 // Known extension types.
-const EXTENSION_TASKPROV: u16 = 0xff00;
+pub(crate) const EXTENSION_TASKPROV: u16 = 0xff00;
 
 pub trait Base64Encode {
     /// Encode to URL-safe base64.
@@ -829,7 +832,8 @@ impl std::fmt::Display for TransitionFailure {
 }
 
 /// An aggregate response sent from the Helper to the Leader.
-#[derive(Debug, PartialEq, Eq, Default)]
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+#[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
 #[allow(missing_docs)]
 pub struct AggregationJobResp {
     pub transitions: Vec<Transition>,
@@ -1049,8 +1053,6 @@ impl ParameterizedDecode<DapVersion> for CollectionReq {
 }
 
 /// A collect response.
-//
-// TODO Add serialization tests.
 #[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
 #[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
 pub struct Collection {
@@ -1120,8 +1122,6 @@ impl ParameterizedDecode<DapVersion> for Collection {
 }
 
 /// An aggregate-share request.
-//
-// TODO Add serialization tests.
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct AggregateShareReq {
     pub draft02_task_id: Option<TaskId>, // Set in draft02
@@ -1189,7 +1189,7 @@ impl ParameterizedDecode<DapVersion> for AggregateShareReq {
 }
 
 /// An aggregate-share response.
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub struct AggregateShare {
     pub encrypted_agg_share: HpkeCiphertext,
 }
@@ -1458,7 +1458,12 @@ fn decode_u16_prefixed<O>(
         .checked_add(len)
         .ok_or_else(|| CodecError::LengthPrefixTooBig(len))?;
 
-    let mut inner = Cursor::new(&bytes.get_ref()[item_start..item_end]);
+    let mut inner = Cursor::new(
+        bytes
+            .get_ref()
+            .get(item_start..item_end)
+            .ok_or(CodecError::LengthPrefixTooBig(len))?,
+    );
     let decoded = d(version, &mut inner, Some(len))?;
 
     let num_bytes_left_over = item_end - item_start - usize::try_from(inner.position()).unwrap();
@@ -1895,6 +1900,14 @@ mod test {
         );
     }
 
+    #[test]
+    fn read_extension_with_length_prefix_past_end() {
+        let data = [0xff, 0x00, 0x00, 0xff, 0xff, 0x00];
+        for version in [DapVersion::Draft02, DapVersion::Draft09, DapVersion::Latest] {
+            assert!(Extension::get_decoded_with_param(&version, &data).is_err());
+        }
+    }
+
     #[test]
     fn test_base64url() {
         let mut rng = thread_rng();
@@ -18,7 +18,7 @@ use std::io::{Cursor, Read};
 use super::{decode_u16_prefixed, encode_u16_prefixed};
 
 // VDAF type codes.
-const VDAF_TYPE_PRIO2: u32 = 0xFFFF_0000;
+pub(crate) const VDAF_TYPE_PRIO2: u32 = 0xFFFF_0000;
 pub(crate) const VDAF_TYPE_PRIO3_SUM_VEC_FIELD64_MULTIPROOF_HMAC_SHA256_AES128: u32 = 0xFFFF_1003;
 
 // Differential privacy mechanism types.
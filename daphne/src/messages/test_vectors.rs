@@ -0,0 +1,500 @@
+// Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Conformance tests for the wire format of DAP messages.
+//!
+//! `test_vectors.json` maps the name of each example message below to its encoding (in hex) for
+//! each DAP version in which it can be encoded. The tests check that each message encodes to the
+//! vector and that the vector decodes to the message. When the encoding of a message changes, or
+//! a version is added, regenerate the vectors with
+//!
+//! ```text
+//! cargo test -p daphne generate_test_vectors -- --ignored
+//! ```
+//!
+//! and review the diff.
+
+use std::{collections::BTreeMap, fmt::Debug, fs, path::PathBuf};
+
+use hpke_rs::HpkePublicKey;
+use prio::codec::{Decode, Encode, ParameterizedDecode, ParameterizedEncode};
+
+use super::{
+    taskprov::{
+        DpConfig, QueryConfig, QueryConfigVar, TaskConfig, UrlBytes, VdafConfig, VdafTypeVar,
+    },
+    AggregateShare, AggregateShareReq, AggregationJobContinueReq, AggregationJobInitReq,
+    AggregationJobResp, BatchId, BatchSelector, Collection, CollectionReq, Draft02AggregationJobId,
+    Extension, HpkeAeadId, HpkeCiphertext, HpkeConfigList, HpkeKdfId, HpkeKemId, Interval,
+    PartialBatchSelector, PlaintextInputShare, PrepareInit, Query, Report, ReportId,
+    ReportMetadata, ReportShare, TaskId, Transition, TransitionFailure, TransitionVar,
+};
+use crate::{hpke::HpkeConfig, DapVersion};
+
+/// The versions for which vectors are generated.
+const VERSIONS: [DapVersion; 3] = [DapVersion::Draft02, DapVersion::Draft09, DapVersion::Latest];
+
+/// Test vectors, indexed by message name and then by version.
+type TestVectors = BTreeMap<String, BTreeMap<String, String>>;
+
+fn test_vectors_path() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/messages/test_vectors.json")
+}
+
+fn test_vectors() -> TestVectors {
+    serde_json::from_str(include_str!("test_vectors.json")).unwrap()
+}
+
+/// A message whose encoding is checked against a test vector.
+trait TestVector {
+    fn encode(&self, version: DapVersion) -> Vec<u8>;
+
+    /// Decode `bytes` and check that the result equals this message. `name` identifies the test
+    /// vector in failure messages.
+    fn check_decode(&self, name: &str, version: DapVersion, bytes: &[u8]);
+}
+
+/// A message whose encoding depends on the version.
+struct Versioned<T>(T);
+
+impl<T> TestVector for Versioned<T>
+where
+    T: ParameterizedEncode<DapVersion> + ParameterizedDecode<DapVersion> + Debug + PartialEq,
+{
+    fn encode(&self, version: DapVersion) -> Vec<u8> {
+        self.0.get_encoded_with_param(&version).unwrap()
+    }
+
+    fn check_decode(&self, name: &str, version: DapVersion, bytes: &[u8]) {
+        let decoded = T::get_decoded_with_param(&version, bytes)
+            .unwrap_or_else(|e| panic!("{name} ({version}): {e}"));
+        assert_eq!(decoded, self.0, "{name} ({version})");
+    }
+}
+
+/// A message whose encoding is the same in every version.
+struct Unversioned<T>(T);
+
+impl<T> TestVector for Unversioned<T>
+where
+    T: Encode + Decode + Debug + PartialEq,
+{
+    fn encode(&self, _version: DapVersion) -> Vec<u8> {
+        self.0.get_encoded().unwrap()
+    }
+
+    fn check_decode(&self, name: &str, version: DapVersion, bytes: &[u8]) {
+        let decoded = T::get_decoded(bytes).unwrap_or_else(|e| panic!("{name} ({version}): {e}"));
+        assert_eq!(decoded, self.0, "{name} ({version})");
+    }
+}
+
+fn versioned<T>(name: &str, msg: T) -> (String, Box<dyn TestVector>)
+where
+    T: ParameterizedEncode<DapVersion>
+        + ParameterizedDecode<DapVersion>
+        + Debug
+        + PartialEq
+        + 'static,
+{
+    (name.to_string(), Box::new(Versioned(msg)))
+}
+
+fn unversioned<T>(name: &str, msg: T) -> (String, Box<dyn TestVector>)
+where
+    T: Encode + Decode + Debug + PartialEq + 'static,
+{
+    (name.to_string(), Box::new(Unversioned(msg)))
+}
+
+/// Set a field that only exists in draft02.
+fn draft02<T>(version: DapVersion, value: T) -> Option<T> {
+    (version == DapVersion::Draft02).then_some(value)
+}
+
+/// Set a field that doesn't exist in draft02.
+fn not_draft02<T>(version: DapVersion, value: T) -> Option<T> {
+    (version != DapVersion::Draft02).then_some(value)
+}
+
+fn batch_interval() -> Interval {
+    Interval {
+        start: 1_700_000_000,
+        duration: 3600,
+    }
+}
+
+fn hpke_ciphertext(config_id: u8) -> HpkeCiphertext {
+    HpkeCiphertext {
+        config_id,
+        enc: b"encapsulated key".to_vec(),
+        payload: b"ciphertext".to_vec(),
+    }
+}
+
+fn taskprov_extension(version: DapVersion) -> Extension {
+    Extension::Taskprov {
+        draft02_payload: draft02(version, b"task config".to_vec()),
+    }
+}
+
+fn report_metadata(version: DapVersion) -> ReportMetadata {
+    ReportMetadata {
+        id: ReportId([1; 16]),
+        time: 1_700_000_123,
+        draft02_extensions: draft02(
+            version,
+            vec![
+                taskprov_extension(version),
+                Extension::NotImplemented {
+                    typ: 0x1337,
+                    payload: b"extension payload".to_vec(),
+                },
+            ],
+        ),
+    }
+}
+
+fn report_share(version: DapVersion) -> ReportShare {
+    ReportShare {
+        report_metadata: report_metadata(version),
+        public_share: b"public share".to_vec(),
+        encrypted_input_share: hpke_ciphertext(1),
+    }
+}
+
+fn prepare_init(version: DapVersion) -> PrepareInit {
+    PrepareInit {
+        report_share: report_share(version),
+        draft09_payload: not_draft02(version, b"prep share".to_vec()),
+    }
+}
+
+fn transitions() -> Vec<Transition> {
+    vec![
+        Transition {
+            report_id: ReportId([1; 16]),
+            var: TransitionVar::Continued(b"prep message".to_vec()),
+        },
+        Transition {
+            report_id: ReportId([2; 16]),
+            var: TransitionVar::Finished,
+        },
+        Transition {
+            report_id: ReportId([3; 16]),
+            var: TransitionVar::Failed(TransitionFailure::ReportReplayed),
+        },
+    ]
+}
+
+fn hpke_config() -> HpkeConfig {
+    HpkeConfig {
+        id: 23,
+        kem_id: HpkeKemId::X25519HkdfSha256,
+        kdf_id: HpkeKdfId::HkdfSha256,
+        aead_id: HpkeAeadId::Aes128Gcm,
+        public_key: HpkePublicKey::from(b"this is a public key".to_vec()),
+    }
+}
+
+fn taskprov_task_config(
+    query_config_var: QueryConfigVar,
+    vdaf_type_var: VdafTypeVar,
+) -> TaskConfig {
+    TaskConfig {
+        task_info: b"task info".to_vec(),
+        leader_url: UrlBytes {
+            bytes: b"https://leader.example.com/".to_vec(),
+        },
+        helper_url: UrlBytes {
+            bytes: b"https://helper.example.com/".to_vec(),
+        },
+        query_config: QueryConfig {
+            time_precision: 3600,
+            max_batch_query_count: 1,
+            min_batch_size: 10,
+            var: query_config_var,
+        },
+        task_expiration: 1_800_000_000,
+        vdaf_config: VdafConfig {
+            dp_config: DpConfig::None,
+            var: vdaf_type_var,
+        },
+    }
+}
+
+/// The example messages for the given version.
+fn messages(version: DapVersion) -> Vec<(String, Box<dyn TestVector>)> {
+    let task_id = draft02(version, TaskId([7; 32]));
+    let mut messages = vec![
+        versioned("Extension/Taskprov", taskprov_extension(version)),
+        versioned(
+            "Extension/NotImplemented",
+            Extension::NotImplemented {
+                typ: 0x1337,
+                payload: b"extension payload".to_vec(),
+            },
+        ),
+        versioned("ReportMetadata", report_metadata(version)),
+        versioned(
+            "Report",
+            Report {
+                draft02_task_id: task_id,
+                report_metadata: report_metadata(version),
+                public_share: b"public share".to_vec(),
+                encrypted_input_shares: [hpke_ciphertext(1), hpke_ciphertext(2)],
+            },
+        ),
+        versioned(
+            "PlaintextInputShare",
+            PlaintextInputShare {
+                extensions: if version == DapVersion::Draft02 {
+                    Vec::new()
+                } else {
+                    vec![taskprov_extension(version)]
+                },
+                payload: b"input share".to_vec(),
+            },
+        ),
+        versioned("ReportShare", report_share(version)),
+        versioned("PrepareInit", prepare_init(version)),
+        unversioned(
+            "PartialBatchSelector/TimeInterval",
+            PartialBatchSelector::TimeInterval,
+        ),
+        unversioned(
+            "PartialBatchSelector/FixedSizeByBatchId",
+            PartialBatchSelector::FixedSizeByBatchId {
+                batch_id: BatchId([5; 32]),
+            },
+        ),
+        unversioned(
+            "BatchSelector/TimeInterval",
+            BatchSelector::TimeInterval {
+                batch_interval: batch_interval(),
+            },
+        ),
+        unversioned(
+            "BatchSelector/FixedSizeByBatchId",
+            BatchSelector::FixedSizeByBatchId {
+                batch_id: BatchId([5; 32]),
+            },
+        ),
+        versioned(
+            "AggregationJobInitReq",
+            AggregationJobInitReq {
+                draft02_task_id: task_id,
+                draft02_agg_job_id: draft02(version, Draft02AggregationJobId([9; 32])),
+                agg_param: b"aggregation parameter".to_vec(),
+                part_batch_sel: PartialBatchSelector::FixedSizeByBatchId {
+                    batch_id: BatchId([5; 32]),
+                },
+                prep_inits: vec![prepare_init(version), prepare_init(version)],
+            },
+        ),
+        versioned(
+            "AggregationJobContinueReq",
+            AggregationJobContinueReq {
+                draft02_task_id: task_id,
+                draft02_agg_job_id: draft02(version, Draft02AggregationJobId([9; 32])),
+                round: not_draft02(version, 1),
+                transitions: transitions(),
+            },
+        ),
+        unversioned("Transition/Continued", transitions().remove(0)),
+        unversioned("Transition/Finished", transitions().remove(1)),
+        unversioned("Transition/Failed", transitions().remove(2)),
+        unversioned(
+            "AggregationJobResp",
+            AggregationJobResp {
+                transitions: transitions(),
+            },
+        ),
+        unversioned("Interval", batch_interval()),
+        versioned(
+            "Query/TimeInterval",
+            Query::TimeInterval {
+                batch_interval: batch_interval(),
+            },
+        ),
+        versioned(
+            "Query/FixedSizeByBatchId",
+            Query::FixedSizeByBatchId {
+                batch_id: BatchId([5; 32]),
+            },
+        ),
+        versioned(
+            "CollectionReq",
+            CollectionReq {
+                draft02_task_id: task_id,
+                query: Query::TimeInterval {
+                    batch_interval: batch_interval(),
+                },
+                agg_param: b"aggregation parameter".to_vec(),
+            },
+        ),
+        versioned(
+            "Collection",
+            Collection {
+                part_batch_sel: PartialBatchSelector::TimeInterval,
+                report_count: 1337,
+                draft09_interval: not_draft02(version, batch_interval()),
+                encrypted_agg_shares: [hpke_ciphertext(1), hpke_ciphertext(2)],
+            },
+        ),
+        versioned(
+            "AggregateShareReq",
+            AggregateShareReq {
+                draft02_task_id: task_id,
+                batch_sel: BatchSelector::TimeInterval {
+                    batch_interval: batch_interval(),
+                },
+                agg_param: b"aggregation parameter".to_vec(),
+                report_count: 1337,
+                checksum: [3; 32],
+            },
+        ),
+        unversioned(
+            "AggregateShare",
+            AggregateShare {
+                encrypted_agg_share: hpke_ciphertext(3),
+            },
+        ),
+        unversioned("HpkeCiphertext", hpke_ciphertext(1)),
+        unversioned("HpkeConfig", hpke_config()),
+        unversioned(
+            "HpkeConfig/NotImplemented",
+            HpkeConfig {
+                id: 24,
+                kem_id: HpkeKemId::NotImplemented(99),
+                kdf_id: HpkeKdfId::NotImplemented(99),
+                aead_id: HpkeAeadId::NotImplemented(99),
+                public_key: HpkePublicKey::from(b"this is a public key".to_vec()),
+            },
+        ),
+        unversioned(
+            "HpkeConfigList",
+            HpkeConfigList {
+                hpke_configs: vec![hpke_config()],
+            },
+        ),
+        versioned(
+            "taskprov/TaskConfig/TimeInterval/Prio2",
+            taskprov_task_config(
+                QueryConfigVar::TimeInterval,
+                VdafTypeVar::Prio2 { dimension: 10 },
+            ),
+        ),
+        versioned(
+            "taskprov/TaskConfig/FixedSize/Prio3SumVecField64MultiproofHmacSha256Aes128",
+            taskprov_task_config(
+                QueryConfigVar::FixedSize {
+                    max_batch_size: 100,
+                },
+                VdafTypeVar::Prio3SumVecField64MultiproofHmacSha256Aes128 {
+                    length: 10,
+                    bits: 1,
+                    chunk_length: 4,
+                    num_proofs: 2,
+                },
+            ),
+        ),
+    ];
+
+    // Messages that can't be encoded in draft02.
+    if version != DapVersion::Draft02 {
+        messages.extend([
+            versioned("Query/FixedSizeCurrentBatch", Query::FixedSizeCurrentBatch),
+            versioned(
+                "taskprov/TaskConfig/NotImplemented",
+                TaskConfig {
+                    vdaf_config: VdafConfig {
+                        dp_config: DpConfig::NotImplemented {
+                            typ: 99,
+                            param: b"dp config".to_vec(),
+                        },
+                        var: VdafTypeVar::NotImplemented {
+                            typ: 0xffff_ffff,
+                            param: b"vdaf config".to_vec(),
+                        },
+                    },
+                    ..taskprov_task_config(
+                        QueryConfigVar::NotImplemented {
+                            typ: 99,
+                            param: b"query config".to_vec(),
+                        },
+                        VdafTypeVar::Prio2 { dimension: 10 },
+                    )
+                },
+            ),
+        ]);
+    }
+
+    messages
+}
+
+fn generate() -> TestVectors {
+    let mut vectors = TestVectors::new();
+    for version in VERSIONS {
+        for (name, msg) in messages(version) {
+            vectors
+                .entry(name)
+                .or_default()
+                .insert(version.to_string(), hex::encode(msg.encode(version)));
+        }
+    }
+    vectors
+}
+
+#[test]
+fn test_vectors_are_complete() {
+    assert_eq!(
+        test_vectors()
+            .into_iter()
+            .map(|(name, by_version)| (name, by_version.into_keys().collect::<Vec<_>>()))
+            .collect::<Vec<_>>(),
+        generate()
+            .into_iter()
+            .map(|(name, by_version)| (name, by_version.into_keys().collect::<Vec<_>>()))
+            .collect::<Vec<_>>(),
+        "test vectors are out of date"
+    );
+}
+
+#[test]
+fn encode() {
+    let vectors = test_vectors();
+    for version in VERSIONS {
+        for (name, msg) in messages(version) {
+            let Some(want) = vectors.get(&name).and_then(|v| v.get(version.as_ref())) else {
+                panic!("{name} ({version}): no test vector");
+            };
+            assert_eq!(
+                &hex::encode(msg.encode(version)),
+                want,
+                "{name} ({version})"
+            );
+        }
+    }
+}
+
+#[test]
+fn decode() {
+    let vectors = test_vectors();
+    for version in VERSIONS {
+        for (name, msg) in messages(version) {
+            let Some(vector) = vectors.get(&name).and_then(|v| v.get(version.as_ref())) else {
+                panic!("{name} ({version}): no test vector");
+            };
+            msg.check_decode(&name, version, &hex::decode(vector).unwrap());
+        }
+    }
+}
+
+#[test]
+#[ignore = "overwrites the test vectors"]
+fn generate_test_vectors() {
+    let mut json = serde_json::to_string_pretty(&generate()).unwrap();
+    json.push('\n');
+    fs::write(test_vectors_path(), json).unwrap();
+}
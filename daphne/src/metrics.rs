@@ -3,13 +3,56 @@
 
 //! Daphne metrics.
 
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{error::DapAbort, messages::TaskId, DapTaskConfig, VdafConfig};
+
 pub trait DaphneMetrics: Send + Sync {
-    fn inbound_req_inc(&self, request_type: DaphneRequestType);
-    fn report_inc_by(&self, status: &str, val: u64);
+    fn inbound_req_inc(&self, request_type: DaphneRequestType, task: Option<TaskLabels<'_>>);
+    fn report_inc_by(&self, status: &str, val: u64, task: TaskLabels<'_>);
     fn agg_job_observe_batch_size(&self, val: usize);
-    fn agg_job_started_inc(&self);
-    fn agg_job_completed_inc(&self);
+    fn agg_job_started_inc(&self, task: TaskLabels<'_>);
+    fn agg_job_completed_inc(&self, task: TaskLabels<'_>);
     fn agg_job_put_span_retry_inc(&self);
+    fn peer_abort_inc(&self, abort: &DapAbort, task: TaskLabels<'_>);
+}
+
+/// The task to which a metric pertains. Whether this is reflected in the metric's labels is up to
+/// the implementation of [`DaphneMetrics`].
+#[derive(Clone, Copy, Debug)]
+pub struct TaskLabels<'a> {
+    pub task_id: &'a TaskId,
+    pub vdaf: &'a VdafConfig,
+}
+
+impl<'a> TaskLabels<'a> {
+    pub fn new(task_id: &'a TaskId, task_config: &'a DapTaskConfig) -> Self {
+        Self {
+            task_id,
+            vdaf: &task_config.vdaf,
+        }
+    }
+}
+
+/// Policy for labeling metrics by task.
+///
+/// Each task that is labeled individually adds a time series to every per-task metric, so the
+/// number of such tasks needs to be bounded. Tasks that are not labeled individually are counted
+/// under the task label "other".
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskLabelPolicy {
+    /// Metrics are not labeled by task or VDAF.
+    #[default]
+    Disabled,
+
+    /// Only the listed tasks are labeled individually.
+    Allowlist { tasks: HashSet<TaskId> },
+
+    /// The first `max_tasks` distinct tasks observed by this process are labeled individually.
+    Limit { max_tasks: usize },
 }
 
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
@@ -26,14 +69,28 @@ pub enum DaphneRequestType {
 
 #[cfg(any(feature = "prometheus", feature = "test-utils", test))]
 pub mod prometheus {
-    use super::{DaphneMetrics, DaphneRequestType};
-    use crate::{fatal_error, DapError};
+    use std::{
+        collections::HashSet,
+        sync::{Arc, Mutex},
+    };
+
+    use super::{DaphneMetrics, DaphneRequestType, TaskLabelPolicy, TaskLabels};
+    use crate::{
+        error::DapAbort,
+        fatal_error,
+        messages::{Base64Encode, TaskId},
+        vdaf::VdafConfig,
+        DapError,
+    };
     use ::prometheus::{
         exponential_buckets, register_histogram_with_registry,
         register_int_counter_vec_with_registry, register_int_counter_with_registry, Histogram,
         IntCounter, IntCounterVec, Registry,
     };
 
+    /// Label value used for tasks that are not labeled individually.
+    const OTHER_TASK: &str = "other";
+
     #[derive(Clone)]
     pub struct DaphnePromMetrics {
         /// Inbound request metrics: Successful requests served, broken down by type.
@@ -51,17 +108,34 @@ pub mod prometheus {
 
         /// Helper: Number of times replays caused the aggregation to be retried.
         aggregation_job_put_span_retry_counter: IntCounter,
+
+        /// Leader: Requests aborted by the Helper, broken down by abort type.
+        peer_abort_counter: IntCounterVec,
+
+        /// Decides which task label, if any, to attach to per-task metrics.
+        task_labeler: TaskLabeler,
     }
 
     impl DaphnePromMetrics {
         /// Register Daphne metrics with the specified registry. If a prefix is provided, then
         /// "{prefix_}" is prepended to the name.
         pub fn register(registry: &Registry) -> Result<Self, DapError> {
+            Self::register_with_task_labels(registry, TaskLabelPolicy::Disabled)
+        }
+
+        /// Like [`register`](Self::register), but label the report, aggregation job, and inbound
+        /// request metrics by task and VDAF as determined by `policy`.
+        pub fn register_with_task_labels(
+            registry: &Registry,
+            policy: TaskLabelPolicy,
+        ) -> Result<Self, DapError> {
+            let task_labeler = TaskLabeler::new(policy);
+
             #[allow(clippy::ignored_unit_patterns)]
             let inbound_request_counter = register_int_counter_vec_with_registry!(
                 "inbound_request_counter",
                 "Total number of successful inbound requests.",
-                &["type"],
+                &task_labeler.label_names(&["type"]),
                 registry
             )
             .map_err(|e| fatal_error!(err = ?e, "failed to regsiter inbound_request_counter"))?;
@@ -70,7 +144,7 @@ pub mod prometheus {
             let report_counter = register_int_counter_vec_with_registry!(
                 "report_counter",
                 "Total number reports rejected, aggregated, and collected.",
-                &["status"],
+                &task_labeler.label_names(&["status"]),
                 registry
             )
             .map_err(|e| fatal_error!(err = ?e, "failed to register report_counter"))?;
@@ -90,7 +164,7 @@ pub mod prometheus {
             let aggregation_job_counter = register_int_counter_vec_with_registry!(
                 format!("aggregation_job_counter"),
                 "Total number of aggregation jobs started and completed.",
-                &["status"],
+                &task_labeler.label_names(&["status"]),
                 registry
             )
             .map_err(|e| fatal_error!(err = ?e, "failed to register aggregation_job_counter"))?;
@@ -104,18 +178,29 @@ pub mod prometheus {
                 )
                 .map_err(|e| fatal_error!(err = ?e, "failed to register aggregation_job_put_span_retry_counter"))?;
 
+            #[allow(clippy::ignored_unit_patterns)]
+            let peer_abort_counter = register_int_counter_vec_with_registry!(
+                "peer_abort_counter",
+                "Total number of requests aborted by the peer.",
+                &task_labeler.label_names(&["type"]),
+                registry
+            )
+            .map_err(|e| fatal_error!(err = ?e, "failed to register peer_abort_counter"))?;
+
             Ok(Self {
                 inbound_request_counter,
                 report_counter,
                 aggregation_job_counter,
                 aggregation_job_batch_size_histogram,
                 aggregation_job_put_span_retry_counter,
+                peer_abort_counter,
+                task_labeler,
             })
         }
     }
 
     impl DaphneMetrics for DaphnePromMetrics {
-        fn inbound_req_inc(&self, request_type: DaphneRequestType) {
+        fn inbound_req_inc(&self, request_type: DaphneRequestType, task: Option<TaskLabels<'_>>) {
             let request_type_str = match request_type {
                 DaphneRequestType::HpkeConfig => "hpke_config",
                 DaphneRequestType::Upload => "upload",
@@ -123,13 +208,15 @@ pub mod prometheus {
                 DaphneRequestType::Collect => "collect",
             };
 
-            self.inbound_request_counter
-                .with_label_values(&[request_type_str])
+            self.task_labeler
+                .with_label_values(&self.inbound_request_counter, request_type_str, task)
                 .inc();
         }
 
-        fn report_inc_by(&self, status: &str, val: u64) {
-            self.report_counter.with_label_values(&[status]).inc_by(val);
+        fn report_inc_by(&self, status: &str, val: u64, task: TaskLabels<'_>) {
+            self.task_labeler
+                .with_label_values(&self.report_counter, status, Some(task))
+                .inc_by(val);
         }
 
         fn agg_job_observe_batch_size(&self, val: usize) {
@@ -137,20 +224,182 @@ pub mod prometheus {
                 .observe(val as f64);
         }
 
-        fn agg_job_started_inc(&self) {
-            self.aggregation_job_counter
-                .with_label_values(&["started"])
+        fn agg_job_started_inc(&self, task: TaskLabels<'_>) {
+            self.task_labeler
+                .with_label_values(&self.aggregation_job_counter, "started", Some(task))
                 .inc();
         }
 
-        fn agg_job_completed_inc(&self) {
-            self.aggregation_job_counter
-                .with_label_values(&["completed"])
+        fn agg_job_completed_inc(&self, task: TaskLabels<'_>) {
+            self.task_labeler
+                .with_label_values(&self.aggregation_job_counter, "completed", Some(task))
                 .inc();
         }
 
         fn agg_job_put_span_retry_inc(&self) {
             self.aggregation_job_put_span_retry_counter.inc();
         }
+
+        fn peer_abort_inc(&self, abort: &DapAbort, task: TaskLabels<'_>) {
+            self.task_labeler
+                .with_label_values(&self.peer_abort_counter, &abort.to_string(), Some(task))
+                .inc();
+        }
+    }
+
+    #[derive(Clone)]
+    struct TaskLabeler {
+        policy: TaskLabelPolicy,
+
+        /// The tasks that have been assigned their own label so far. Only used with
+        /// [`TaskLabelPolicy::Limit`].
+        labeled: Arc<Mutex<HashSet<TaskId>>>,
+    }
+
+    impl TaskLabeler {
+        fn new(policy: TaskLabelPolicy) -> Self {
+            Self {
+                policy,
+                labeled: Default::default(),
+            }
+        }
+
+        /// Return the label names for a per-task metric with the given base labels.
+        fn label_names<'a>(&self, names: &[&'a str]) -> Vec<&'a str> {
+            let mut names = names.to_vec();
+            if !matches!(self.policy, TaskLabelPolicy::Disabled) {
+                names.extend(["task", "vdaf"]);
+            }
+            names
+        }
+
+        /// Resolve the counter for the given label value, adding the task and VDAF labels if
+        /// enabled.
+        fn with_label_values(
+            &self,
+            counter: &IntCounterVec,
+            value: &str,
+            task: Option<TaskLabels<'_>>,
+        ) -> IntCounter {
+            if matches!(self.policy, TaskLabelPolicy::Disabled) {
+                return counter.with_label_values(&[value]);
+            }
+
+            let (task_label, vdaf_label) = match task {
+                Some(task) => (self.task_label(task.task_id), vdaf_label(task.vdaf)),
+                None => ("none".into(), "none"),
+            };
+            counter.with_label_values(&[value, &task_label, vdaf_label])
+        }
+
+        fn task_label(&self, task_id: &TaskId) -> String {
+            let labeled = match &self.policy {
+                TaskLabelPolicy::Disabled => false,
+                TaskLabelPolicy::Allowlist { tasks } => tasks.contains(task_id),
+                TaskLabelPolicy::Limit { max_tasks } => {
+                    let mut labeled = self
+                        .labeled
+                        .lock()
+                        .unwrap_or_else(std::sync::PoisonError::into_inner);
+                    labeled.contains(task_id)
+                        || (labeled.len() < *max_tasks && labeled.insert(*task_id))
+                }
+            };
+
+            if labeled {
+                task_id.to_base64url()
+            } else {
+                OTHER_TASK.into()
+            }
+        }
+    }
+
+    /// Label for the VDAF type. VDAF parameters are omitted so that the number of values is
+    /// bounded.
+    fn vdaf_label(vdaf: &VdafConfig) -> &'static str {
+        vdaf.dap_vdaf().map_or("unknown", |vdaf| vdaf.name())
+    }
+
+    #[cfg(test)]
+    mod test {
+        use std::collections::HashSet;
+
+        use prometheus::Registry;
+
+        use super::DaphnePromMetrics;
+        use crate::{
+            assert_metrics_include,
+            messages::{Base64Encode, TaskId},
+            metrics::{DaphneMetrics, DaphneRequestType, TaskLabelPolicy, TaskLabels},
+            vdaf::{Prio3Config, VdafConfig},
+        };
+
+        const VDAF: VdafConfig = VdafConfig::Prio3(Prio3Config::Count);
+
+        fn labels(task_id: &TaskId) -> TaskLabels<'_> {
+            TaskLabels {
+                task_id,
+                vdaf: &VDAF,
+            }
+        }
+
+        #[test]
+        fn task_labels_allowlist() {
+            let registry = Registry::new();
+            let allowed = TaskId([1; 32]);
+            let metrics = DaphnePromMetrics::register_with_task_labels(
+                &registry,
+                TaskLabelPolicy::Allowlist {
+                    tasks: HashSet::from([allowed]),
+                },
+            )
+            .unwrap();
+
+            metrics.report_inc_by("aggregated", 3, labels(&allowed));
+            metrics.report_inc_by("aggregated", 5, labels(&TaskId([2; 32])));
+            metrics.report_inc_by("aggregated", 7, labels(&TaskId([3; 32])));
+            metrics.inbound_req_inc(DaphneRequestType::HpkeConfig, None);
+
+            assert_metrics_include!(registry, {
+                (format!(r#"report_counter{{status="aggregated",task="{}",vdaf="prio3_count"}}"#, allowed.to_base64url())): 3,
+                r#"report_counter{status="aggregated",task="other",vdaf="prio3_count"}"#: 12,
+                r#"inbound_request_counter{task="none",type="hpke_config",vdaf="none"}"#: 1,
+            });
+        }
+
+        #[test]
+        fn task_labels_limit() {
+            let registry = Registry::new();
+            let metrics = DaphnePromMetrics::register_with_task_labels(
+                &registry,
+                TaskLabelPolicy::Limit { max_tasks: 2 },
+            )
+            .unwrap();
+
+            let task_ids = [TaskId([1; 32]), TaskId([2; 32]), TaskId([3; 32])];
+            for task_id in &task_ids {
+                metrics.agg_job_started_inc(labels(task_id));
+            }
+            // A task that was labeled individually keeps its label.
+            metrics.agg_job_started_inc(labels(&task_ids[0]));
+
+            assert_metrics_include!(registry, {
+                (format!(r#"aggregation_job_counter{{status="started",task="{}",vdaf="prio3_count"}}"#, task_ids[0].to_base64url())): 2,
+                (format!(r#"aggregation_job_counter{{status="started",task="{}",vdaf="prio3_count"}}"#, task_ids[1].to_base64url())): 1,
+                r#"aggregation_job_counter{status="started",task="other",vdaf="prio3_count"}"#: 1,
+            });
+        }
+
+        #[test]
+        fn task_labels_disabled() {
+            let registry = Registry::new();
+            let metrics = DaphnePromMetrics::register(&registry).unwrap();
+
+            metrics.report_inc_by("collected", 10, labels(&TaskId([1; 32])));
+
+            assert_metrics_include!(registry, {
+                r#"report_counter{status="collected"}"#: 10,
+            });
+        }
     }
 }
@@ -1,24 +1,25 @@
 // Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
-#[cfg(any(test, feature = "test-utils"))]
-use crate::vdaf::mastic::{mastic_prep_finish, mastic_prep_finish_from_shares, mastic_prep_init};
 use crate::{
     error::DapAbort,
     fatal_error,
     hpke::{HpkeConfig, HpkeDecrypter},
     messages::{
-        encode_u32_bytes, encode_u32_prefixed, AggregationJobContinueReq, AggregationJobInitReq,
-        AggregationJobResp, Base64Encode, BatchSelector, Extension, HpkeCiphertext,
-        PartialBatchSelector, PlaintextInputShare, PrepareInit, Report, ReportId, ReportMetadata,
-        ReportShare, TaskId, Transition, TransitionFailure, TransitionVar,
+        borrowed::AggregationJobInitReqRef, encode_u32_bytes, encode_u32_prefixed,
+        AggregationJobContinueReq, AggregationJobInitReq, AggregationJobResp, Base64Encode,
+        BatchSelector, Extension, HpkeCiphertext, PartialBatchSelector, PlaintextInputShare,
+        PrepareInit, Report, ReportId, ReportMetadata, ReportShare, TaskId, Transition,
+        TransitionFailure, TransitionVar,
+    },
+    metrics::{DaphneMetrics, TaskLabels},
+    roles::{
+        report_extensions::{DapReportExtensionHandlers, DapReportExtensionOutcome},
+        DapReportInitializer,
     },
-    metrics::DaphneMetrics,
-    roles::DapReportInitializer,
     vdaf::{
-        prio2::{prio2_prep_finish, prio2_prep_finish_from_shares, prio2_prep_init},
-        prio3::{prio3_prep_finish, prio3_prep_finish_from_shares, prio3_prep_init},
-        VdafError, VdafPrepMessage, VdafPrepState, VdafVerifyKey,
+        VdafAggregateShare, VdafError, VdafPrepMessage, VdafPrepState, VdafPrepTransition,
+        VdafVerifyKey,
     },
     AggregationJobReportState, DapAggregateShare, DapAggregateSpan, DapAggregationJobState,
     DapAggregationJobUncommitted, DapAggregationParam, DapError, DapHelperAggregationJobTransition,
@@ -40,10 +41,10 @@ use super::{
     CTX_ROLE_CLIENT, CTX_ROLE_COLLECTOR, CTX_ROLE_HELPER, CTX_ROLE_LEADER,
 };
 
-// Ping-pong message framing as defined in draft-irtf-cfrg-vdaf-08, Section 5.8. We do not
-// implement the "continue" message type because we only support 1-round VDAFs.
+// Ping-pong message framing as defined in draft-irtf-cfrg-vdaf-08, Section 5.8.
 enum PingPongMessageType {
     Initialize = 0,
+    Continue = 1,
     Finish = 2,
 }
 
@@ -72,6 +73,127 @@ fn decode_ping_pong_framed(
     Ok(&bytes[message_start..])
 }
 
+// Zero-copy decoding of a length-prefixed byte string.
+fn decode_u32_prefixed_slice<'a>(r: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], CodecError> {
+    let len = usize::try_from(u32::decode(r)?).unwrap();
+    let bytes: &'a [u8] = r.get_ref();
+    let start = usize::try_from(r.position()).unwrap();
+    if bytes.len() - start < len {
+        return Err(CodecError::LengthPrefixTooBig(len));
+    }
+    r.set_position(u64::try_from(start + len).unwrap());
+    Ok(&bytes[start..start + len])
+}
+
+/// A ping-pong message received after the first round of preparation (draft-irtf-cfrg-vdaf-08,
+/// Section 5.8).
+enum PingPongMessage<'a> {
+    Continue {
+        prep_msg: &'a [u8],
+        prep_share: &'a [u8],
+    },
+    Finish {
+        prep_msg: &'a [u8],
+    },
+}
+
+impl<'a> PingPongMessage<'a> {
+    fn decode(bytes: &'a [u8]) -> Result<Self, CodecError> {
+        let mut r = Cursor::new(bytes);
+        let message_type = u8::decode(&mut r)?;
+        let message = if message_type == PingPongMessageType::Continue as u8 {
+            Self::Continue {
+                prep_msg: decode_u32_prefixed_slice(&mut r)?,
+                prep_share: decode_u32_prefixed_slice(&mut r)?,
+            }
+        } else if message_type == PingPongMessageType::Finish as u8 {
+            Self::Finish {
+                prep_msg: decode_u32_prefixed_slice(&mut r)?,
+            }
+        } else {
+            return Err(CodecError::UnexpectedValue);
+        };
+
+        let position = usize::try_from(r.position()).unwrap();
+        if position < bytes.len() {
+            return Err(CodecError::BytesLeftOver(bytes.len() - position));
+        }
+        Ok(message)
+    }
+
+    fn prep_msg(&self) -> &'a [u8] {
+        match self {
+            Self::Continue { prep_msg, .. } | Self::Finish { prep_msg } => prep_msg,
+        }
+    }
+}
+
+/// The outcome of a round of preparation for a single report.
+enum PingPongTransition {
+    /// Preparation continues: the host sends the outbound message and waits for its peer.
+    Continued(VdafPrepState, Vec<u8>),
+
+    /// The host has computed its output share, but its peer needs the outbound message to compute
+    /// its own.
+    FinishedWithOutbound(VdafAggregateShare, Vec<u8>),
+
+    /// Both the host and its peer have computed their output shares.
+    Finished(VdafAggregateShare),
+}
+
+impl VdafConfig {
+    /// Combine the host's prep share with its peer's and produce the outbound ping-pong message.
+    fn ping_pong_transition(
+        &self,
+        agg_id: usize,
+        host_state: VdafPrepState,
+        host_share: VdafPrepMessage,
+        peer_share: &[u8],
+    ) -> Result<PingPongTransition, VdafError> {
+        let (transition, prep_msg) =
+            self.prep_next_from_shares(agg_id, host_state, host_share, peer_share)?;
+        match transition {
+            VdafPrepTransition::Continue(next_state, next_share) => {
+                let next_share = next_share.get_encoded()?;
+                let mut outbound = Vec::with_capacity(9 + prep_msg.len() + next_share.len());
+                outbound.push(PingPongMessageType::Continue as u8);
+                encode_u32_bytes(&mut outbound, &prep_msg)?;
+                encode_u32_bytes(&mut outbound, &next_share)?;
+                Ok(PingPongTransition::Continued(next_state, outbound))
+            }
+            VdafPrepTransition::Finish(out_share) => {
+                let mut outbound = Vec::with_capacity(5 + prep_msg.len());
+                outbound.push(PingPongMessageType::Finish as u8);
+                encode_u32_bytes(&mut outbound, &prep_msg)?;
+                Ok(PingPongTransition::FinishedWithOutbound(
+                    out_share, outbound,
+                ))
+            }
+        }
+    }
+
+    /// Consume a ping-pong message from the host's peer after the first round of preparation.
+    fn ping_pong_continued(
+        &self,
+        agg_id: usize,
+        host_state: VdafPrepState,
+        inbound: &PingPongMessage<'_>,
+    ) -> Result<PingPongTransition, VdafError> {
+        match (inbound, self.prep_next(host_state, inbound.prep_msg())?) {
+            (
+                PingPongMessage::Continue { prep_share, .. },
+                VdafPrepTransition::Continue(next_state, next_share),
+            ) => self.ping_pong_transition(agg_id, next_state, next_share, prep_share),
+            (PingPongMessage::Finish { .. }, VdafPrepTransition::Finish(out_share)) => {
+                Ok(PingPongTransition::Finished(out_share))
+            }
+            _ => Err(VdafError::Vdaf(prio::vdaf::VdafError::Uncategorized(
+                "ping-pong message does not match the VDAF transition".into(),
+            ))),
+        }
+    }
+}
+
 /// Report state during aggregation initialization.
 pub trait EarlyReportState {
     fn metadata(&self) -> &ReportMetadata;
@@ -91,6 +213,8 @@ pub enum EarlyReportStateConsumed {
         //
         // draft02 compatibility: This is only set in the latest draft.
         peer_prep_share: Option<Vec<u8>>,
+        /// Data attached to the report by the handlers of its extensions, keyed by extension type.
+        extension_data: Vec<(u16, Vec<u8>)>,
     },
     Rejected {
         metadata: ReportMetadata,
@@ -99,8 +223,10 @@ pub enum EarlyReportStateConsumed {
 }
 
 impl EarlyReportStateConsumed {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) async fn consume(
         decrypter: &impl HpkeDecrypter,
+        extension_handlers: &DapReportExtensionHandlers,
         is_leader: bool,
         task_id: &TaskId,
         task_config: &DapTaskConfig,
@@ -171,6 +297,7 @@ impl EarlyReportStateConsumed {
         };
 
         // Handle report extensions.
+        let mut extension_data = Vec::new();
         {
             let extensions = match task_config.version {
                 DapVersion::Draft09 | DapVersion::Latest => draft09_extensions.as_ref().unwrap(),
@@ -192,6 +319,31 @@ impl EarlyReportStateConsumed {
                     });
                 }
 
+                // Pass custom extensions to their handlers.
+                if let Extension::NotImplemented { typ, payload } = extension {
+                    if let Some(handler) = extension_handlers.get(*typ) {
+                        match handler.handle(
+                            is_leader,
+                            task_id,
+                            task_config,
+                            &report_share.report_metadata,
+                            payload,
+                        ) {
+                            DapReportExtensionOutcome::Accept => (),
+                            DapReportExtensionOutcome::AcceptWithData(data) => {
+                                extension_data.push((*typ, data));
+                            }
+                            DapReportExtensionOutcome::Reject(failure) => {
+                                return Ok(Self::Rejected {
+                                    metadata: report_share.report_metadata,
+                                    failure,
+                                })
+                            }
+                        }
+                        continue;
+                    }
+                }
+
                 match (task_config.version, extension) {
                     (.., Extension::Taskprov { .. }) if task_config.method_is_taskprov() => {
                         taskprov_indicated = true;
@@ -241,6 +393,7 @@ impl EarlyReportStateConsumed {
             public_share: report_share.public_share,
             peer_prep_share,
             input_share,
+            extension_data,
         })
     }
 
@@ -273,6 +426,7 @@ impl EarlyReportState for EarlyReportStateConsumed {
 /// Report state during aggregation initialization after the VDAF preparation step.
 #[derive(Clone)]
 #[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
+#[allow(clippy::large_enum_variant)] // Rejected reports are rare and short-lived.
 pub enum EarlyReportStateInitialized {
     Ready {
         metadata: ReportMetadata,
@@ -283,6 +437,8 @@ pub enum EarlyReportStateInitialized {
         peer_prep_share: Option<Vec<u8>>,
         prep_share: VdafPrepMessage,
         prep_state: VdafPrepState,
+        /// Data attached to the report by the handlers of its extensions, keyed by extension type.
+        extension_data: Vec<(u16, Vec<u8>)>,
     },
     Rejected {
         metadata: ReportMetadata,
@@ -300,54 +456,40 @@ impl EarlyReportStateInitialized {
         agg_param: &DapAggregationParam,
         early_report_state_consumed: EarlyReportStateConsumed,
     ) -> Result<Self, DapError> {
-        // We need to use this variable for Mastic, which is currently only enabled in tests or
-        // when the feature "test-utils" is enabled.
-        let _ = agg_param;
-
-        let (metadata, public_share, input_share, peer_prep_share) =
+        let (metadata, public_share, input_share, peer_prep_share, extension_data) =
             match early_report_state_consumed {
                 EarlyReportStateConsumed::Ready {
                     metadata,
                     public_share,
                     input_share,
                     peer_prep_share,
-                } => (metadata, public_share, input_share, peer_prep_share),
+                    extension_data,
+                } => (
+                    metadata,
+                    public_share,
+                    input_share,
+                    peer_prep_share,
+                    extension_data,
+                ),
                 EarlyReportStateConsumed::Rejected { metadata, failure } => {
                     return Ok(Self::Rejected { metadata, failure })
                 }
             };
 
         let agg_id = usize::from(!is_leader);
-        let res = match vdaf_config {
-            VdafConfig::Prio3(ref prio3_config) => prio3_prep_init(
-                prio3_config,
-                vdaf_verify_key,
-                agg_id,
-                &metadata.id.0,
-                &public_share,
-                &input_share,
-            ),
-            VdafConfig::Prio2 { dimension } => prio2_prep_init(
-                *dimension,
-                vdaf_verify_key,
-                agg_id,
-                &metadata.id.0,
-                &public_share,
-                &input_share,
-            ),
-            #[cfg(any(test, feature = "test-utils"))]
-            VdafConfig::Mastic {
-                input_size,
-                weight_config,
-            } => mastic_prep_init(
-                *input_size,
-                *weight_config,
-                vdaf_verify_key,
-                agg_param,
-                &public_share,
-                input_share.as_ref(),
-            ),
-        };
+        let res = vdaf_config
+            .dap_vdaf()
+            .map_err(VdafError::Dap)
+            .and_then(|vdaf| {
+                vdaf.prep_init(
+                    vdaf_verify_key,
+                    agg_id,
+                    agg_param,
+                    &metadata.id.0,
+                    &public_share,
+                    &input_share,
+                )
+            });
 
         let early_report_state_initialized = match res {
             Ok((prep_state, prep_share)) => Self::Ready {
@@ -356,6 +498,7 @@ impl EarlyReportStateInitialized {
                 peer_prep_share,
                 prep_share,
                 prep_state,
+                extension_data,
             },
             Err(..) => Self::Rejected {
                 metadata,
@@ -407,11 +550,12 @@ impl DapTaskConfig {
         &self,
         decrypter: &impl HpkeDecrypter,
         initializer: &impl DapReportInitializer,
+        extension_handlers: &DapReportExtensionHandlers,
         task_id: &TaskId,
         agg_job_id: &MetaAggregationJobId,
         part_batch_sel: &PartialBatchSelector,
         agg_param: &DapAggregationParam,
-        reports: Vec<Report>,
+        reports: &[Report],
         metrics: &dyn DaphneMetrics,
     ) -> Result<DapLeaderAggregationJobTransition<AggregationJobInitReq>, DapError> {
         let mut consumed_reports = Vec::with_capacity(reports.len());
@@ -427,24 +571,25 @@ impl DapTaskConfig {
                 }
                 processed.insert(report.report_metadata.id);
 
-                let [leader_share, helper_share] = report.encrypted_input_shares;
+                let [leader_share, helper_share] = &report.encrypted_input_shares;
 
                 consumed_reports.push(
                     EarlyReportStateConsumed::consume(
                         decrypter,
+                        extension_handlers,
                         true,
                         task_id,
                         self,
                         ReportShare {
-                            report_metadata: report.report_metadata,
-                            public_share: report.public_share,
-                            encrypted_input_share: leader_share,
+                            report_metadata: report.report_metadata.clone(),
+                            public_share: report.public_share.clone(),
+                            encrypted_input_share: leader_share.clone(),
                         },
                         None,
                     )
                     .await?,
                 );
-                helper_shares.push(helper_share);
+                helper_shares.push(helper_share.clone());
             }
         }
         let initialized_reports = initializer
@@ -463,6 +608,7 @@ impl DapTaskConfig {
                     peer_prep_share: _,
                     prep_share,
                     prep_state,
+                    extension_data: _,
                 } => {
                     // draft02 compatibility: In the latest version, the Leader sends the Helper
                     // its initial prep share in the first request.
@@ -502,7 +648,11 @@ impl DapTaskConfig {
 
                 EarlyReportStateInitialized::Rejected { failure, .. } => {
                     // Skip report that can't be processed any further.
-                    metrics.report_inc_by(&format!("rejected_{failure}"), 1);
+                    metrics.report_inc_by(
+                        &format!("rejected_{failure}"),
+                        1,
+                        TaskLabels::new(task_id, self),
+                    );
                     continue;
                 }
             }
@@ -518,6 +668,12 @@ impl DapTaskConfig {
             DapAggregationJobState {
                 seq: states,
                 part_batch_sel: part_batch_sel.clone(),
+                round: 0,
+                last_resp: None,
+                pending_init_req: None,
+                init_req_hash: None,
+                processing_lease: None,
+                failure: None,
             },
             AggregationJobInitReq {
                 draft02_task_id: task_id.for_request_payload(&self.version),
@@ -533,8 +689,9 @@ impl DapTaskConfig {
         &self,
         decrypter: &impl HpkeDecrypter,
         initializer: &impl DapReportInitializer,
+        extension_handlers: &DapReportExtensionHandlers,
         task_id: &TaskId,
-        agg_job_init_req: AggregationJobInitReq,
+        agg_job_init_req: AggregationJobInitReqRef<'_>,
     ) -> Result<Vec<EarlyReportStateInitialized>, DapError> {
         let num_reports = agg_job_init_req.prep_inits.len();
         let mut consumed_reports = Vec::with_capacity(num_reports);
@@ -556,11 +713,13 @@ impl DapTaskConfig {
                 consumed_reports.push(
                     EarlyReportStateConsumed::consume(
                         decrypter,
+                        extension_handlers,
                         false,
                         task_id,
                         self,
-                        prep_init.report_share,
-                        prep_init.draft09_payload,
+                        // Only copy the report currently being consumed out of the request.
+                        prep_init.report_share.into_owned(),
+                        prep_init.draft09_payload.map(<[u8]>::to_vec),
                     )
                     .await?,
                 );
@@ -568,7 +727,7 @@ impl DapTaskConfig {
         }
 
         let agg_param =
-            DapAggregationParam::get_decoded_with_param(&self.vdaf, &agg_job_init_req.agg_param)
+            DapAggregationParam::get_decoded_with_param(&self.vdaf, agg_job_init_req.agg_param)
                 .map_err(|e| DapAbort::from_codec_error(e, *task_id))?;
 
         let initialized_reports = initializer
@@ -583,13 +742,15 @@ impl DapTaskConfig {
     /// run by the Helper.
     pub(crate) fn handle_agg_job_init_req(
         &self,
+        task_id: &TaskId,
         report_status: &HashMap<ReportId, ReportProcessedStatus>,
         part_batch_sel: &PartialBatchSelector,
         initialized_reports: &[EarlyReportStateInitialized],
         metrics: &dyn DaphneMetrics,
     ) -> Result<DapHelperAggregationJobTransition<AggregationJobResp>, DapError> {
         match self.version {
-            DapVersion::Draft02 => Self::draft02_handle_agg_job_init_req(
+            DapVersion::Draft02 => self.draft02_handle_agg_job_init_req(
+                task_id,
                 report_status,
                 part_batch_sel,
                 initialized_reports,
@@ -604,6 +765,8 @@ impl DapTaskConfig {
     }
 
     fn draft02_handle_agg_job_init_req(
+        &self,
+        task_id: &TaskId,
         report_status: &HashMap<ReportId, ReportProcessedStatus>,
         part_batch_sel: &PartialBatchSelector,
         initialized_reports: &[EarlyReportStateInitialized],
@@ -624,6 +787,7 @@ impl DapTaskConfig {
                         peer_prep_share: None,
                         prep_share: helper_prep_share,
                         prep_state: helper_prep_state,
+                        extension_data: _,
                     } => {
                         states.push(AggregationJobReportState {
                             draft02_prep_share: None,
@@ -648,7 +812,11 @@ impl DapTaskConfig {
                         metadata: _,
                         failure,
                     } => {
-                        metrics.report_inc_by(&format!("rejected_{failure}"), 1);
+                        metrics.report_inc_by(
+                            &format!("rejected_{failure}"),
+                            1,
+                            TaskLabels::new(task_id, self),
+                        );
                         TransitionVar::Failed(*failure)
                     }
                 },
@@ -664,6 +832,12 @@ impl DapTaskConfig {
             DapAggregationJobState {
                 part_batch_sel: part_batch_sel.clone(),
                 seq: states,
+                round: 0,
+                last_resp: None,
+                pending_init_req: None,
+                init_req_hash: None,
+                processing_lease: None,
+                failure: None,
             },
             AggregationJobResp { transitions },
         ))
@@ -677,6 +851,7 @@ impl DapTaskConfig {
     ) -> Result<DapHelperAggregationJobTransition<AggregationJobResp>, DapError> {
         let num_reports = initialized_reports.len();
         let mut agg_span = DapAggregateSpan::default();
+        let mut states = Vec::new();
         let mut transitions = Vec::with_capacity(num_reports);
 
         for initialized_report in initialized_reports {
@@ -690,35 +865,27 @@ impl DapTaskConfig {
                         peer_prep_share: Some(leader_prep_share),
                         prep_share: helper_prep_share,
                         prep_state: helper_prep_state,
+                        extension_data: _,
                     } => {
-                        let res = match &self.vdaf {
-                            VdafConfig::Prio3(prio3_config) => prio3_prep_finish_from_shares(
-                                prio3_config,
-                                1,
-                                helper_prep_state.clone(),
-                                helper_prep_share.clone(),
-                                leader_prep_share,
-                            ),
-                            VdafConfig::Prio2 { dimension } => prio2_prep_finish_from_shares(
-                                *dimension,
-                                helper_prep_state.clone(),
-                                helper_prep_share.clone(),
-                                leader_prep_share,
-                            ),
-                            #[cfg(any(test, feature = "test-utils"))]
-                            VdafConfig::Mastic {
-                                input_size: _,
-                                weight_config,
-                            } => mastic_prep_finish_from_shares(
-                                *weight_config,
-                                helper_prep_state.clone(),
-                                helper_prep_share.clone(),
-                                leader_prep_share,
-                            ),
-                        };
+                        let res = self.vdaf.ping_pong_transition(
+                            1,
+                            helper_prep_state.clone(),
+                            helper_prep_share.clone(),
+                            leader_prep_share,
+                        );
 
                         match res {
-                            Ok((data, prep_msg)) => {
+                            Ok(PingPongTransition::Continued(prep_state, outbound)) => {
+                                states.push(AggregationJobReportState {
+                                    draft02_prep_share: None,
+                                    prep_state,
+                                    time: metadata.time,
+                                    report_id: metadata.id,
+                                });
+                                TransitionVar::Continued(outbound)
+                            }
+
+                            Ok(PingPongTransition::FinishedWithOutbound(data, outbound)) => {
                                 agg_span.add_out_share(
                                     self,
                                     part_batch_sel,
@@ -726,16 +893,15 @@ impl DapTaskConfig {
                                     metadata.time,
                                     data,
                                 )?;
-
-                                let mut outbound = Vec::with_capacity(1 + prep_msg.len());
-                                // Add ping-pong "finish" message framing (draft-irtf-cfrg-vdaf-08,
-                                // Section 5.8).
-                                outbound.push(PingPongMessageType::Finish as u8);
-                                encode_u32_bytes(&mut outbound, &prep_msg)
-                                    .map_err(DapError::encoding)?;
                                 TransitionVar::Continued(outbound)
                             }
 
+                            Ok(PingPongTransition::Finished(..)) => {
+                                return Err(fatal_error!(
+                                    err = "unexpected transition (finished) in the first round"
+                                ))
+                            }
+
                             Err(VdafError::Codec(..) | VdafError::Vdaf(..)) => {
                                 let failure = TransitionFailure::VdafPrepError;
                                 TransitionVar::Failed(failure)
@@ -763,10 +929,20 @@ impl DapTaskConfig {
             });
         }
 
-        Ok(DapHelperAggregationJobTransition::Finished(
+        helper_transition(
             agg_span,
+            DapAggregationJobState {
+                seq: states,
+                part_batch_sel: part_batch_sel.clone(),
+                round: 0,
+                last_resp: None,
+                pending_init_req: None,
+                init_req_hash: None,
+                processing_lease: None,
+                failure: None,
+            },
             AggregationJobResp { transitions },
-        ))
+        )
     }
 
     /// Handle an aggregate response from the Helper. This method is run by the Leader.
@@ -783,7 +959,7 @@ impl DapTaskConfig {
                 .draft02_handle_agg_job_resp(task_id, agg_job_id, state, agg_job_resp, metrics)
                 .map_err(Into::into),
             DapVersion::Draft09 | DapVersion::Latest => {
-                self.draft09_handle_agg_job_resp(task_id, state, agg_job_resp, metrics)
+                self.draft09_handle_agg_job_resp(task_id, agg_job_id, state, agg_job_resp, metrics)
             }
         }
     }
@@ -827,7 +1003,11 @@ impl DapTaskConfig {
 
                 // Skip report that can't be processed any further.
                 TransitionVar::Failed(failure) => {
-                    metrics.report_inc_by(&format!("rejected_{failure}"), 1);
+                    metrics.report_inc_by(
+                        &format!("rejected_{failure}"),
+                        1,
+                        TaskLabels::new(task_id, self),
+                    );
                     continue;
                 }
 
@@ -840,26 +1020,15 @@ impl DapTaskConfig {
                 }
             };
 
-            let res = match &self.vdaf {
-                VdafConfig::Prio3(prio3_config) => prio3_prep_finish_from_shares(
-                    prio3_config,
+            let res = self
+                .vdaf
+                .prep_next_from_shares(
                     0,
                     leader.prep_state,
                     leader.draft02_prep_share.unwrap(),
                     helper_prep_share,
-                ),
-                VdafConfig::Prio2 { dimension } => prio2_prep_finish_from_shares(
-                    *dimension,
-                    leader.prep_state,
-                    leader.draft02_prep_share.unwrap(),
-                    helper_prep_share,
-                ),
-                #[cfg(any(test, feature = "test-utils"))]
-                // draft02 compatibility: In theory we should be able to support Mastic (or
-                // Poplar1) in a limited capacity. However, this would probably overcomplicate the
-                // code. Since we don't plan to use it, don't support it.
-                VdafConfig::Mastic { .. } => unreachable!("draft02: Mastic is not supported"),
-            };
+                )
+                .and_then(|(transition, prep_msg)| Ok((draft02_finish(transition)?, prep_msg)));
 
             match res {
                 Ok((data, prep_msg)) => {
@@ -878,7 +1047,11 @@ impl DapTaskConfig {
                 // Skip report that can't be processed any further.
                 Err(VdafError::Codec(..) | VdafError::Vdaf(..)) => {
                     let failure = TransitionFailure::VdafPrepError;
-                    metrics.report_inc_by(&format!("rejected_{failure}"), 1);
+                    metrics.report_inc_by(
+                        &format!("rejected_{failure}"),
+                        1,
+                        TaskLabels::new(task_id, self),
+                    );
                 }
 
                 Err(VdafError::Dap(e)) => return Err(e),
@@ -908,6 +1081,7 @@ impl DapTaskConfig {
     fn draft09_handle_agg_job_resp(
         &self,
         task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
         state: DapAggregationJobState,
         agg_job_resp: AggregationJobResp,
         metrics: &dyn DaphneMetrics,
@@ -924,7 +1098,14 @@ impl DapTaskConfig {
             .into());
         }
 
+        let round = state
+            .round
+            .checked_add(1)
+            .ok_or_else(|| fatal_error!(err = "aggregation job exceeded the maximum round"))?;
         let mut agg_span = DapAggregateSpan::default();
+        let mut states = Vec::new();
+        let mut out_shares = Vec::new();
+        let mut transitions = Vec::new();
         for (helper, leader) in zip(agg_job_resp.transitions, state.seq) {
             if helper.report_id != leader.report_id {
                 return Err(DapAbort::InvalidMessage {
@@ -937,13 +1118,12 @@ impl DapTaskConfig {
                 .into());
             }
 
-            let prep_msg = match &helper.var {
+            let inbound = match &helper.var {
                 TransitionVar::Continued(inbound) => {
-                    // Decode the ping-pong "finish" message frame (draft-irtf-cfrg-vdaf-08,
-                    // Section 5.8). Abort the aggregation job if not found.
-                    let Ok(prep_msg) =
-                        decode_ping_pong_framed(inbound, PingPongMessageType::Finish)
-                    else {
+                    // Decode the ping-pong "continue" or "finish" message frame
+                    // (draft-irtf-cfrg-vdaf-08, Section 5.8). Abort the aggregation job if not
+                    // found.
+                    let Ok(inbound) = PingPongMessage::decode(inbound) else {
                         // The Helper has done something wrong but may have already committed this
                         // report to storage. If we just reject it, then a batch mismatch is
                         // inevitable.
@@ -953,12 +1133,16 @@ impl DapTaskConfig {
                         }.into());
                     };
 
-                    prep_msg
+                    inbound
                 }
 
                 // Skip report that can't be processed any further.
                 TransitionVar::Failed(failure) => {
-                    metrics.report_inc_by(&format!("rejected_{failure}"), 1);
+                    metrics.report_inc_by(
+                        &format!("rejected_{failure}"),
+                        1,
+                        TaskLabels::new(task_id, self),
+                    );
                     continue;
                 }
 
@@ -971,19 +1155,36 @@ impl DapTaskConfig {
                 }
             };
 
-            let res = match &self.vdaf {
-                VdafConfig::Prio3(prio3_config) => {
-                    prio3_prep_finish(prio3_config, leader.prep_state, prep_msg)
+            match self
+                .vdaf
+                .ping_pong_continued(0, leader.prep_state, &inbound)
+            {
+                Ok(PingPongTransition::Continued(prep_state, outbound)) => {
+                    states.push(AggregationJobReportState {
+                        draft02_prep_share: None,
+                        prep_state,
+                        time: leader.time,
+                        report_id: leader.report_id,
+                    });
+                    transitions.push(Transition {
+                        report_id: leader.report_id,
+                        var: TransitionVar::Continued(outbound),
+                    });
                 }
-                VdafConfig::Prio2 { dimension } => {
-                    prio2_prep_finish(*dimension, leader.prep_state, prep_msg)
+
+                Ok(PingPongTransition::FinishedWithOutbound(data, outbound)) => {
+                    out_shares.push(DapOutputShare {
+                        report_id: leader.report_id,
+                        time: leader.time,
+                        data,
+                    });
+                    transitions.push(Transition {
+                        report_id: leader.report_id,
+                        var: TransitionVar::Continued(outbound),
+                    });
                 }
-                #[cfg(any(test, feature = "test-utils"))]
-                VdafConfig::Mastic { .. } => mastic_prep_finish(leader.prep_state, prep_msg),
-            };
 
-            match res {
-                Ok(data) => {
+                Ok(PingPongTransition::Finished(data)) => {
                     agg_span.add_out_share(
                         self,
                         &state.part_batch_sel,
@@ -995,14 +1196,58 @@ impl DapTaskConfig {
 
                 Err(VdafError::Codec(..) | VdafError::Vdaf(..)) => {
                     let failure = TransitionFailure::VdafPrepError;
-                    metrics.report_inc_by(&format!("rejected_{failure}"), 1);
+                    metrics.report_inc_by(
+                        &format!("rejected_{failure}"),
+                        1,
+                        TaskLabels::new(task_id, self),
+                    );
                 }
 
                 Err(VdafError::Dap(e)) => return Err(e),
             }
         }
 
-        Ok(DapLeaderAggregationJobTransition::Finished(agg_span))
+        // Every report is expected to take the same number of rounds. If any report finished,
+        // then the aggregation job is finished.
+        if transitions.is_empty() {
+            return Ok(DapLeaderAggregationJobTransition::Finished(agg_span));
+        }
+        if agg_span.report_count() > 0 || (!states.is_empty() && !out_shares.is_empty()) {
+            return Err(fatal_error!(
+                err = "reports finished preparation in different rounds"
+            ));
+        }
+
+        let agg_job_cont_req = AggregationJobContinueReq {
+            draft02_task_id: task_id.for_request_payload(&self.version),
+            draft02_agg_job_id: agg_job_id.for_request_payload(),
+            round: Some(round),
+            transitions,
+        };
+
+        if states.is_empty() {
+            Ok(DapLeaderAggregationJobTransition::Uncommitted(
+                DapAggregationJobUncommitted {
+                    seq: out_shares,
+                    part_batch_sel: state.part_batch_sel,
+                },
+                agg_job_cont_req,
+            ))
+        } else {
+            Ok(DapLeaderAggregationJobTransition::Continued(
+                DapAggregationJobState {
+                    seq: states,
+                    part_batch_sel: state.part_batch_sel,
+                    round,
+                    last_resp: None,
+                    pending_init_req: None,
+                    init_req_hash: None,
+                    processing_lease: None,
+                    failure: None,
+                },
+                agg_job_cont_req,
+            ))
+        }
     }
 
     /// Handle an aggregate request from the Leader. This method is called by the Helper.
@@ -1015,6 +1260,9 @@ impl DapTaskConfig {
     /// * `state` is the helper's current state.
     ///
     /// * `agg_cont_req` is the aggregate request sent by the Leader.
+    ///
+    /// The request is expected to be for the round following `state.round`. Recovering from the
+    /// Leader retrying the current round is up to the caller.
     #[allow(clippy::too_many_arguments)]
     pub(crate) fn handle_agg_job_cont_req(
         &self,
@@ -1023,9 +1271,10 @@ impl DapTaskConfig {
         report_status: &HashMap<ReportId, ReportProcessedStatus>,
         agg_job_id: &MetaAggregationJobId,
         agg_job_cont_req: &AggregationJobContinueReq,
-    ) -> Result<(DapAggregateSpan<DapAggregateShare>, AggregationJobResp), DapError> {
+    ) -> Result<DapHelperAggregationJobTransition<AggregationJobResp>, DapError> {
+        let expected_round = u32::from(state.round) + 1;
         match agg_job_cont_req.round {
-            Some(1) | None => {}
+            None => {}
             Some(0) => {
                 return Err(DapAbort::InvalidMessage {
                     detail: "request shouldn't indicate round 0".into(),
@@ -1033,17 +1282,19 @@ impl DapTaskConfig {
                 }
                 .into())
             }
-            // TODO(bhalleycf) For now, there is only ever one round, and we don't try to do
-            // aggregation-round-skew-recovery.
+            Some(r) if u32::from(r) == expected_round => {}
             Some(r) => {
                 return Err(DapAbort::RoundMismatch {
-                    detail: format!("The request indicates round {r}; round 1 was expected."),
+                    detail: format!(
+                        "The request indicates round {r}; round {expected_round} was expected."
+                    ),
                     task_id: *task_id,
                     agg_job_id_base64url: agg_job_id.to_base64url(),
                 }
                 .into())
             }
         }
+        let mut states = Vec::new();
         let mut processed = HashSet::with_capacity(state.seq.len());
         let recognized = state
             .seq
@@ -1113,25 +1364,63 @@ impl DapTaskConfig {
             let var = match report_status.get(&leader.report_id) {
                 Some(ReportProcessedStatus::Rejected(failure)) => TransitionVar::Failed(*failure),
                 Some(ReportProcessedStatus::Aggregated) => TransitionVar::Finished,
-                None => {
-                    let res = match &self.vdaf {
-                        VdafConfig::Prio3(prio3_config) => {
-                            prio3_prep_finish(prio3_config, prep_state.clone(), leader_message)
-                        }
-                        VdafConfig::Prio2 { dimension } => {
-                            prio2_prep_finish(*dimension, prep_state.clone(), leader_message)
+                None if self.version == DapVersion::Draft02 => {
+                    let res = self
+                        .vdaf
+                        .prep_next(prep_state.clone(), leader_message)
+                        .and_then(draft02_finish);
+
+                    match res {
+                        Ok(data) => {
+                            agg_span.add_out_share(
+                                self,
+                                &state.part_batch_sel,
+                                *report_id,
+                                *time,
+                                data,
+                            )?;
+                            TransitionVar::Finished
                         }
-                        #[cfg(any(test, feature = "test-utils"))]
-                        // draft02 compatibility: In theory we should be able to support Mastic (or
-                        // Poplar1) in a limited capacity. However, this would probably
-                        // overcomplicate the code.
-                        VdafConfig::Mastic { .. } => {
-                            unreachable!("draft02: Mastic is not supported")
+
+                        Err(VdafError::Codec(..) | VdafError::Vdaf(..)) => {
+                            let failure = TransitionFailure::VdafPrepError;
+                            TransitionVar::Failed(failure)
                         }
-                    };
+
+                        Err(VdafError::Dap(e)) => return Err(e),
+                    }
+                }
+                None => {
+                    let res = PingPongMessage::decode(leader_message)
+                        .map_err(VdafError::from)
+                        .and_then(|inbound| {
+                            self.vdaf
+                                .ping_pong_continued(1, prep_state.clone(), &inbound)
+                        });
 
                     match res {
-                        Ok(data) => {
+                        Ok(PingPongTransition::Continued(prep_state, outbound)) => {
+                            states.push(AggregationJobReportState {
+                                draft02_prep_share: None,
+                                prep_state,
+                                time: *time,
+                                report_id: *report_id,
+                            });
+                            TransitionVar::Continued(outbound)
+                        }
+
+                        Ok(PingPongTransition::FinishedWithOutbound(data, outbound)) => {
+                            agg_span.add_out_share(
+                                self,
+                                &state.part_batch_sel,
+                                *report_id,
+                                *time,
+                                data,
+                            )?;
+                            TransitionVar::Continued(outbound)
+                        }
+
+                        Ok(PingPongTransition::Finished(data)) => {
                             agg_span.add_out_share(
                                 self,
                                 &state.part_batch_sel,
@@ -1158,12 +1447,26 @@ impl DapTaskConfig {
             });
         }
 
-        Ok((agg_span, AggregationJobResp { transitions }))
+        helper_transition(
+            agg_span,
+            DapAggregationJobState {
+                seq: states,
+                part_batch_sel: state.part_batch_sel.clone(),
+                round: agg_job_cont_req.round.unwrap_or(0),
+                last_resp: None,
+                pending_init_req: None,
+                init_req_hash: None,
+                processing_lease: None,
+                failure: None,
+            },
+            AggregationJobResp { transitions },
+        )
     }
 
     /// Handle the last aggregate response from the Helper. This method is run by the Leader.
     pub fn handle_final_agg_job_resp(
         &self,
+        task_id: &TaskId,
         state: DapAggregationJobUncommitted,
         agg_job_resp: AggregationJobResp,
         metrics: &dyn DaphneMetrics,
@@ -1204,7 +1507,11 @@ impl DapTaskConfig {
 
                 // Skip report that can't be processed any further.
                 TransitionVar::Failed(failure) => {
-                    metrics.report_inc_by(&format!("rejected_{failure}"), 1);
+                    metrics.report_inc_by(
+                        &format!("rejected_{failure}"),
+                        1,
+                        TaskLabels::new(task_id, self),
+                    );
                     continue;
                 }
 
@@ -1311,3 +1618,39 @@ fn produce_encrypted_agg_share(
         payload,
     })
 }
+
+/// Decide the Helper's transition for the current round. Every report is expected to take the same
+/// number of rounds, so the aggregation job continues only if none of its reports has finished.
+fn helper_transition(
+    agg_span: DapAggregateSpan<DapAggregateShare>,
+    state: DapAggregationJobState,
+    agg_job_resp: AggregationJobResp,
+) -> Result<DapHelperAggregationJobTransition<AggregationJobResp>, DapError> {
+    if state.seq.is_empty() {
+        Ok(DapHelperAggregationJobTransition::Finished(
+            agg_span,
+            agg_job_resp,
+        ))
+    } else if agg_span.report_count() == 0 {
+        Ok(DapHelperAggregationJobTransition::Continued(
+            state,
+            agg_job_resp,
+        ))
+    } else {
+        Err(fatal_error!(
+            err = "reports finished preparation in different rounds"
+        ))
+    }
+}
+
+/// draft02 compatibility: Only single-round VDAFs are supported. In theory we should be able to
+/// support Mastic (or Poplar1) in a limited capacity. However, this would probably overcomplicate
+/// the code. Since we don't plan to use it, don't support it.
+fn draft02_finish(transition: VdafPrepTransition) -> Result<VdafAggregateShare, VdafError> {
+    match transition {
+        VdafPrepTransition::Finish(out_share) => Ok(out_share),
+        VdafPrepTransition::Continue(..) => Err(VdafError::Dap(fatal_error!(
+            err = "draft02: multi-round VDAFs are not supported"
+        ))),
+    }
+}
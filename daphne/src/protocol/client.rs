@@ -1,8 +1,6 @@
 // Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
-#[cfg(any(test, feature = "test-utils"))]
-use crate::vdaf::mastic::mastic_shard;
 use crate::{
     fatal_error,
     hpke::HpkeConfig,
@@ -10,7 +8,6 @@ use crate::{
         encode_u32_bytes, Extension, HpkeCiphertext, PlaintextInputShare, Report, ReportId,
         ReportMetadata, TaskId, Time,
     },
-    vdaf::{prio2::prio2_shard, prio3::prio3_shard},
     DapError, DapMeasurement, DapVersion, VdafConfig,
 };
 use prio::codec::{Encode, ParameterizedEncode};
@@ -167,15 +164,7 @@ impl VdafConfig {
         measurement: DapMeasurement,
         nonce: &[u8; 16],
     ) -> Result<(Vec<u8>, Vec<Vec<u8>>), DapError> {
-        match self {
-            Self::Prio3(prio3_config) => Ok(prio3_shard(prio3_config, measurement, nonce)?),
-            Self::Prio2 { dimension } => Ok(prio2_shard(*dimension, measurement, nonce)?),
-            #[cfg(any(test, feature = "test-utils"))]
-            VdafConfig::Mastic {
-                input_size,
-                weight_config,
-            } => Ok(mastic_shard(*input_size, *weight_config, measurement)?),
-        }
+        Ok(self.dap_vdaf()?.shard(measurement, nonce)?)
     }
 
     /// Generate a report for a measurement. This method is run by the Client.
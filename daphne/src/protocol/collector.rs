@@ -1,13 +1,10 @@
 // Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
-#[cfg(any(test, feature = "test-utils"))]
-use crate::vdaf::mastic::mastic_unshard;
 use crate::{
     fatal_error,
     hpke::HpkeDecrypter,
     messages::{encode_u32_prefixed, BatchSelector, HpkeCiphertext, TaskId},
-    vdaf::{prio2::prio2_unshard, prio3::prio3_unshard},
     DapAggregateResult, DapAggregationParam, DapError, DapVersion, VdafConfig,
 };
 use prio::codec::Encode;
@@ -89,18 +86,8 @@ impl VdafConfig {
         }
 
         let num_measurements = usize::try_from(report_count).unwrap();
-        match self {
-            Self::Prio3(prio3_config) => {
-                Ok(prio3_unshard(prio3_config, num_measurements, agg_shares)?)
-            }
-            Self::Prio2 { dimension } => {
-                Ok(prio2_unshard(*dimension, num_measurements, agg_shares)?)
-            }
-            #[cfg(any(test, feature = "test-utils"))]
-            Self::Mastic {
-                input_size: _,
-                weight_config,
-            } => Ok(mastic_unshard(*weight_config, agg_param, agg_shares)?),
-        }
+        Ok(self
+            .dap_vdaf()?
+            .unshard(agg_param, num_measurements, agg_shares)?)
     }
 }
@@ -22,32 +22,37 @@ mod test {
         hpke::{HpkeAeadId, HpkeConfig, HpkeKdfId, HpkeKemId},
         messages::{
             AggregationJobInitReq, BatchSelector, Extension, Interval, PartialBatchSelector,
-            PrepareInit, Report, ReportId, ReportShare, Transition, TransitionFailure,
-            TransitionVar,
+            PrepareInit, Report, ReportId, ReportMetadata, ReportShare, TaskId, Transition,
+            TransitionFailure, TransitionVar,
         },
         protocol::aggregator::{
             EarlyReportState, EarlyReportStateConsumed, EarlyReportStateInitialized,
         },
+        roles::report_extensions::{
+            DapReportExtensionHandler, DapReportExtensionHandlers, DapReportExtensionOutcome,
+        },
         test_versions,
         testing::AggregationJobTest,
-        vdaf::{Prio3Config, VdafConfig},
+        vdaf::{mastic::MasticWeight, MasticWeightConfig, Prio3Config, VdafConfig},
         DapAggregateResult, DapAggregateShare, DapAggregateSpan, DapAggregationJobState,
         DapAggregationJobUncommitted, DapAggregationParam, DapError,
         DapHelperAggregationJobTransition, DapLeaderAggregationJobTransition, DapMeasurement,
-        DapVersion, VdafAggregateShare, VdafPrepMessage, VdafPrepState,
+        DapTaskConfig, DapVersion, VdafAggregateShare, VdafPrepMessage, VdafPrepState,
     };
     use assert_matches::assert_matches;
     use hpke_rs::HpkePublicKey;
     use prio::{
         codec::Encode,
         field::Field64,
+        idpf::IdpfInput,
         vdaf::{
-            prio3::Prio3, AggregateShare, Aggregator as VdafAggregator, Collector as VdafCollector,
-            OutputShare, PrepareTransition,
+            poplar1::Poplar1AggregationParam, prio3::Prio3, AggregateShare,
+            Aggregator as VdafAggregator, Collector as VdafCollector, OutputShare,
+            PrepareTransition,
         },
     };
     use rand::prelude::*;
-    use std::{fmt::Debug, iter::zip};
+    use std::{fmt::Debug, iter::zip, sync::Arc};
 
     impl<M: Debug> DapLeaderAggregationJobTransition<M> {
         fn unwrap_continued(self) -> (DapAggregationJobState, M) {
@@ -97,6 +102,32 @@ mod test {
     // TODO Exercise all of the Prio3 variants and not just Count.
     const TEST_VDAF: &VdafConfig = &VdafConfig::Prio3(Prio3Config::Count);
 
+    // A VDAF with more than one round of preparation.
+    const TEST_MULTI_ROUND_VDAF: &VdafConfig = &VdafConfig::Mastic {
+        input_size: 1,
+        weight_config: MasticWeightConfig::Count,
+    };
+
+    fn multi_round_test_setup() -> (AggregationJobTest, Vec<Report>, DapAggregationParam) {
+        let t = AggregationJobTest::new(
+            TEST_MULTI_ROUND_VDAF,
+            HpkeKemId::X25519HkdfSha256,
+            DapVersion::Latest,
+        );
+        let reports = t.produce_reports(
+            (0..3)
+                .map(|i| DapMeasurement::Mastic {
+                    input: vec![i],
+                    weight: MasticWeight::Bool(true),
+                })
+                .collect(),
+        );
+        let agg_param = DapAggregationParam::Mastic(
+            Poplar1AggregationParam::try_from_prefixes(vec![IdpfInput::from_bytes(&[0])]).unwrap(),
+        );
+        (t, reports, agg_param)
+    }
+
     async fn roundtrip_report(version: DapVersion) {
         let t = AggregationJobTest::new(TEST_VDAF, HpkeKemId::X25519HkdfSha256, version);
         let report = t
@@ -115,6 +146,7 @@ mod test {
 
         let early_report_state_consumed = EarlyReportStateConsumed::consume(
             &t.leader_hpke_receiver_config,
+            DapReportExtensionHandlers::none(),
             true, // is_leader
             &t.task_id,
             &t.task_config,
@@ -145,6 +177,7 @@ mod test {
 
         let early_report_state_consumed = EarlyReportStateConsumed::consume(
             &t.helper_hpke_receiver_config,
+            DapReportExtensionHandlers::none(),
             false, // is_helper
             &t.task_id,
             &t.task_config,
@@ -574,8 +607,9 @@ mod test {
                         .handle_agg_job_resp(leader_state, agg_job_resp)
                         .unwrap_uncommitted();
 
-                    let (helper_agg_span, agg_job_resp) =
-                        t.handle_agg_job_cont_req(&helper_state, &agg_job_cont_req);
+                    let (helper_agg_span, agg_job_resp) = t
+                        .handle_agg_job_cont_req(&helper_state, &agg_job_cont_req)
+                        .unwrap_finished();
                     assert_eq!(helper_agg_span.report_count(), 5);
                     assert_eq!(agg_job_resp.transitions.len(), 5);
 
@@ -646,8 +680,9 @@ mod test {
             .handle_agg_job_resp(leader_state, agg_job_resp)
             .unwrap_uncommitted();
 
-        let (helper_agg_span, agg_job_resp) =
-            t.handle_agg_job_cont_req(&helper_state, &agg_job_cont_req);
+        let (helper_agg_span, agg_job_resp) = t
+            .handle_agg_job_cont_req(&helper_state, &agg_job_cont_req)
+            .unwrap_finished();
 
         assert_eq!(2, helper_agg_span.report_count());
         assert_eq!(2, agg_job_resp.transitions.len());
@@ -863,6 +898,121 @@ mod test {
         assert!(DapAggregationJobState::get_decoded(TEST_VDAF, b"invalid helper state").is_err());
     }
 
+    #[tokio::test]
+    async fn helper_state_serialization_multi_round() {
+        let (t, reports, agg_param) = multi_round_test_setup();
+        let (_, agg_job_init_req) = t
+            .produce_agg_job_init_req(&agg_param, reports)
+            .await
+            .unwrap_continued();
+        let (mut want, agg_job_resp) = t
+            .handle_agg_job_init_req(agg_job_init_req)
+            .await
+            .unwrap_continued();
+        want.round = 1;
+        want.last_resp = Some(agg_job_resp);
+
+        let got = DapAggregationJobState::get_decoded(
+            TEST_MULTI_ROUND_VDAF,
+            &want.get_encoded().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(got.round, 1);
+        assert_eq!(got.last_resp, want.last_resp);
+        assert_eq!(got.seq.len(), 3);
+        assert_eq!(got.get_encoded().unwrap(), want.get_encoded().unwrap());
+    }
+
+    #[test]
+    fn helper_state_serialization_pending() {
+        let want = DapAggregationJobState::pending(
+            PartialBatchSelector::TimeInterval,
+            b"some AggregationJobInitReq".to_vec(),
+            [1; 32],
+            1337,
+        );
+
+        let got =
+            DapAggregationJobState::get_decoded(TEST_VDAF, &want.get_encoded().unwrap()).unwrap();
+        assert_eq!(got.pending_init_req, want.pending_init_req);
+        assert_eq!(got.init_req_hash, Some([1; 32]));
+        assert_eq!(got.processing_lease, Some(1337));
+        assert!(got.seq.is_empty());
+        assert_eq!(got.get_encoded().unwrap(), want.get_encoded().unwrap());
+    }
+
+    #[test]
+    fn helper_state_serialization_failed() {
+        let want = DapAggregationJobState::failed(
+            PartialBatchSelector::TimeInterval,
+            [1; 32],
+            DapAbort::BadRequest("something went wrong".into()).into_problem_details(),
+        );
+
+        let got =
+            DapAggregationJobState::get_decoded(TEST_VDAF, &want.get_encoded().unwrap()).unwrap();
+        assert_eq!(
+            got.failure
+                .as_deref()
+                .and_then(DapAbort::from_problem_details),
+            Some(DapAbort::BadRequest("something went wrong".into()))
+        );
+        assert!(!got.is_processing());
+        assert_eq!(got.get_encoded().unwrap(), want.get_encoded().unwrap());
+    }
+
+    #[tokio::test]
+    async fn agg_job_multi_round() {
+        let (t, reports, agg_param) = multi_round_test_setup();
+        let (leader_state, agg_job_init_req) = t
+            .produce_agg_job_init_req(&agg_param, reports)
+            .await
+            .unwrap_continued();
+
+        // Round 0: The Helper is waiting for the Leader.
+        let (helper_state, agg_job_resp) = t
+            .handle_agg_job_init_req(agg_job_init_req)
+            .await
+            .unwrap_continued();
+        assert_eq!(helper_state.round, 0);
+
+        // Round 1: The Leader finishes first, then the Helper.
+        let (leader_uncommitted, agg_job_cont_req) = t
+            .handle_agg_job_resp(leader_state, agg_job_resp)
+            .unwrap_uncommitted();
+        assert_eq!(agg_job_cont_req.round, Some(1));
+        let (helper_agg_span, agg_job_resp) = t
+            .handle_agg_job_cont_req(&helper_state, &agg_job_cont_req)
+            .unwrap_finished();
+        assert_eq!(helper_agg_span.report_count(), 3);
+
+        let leader_agg_span = t.handle_final_agg_job_resp(leader_uncommitted, agg_job_resp);
+        assert_eq!(leader_agg_span.report_count(), 3);
+    }
+
+    #[tokio::test]
+    async fn agg_job_cont_req_abort_round_mismatch() {
+        let (t, reports, agg_param) = multi_round_test_setup();
+        let (leader_state, agg_job_init_req) = t
+            .produce_agg_job_init_req(&agg_param, reports)
+            .await
+            .unwrap_continued();
+        let (helper_state, agg_job_resp) = t
+            .handle_agg_job_init_req(agg_job_init_req)
+            .await
+            .unwrap_continued();
+        let (_, mut agg_job_cont_req) = t
+            .handle_agg_job_resp(leader_state, agg_job_resp)
+            .unwrap_uncommitted();
+
+        // The Leader skipped a round.
+        agg_job_cont_req.round = Some(2);
+        assert_matches!(
+            t.handle_agg_job_cont_req_expect_err(helper_state, &agg_job_cont_req),
+            DapError::Abort(DapAbort::RoundMismatch { .. })
+        );
+    }
+
     async fn handle_unrecognized_report_extensions(version: DapVersion) {
         let t = AggregationJobTest::new(TEST_VDAF, HpkeKemId::X25519HkdfSha256, version);
         let report = t
@@ -885,6 +1035,7 @@ mod test {
         let report_metadata = report.report_metadata.clone();
         let consumed_report = EarlyReportStateConsumed::consume(
             &t.leader_hpke_receiver_config,
+            DapReportExtensionHandlers::none(),
             true,
             &t.task_id,
             &t.task_config,
@@ -940,6 +1091,7 @@ mod test {
         let [leader_share, _] = report.encrypted_input_shares;
         let consumed_report = EarlyReportStateConsumed::consume(
             &t.leader_hpke_receiver_config,
+            DapReportExtensionHandlers::none(),
             true,
             &t.task_id,
             &t.task_config,
@@ -959,6 +1111,122 @@ mod test {
 
     async_test_versions! { handle_repeated_report_extensions }
 
+    struct TestExtensionHandler;
+
+    impl DapReportExtensionHandler for TestExtensionHandler {
+        fn extension_type(&self) -> u16 {
+            0xfffe
+        }
+
+        fn handle(
+            &self,
+            _is_leader: bool,
+            _task_id: &TaskId,
+            _task_config: &DapTaskConfig,
+            _metadata: &ReportMetadata,
+            payload: &[u8],
+        ) -> DapReportExtensionOutcome {
+            match payload {
+                b"accept" => DapReportExtensionOutcome::Accept,
+                b"attach" => DapReportExtensionOutcome::AcceptWithData(b"attached".to_vec()),
+                _ => DapReportExtensionOutcome::Reject(TransitionFailure::ReportDropped),
+            }
+        }
+    }
+
+    async fn handle_report_extensions_with_handler(version: DapVersion) {
+        let t = AggregationJobTest::new(TEST_VDAF, HpkeKemId::X25519HkdfSha256, version);
+        let mut extension_handlers = DapReportExtensionHandlers::default();
+        extension_handlers
+            .register(Arc::new(TestExtensionHandler))
+            .unwrap();
+
+        for (payload, expected) in [
+            (&b"accept"[..], Ok(vec![])),
+            (b"attach", Ok(vec![(0xfffe, b"attached".to_vec())])),
+            (b"reject", Err(TransitionFailure::ReportDropped)),
+        ] {
+            let report = t
+                .task_config
+                .vdaf
+                .produce_report_with_extensions(
+                    &t.client_hpke_config_list,
+                    t.now,
+                    &t.task_id,
+                    DapMeasurement::U64(1),
+                    vec![Extension::NotImplemented {
+                        typ: 0xfffe,
+                        payload: payload.to_vec(),
+                    }],
+                    t.task_config.version,
+                )
+                .unwrap();
+
+            let [leader_share, _] = report.encrypted_input_shares;
+            let consumed_report = EarlyReportStateConsumed::consume(
+                &t.leader_hpke_receiver_config,
+                &extension_handlers,
+                true,
+                &t.task_id,
+                &t.task_config,
+                ReportShare {
+                    report_metadata: report.report_metadata,
+                    public_share: report.public_share,
+                    encrypted_input_share: leader_share,
+                },
+                None,
+            )
+            .await
+            .unwrap();
+
+            match (&consumed_report, &expected) {
+                (EarlyReportStateConsumed::Ready { extension_data, .. }, Ok(expected)) => {
+                    assert_eq!(extension_data, expected);
+                }
+                (EarlyReportStateConsumed::Rejected { failure, .. }, Err(expected)) => {
+                    assert_eq!(failure, expected);
+                }
+                (_, expected) => panic!("unexpected outcome: expected {expected:?}"),
+            }
+
+            // The attached data is carried through VDAF preparation.
+            let initialized_report = EarlyReportStateInitialized::initialize(
+                true,
+                &t.task_config.vdaf_verify_key,
+                &t.task_config.vdaf,
+                &DapAggregationParam::Empty,
+                consumed_report,
+            )
+            .unwrap();
+            match (initialized_report, expected) {
+                (EarlyReportStateInitialized::Ready { extension_data, .. }, Ok(expected)) => {
+                    assert_eq!(extension_data, expected);
+                }
+                (EarlyReportStateInitialized::Rejected { failure, .. }, Err(expected)) => {
+                    assert_eq!(failure, expected);
+                }
+                (_, expected) => panic!("unexpected outcome: expected {expected:?}"),
+            }
+        }
+    }
+
+    async_test_versions! { handle_report_extensions_with_handler }
+
+    #[test]
+    fn register_report_extension_handler() {
+        let mut extension_handlers = DapReportExtensionHandlers::default();
+        extension_handlers
+            .register(Arc::new(TestExtensionHandler))
+            .unwrap();
+        assert!(extension_handlers.get(0xfffe).is_some());
+        assert!(extension_handlers.get(0xfffd).is_none());
+
+        // Each extension type may only have one handler.
+        assert!(extension_handlers
+            .register(Arc::new(TestExtensionHandler))
+            .is_err());
+    }
+
     impl AggregationJobTest {
         // Tweak the Helper's share so that decoding succeeds but preparation fails.
         fn produce_invalid_report_vdaf_prep_failure(
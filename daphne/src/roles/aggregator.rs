@@ -1,21 +1,24 @@
 // Copyright (c) 2023 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 
 use async_trait::async_trait;
 use prio::codec::Encode;
 
+use super::report_extensions::DapReportExtensionHandlers;
+
 use crate::{
     audit_log::AuditLog,
     constants::DapMediaType,
     error::DapAbort,
     hpke::{HpkeConfig, HpkeDecrypter},
     messages::{BatchId, BatchSelector, HpkeConfigList, ReportId, TaskId, Time},
-    metrics::{DaphneMetrics, DaphneRequestType},
+    metrics::{DaphneMetrics, DaphneRequestType, TaskLabels},
     protocol::aggregator::{EarlyReportStateConsumed, EarlyReportStateInitialized},
-    DapAggregateShare, DapAggregateSpan, DapAggregationParam, DapError, DapGlobalConfig,
-    DapRequest, DapResponse, DapTaskConfig, DapVersion,
+    DapAggregateShare, DapAggregateSpan, DapAggregationParam, DapBatchBucket, DapCollectionQuery,
+    DapError, DapGlobalConfig, DapRequest, DapResponse, DapResponseStatus, DapTaskConfig,
+    DapVersion, MetaAggregationJobId,
 };
 
 /// Report initializer. Used by a DAP Aggregator [`DapAggregator`] when initializing an aggregation
@@ -36,6 +39,7 @@ pub trait DapReportInitializer {
 #[derive(Debug)]
 pub enum MergeAggShareError {
     AlreadyCollected,
+    BatchSaturated,
     ReplaysDetected(HashSet<ReportId>),
     Other(DapError),
 }
@@ -62,6 +66,12 @@ pub trait DapAggregator<S: Sync>: HpkeDecrypter + DapReportInitializer + Sized {
     /// Look up the DAP global configuration.
     fn get_global_config(&self) -> &DapGlobalConfig;
 
+    /// The handlers for report extensions that are not built into Daphne. Reports carrying an
+    /// extension without a handler are rejected.
+    fn report_extension_handlers(&self) -> &DapReportExtensionHandlers {
+        DapReportExtensionHandlers::none()
+    }
+
     /// taskprov: The VDAF verification key initializer. Used to derive the VDAF verify key for all
     /// tasks configured by this extension.
     fn taskprov_vdaf_verify_key_init(&self) -> Option<&[u8; 32]>;
@@ -98,19 +108,21 @@ pub trait DapAggregator<S: Sync>: HpkeDecrypter + DapReportInitializer + Sized {
     /// Get the current time (number of seconds since the beginning of UNIX time).
     fn get_current_time(&self) -> Time;
 
-    /// Check whether the batch determined by the collect request would overlap with a previously
-    /// collected batch.
-    async fn is_batch_overlapping(
+    /// Return the number of times the batch determined by the collect request has been collected,
+    /// i.e., the largest number of times any of the buckets it spans has been collected.
+    async fn get_batch_query_count(
         &self,
         task_id: &TaskId,
         batch_sel: &BatchSelector,
-    ) -> Result<bool, DapError>;
+    ) -> Result<u64, DapError>;
 
     /// Check whether the given batch ID has been observed before. This is called by the Leader
     /// (resp. Helper) in response to a CollectReq (resp. AggregateShareReq) for fixed-size tasks.
     async fn batch_exists(&self, task_id: &TaskId, batch_id: &BatchId) -> Result<bool, DapError>;
 
-    /// Store a set of output shares and mark the corresponding reports as aggregated.
+    /// Store a set of output shares and mark the corresponding reports as aggregated. The
+    /// aggregation job the output shares were produced by is passed so that implementations can
+    /// use it to spread writes for the same bucket across storage.
     ///
     /// If any report within a bucket has already been aggregated (is a replay) then that entire
     /// bucket must be skipped without changing any state, such that this operation is idempotent.
@@ -124,11 +136,15 @@ pub trait DapAggregator<S: Sync>: HpkeDecrypter + DapReportInitializer + Sized {
     ///                                              means no aggregate shares where merged.
     /// - `Err(MergeAggShareError::AlreadyCollected)` This span belong to an aggregate share that
     ///                                               has been collected.
+    /// - `Err(MergeAggShareError::BatchSaturated)` Merging this span would exceed the maximum batch
+    ///                                             size of the task. No aggregate shares were
+    ///                                             merged.
     /// - `Err(MergeAggShareError::Other)` if another unrecoverable error occurred.
     async fn try_put_agg_share_span(
         &self,
         task_id: &TaskId,
         task_config: &DapTaskConfig,
+        agg_job_id: &MetaAggregationJobId,
         agg_share_span: DapAggregateSpan<DapAggregateShare>,
     ) -> DapAggregateSpan<Result<(), MergeAggShareError>>;
 
@@ -139,11 +155,22 @@ pub trait DapAggregator<S: Sync>: HpkeDecrypter + DapReportInitializer + Sized {
         batch_sel: &BatchSelector,
     ) -> Result<DapAggregateShare, DapError>;
 
-    /// Mark a batch as collected.
+    /// Fetch the IDs of the reports aggregated into each bucket of the given batch. This is used
+    /// to diagnose a mismatch between the aggregate shares computed by the Leader and Helper.
+    async fn get_agg_report_ids(
+        &self,
+        task_id: &TaskId,
+        batch_sel: &BatchSelector,
+    ) -> Result<HashMap<DapBatchBucket, HashSet<ReportId>>, DapError>;
+
+    /// Mark a batch as collected by the given query, i.e., increment the number of times each
+    /// bucket it spans has been collected. Marking the batch again for the same query has no
+    /// effect.
     async fn mark_collected(
         &self,
         task_id: &TaskId,
         batch_sel: &BatchSelector,
+        query: &DapCollectionQuery,
     ) -> Result<(), DapError>;
 
     /// Access the Prometheus metrics.
@@ -173,9 +200,9 @@ where
         .get_hpke_config_for(req.version, task_id.as_ref())
         .await?;
 
-    if let Some(task_id) = task_id {
+    let task_config = if let Some(task_id) = &task_id {
         let task_config = aggregator
-            .get_task_config_for(&task_id)
+            .get_task_config_for(task_id)
             .await?
             .ok_or(DapAbort::UnrecognizedTask)?;
 
@@ -185,7 +212,10 @@ where
                 DapAbort::version_mismatch(req.version, task_config.as_ref().version).into(),
             );
         }
-    }
+        Some(task_config)
+    } else {
+        None
+    };
 
     let payload = match req.version {
         DapVersion::Draft02 => hpke_config
@@ -200,10 +230,17 @@ where
         }
     };
 
-    metrics.inbound_req_inc(DaphneRequestType::HpkeConfig);
+    metrics.inbound_req_inc(
+        DaphneRequestType::HpkeConfig,
+        task_id
+            .as_ref()
+            .zip(task_config.as_ref())
+            .map(|(task_id, task_config)| TaskLabels::new(task_id, task_config.as_ref())),
+    );
     Ok(DapResponse {
         version: req.version,
         media_type: DapMediaType::HpkeConfigList,
+        status: DapResponseStatus::Done,
         payload,
     })
 }
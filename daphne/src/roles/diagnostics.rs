@@ -0,0 +1,183 @@
+// Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Diagnostics for batches whose aggregate shares do not match.
+//!
+//! If the Leader and Helper aggregate different sets of reports into a batch, then the Helper
+//! aborts the aggregate share request with "batchMismatch". To find out which reports are to
+//! blame, the operator fetches the IDs of the reports aggregated into the batch by each
+//! Aggregator (see [`get_batch_report_ids`]) and compares them on the Leader (see
+//! [`diagnose_batch_mismatch`]).
+
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use super::DapAggregator;
+use crate::{
+    messages::{BatchSelector, ReportId, TaskId},
+    DapBatchBucket, DapError,
+};
+
+/// The IDs of the reports aggregated into each bucket of a batch.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub struct DapBatchReportIds {
+    pub buckets: Vec<DapBucketReportIds>,
+}
+
+/// The IDs of the reports aggregated into a bucket.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct DapBucketReportIds {
+    pub bucket: DapBatchBucket,
+    pub report_ids: Vec<ReportId>,
+}
+
+/// The difference between the sets of reports the Leader and Helper aggregated into a bucket.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct DapBucketMismatch {
+    pub bucket: DapBatchBucket,
+
+    /// Reports aggregated by the Leader but not by the Helper.
+    pub missing_from_helper: Vec<ReportId>,
+
+    /// Reports aggregated by the Helper but not by the Leader.
+    pub missing_from_leader: Vec<ReportId>,
+}
+
+/// Fetch the IDs of the reports this Aggregator aggregated into the given batch.
+pub async fn get_batch_report_ids<S: Sync, A: DapAggregator<S>>(
+    aggregator: &A,
+    task_id: &TaskId,
+    batch_sel: &BatchSelector,
+) -> Result<DapBatchReportIds, DapError> {
+    let mut buckets = aggregator
+        .get_agg_report_ids(task_id, batch_sel)
+        .await?
+        .into_iter()
+        .map(|(bucket, report_ids)| {
+            let mut report_ids = report_ids.into_iter().collect::<Vec<_>>();
+            report_ids.sort_unstable();
+            DapBucketReportIds { bucket, report_ids }
+        })
+        .collect::<Vec<_>>();
+    buckets.sort_unstable_by(|a, b| a.bucket.cmp(&b.bucket));
+    Ok(DapBatchReportIds { buckets })
+}
+
+/// Leader: Compare the reports aggregated into a batch by the Leader with the reports aggregated
+/// by the Helper. Return the buckets in which they differ.
+pub fn diagnose_batch_mismatch(
+    leader: &DapBatchReportIds,
+    helper: &DapBatchReportIds,
+) -> Vec<DapBucketMismatch> {
+    fn by_bucket(ids: &DapBatchReportIds) -> HashMap<&DapBatchBucket, HashSet<&ReportId>> {
+        let mut by_bucket = HashMap::<_, HashSet<_>>::new();
+        for b in &ids.buckets {
+            by_bucket
+                .entry(&b.bucket)
+                .or_default()
+                .extend(&b.report_ids);
+        }
+        by_bucket
+    }
+
+    let leader = by_bucket(leader);
+    let helper = by_bucket(helper);
+    let empty = HashSet::new();
+
+    let mut buckets = leader.keys().chain(helper.keys()).collect::<HashSet<_>>();
+    let mut mismatches = buckets
+        .drain()
+        .filter_map(|bucket| {
+            let leader_ids = leader.get(bucket).unwrap_or(&empty);
+            let helper_ids = helper.get(bucket).unwrap_or(&empty);
+            let mut missing_from_helper = leader_ids
+                .difference(helper_ids)
+                .map(|id| **id)
+                .collect::<Vec<_>>();
+            let mut missing_from_leader = helper_ids
+                .difference(leader_ids)
+                .map(|id| **id)
+                .collect::<Vec<_>>();
+            if missing_from_helper.is_empty() && missing_from_leader.is_empty() {
+                return None;
+            }
+            missing_from_helper.sort_unstable();
+            missing_from_leader.sort_unstable();
+            Some(DapBucketMismatch {
+                bucket: (*bucket).clone(),
+                missing_from_helper,
+                missing_from_leader,
+            })
+        })
+        .collect::<Vec<_>>();
+    mismatches.sort_unstable_by(|a, b| a.bucket.cmp(&b.bucket));
+    mismatches
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        diagnose_batch_mismatch, DapBatchReportIds, DapBucketMismatch, DapBucketReportIds,
+    };
+    use crate::{messages::ReportId, DapBatchBucket};
+
+    fn ids(ids: &[u8]) -> Vec<ReportId> {
+        ids.iter().map(|i| ReportId([*i; 16])).collect()
+    }
+
+    #[test]
+    fn diagnose_batch_mismatch_symmetric_difference() {
+        let window = |batch_window| DapBatchBucket::TimeInterval { batch_window };
+        let leader = DapBatchReportIds {
+            buckets: vec![
+                DapBucketReportIds {
+                    bucket: window(0),
+                    report_ids: ids(&[1, 2, 3]),
+                },
+                DapBucketReportIds {
+                    bucket: window(3600),
+                    report_ids: ids(&[4, 5]),
+                },
+                DapBucketReportIds {
+                    bucket: window(7200),
+                    report_ids: ids(&[7]),
+                },
+            ],
+        };
+        let helper = DapBatchReportIds {
+            buckets: vec![
+                DapBucketReportIds {
+                    bucket: window(0),
+                    report_ids: ids(&[1, 3]),
+                },
+                DapBucketReportIds {
+                    bucket: window(3600),
+                    report_ids: ids(&[4, 5, 6]),
+                },
+            ],
+        };
+
+        assert_eq!(
+            diagnose_batch_mismatch(&leader, &helper),
+            vec![
+                DapBucketMismatch {
+                    bucket: window(0),
+                    missing_from_helper: ids(&[2]),
+                    missing_from_leader: vec![],
+                },
+                DapBucketMismatch {
+                    bucket: window(3600),
+                    missing_from_helper: vec![],
+                    missing_from_leader: ids(&[6]),
+                },
+                DapBucketMismatch {
+                    bucket: window(7200),
+                    missing_from_helper: ids(&[7]),
+                    missing_from_leader: vec![],
+                },
+            ]
+        );
+        assert!(diagnose_batch_mismatch(&leader, &leader).is_empty());
+    }
+}
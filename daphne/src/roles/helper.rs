@@ -5,25 +5,26 @@ use std::{collections::HashMap, sync::Once};
 
 use async_trait::async_trait;
 use prio::codec::{Encode, ParameterizedDecode};
+use ring::digest;
 use tracing::error;
 
 use super::{check_batch, check_request_content_type, resolve_taskprov, DapAggregator};
 use crate::{
     audit_log::AggregationJobAuditAction,
     constants::DapMediaType,
-    error::DapAbort,
+    error::{aborts::ProblemDetails, DapAbort},
     fatal_error,
     messages::{
-        constant_time_eq, AggregateShare, AggregateShareReq, AggregationJobContinueReq,
-        AggregationJobInitReq, AggregationJobResp, Draft02AggregationJobId, PartialBatchSelector,
-        ReportId, TaskId, TransitionFailure, TransitionVar,
+        borrowed::AggregationJobInitReqRef, constant_time_eq, AggregateShare, AggregateShareReq,
+        AggregationJobContinueReq, AggregationJobResp, Draft02AggregationJobId,
+        PartialBatchSelector, ReportId, TaskId, Time, TransitionFailure, TransitionVar,
     },
-    metrics::{DaphneMetrics, DaphneRequestType},
+    metrics::{DaphneMetrics, DaphneRequestType, TaskLabels},
     protocol::aggregator::ReportProcessedStatus,
     roles::aggregator::MergeAggShareError,
-    DapAggregateShare, DapAggregateSpan, DapAggregationJobState, DapAggregationParam, DapError,
-    DapHelperAggregationJobTransition, DapRequest, DapResource, DapResponse, DapTaskConfig,
-    DapVersion, MetaAggregationJobId,
+    DapAggregateShare, DapAggregateSpan, DapAggregationJobState, DapAggregationParam,
+    DapCollectionQuery, DapError, DapHelperAggregationJobTransition, DapRequest, DapResource,
+    DapResponse, DapResponseStatus, DapTaskConfig, DapVersion, MetaAggregationJobId,
 };
 
 /// DAP Helper functionality.
@@ -40,6 +41,29 @@ pub trait DapHelper<S: Sync>: DapAggregator<S> {
     where
         Id: Into<MetaAggregationJobId> + Send;
 
+    /// Store the Helper's aggregation-flow state, overwriting the state from the previous round.
+    async fn put_helper_state<Id>(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: Id,
+        helper_state: &DapAggregationJobState,
+    ) -> Result<(), DapError>
+    where
+        Id: Into<MetaAggregationJobId> + Send;
+
+    /// Store the Helper's aggregation-flow state, but only if the current state is `current`.
+    /// Returns a boolean indicating if the operation succeeded. This is used to hand off an
+    /// aggregation job that is being processed asynchronously from one worker to another.
+    async fn put_helper_state_if_unchanged<Id>(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: Id,
+        current: &DapAggregationJobState,
+        helper_state: &DapAggregationJobState,
+    ) -> Result<bool, DapError>
+    where
+        Id: Into<MetaAggregationJobId> + Send;
+
     /// Fetch the Helper's aggregation-flow state. `None` is returned if the Helper has no state
     /// associated with the given task and aggregation job.
     async fn get_helper_state<Id>(
@@ -51,14 +75,72 @@ pub trait DapHelper<S: Sync>: DapAggregator<S> {
         Id: Into<MetaAggregationJobId> + Send;
 }
 
+/// The payload of a response to an aggregation job request, before it is encoded.
+enum AggJobRespPayload {
+    /// The payload was already encoded, e.g., because it was stored in case the Leader retries
+    /// its request.
+    Encoded(Vec<u8>),
+    Resp(AggregationJobResp),
+    /// The aggregation job is still being processed, so there is no payload yet.
+    Processing,
+}
+
+/// Number of seconds for which a worker holds an aggregation job that is being processed. If the
+/// worker doesn't finish in time, e.g., because it crashed, then the aggregation job is processed
+/// again the next time the Leader polls for it or retries the request that initialized it.
+const AGG_JOB_PROCESSING_LEASE_SECS: Time = 60;
+
+/// The outcome of checking whether an `AggregationJobInitReq` is a retry.
+enum AggJobInitRetry {
+    /// The aggregation job doesn't exist yet.
+    New,
+
+    /// The aggregation job was already initialized by this request.
+    Done(AggJobRespPayload),
+
+    /// A previous attempt at this request claimed the aggregation job, but its lease expired
+    /// before it stored the response. The state is the claim to take over.
+    Abandoned(DapAggregationJobState),
+}
+
+impl AggJobRespPayload {
+    fn status(&self) -> DapResponseStatus {
+        match self {
+            Self::Encoded(..) | Self::Resp(..) => DapResponseStatus::Done,
+            Self::Processing => DapResponseStatus::Processing,
+        }
+    }
+
+    fn encode(self) -> Result<Vec<u8>, DapError> {
+        match self {
+            Self::Encoded(payload) => Ok(payload),
+            Self::Resp(agg_job_resp) => agg_job_resp.get_encoded().map_err(DapError::encoding),
+            Self::Processing => Ok(Vec::new()),
+        }
+    }
+}
+
 pub async fn handle_agg_job_init_req<'req, S: Sync, A: DapHelper<S>>(
     aggregator: &A,
     req: &'req DapRequest<S>,
 ) -> Result<DapResponse, DapError> {
+    let payload = agg_job_init(aggregator, req).await?;
+    Ok(DapResponse {
+        version: req.version,
+        media_type: DapMediaType::AggregationJobResp,
+        status: payload.status(),
+        payload: payload.encode()?,
+    })
+}
+
+async fn agg_job_init<S: Sync, A: DapHelper<S>>(
+    aggregator: &A,
+    req: &DapRequest<S>,
+) -> Result<AggJobRespPayload, DapError> {
     let task_id = req.task_id()?;
     let metrics = aggregator.metrics();
     let agg_job_init_req =
-        AggregationJobInitReq::get_decoded_with_param(&req.version, &req.payload)
+        AggregationJobInitReqRef::get_decoded_with_param(&req.version, &req.payload)
             .map_err(|e| DapAbort::from_codec_error(e, *task_id))?;
 
     metrics.agg_job_observe_batch_size(agg_job_init_req.prep_inits.len());
@@ -133,19 +215,195 @@ pub async fn handle_agg_job_init_req<'req, S: Sync, A: DapHelper<S>>(
         task_id,
         task_config,
         &agg_job_init_req.part_batch_sel,
-        &agg_job_init_req.agg_param,
+        agg_job_init_req.agg_param,
     )?;
 
+    // Idempotency: If the Leader is retrying a request that we already handled, e.g., because it
+    // didn't get the response, then send the same response as before.
+    let init_req_hash = agg_job_init_req_hash(&req.payload);
+    let abandoned =
+        match get_agg_job_init_resp_for_retry(aggregator, task_id, agg_job_id, &init_req_hash)
+            .await?
+        {
+            AggJobInitRetry::New => None,
+            AggJobInitRetry::Done(payload) => {
+                metrics.inbound_req_inc(
+                    DaphneRequestType::Aggregate,
+                    Some(TaskLabels::new(task_id, task_config)),
+                );
+                return Ok(payload);
+            }
+            AggJobInitRetry::Abandoned(state) => Some(state),
+        };
+
+    // Asynchronous mode: Accept the aggregation job and process it in the background. The Leader
+    // polls for the response.
+    if abandoned.is_none()
+        && aggregator.get_global_config().helper_async_agg_jobs
+        && req.version != DapVersion::Draft02
+    {
+        let state = DapAggregationJobState::pending(
+            agg_job_init_req.part_batch_sel.clone(),
+            req.payload.clone(),
+            init_req_hash,
+            aggregator.get_current_time() + AGG_JOB_PROCESSING_LEASE_SECS,
+        );
+        if !aggregator
+            .put_helper_state_if_not_exists(task_id, agg_job_id, &state)
+            .await?
+        {
+            return Err(DapAbort::BadRequest(
+                "unexpected message for aggregation job (already exists)".into(),
+            )
+            .into());
+        }
+
+        metrics.inbound_req_inc(
+            DaphneRequestType::Aggregate,
+            Some(TaskLabels::new(task_id, task_config)),
+        );
+        return Ok(AggJobRespPayload::Processing);
+    }
+
+    let agg_job_resp = run_agg_job_init(
+        aggregator,
+        task_id,
+        task_config,
+        agg_job_id,
+        agg_job_init_req,
+        init_req_hash,
+        abandoned.as_ref(),
+    )
+    .await?;
+
+    metrics.inbound_req_inc(
+        DaphneRequestType::Aggregate,
+        Some(TaskLabels::new(task_id, task_config)),
+    );
+    Ok(AggJobRespPayload::Resp(agg_job_resp))
+}
+
+/// Process an aggregation job that was accepted for asynchronous processing by
+/// [`handle_agg_job_init_req`]. This is expected to be run in the background; the Leader polls for
+/// the response with [`handle_agg_job_poll_req`].
+///
+/// This is a no-op if the aggregation job has already been processed. If processing fails with an
+/// abort, then the failure is stored and sent to the Leader when it polls. If processing fails for
+/// any other reason, then the aggregation job remains pending and is processed again the next time
+/// the Leader polls.
+pub async fn process_agg_job<S: Sync, A: DapHelper<S>>(
+    aggregator: &A,
+    task_id: &TaskId,
+    agg_job_id: MetaAggregationJobId,
+) -> Result<(), DapError> {
+    let wrapped_task_config = aggregator
+        .get_task_config_for(task_id)
+        .await?
+        .ok_or(DapAbort::UnrecognizedTask)?;
+    let task_config = wrapped_task_config.as_ref();
+
+    let state = aggregator
+        .get_helper_state(task_id, agg_job_id)
+        .await?
+        .ok_or_else(|| DapAbort::UnrecognizedAggregationJob {
+            task_id: *task_id,
+            agg_job_id_base64url: agg_job_id.to_base64url(),
+        })?;
+    let Some(init_req_data) = &state.pending_init_req else {
+        return Ok(());
+    };
+    let init_req_hash = agg_job_init_req_hash(init_req_data);
+
+    // Take the aggregation job over from whichever worker held it before, unless another worker
+    // beats us to it.
+    let leased = DapAggregationJobState {
+        processing_lease: Some(aggregator.get_current_time() + AGG_JOB_PROCESSING_LEASE_SECS),
+        ..state.clone()
+    };
+    if !aggregator
+        .put_helper_state_if_unchanged(task_id, agg_job_id, &state, &leased)
+        .await?
+    {
+        return Ok(());
+    }
+
+    let result =
+        match AggregationJobInitReqRef::get_decoded_with_param(&task_config.version, init_req_data)
+        {
+            Ok(agg_job_init_req) => {
+                run_agg_job_init(
+                    aggregator,
+                    task_id,
+                    task_config,
+                    agg_job_id,
+                    agg_job_init_req,
+                    init_req_hash,
+                    Some(&leased),
+                )
+                .await
+            }
+            Err(e) => Err(DapAbort::from_codec_error(e, *task_id).into()),
+        };
+
+    match result {
+        Ok(_agg_job_resp) => Ok(()),
+        Err(DapError::Abort(abort)) => {
+            let failed = DapAggregationJobState::failed(
+                state.part_batch_sel.clone(),
+                init_req_hash,
+                abort.into_problem_details(),
+            );
+            aggregator
+                .put_helper_state_if_unchanged(task_id, agg_job_id, &leased, &failed)
+                .await?;
+            Ok(())
+        }
+        Err(e) => {
+            // Release the aggregation job so that it can be processed again.
+            let released = DapAggregationJobState {
+                processing_lease: None,
+                ..state
+            };
+            aggregator
+                .put_helper_state_if_unchanged(task_id, agg_job_id, &leased, &released)
+                .await?;
+            Err(e)
+        }
+    }
+}
+
+/// Initialize the aggregation job and store the Helper's state, along with the response in case
+/// the Leader retries the request. If the aggregation job is being processed asynchronously, or if
+/// a previous attempt abandoned it, then `pending` is the state of the aggregation job taken over
+/// by this worker; it is replaced unless another worker has taken the aggregation job over in the
+/// meantime.
+async fn run_agg_job_init<S: Sync, A: DapHelper<S>>(
+    aggregator: &A,
+    task_id: &TaskId,
+    task_config: &DapTaskConfig,
+    agg_job_id: MetaAggregationJobId,
+    agg_job_init_req: AggregationJobInitReqRef<'_>,
+    init_req_hash: [u8; 32],
+    pending: Option<&DapAggregationJobState>,
+) -> Result<AggregationJobResp, DapError> {
+    let metrics = aggregator.metrics();
     let prep_init_count = agg_job_init_req.prep_inits.len();
     let part_batch_sel = agg_job_init_req.part_batch_sel.clone();
     let initialized_reports = task_config
-        .helper_initialize_reports(aggregator, aggregator, task_id, agg_job_init_req)
+        .helper_initialize_reports(
+            aggregator,
+            aggregator,
+            aggregator.report_extension_handlers(),
+            task_id,
+            agg_job_init_req,
+        )
         .await?;
 
     let agg_job_resp = match task_config.version {
         DapVersion::Draft02 => {
-            let DapHelperAggregationJobTransition::Continued(state, agg_job_resp) = task_config
+            let DapHelperAggregationJobTransition::Continued(mut state, agg_job_resp) = task_config
                 .handle_agg_job_init_req(
+                    task_id,
                     &HashMap::default(), // no reports have been processed yet
                     &part_batch_sel,
                     &initialized_reports,
@@ -155,6 +413,8 @@ pub async fn handle_agg_job_init_req<'req, S: Sync, A: DapHelper<S>>(
                 return Err(fatal_error!(err = "unexpected transition"));
             };
 
+            state.last_resp = Some(agg_job_resp.clone());
+            state.init_req_hash = Some(init_req_hash);
             if !aggregator
                 .put_helper_state_if_not_exists(task_id, agg_job_id, &state)
                 .await?
@@ -164,35 +424,157 @@ pub async fn handle_agg_job_init_req<'req, S: Sync, A: DapHelper<S>>(
                 )
                 .into());
             }
-            metrics.agg_job_started_inc();
+            metrics.agg_job_started_inc(TaskLabels::new(task_id, task_config));
             agg_job_resp
         }
 
         DapVersion::Draft09 | DapVersion::Latest => {
-            let agg_job_resp = finish_agg_job_and_aggregate(
-                aggregator,
+            let transition = task_config.handle_agg_job_init_req(
                 task_id,
-                task_config,
+                &HashMap::default(), // no reports have been processed yet
+                &part_batch_sel,
+                &initialized_reports,
                 metrics,
-                |report_status| {
-                    let DapHelperAggregationJobTransition::Finished(agg_span, agg_job_resp) =
-                        task_config.handle_agg_job_init_req(
-                            report_status,
-                            &part_batch_sel,
-                            &initialized_reports,
+            )?;
+
+            match transition {
+                // The VDAF takes more than one round: store our state and wait for the Leader.
+                DapHelperAggregationJobTransition::Continued(mut state, agg_job_resp) => {
+                    state.last_resp = Some(agg_job_resp.clone());
+                    state.init_req_hash = Some(init_req_hash);
+                    if !put_agg_job_init_state(aggregator, task_id, agg_job_id, pending, &state)
+                        .await?
+                    {
+                        return Err(agg_job_init_conflict(
+                            aggregator,
+                            task_id,
+                            agg_job_id,
+                            &init_req_hash,
+                        )
+                        .await);
+                    }
+                    metrics.agg_job_started_inc(TaskLabels::new(task_id, task_config));
+                    agg_job_resp
+                }
+
+                DapHelperAggregationJobTransition::Finished(agg_span, agg_job_resp) => {
+                    // Claim the aggregation job before aggregating the output shares. If the
+                    // Leader retries the request while we hold the claim, then the retry is told
+                    // to try again later rather than aggregating the same reports concurrently.
+                    // If we don't finish before the lease expires, e.g., because we crashed, then
+                    // the aggregation job is taken over by the next retry (or poll, if it is being
+                    // processed asynchronously, in which case the pending request is kept).
+                    let processing_lease =
+                        aggregator.get_current_time() + AGG_JOB_PROCESSING_LEASE_SECS;
+                    let claim = match pending {
+                        Some(pending) if pending.pending_init_req.is_some() => {
+                            DapAggregationJobState {
+                                processing_lease: Some(processing_lease),
+                                ..pending.clone()
+                            }
+                        }
+                        _ => DapAggregationJobState::processing(
+                            part_batch_sel.clone(),
+                            init_req_hash,
+                            processing_lease,
+                        ),
+                    };
+                    if !put_agg_job_init_state(aggregator, task_id, agg_job_id, pending, &claim)
+                        .await?
+                    {
+                        return Err(agg_job_init_conflict(
+                            aggregator,
+                            task_id,
+                            agg_job_id,
+                            &init_req_hash,
+                        )
+                        .await);
+                    }
+
+                    let result =
+                        finish_agg_job_and_aggregate(
+                            aggregator,
+                            task_id,
+                            task_config,
+                            &agg_job_id,
                             metrics,
-                        )?
-                    else {
-                        return Err(fatal_error!(err = "unexpected transition"));
+                            (agg_span, agg_job_resp),
+                            |report_status| {
+                                let DapHelperAggregationJobTransition::Finished(
+                                    agg_span,
+                                    agg_job_resp,
+                                ) = task_config.handle_agg_job_init_req(
+                                    task_id,
+                                    report_status,
+                                    &part_batch_sel,
+                                    &initialized_reports,
+                                    metrics,
+                                )?
+                                else {
+                                    return Err(fatal_error!(err = "unexpected transition"));
+                                };
+                                Ok((agg_span, agg_job_resp))
+                            },
+                        )
+                        .await;
+
+                    let agg_job_resp = match result {
+                        Ok(agg_job_resp) => agg_job_resp,
+                        // The aggregation job is invalid. Remember the failure so that it can be
+                        // sent to the Leader if it retries the request (or is polling for it).
+                        Err(DapError::Abort(abort)) => {
+                            let failure = abort.into_problem_details();
+                            aggregator
+                                .put_helper_state(
+                                    task_id,
+                                    agg_job_id,
+                                    &DapAggregationJobState::failed(
+                                        part_batch_sel.clone(),
+                                        init_req_hash,
+                                        failure.clone(),
+                                    ),
+                                )
+                                .await?;
+                            return Err(agg_job_failure_to_error(failure));
+                        }
+                        // Release the claim so that the aggregation job is processed again when
+                        // the Leader retries. This is safe even if some of the output shares
+                        // were aggregated, since the replay check rejects them the second time.
+                        Err(e) => {
+                            let released = DapAggregationJobState {
+                                processing_lease: None,
+                                ..claim.clone()
+                            };
+                            aggregator
+                                .put_helper_state_if_unchanged(
+                                    task_id, agg_job_id, &claim, &released,
+                                )
+                                .await?;
+                            return Err(e);
+                        }
                     };
-                    Ok((agg_span, agg_job_resp))
-                },
-            )
-            .await?;
 
-            metrics.agg_job_started_inc();
-            metrics.agg_job_completed_inc();
-            agg_job_resp
+                    // Keep the response in case the Leader retries the request (or is polling
+                    // for it).
+                    let state = DapAggregationJobState {
+                        part_batch_sel: part_batch_sel.clone(),
+                        seq: Vec::new(),
+                        round: 0,
+                        last_resp: Some(agg_job_resp.clone()),
+                        pending_init_req: None,
+                        init_req_hash: Some(init_req_hash),
+                        processing_lease: None,
+                        failure: None,
+                    };
+                    aggregator
+                        .put_helper_state(task_id, agg_job_id, &state)
+                        .await?;
+
+                    metrics.agg_job_started_inc(TaskLabels::new(task_id, task_config));
+                    metrics.agg_job_completed_inc(TaskLabels::new(task_id, task_config));
+                    agg_job_resp
+                }
+            }
         }
     };
 
@@ -204,18 +586,26 @@ pub async fn handle_agg_job_init_req<'req, S: Sync, A: DapHelper<S>>(
         AggregationJobAuditAction::Init,
     );
 
-    metrics.inbound_req_inc(DaphneRequestType::Aggregate);
-    Ok(DapResponse {
-        version: req.version,
-        media_type: DapMediaType::AggregationJobResp,
-        payload: agg_job_resp.get_encoded().map_err(DapError::encoding)?,
-    })
+    Ok(agg_job_resp)
 }
 
 pub async fn handle_agg_job_cont_req<'req, S: Sync, A: DapHelper<S>>(
     aggregator: &A,
     req: &'req DapRequest<S>,
 ) -> Result<DapResponse, DapError> {
+    let payload = agg_job_cont(aggregator, req).await?;
+    Ok(DapResponse {
+        version: req.version,
+        media_type: DapMediaType::agg_job_cont_resp_for_version(req.version),
+        status: payload.status(),
+        payload: payload.encode()?,
+    })
+}
+
+async fn agg_job_cont<S: Sync, A: DapHelper<S>>(
+    aggregator: &A,
+    req: &DapRequest<S>,
+) -> Result<AggJobRespPayload, DapError> {
     let task_id = req.task_id()?;
     let metrics = aggregator.metrics();
 
@@ -248,7 +638,7 @@ pub async fn handle_agg_job_cont_req<'req, S: Sync, A: DapHelper<S>>(
 
     let agg_job_id = resolve_agg_job_id(req, agg_job_cont_req.draft02_agg_job_id.as_ref())?;
 
-    let state = aggregator
+    let mut state = aggregator
         .get_helper_state(task_id, agg_job_id)
         .await?
         .ok_or_else(|| DapAbort::UnrecognizedAggregationJob {
@@ -256,17 +646,93 @@ pub async fn handle_agg_job_cont_req<'req, S: Sync, A: DapHelper<S>>(
             agg_job_id_base64url: agg_job_id.to_base64url(),
         })?;
 
-    let agg_job_resp =
-        finish_agg_job_and_aggregate(aggregator, task_id, task_config, metrics, |report_status| {
-            task_config.handle_agg_job_cont_req(
+    if state.is_processing() {
+        return Err(DapAbort::BadRequest(
+            "unexpected message for aggregation job (still being processed)".into(),
+        )
+        .into());
+    }
+
+    // Round skew recovery: If the Leader is retrying the round we just completed, then send the
+    // same response as before. (See draft-ietf-ppm-dap-09, Section 4.5.2.2.)
+    if agg_job_cont_req.round == Some(state.round) {
+        if let Some(last_resp) = state.last_resp.take() {
+            metrics.inbound_req_inc(
+                DaphneRequestType::Aggregate,
+                Some(TaskLabels::new(task_id, task_config)),
+            );
+            return Ok(AggJobRespPayload::Resp(last_resp));
+        }
+    }
+
+    let transition = task_config.handle_agg_job_cont_req(
+        task_id,
+        &state,
+        &HashMap::default(), // no reports have been processed yet
+        &agg_job_id,
+        &agg_job_cont_req,
+    )?;
+
+    let agg_job_resp = match transition {
+        // The VDAF needs another round: store our state and wait for the Leader.
+        DapHelperAggregationJobTransition::Continued(mut next_state, agg_job_resp) => {
+            next_state.last_resp = Some(agg_job_resp.clone());
+            next_state.init_req_hash = state.init_req_hash;
+            aggregator
+                .put_helper_state(task_id, agg_job_id, &next_state)
+                .await?;
+            agg_job_resp
+        }
+
+        DapHelperAggregationJobTransition::Finished(agg_span, agg_job_resp) => {
+            let agg_job_resp = finish_agg_job_and_aggregate(
+                aggregator,
                 task_id,
-                &state,
-                report_status,
+                task_config,
                 &agg_job_id,
-                &agg_job_cont_req,
+                metrics,
+                (agg_span, agg_job_resp),
+                |report_status| {
+                    let DapHelperAggregationJobTransition::Finished(agg_span, agg_job_resp) =
+                        task_config.handle_agg_job_cont_req(
+                            task_id,
+                            &state,
+                            report_status,
+                            &agg_job_id,
+                            &agg_job_cont_req,
+                        )?
+                    else {
+                        return Err(fatal_error!(err = "unexpected transition"));
+                    };
+                    Ok((agg_span, agg_job_resp))
+                },
             )
-        })
-        .await?;
+            .await?;
+
+            // Keep the response in case the Leader retries this round.
+            if let Some(round) = agg_job_cont_req.round {
+                aggregator
+                    .put_helper_state(
+                        task_id,
+                        agg_job_id,
+                        &DapAggregationJobState {
+                            seq: Vec::new(),
+                            part_batch_sel: state.part_batch_sel.clone(),
+                            round,
+                            last_resp: Some(agg_job_resp.clone()),
+                            pending_init_req: None,
+                            init_req_hash: state.init_req_hash,
+                            processing_lease: None,
+                            failure: None,
+                        },
+                    )
+                    .await?;
+            }
+
+            metrics.agg_job_completed_inc(TaskLabels::new(task_id, task_config));
+            agg_job_resp
+        }
+    };
 
     let out_shares_count = agg_job_resp
         .transitions
@@ -283,13 +749,11 @@ pub async fn handle_agg_job_cont_req<'req, S: Sync, A: DapHelper<S>>(
         AggregationJobAuditAction::Continue,
     );
 
-    metrics.agg_job_completed_inc();
-    metrics.inbound_req_inc(DaphneRequestType::Aggregate);
-    Ok(DapResponse {
-        version: req.version,
-        media_type: DapMediaType::agg_job_cont_resp_for_version(task_config.version),
-        payload: agg_job_resp.get_encoded().map_err(DapError::encoding)?,
-    })
+    metrics.inbound_req_inc(
+        DaphneRequestType::Aggregate,
+        Some(TaskLabels::new(task_id, task_config)),
+    );
+    Ok(AggJobRespPayload::Resp(agg_job_resp))
 }
 
 /// Handle a request pertaining to an aggregation job.
@@ -307,6 +771,98 @@ pub async fn handle_agg_job_req<'req, S: Sync, A: DapHelper<S>>(
     }
 }
 
+/// Handle a request from the Leader to poll an aggregation job that is being processed
+/// asynchronously (see [`crate::DapGlobalConfig::helper_async_agg_jobs`]). The status of the
+/// response is [`DapResponseStatus::Processing`] if the aggregation job is still being processed.
+///
+/// If no worker holds the aggregation job, e.g., because the worker that accepted it crashed, then
+/// it is processed before responding.
+pub async fn handle_agg_job_poll_req<S: Sync, A: DapHelper<S>>(
+    aggregator: &A,
+    req: &DapRequest<S>,
+) -> Result<DapResponse, DapError> {
+    let task_id = req.task_id()?;
+    let metrics = aggregator.metrics();
+
+    if aggregator.get_global_config().allow_taskprov {
+        resolve_taskprov(aggregator, task_id, req, None).await?;
+    }
+    let wrapped_task_config = aggregator
+        .get_task_config_for(task_id)
+        .await?
+        .ok_or(DapAbort::UnrecognizedTask)?;
+    let task_config = wrapped_task_config.as_ref();
+
+    if let Some(reason) = aggregator.unauthorized_reason(task_config, req).await? {
+        error!("aborted unauthorized poll request: {reason}");
+        return Err(DapAbort::UnauthorizedRequest {
+            detail: reason,
+            task_id: *task_id,
+        }
+        .into());
+    }
+
+    // Check whether the DAP version in the request matches the task config.
+    if task_config.version != req.version {
+        return Err(DapAbort::version_mismatch(req.version, task_config.version).into());
+    }
+
+    if req.version == DapVersion::Draft02 {
+        return Err(
+            DapAbort::BadRequest("aggregation jobs cannot be polled in draft02".into()).into(),
+        );
+    }
+
+    let agg_job_id = resolve_agg_job_id(req, None)?;
+    let get_state = || async {
+        aggregator
+            .get_helper_state(task_id, agg_job_id)
+            .await?
+            .ok_or_else(|| {
+                DapError::Abort(DapAbort::UnrecognizedAggregationJob {
+                    task_id: *task_id,
+                    agg_job_id_base64url: agg_job_id.to_base64url(),
+                })
+            })
+    };
+    let mut state = get_state().await?;
+
+    if state.pending_init_req.is_some() && !state.is_held(aggregator.get_current_time()) {
+        if let Err(error) = process_agg_job(aggregator, task_id, agg_job_id).await {
+            error!(?error, "failed to process aggregation job");
+        }
+        state = get_state().await?;
+    }
+
+    if let Some(failure) = state.failure {
+        return Err(agg_job_failure_to_error(*failure));
+    }
+
+    let (status, payload) = match (state.is_processing(), state.last_resp) {
+        (true, _) => (DapResponseStatus::Processing, Vec::new()),
+        (false, Some(agg_job_resp)) => (
+            DapResponseStatus::Done,
+            agg_job_resp.get_encoded().map_err(DapError::encoding)?,
+        ),
+        (false, None) => {
+            return Err(
+                DapAbort::BadRequest("aggregation job has no response to poll".into()).into(),
+            )
+        }
+    };
+
+    metrics.inbound_req_inc(
+        DaphneRequestType::Aggregate,
+        Some(TaskLabels::new(task_id, task_config)),
+    );
+    Ok(DapResponse {
+        version: req.version,
+        media_type: DapMediaType::AggregationJobResp,
+        status,
+        payload,
+    })
+}
+
 /// Handle a request for an aggregate share. This is called by the Leader to complete a
 /// collection job.
 pub async fn handle_agg_share_req<'req, S: Sync, A: DapHelper<S>>(
@@ -350,8 +906,8 @@ pub async fn handle_agg_share_req<'req, S: Sync, A: DapHelper<S>>(
         DapAggregationParam::get_decoded_with_param(&task_config.vdaf, &agg_share_req.agg_param)
             .map_err(|e| DapAbort::from_codec_error(e, *task_id))?;
 
-    // Ensure the batch boundaries are valid and that the batch doesn't overlap with previosuly
-    // collected batches.
+    // Ensure the batch boundaries are valid and that the batch hasn't been collected too many
+    // times.
     check_batch(
         aggregator,
         task_config,
@@ -381,10 +937,7 @@ pub async fn handle_agg_share_req<'req, S: Sync, A: DapHelper<S>>(
     }
 
     // Check the batch size.
-    if !task_config
-        .is_report_count_compatible(task_id, agg_share.report_count)
-        .unwrap_or(false)
-    {
+    if !task_config.is_report_count_compatible(task_id, agg_share.report_count)? {
         return Err(DapAbort::InvalidBatchSize {
             detail: format!(
                 "Report count ({}) is less than minimum ({})",
@@ -397,7 +950,11 @@ pub async fn handle_agg_share_req<'req, S: Sync, A: DapHelper<S>>(
 
     // Mark each aggregated report as collected.
     aggregator
-        .mark_collected(task_id, &agg_share_req.batch_sel)
+        .mark_collected(
+            task_id,
+            &agg_share_req.batch_sel,
+            &DapCollectionQuery::for_agg_share_req(&agg_share_req, task_config.version)?,
+        )
         .await?;
 
     let encrypted_agg_share = task_config.produce_helper_encrypted_agg_share(
@@ -412,12 +969,22 @@ pub async fn handle_agg_share_req<'req, S: Sync, A: DapHelper<S>>(
     let agg_share_resp = AggregateShare {
         encrypted_agg_share,
     };
+    aggregator.audit_log().on_aggregate_share(
+        aggregator.host(),
+        task_id,
+        task_config,
+        &agg_share_req.batch_sel,
+        agg_share.report_count,
+        &agg_share.checksum,
+    );
 
-    metrics.report_inc_by("collected", agg_share_req.report_count);
-    metrics.inbound_req_inc(DaphneRequestType::Collect);
+    let task_labels = TaskLabels::new(task_id, task_config);
+    metrics.report_inc_by("collected", agg_share_req.report_count, task_labels);
+    metrics.inbound_req_inc(DaphneRequestType::Collect, Some(task_labels));
     Ok(DapResponse {
         version: req.version,
         media_type: DapMediaType::AggregateShare,
+        status: DapResponseStatus::Done,
         payload: agg_share_resp.get_encoded().map_err(DapError::encoding)?,
     })
 }
@@ -471,11 +1038,117 @@ fn resolve_agg_job_id<'id, S>(
     }
 }
 
+/// Compute the hash of an encoded `AggregationJobInitReq`. This is used to recognize a retried
+/// request.
+fn agg_job_init_req_hash(init_req_data: &[u8]) -> [u8; 32] {
+    let d = digest::digest(&digest::SHA256, init_req_data);
+    d.as_ref()
+        .try_into()
+        .expect("SHA-256 digest to be 32 bytes")
+}
+
+/// Check if the aggregation job was already initialized by a previous request. If so, and the
+/// previous request is identical to this one, then return the encoded response to send again. If
+/// the response is not yet known, then return a transient error so that the Leader tries again
+/// later, unless the previous attempt abandoned the aggregation job. If the previous request is
+/// different, then abort.
+async fn get_agg_job_init_resp_for_retry<S: Sync, A: DapHelper<S>>(
+    aggregator: &A,
+    task_id: &TaskId,
+    agg_job_id: MetaAggregationJobId,
+    init_req_hash: &[u8; 32],
+) -> Result<AggJobInitRetry, DapError> {
+    let Some(state) = aggregator.get_helper_state(task_id, agg_job_id).await? else {
+        return Ok(AggJobInitRetry::New);
+    };
+
+    if state.init_req_hash.as_ref() != Some(init_req_hash) {
+        return Err(DapAbort::BadRequest(
+            "unexpected message for aggregation job (already exists)".into(),
+        )
+        .into());
+    }
+
+    if let Some(failure) = state.failure {
+        return Err(agg_job_failure_to_error(*failure));
+    }
+
+    match (&state.pending_init_req, state.round, &state.last_resp) {
+        // The aggregation job is still being processed.
+        (Some(..), ..) => Ok(AggJobInitRetry::Done(AggJobRespPayload::Processing)),
+        (None, 0, Some(agg_job_resp)) => Ok(AggJobInitRetry::Done(AggJobRespPayload::Encoded(
+            agg_job_resp.get_encoded().map_err(DapError::encoding)?,
+        ))),
+        // The output shares are being aggregated by another request. The response isn't known
+        // yet, so the Leader needs to try again later.
+        (None, 0, None) if state.is_held(aggregator.get_current_time()) => Err(
+            DapError::Transient("aggregation job is still being processed".into()),
+        ),
+        (None, 0, None) => Ok(AggJobInitRetry::Abandoned(state)),
+        (None, round, _) => Err(DapAbort::RoundMismatch {
+            detail: format!("aggregation job was already continued to round {round}"),
+            task_id: *task_id,
+            agg_job_id_base64url: agg_job_id.to_base64url(),
+        }
+        .into()),
+    }
+}
+
+/// Store the state of an aggregation job that is being initialized. If the aggregation job is
+/// pending, then the state is replaced unless another worker has taken the aggregation job over.
+/// Otherwise the state is stored unless it already exists. Returns a boolean indicating if the
+/// operation succeeded.
+async fn put_agg_job_init_state<S: Sync, A: DapHelper<S>>(
+    aggregator: &A,
+    task_id: &TaskId,
+    agg_job_id: MetaAggregationJobId,
+    pending: Option<&DapAggregationJobState>,
+    state: &DapAggregationJobState,
+) -> Result<bool, DapError> {
+    match pending {
+        Some(pending) => {
+            aggregator
+                .put_helper_state_if_unchanged(task_id, agg_job_id, pending, state)
+                .await
+        }
+        None => {
+            aggregator
+                .put_helper_state_if_not_exists(task_id, agg_job_id, state)
+                .await
+        }
+    }
+}
+
+/// Construct the error for a request that lost the race to initialize the aggregation job.
+async fn agg_job_init_conflict<S: Sync, A: DapHelper<S>>(
+    aggregator: &A,
+    task_id: &TaskId,
+    agg_job_id: MetaAggregationJobId,
+    init_req_hash: &[u8; 32],
+) -> DapError {
+    match get_agg_job_init_resp_for_retry(aggregator, task_id, agg_job_id, init_req_hash).await {
+        // The same request is being (or has been) handled concurrently. Have the Leader try again
+        // to get the response.
+        Ok(_) => DapError::Transient("aggregation job is being processed concurrently".into()),
+        Err(e) => e,
+    }
+}
+
+/// Reconstruct the error with which an aggregation job failed.
+fn agg_job_failure_to_error(failure: ProblemDetails) -> DapError {
+    DapAbort::from_problem_details(&failure).map_or_else(
+        || fatal_error!(err = "aggregation job failed", ?failure),
+        DapError::Abort,
+    )
+}
+
 async fn finish_agg_job_and_aggregate<S: Sync>(
     helper: &impl DapHelper<S>,
     task_id: &TaskId,
     task_config: &DapTaskConfig,
+    agg_job_id: &MetaAggregationJobId,
     metrics: &dyn DaphneMetrics,
+    first_attempt: (DapAggregateSpan<DapAggregateShare>, AggregationJobResp),
     finish_agg_job: impl Fn(
         &HashMap<ReportId, ReportProcessedStatus>,
     ) -> Result<
@@ -485,7 +1158,8 @@ async fn finish_agg_job_and_aggregate<S: Sync>(
 ) -> Result<AggregationJobResp, DapError> {
     // This loop is intended to run at most once on the "happy path". The intent is as follows:
     //
-    // - try to aggregate the output shares into an `DapAggregateShareSpan`
+    // - try to aggregate the output shares into an `DapAggregateShareSpan` (the caller computes
+    //   the first attempt, since it needs to know whether the aggregation job is finished)
     // - pass it to `try_put_agg_share_span`
     //   - if replays are found, then try again, rejecting the reports that were replayed
     //   - else break with the finished (of failed) transitions
@@ -495,14 +1169,19 @@ async fn finish_agg_job_and_aggregate<S: Sync>(
     // won't happen often enough that it matters.
     const RETRY_COUNT: u32 = 3;
     let mut report_status = HashMap::new();
+    let mut first_attempt = Some(first_attempt);
     for _ in 0..RETRY_COUNT {
-        let (agg_span, agg_job_resp) = finish_agg_job(&report_status)?;
+        let (agg_span, agg_job_resp) = match first_attempt.take() {
+            Some(attempt) => attempt,
+            None => finish_agg_job(&report_status)?,
+        };
 
         let put_shares_result = helper
-            .try_put_agg_share_span(task_id, task_config, agg_span)
+            .try_put_agg_share_span(task_id, task_config, agg_job_id, agg_span)
             .await;
 
         let inc_restart_metric = Once::new();
+        let mut batch_saturated = false;
         for (_bucket, result) in put_shares_result {
             match result {
                 // This bucket had no replays.
@@ -535,6 +1214,18 @@ async fn finish_agg_job_and_aggregate<S: Sync>(
                     }));
                     inc_restart_metric.call_once(|| metrics.agg_job_put_span_retry_inc());
                 }
+                // Aggregating this bucket would exceed the maximum batch size.
+                (Err(MergeAggShareError::BatchSaturated), reports) => {
+                    report_status.extend(reports.into_iter().map(|(report_id, _)| {
+                        (
+                            report_id,
+                            ReportProcessedStatus::Rejected(TransitionFailure::BatchSaturated),
+                        )
+                    }));
+                    // The transitions need to be recomputed, but nothing conflicted with this
+                    // aggregation job, so this doesn't count as a retry.
+                    batch_saturated = true;
+                }
                 // If this happens, the leader and helper can possibly have inconsistent state.
                 // The leader will still think all of the reports in this job have yet to be
                 // aggregated. But we could have aggregated some and not others due to the
@@ -547,7 +1238,7 @@ async fn finish_agg_job_and_aggregate<S: Sync>(
                 (Err(MergeAggShareError::Other(other)), _) => return Err(other),
             }
         }
-        if !inc_restart_metric.is_completed() {
+        if !inc_restart_metric.is_completed() && !batch_saturated {
             let out_shares_count = agg_job_resp
                 .transitions
                 .iter()
@@ -555,11 +1246,12 @@ async fn finish_agg_job_and_aggregate<S: Sync>(
                 .count()
                 .try_into()
                 .expect("usize to fit in u64");
-            metrics.report_inc_by("aggregated", out_shares_count);
+            let task_labels = TaskLabels::new(task_id, task_config);
+            metrics.report_inc_by("aggregated", out_shares_count, task_labels);
 
             for transition in &agg_job_resp.transitions {
                 if let TransitionVar::Failed(failure) = &transition.var {
-                    metrics.report_inc_by(&format!("rejected_{failure}"), 1);
+                    metrics.report_inc_by(&format!("rejected_{failure}"), 1, task_labels);
                 }
             }
 
@@ -584,7 +1276,7 @@ mod tests {
     use crate::messages::{AggregationJobInitReq, AggregationJobResp, Transition, TransitionVar};
     use crate::vdaf::{Prio3Config, VdafConfig};
     use crate::{assert_metrics_include, MetaAggregationJobId};
-    use crate::{roles::test::TestData, DapVersion};
+    use crate::{roles::test::TestData, testing::MockClock, DapVersion};
 
     #[tokio::test]
     async fn replay_reports_when_continuing_aggregation_draft02() {
@@ -684,4 +1376,87 @@ mod tests {
             r#"report_counter{env="test_helper",host="helper.org",status="rejected_report_replayed"}"#: 1,
         });
     }
+
+    #[tokio::test]
+    async fn retry_agg_job_init_while_aggregating() {
+        let version = DapVersion::Latest;
+        let mut data = TestData::new(version);
+        let task_id = data.insert_task(version, VdafConfig::Prio3(Prio3Config::Count));
+        let helper = Arc::into_inner(data.new_helper()).unwrap();
+        let clock = MockClock::new(helper.get_current_time());
+        let helper = Arc::new(helper.with_clock(clock.clone()));
+        let test = data.with_leader(Arc::clone(&helper));
+
+        let report = test.gen_test_report(&task_id).await;
+        let (_, req) = test
+            .gen_test_agg_job_init_req(&task_id, version, DapAggregationParam::Empty, vec![report])
+            .await;
+        let DapResource::AggregationJob(agg_job_id) = req.resource else {
+            panic!("unexpected resource");
+        };
+
+        // Simulate a request that claimed the aggregation job but didn't store the response,
+        // e.g., because it is still aggregating the output shares or because it crashed.
+        assert!(helper
+            .put_helper_state_if_not_exists(
+                &task_id,
+                agg_job_id,
+                &DapAggregationJobState::processing(
+                    PartialBatchSelector::TimeInterval,
+                    agg_job_init_req_hash(&req.payload),
+                    clock.now() + AGG_JOB_PROCESSING_LEASE_SECS,
+                ),
+            )
+            .await
+            .unwrap());
+
+        // Expect the Helper to tell the Leader to try again later without aggregating the report.
+        assert_matches!(
+            handle_agg_job_init_req(&*helper, &req).await,
+            Err(DapError::Transient(..))
+        );
+        assert_eq!(helper.audit_log.invocations(), 0);
+
+        // Expect the aggregation job to be taken over once the claim's lease expires.
+        clock.advance(AGG_JOB_PROCESSING_LEASE_SECS);
+        let resp = handle_agg_job_init_req(&*helper, &req).await.unwrap();
+        let agg_job_resp =
+            AggregationJobResp::get_decoded_with_param(&version, &resp.payload).unwrap();
+        assert_eq!(agg_job_resp.transitions.len(), 1);
+        assert_matches!(
+            agg_job_resp.transitions[0].var,
+            TransitionVar::Continued(..)
+        );
+        assert_eq!(helper.audit_log.invocations(), 1);
+    }
+
+    #[tokio::test]
+    async fn retry_agg_job_init_after_transient_merge_failure() {
+        let version = DapVersion::Latest;
+        let mut data = TestData::new(version);
+        let task_id = data.insert_task(version, VdafConfig::Prio3(Prio3Config::Count));
+        let helper = data.new_helper();
+        let test = data.with_leader(Arc::clone(&helper));
+
+        let report = test.gen_test_report(&task_id).await;
+        let (_, req) = test
+            .gen_test_agg_job_init_req(&task_id, version, DapAggregationParam::Empty, vec![report])
+            .await;
+
+        helper.fail_next_agg_share_merges(1);
+        assert_matches!(
+            handle_agg_job_init_req(&*helper, &req).await,
+            Err(DapError::Transient(..))
+        );
+
+        // Expect the retry to aggregate the report rather than replay the failure.
+        let resp = handle_agg_job_init_req(&*helper, &req).await.unwrap();
+        let agg_job_resp =
+            AggregationJobResp::get_decoded_with_param(&version, &resp.payload).unwrap();
+        assert_eq!(agg_job_resp.transitions.len(), 1);
+        assert_matches!(
+            agg_job_resp.transitions[0].var,
+            TransitionVar::Continued(..)
+        );
+    }
 }
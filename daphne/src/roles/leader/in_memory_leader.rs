@@ -5,7 +5,7 @@
 //! leader. For a real production implementation this should not be used as it means a machine
 //! crash or shutdown would cause in progress tasks to be lost.
 
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 
 use rand::{thread_rng, Rng};
 use url::Url;
@@ -13,15 +13,21 @@ use url::Url;
 use crate::{
     error::DapAbort,
     fatal_error,
-    messages::{Base64Encode, BatchId, BatchSelector, Collection, CollectionJobId, Report, TaskId},
-    roles::leader::WorkItem,
-    DapAggregationParam, DapBatchBucket, DapCollectionJob, DapError, DapQueryConfig, DapTaskConfig,
+    messages::{
+        Base64Encode, BatchId, BatchSelector, Collection, CollectionJobId, PartialBatchSelector,
+        Report, TaskId, Time,
+    },
+    roles::leader::{WorkItem, WorkItemRetry},
+    DapAggregationJobPolicy, DapAggregationParam, DapBatchBucket, DapCollectionJob, DapError,
+    DapQueryConfig, DapTaskConfig,
 };
 
 #[derive(Default)]
 pub struct InMemoryLeaderState {
     work_queue: VecDeque<WorkItem>,
     per_task: HashMap<TaskId, MockLeaderMemoryPerTask>,
+    /// Work items that failed permanently, along with the error that caused the last failure.
+    dead_letters: Vec<(WorkItem, String)>,
 }
 
 impl InMemoryLeaderState {
@@ -35,6 +41,11 @@ impl InMemoryLeaderState {
         &mut self.work_queue
     }
 
+    #[cfg(any(test, feature = "test-utils"))]
+    pub fn dead_letters(&self) -> &[(WorkItem, String)] {
+        &self.dead_letters
+    }
+
     #[cfg(any(test, feature = "test-utils"))]
     pub fn contains_queued_task_of_batch(&self, task_id: &TaskId, batch_id: &BatchId) -> bool {
         self.per_task
@@ -51,25 +62,47 @@ impl InMemoryLeaderState {
     pub fn delete_all(&mut self) {
         self.work_queue.clear();
         self.per_task.clear();
+        self.dead_letters.clear();
     }
 
+    /// Store a report received at time `now`. If the policy calls for eager aggregation and the
+    /// VDAF allows it, then an aggregation job is queued as soon as the bucket has enough reports.
+    /// Otherwise, the report is stored until a collection job is initialized for it.
     pub fn put_report(
         &mut self,
         task_id: &TaskId,
         task_config: &DapTaskConfig,
         report: Report,
+        policy: &DapAggregationJobPolicy,
+        now: Time,
     ) -> Result<(), DapError> {
         let per_task = self.per_task.entry(*task_id).or_default();
         let bucket = per_task.assign_report_to_bucket(task_config, &report);
+        per_task.eager = policy.eager_aggregation && !task_config.vdaf.uses_agg_param();
 
-        // Store the report until a collection job is initialized for it. Note that, in a
-        // production Leader, it will usually be desirable to start aggregating reports immediately
-        // (if allowed by the VDAF).
-        per_task
+        let pending = per_task
             .pending_reports
-            .entry(bucket)
-            .or_default()
-            .push_back(report);
+            .entry(bucket.clone())
+            .or_insert_with(|| PendingReports {
+                reports: VecDeque::new(),
+                oldest: now,
+            });
+        pending.reports.push_back(report);
+
+        if per_task.eager && pending.reports.len() >= policy.max_reports {
+            let pending = per_task
+                .pending_reports
+                .remove(&bucket)
+                .expect("pending reports to exist");
+            queue_agg_jobs(
+                &mut self.work_queue,
+                task_id,
+                &bucket.into(),
+                &DapAggregationParam::Empty,
+                pending.reports,
+                policy,
+            );
+        }
         Ok(())
     }
 
@@ -88,11 +121,22 @@ impl InMemoryLeaderState {
             return Err(DapError::Abort(DapAbort::UnrecognizedTask));
         };
 
+        // Batches are queued in the order in which they were opened, so the first batch that has
+        // enough reports is the oldest batch that is ready to be collected.
         per_task
             .batch_queue
-            .front()
+            .iter()
+            .find(|(_batch_id, report_count)| *report_count >= task_config.min_batch_size)
             .map(|(batch_id, _report_count)| *batch_id)
-            .ok_or_else(|| DapError::Abort(DapAbort::BadRequest("empty batch queue".into())))
+            .ok_or_else(|| {
+                DapError::Abort(DapAbort::InvalidBatchSize {
+                    detail: format!(
+                        "no batch has reached the minimum batch size ({})",
+                        task_config.min_batch_size
+                    ),
+                    task_id: *task_id,
+                })
+            })
     }
 
     pub fn enqueue_work(&mut self, work_items: Vec<WorkItem>) -> Result<(), DapError> {
@@ -100,14 +144,106 @@ impl InMemoryLeaderState {
         Ok(())
     }
 
-    pub fn dequeue_work(&mut self, num_items: usize) -> Result<Vec<WorkItem>, DapError> {
+    pub fn dead_letter_work(&mut self, work_item: WorkItem, error: &DapError) {
+        self.dead_letters.push((work_item, error.to_string()));
+    }
+
+    /// Check whether an aggregation job that includes reports in the batch was dead-lettered.
+    pub fn batch_has_dead_lettered_reports(
+        &self,
+        task_id: &TaskId,
+        batch_sel: &BatchSelector,
+    ) -> bool {
+        self.dead_letters
+            .iter()
+            .any(|(work_item, _error)| match work_item {
+                WorkItem::AggregationJob {
+                    task_id: agg_job_task_id,
+                    part_batch_sel,
+                    reports,
+                    ..
+                } if agg_job_task_id == task_id => match (batch_sel, part_batch_sel) {
+                    (
+                        BatchSelector::FixedSizeByBatchId { batch_id },
+                        PartialBatchSelector::FixedSizeByBatchId {
+                            batch_id: agg_job_batch_id,
+                        },
+                    ) => batch_id == agg_job_batch_id,
+                    (
+                        BatchSelector::TimeInterval { batch_interval },
+                        PartialBatchSelector::TimeInterval,
+                    ) => reports.iter().any(|report| {
+                        let time = report.report_metadata.time;
+                        batch_interval.start <= time && time < batch_interval.end()
+                    }),
+                    _ => false,
+                },
+                _ => false,
+            })
+    }
+
+    /// Drain at most `num_items` items from the work queue. Before doing so, queue an aggregation
+    /// job for each bucket of reports that has been waiting longer than the policy allows.
+    ///
+    /// Work items that are waiting to be retried are skipped, as are any later work items for the
+    /// same task, so that work items for a task are always processed in order.
+    pub fn dequeue_work(
+        &mut self,
+        num_items: usize,
+        policy: &DapAggregationJobPolicy,
+        now: Time,
+    ) -> Result<Vec<WorkItem>, DapError> {
+        for (task_id, per_task) in &mut self.per_task {
+            if !per_task.eager {
+                continue;
+            }
+
+            let expired_buckets = per_task
+                .pending_reports
+                .iter()
+                .filter(|(_bucket, pending)| {
+                    pending.reports.len() >= policy.min_reports
+                        || now >= pending.oldest.saturating_add(policy.max_report_age)
+                })
+                .map(|(bucket, _pending)| bucket.clone())
+                .collect::<Vec<_>>();
+            for bucket in expired_buckets {
+                let pending = per_task
+                    .pending_reports
+                    .remove(&bucket)
+                    .expect("pending reports to exist");
+                queue_agg_jobs(
+                    &mut self.work_queue,
+                    task_id,
+                    &bucket.into(),
+                    &DapAggregationParam::Empty,
+                    pending.reports,
+                    policy,
+                );
+            }
+        }
+
         let mut work_items = Vec::with_capacity(num_items);
+        let mut skipped = VecDeque::new();
+        let mut skipped_tasks = HashSet::new();
 
         // Drain the work queue for each task, in an arbitrary order. Note that a production
         // Leader would likely need to handle tasks in some priority order, e.g., drain the
         // oldest tasks first.
-        let n = std::cmp::min(self.work_queue.len(), num_items);
-        work_items.extend(self.work_queue.drain(..n));
+        while work_items.len() < num_items {
+            let Some(work_item) = self.work_queue.pop_front() else {
+                break;
+            };
+
+            if work_item.retry().not_before > now || skipped_tasks.contains(work_item.task_id()) {
+                skipped_tasks.insert(*work_item.task_id());
+                skipped.push_back(work_item);
+            } else {
+                work_items.push(work_item);
+            }
+        }
+        skipped.append(&mut self.work_queue);
+        self.work_queue = skipped;
         Ok(work_items)
     }
 
@@ -118,6 +254,7 @@ impl InMemoryLeaderState {
         coll_job_id: &Option<CollectionJobId>,
         batch_sel: BatchSelector,
         agg_param: DapAggregationParam,
+        policy: &DapAggregationJobPolicy,
     ) -> Result<Url, DapError> {
         let per_task = self.per_task.entry(*task_id).or_default();
 
@@ -144,16 +281,19 @@ impl InMemoryLeaderState {
             .coll_jobs
             .insert(coll_job_id, DapCollectionJob::Pending);
 
-        // Fill the work queue. Queue an aggregation job for each bucket of pending reports
-        // incident to the collection job.
+        // Fill the work queue. Queue aggregation jobs for each bucket of pending reports incident
+        // to the collection job. The reports are needed for the collection job, so the minimum
+        // number of reports per aggregation job does not apply.
         for bucket in task_config.batch_span_for_sel(&batch_sel)? {
-            if let Some(reports) = per_task.pending_reports.remove(&bucket) {
-                self.work_queue.push_back(WorkItem::AggregationJob {
-                    task_id: *task_id,
-                    part_batch_sel: batch_sel.clone().into(),
-                    agg_param: agg_param.clone(),
-                    reports: reports.into(),
-                });
+            if let Some(pending) = per_task.pending_reports.remove(&bucket) {
+                queue_agg_jobs(
+                    &mut self.work_queue,
+                    task_id,
+                    &batch_sel.clone().into(),
+                    &agg_param,
+                    pending.reports,
+                    policy,
+                );
             }
 
             // The batch will be collected, so remove it from the batch queue.
@@ -170,6 +310,7 @@ impl InMemoryLeaderState {
             coll_job_id,
             batch_sel,
             agg_param,
+            retry: WorkItemRetry::default(),
         });
 
         Ok(coll_job_uri)
@@ -221,11 +362,45 @@ impl InMemoryLeaderState {
     }
 }
 
+/// Split `reports` into aggregation jobs of at most `policy.max_reports` reports each and queue
+/// them.
+fn queue_agg_jobs(
+    work_queue: &mut VecDeque<WorkItem>,
+    task_id: &TaskId,
+    part_batch_sel: &PartialBatchSelector,
+    agg_param: &DapAggregationParam,
+    reports: VecDeque<Report>,
+    policy: &DapAggregationJobPolicy,
+) {
+    let mut reports = Vec::from(reports);
+    while !reports.is_empty() {
+        let rest = reports.split_off(std::cmp::min(reports.len(), policy.max_reports.max(1)));
+        work_queue.push_back(WorkItem::AggregationJob {
+            task_id: *task_id,
+            part_batch_sel: part_batch_sel.clone(),
+            agg_param: agg_param.clone(),
+            reports,
+            agg_job_id: None,
+            retry: WorkItemRetry::default(),
+        });
+        reports = rest;
+    }
+}
+
+/// Reports that have not yet been assigned to an aggregation job.
+struct PendingReports {
+    reports: VecDeque<Report>,
+    /// The time at which the oldest of the reports was received.
+    oldest: Time,
+}
+
 #[derive(Default)]
 struct MockLeaderMemoryPerTask {
-    pending_reports: HashMap<DapBatchBucket, VecDeque<Report>>,
+    pending_reports: HashMap<DapBatchBucket, PendingReports>,
     coll_jobs: HashMap<CollectionJobId, DapCollectionJob>,
     batch_queue: VecDeque<(BatchId, u64)>, // Batch ID, batch size
+    /// Indicates if reports are aggregated as soon as they are uploaded.
+    eager: bool,
 }
 
 impl MockLeaderMemoryPerTask {
@@ -237,10 +412,13 @@ impl MockLeaderMemoryPerTask {
         let mut rng = thread_rng();
         match task_config.query {
             // For fixed-size queries, the bucket corresponds to a single batch.
-            DapQueryConfig::FixedSize { .. } => {
-                // Assign the report to the first unsaturated batch.
+            DapQueryConfig::FixedSize { max_batch_size } => {
+                // Assign the report to the first batch that is not full. If the task has no
+                // maximum batch size, then the batch is filled until it is collected.
                 for (batch_id, report_count) in &mut self.batch_queue {
-                    if *report_count < task_config.min_batch_size {
+                    let is_full = max_batch_size
+                        .is_some_and(|max_batch_size| *report_count >= max_batch_size);
+                    if !is_full {
                         *report_count += 1;
                         return DapBatchBucket::FixedSize {
                             batch_id: *batch_id,
@@ -248,7 +426,7 @@ impl MockLeaderMemoryPerTask {
                     }
                 }
 
-                // No unsaturated batch exists, so create a new batch.
+                // Every batch is full, so open a new batch.
                 let batch_id = BatchId(rng.gen());
                 self.batch_queue.push_back((batch_id, 1));
                 DapBatchBucket::FixedSize { batch_id }
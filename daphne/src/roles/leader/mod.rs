@@ -6,8 +6,9 @@ pub mod in_memory_leader;
 use std::collections::HashMap;
 
 use async_trait::async_trait;
-use futures::future::try_join_all;
+use futures::{future::join_all, stream, Future, StreamExt};
 use prio::codec::{Decode, Encode, ParameterizedDecode, ParameterizedEncode};
+use rand::Rng;
 use tracing::{debug, error};
 use url::Url;
 
@@ -20,14 +21,14 @@ use crate::{
     error::DapAbort,
     fatal_error,
     messages::{
-        AggregateShare, AggregateShareReq, AggregationJobResp, Base64Encode, BatchId,
-        BatchSelector, Collection, CollectionJobId, CollectionReq, Interval, PartialBatchSelector,
-        Query, Report, TaskId,
+        AggregateShare, AggregateShareReq, AggregationJobContinueReq, AggregationJobResp,
+        Base64Encode, BatchId, BatchSelector, Collection, CollectionJobId, CollectionReq, Interval,
+        PartialBatchSelector, Query, Report, TaskId, Time,
     },
-    metrics::DaphneRequestType,
-    DapAggregationParam, DapCollectionJob, DapError, DapLeaderAggregationJobTransition,
-    DapLeaderProcessTelemetry, DapRequest, DapResource, DapResponse, DapTaskConfig, DapVersion,
-    MetaAggregationJobId,
+    metrics::{DaphneRequestType, TaskLabels},
+    DapAggregationParam, DapCollectionJob, DapCollectionQuery, DapError,
+    DapLeaderAggregationJobTransition, DapLeaderProcessTelemetry, DapRequest, DapResource,
+    DapResponse, DapResponseStatus, DapTaskConfig, DapVersion, MetaAggregationJobId,
 };
 
 struct LeaderHttpRequestOptions<'p> {
@@ -41,10 +42,15 @@ struct LeaderHttpRequestOptions<'p> {
 }
 
 enum LeaderHttpRequestMethod {
+    Get,
     Post,
     Put,
 }
 
+/// Maximum number of times the Leader polls the Helper for the response to an aggregation job that
+/// is being processed asynchronously.
+const MAX_AGG_JOB_POLL_ATTEMPTS: u32 = 30;
+
 async fn leader_send_http_request<S: Sync>(
     role: &impl DapLeader<S>,
     task_id: &TaskId,
@@ -79,10 +85,17 @@ async fn leader_send_http_request<S: Sync>(
         taskprov,
     };
 
-    let resp = match method {
-        LeaderHttpRequestMethod::Put => role.send_http_put(req, url).await?,
-        LeaderHttpRequestMethod::Post => role.send_http_post(req, url).await?,
+    let result = match method {
+        LeaderHttpRequestMethod::Get => role.send_http_get(req, url).await,
+        LeaderHttpRequestMethod::Put => role.send_http_put(req, url).await,
+        LeaderHttpRequestMethod::Post => role.send_http_post(req, url).await,
     };
+    if let Err(DapError::PeerAbort { abort, instance }) = &result {
+        error!(%task_id, ?abort, ?instance, "request aborted by Helper");
+        role.metrics()
+            .peer_abort_inc(abort, TaskLabels::new(task_id, task_config));
+    }
+    let resp = result?;
 
     check_response_content_type(&resp, resp_media_type)?;
     Ok(resp)
@@ -110,15 +123,31 @@ pub enum WorkItem {
         part_batch_sel: PartialBatchSelector,
         agg_param: DapAggregationParam,
         reports: Vec<Report>,
+        /// The ID of the aggregation job. This is set once the aggregation job has been attempted
+        /// so that, if it is retried, the Helper recognizes the retried request.
+        agg_job_id: Option<MetaAggregationJobId>,
+        retry: WorkItemRetry,
     },
     CollectionJob {
         task_id: TaskId,
         coll_job_id: CollectionJobId,
         batch_sel: BatchSelector,
         agg_param: DapAggregationParam,
+        retry: WorkItemRetry,
     },
 }
 
+/// The state of a work item that is being retried.
+#[derive(Clone, Debug, Default)]
+#[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
+pub struct WorkItemRetry {
+    /// The number of times the work item has failed.
+    pub failed_attempts: u32,
+
+    /// The work item is not to be processed before this time.
+    pub not_before: Time,
+}
+
 impl WorkItem {
     /// Get the ID for the task to which the work item is associated.
     pub fn task_id(&self) -> &TaskId {
@@ -126,6 +155,19 @@ impl WorkItem {
             Self::AggregationJob { task_id, .. } | Self::CollectionJob { task_id, .. } => task_id,
         }
     }
+
+    /// Get the retry state of the work item.
+    pub fn retry(&self) -> &WorkItemRetry {
+        match self {
+            Self::AggregationJob { retry, .. } | Self::CollectionJob { retry, .. } => retry,
+        }
+    }
+
+    fn retry_mut(&mut self) -> &mut WorkItemRetry {
+        match self {
+            Self::AggregationJob { retry, .. } | Self::CollectionJob { retry, .. } => retry,
+        }
+    }
 }
 
 /// DAP Leader functionality.
@@ -134,7 +176,9 @@ pub trait DapLeader<S: Sync>: DapAuthorizedSender<S> + DapAggregator<S> {
     /// Store a report for use later on.
     async fn put_report(&self, report: &Report, task_id: &TaskId) -> Result<(), DapError>;
 
-    /// Fixed-size tasks: Return the ID of the batch currently being filled.
+    /// Fixed-size tasks: Return the ID of the oldest batch that has not yet been collected and is
+    /// ready to be, i.e., it contains at least `min_batch_size` reports. Batches are filled up to
+    /// `max_batch_size` reports, if set.
     async fn current_batch(&self, task_id: &TaskId) -> Result<BatchId, DapError>;
 
     /// Initialize a collection job.
@@ -159,6 +203,18 @@ pub trait DapLeader<S: Sync>: DapAuthorizedSender<S> + DapAggregator<S> {
     /// Append `items` to the work queue.
     async fn enqueue_work(&self, items: Vec<WorkItem>) -> Result<(), DapError>;
 
+    /// Set aside a work item that could not be completed, either because it failed with an error
+    /// that is not transient or because it failed too many times. The work item is not retried.
+    async fn dead_letter_work(&self, item: WorkItem, error: &DapError) -> Result<(), DapError>;
+
+    /// Check whether an aggregation job that includes reports in the batch was dead-lettered. If
+    /// so, then the batch is missing reports and can't be collected.
+    async fn batch_has_dead_lettered_reports(
+        &self,
+        task_id: &TaskId,
+        batch_sel: &BatchSelector,
+    ) -> Result<bool, DapError>;
+
     /// Complete a collect job by assigning it the completed
     /// [`Collection`](crate::messages::Collection).
     async fn finish_collect_job(
@@ -173,6 +229,13 @@ pub trait DapLeader<S: Sync>: DapAuthorizedSender<S> + DapAggregator<S> {
 
     /// Send an HTTP PUT request.
     async fn send_http_put(&self, req: DapRequest<S>, url: Url) -> Result<DapResponse, DapError>;
+
+    /// Send an HTTP GET request.
+    async fn send_http_get(&self, req: DapRequest<S>, url: Url) -> Result<DapResponse, DapError>;
+
+    /// Wait before polling the Helper for the response to an aggregation job that it is processing
+    /// asynchronously. `attempt` is the number of times the Helper has been polled so far.
+    async fn wait_before_agg_job_poll(&self, attempt: u32);
 }
 
 /// Handle a report from a Client.
@@ -234,8 +297,17 @@ pub async fn handle_upload_req<S: Sync, A: DapLeader<S>>(
     // the Leader detects that the report was replayed or pertains to a batch that has already
     // been collected.
     aggregator.put_report(&report, req.task_id()?).await?;
-
-    metrics.inbound_req_inc(DaphneRequestType::Upload);
+    aggregator.audit_log().on_upload(
+        aggregator.host(),
+        task_id,
+        task_config.as_ref(),
+        &report.report_metadata.id,
+    );
+
+    metrics.inbound_req_inc(
+        DaphneRequestType::Upload,
+        Some(TaskLabels::new(task_id, task_config.as_ref())),
+    );
     Ok(())
 }
 
@@ -321,10 +393,20 @@ pub async fn handle_coll_job_req<S: Sync, A: DapLeader<S>>(
     };
 
     let collect_job_uri = aggregator
-        .init_collect_job(task_id, &collect_job_id, batch_sel, agg_param)
+        .init_collect_job(task_id, &collect_job_id, batch_sel.clone(), agg_param)
         .await?;
-
-    metrics.inbound_req_inc(DaphneRequestType::Collect);
+    aggregator.audit_log().on_collection_job(
+        aggregator.host(),
+        task_id,
+        task_config,
+        collect_job_id.as_ref(),
+        &batch_sel,
+    );
+
+    metrics.inbound_req_inc(
+        DaphneRequestType::Collect,
+        Some(TaskLabels::new(task_id, task_config)),
+    );
     Ok(collect_job_uri)
 }
 
@@ -334,22 +416,23 @@ async fn run_agg_job<S: Sync, A: DapLeader<S>>(
     aggregator: &A,
     task_id: &TaskId,
     task_config: &DapTaskConfig,
+    agg_job_id: &MetaAggregationJobId,
     part_batch_sel: &PartialBatchSelector,
     agg_param: &DapAggregationParam,
-    reports: Vec<Report>,
+    reports: &[Report],
 ) -> Result<u64, DapError> {
     let metrics = aggregator.metrics();
 
     let taskprov = task_config.resolve_taskprove_advertisement()?;
 
     // Prepare AggregationJobInitReq.
-    let agg_job_id = MetaAggregationJobId::gen_for_version(task_config.version);
     let transition = task_config
         .produce_agg_job_init_req(
             aggregator,
             aggregator,
+            aggregator.report_extension_handlers(),
             task_id,
-            &agg_job_id,
+            agg_job_id,
             part_batch_sel,
             agg_param,
             reports,
@@ -404,50 +487,96 @@ async fn run_agg_job<S: Sync, A: DapLeader<S>>(
         },
     )
     .await?;
+
+    // The Helper may process the aggregation job asynchronously, in which case we need to poll
+    // for the response.
+    let mut resp = resp;
+    let mut attempt = 0;
+    while resp.status == DapResponseStatus::Processing {
+        if attempt == MAX_AGG_JOB_POLL_ATTEMPTS {
+            // The aggregation job is retried with the same ID, at which point the Helper responds
+            // with the status of the job and we resume polling.
+            return Err(DapError::Transient(format!(
+                "helper did not finish processing aggregation job {} for task {task_id}",
+                agg_job_id.to_base64url(),
+            )));
+        }
+        aggregator.wait_before_agg_job_poll(attempt).await;
+        attempt += 1;
+        resp = leader_send_http_request(
+            aggregator,
+            task_id,
+            task_config,
+            LeaderHttpRequestOptions {
+                path: &url_path,
+                req_media_type: DapMediaType::AggregationJobInitReq,
+                resp_media_type: DapMediaType::AggregationJobResp,
+                resource: agg_job_id.for_request_path(),
+                req_data: Vec::new(),
+                method: LeaderHttpRequestMethod::Get,
+                taskprov: taskprov.clone(),
+            },
+        )
+        .await?;
+    }
+
     let agg_job_resp = AggregationJobResp::get_decoded(&resp.payload)
         .map_err(|e| DapAbort::from_codec_error(e, *task_id))?;
 
-    // Handle AggregationJobResp.
-    let transition =
-        task_config.handle_agg_job_resp(task_id, &agg_job_id, state, agg_job_resp, metrics)?;
-    let agg_span = match transition {
-        DapLeaderAggregationJobTransition::Uncommitted(uncommited, agg_job_cont_req) => {
-            // Send AggregationJobContinueReq and receive AggregationJobResp.
-            let resp = leader_send_http_request(
-                aggregator,
-                task_id,
-                task_config,
-                LeaderHttpRequestOptions {
-                    path: &url_path,
-                    req_media_type: DapMediaType::AggregationJobContinueReq,
-                    resp_media_type: DapMediaType::agg_job_cont_resp_for_version(
-                        task_config.version,
-                    ),
-                    resource: agg_job_id.for_request_path(),
-                    req_data: agg_job_cont_req
-                        .get_encoded_with_param(&task_config.version)
-                        .map_err(DapError::encoding)?,
-                    method: LeaderHttpRequestMethod::Post,
+    // Handle AggregationJobResp. Multi-round VDAFs require us to continue the aggregation job
+    // until we've computed our output shares.
+    let mut transition =
+        task_config.handle_agg_job_resp(task_id, agg_job_id, state, agg_job_resp, metrics)?;
+    let agg_span = loop {
+        match transition {
+            DapLeaderAggregationJobTransition::Continued(state, agg_job_cont_req) => {
+                let agg_job_resp = send_agg_job_cont_req(
+                    aggregator,
+                    task_id,
+                    task_config,
+                    &url_path,
+                    agg_job_id,
+                    &agg_job_cont_req,
+                    taskprov.clone(),
+                )
+                .await?;
+
+                // Handle AggregationJobResp.
+                transition = task_config.handle_agg_job_resp(
+                    task_id,
+                    agg_job_id,
+                    state,
+                    agg_job_resp,
+                    metrics,
+                )?;
+            }
+            DapLeaderAggregationJobTransition::Uncommitted(uncommited, agg_job_cont_req) => {
+                let agg_job_resp = send_agg_job_cont_req(
+                    aggregator,
+                    task_id,
+                    task_config,
+                    &url_path,
+                    agg_job_id,
+                    &agg_job_cont_req,
                     taskprov,
-                },
-            )
-            .await?;
-            let agg_job_resp = AggregationJobResp::get_decoded(&resp.payload)
-                .map_err(|e| DapAbort::from_codec_error(e, *task_id))?;
+                )
+                .await?;
 
-            // Handle AggregationJobResp.
-            task_config.handle_final_agg_job_resp(uncommited, agg_job_resp, metrics)?
-        }
-        DapLeaderAggregationJobTransition::Finished(agg_span) => {
-            if agg_span.report_count() > 0 {
-                agg_span
-            } else {
+                // Handle AggregationJobResp.
+                break task_config.handle_final_agg_job_resp(
+                    task_id,
+                    uncommited,
+                    agg_job_resp,
+                    metrics,
+                )?;
+            }
+            DapLeaderAggregationJobTransition::Finished(agg_span) => {
+                if agg_span.report_count() > 0 {
+                    break agg_span;
+                }
                 return Ok(0);
             }
         }
-        DapLeaderAggregationJobTransition::Continued(..) => {
-            return Err(fatal_error!(err = "unexpected state transition (continue)"))
-        }
     };
 
     let out_shares_count = agg_span.report_count() as u64;
@@ -456,19 +585,20 @@ async fn run_agg_job<S: Sync, A: DapLeader<S>>(
     // report was replayed at this stage or the span overlaps with a collected batch), then we
     // may end up with a batch mismatch. However, this should only happen if there are multiple
     // aggregation jobs in-flight that include the same report.
-    let (replayed, collected) = aggregator
-        .try_put_agg_share_span(task_id, task_config, agg_span)
+    let (replayed, collected, saturated) = aggregator
+        .try_put_agg_share_span(task_id, task_config, agg_job_id, agg_span)
         .await
         .into_iter()
         .map(|(_bucket, (result, _report_metadata))| match result {
-            Ok(()) => Ok((0, 0)),
-            Err(MergeAggShareError::AlreadyCollected) => Ok((0, 1)),
-            Err(MergeAggShareError::ReplaysDetected(replays)) => Ok((replays.len(), 0)),
+            Ok(()) => Ok((0, 0, 0)),
+            Err(MergeAggShareError::AlreadyCollected) => Ok((0, 1, 0)),
+            Err(MergeAggShareError::BatchSaturated) => Ok((0, 0, 1)),
+            Err(MergeAggShareError::ReplaysDetected(replays)) => Ok((replays.len(), 0, 0)),
             Err(MergeAggShareError::Other(e)) => Err(e),
         })
-        .try_fold((0, 0), |(replayed, collected), rc| {
-            let (r, c) = rc?;
-            Ok::<_, DapError>((replayed + r, collected + c))
+        .try_fold((0, 0, 0), |(replayed, collected, saturated), rcs| {
+            let (r, c, s) = rcs?;
+            Ok::<_, DapError>((replayed + r, collected + c, saturated + s))
         })?;
 
     if replayed > 0 {
@@ -485,10 +615,52 @@ async fn run_agg_job<S: Sync, A: DapLeader<S>>(
         );
     }
 
-    metrics.report_inc_by("aggregated", out_shares_count);
+    if saturated > 0 {
+        tracing::error!(
+            saturated_count = saturated,
+            "tried to aggregate reports belonging to full batches"
+        );
+    }
+
+    metrics.report_inc_by(
+        "aggregated",
+        out_shares_count,
+        TaskLabels::new(task_id, task_config),
+    );
     Ok(out_shares_count)
 }
 
+/// Send `AggregationJobContinueReq` and receive `AggregationJobResp`.
+async fn send_agg_job_cont_req<S: Sync, A: DapLeader<S>>(
+    aggregator: &A,
+    task_id: &TaskId,
+    task_config: &DapTaskConfig,
+    url_path: &str,
+    agg_job_id: &MetaAggregationJobId,
+    agg_job_cont_req: &AggregationJobContinueReq,
+    taskprov: Option<String>,
+) -> Result<AggregationJobResp, DapError> {
+    let resp = leader_send_http_request(
+        aggregator,
+        task_id,
+        task_config,
+        LeaderHttpRequestOptions {
+            path: url_path,
+            req_media_type: DapMediaType::AggregationJobContinueReq,
+            resp_media_type: DapMediaType::agg_job_cont_resp_for_version(task_config.version),
+            resource: agg_job_id.for_request_path(),
+            req_data: agg_job_cont_req
+                .get_encoded_with_param(&task_config.version)
+                .map_err(DapError::encoding)?,
+            method: LeaderHttpRequestMethod::Post,
+            taskprov,
+        },
+    )
+    .await?;
+    AggregationJobResp::get_decoded(&resp.payload)
+        .map_err(|e| DapAbort::from_codec_error(e, *task_id).into())
+}
+
 /// Handle a pending collection job. If the results are ready, then compute the aggregate
 /// results and store them to be retrieved by the Collector later. Returns the number of
 /// reports in the batch.
@@ -503,6 +675,20 @@ async fn run_coll_job<S: Sync, A: DapLeader<S>>(
     let metrics = aggregator.metrics();
 
     debug!("collecting id {coll_job_id}");
+
+    // Collecting the batch would silently omit the reports of any aggregation job that failed
+    // permanently, so fail the collection job instead.
+    if aggregator
+        .batch_has_dead_lettered_reports(task_id, batch_sel)
+        .await?
+    {
+        return Err(fatal_error!(
+            err = "batch includes reports from a dead-lettered aggregation job",
+            %task_id,
+            %coll_job_id,
+        ));
+    }
+
     let leader_agg_share = aggregator.get_agg_share(task_id, batch_sel).await?;
 
     let taskprov = task_config.resolve_taskprove_advertisement()?;
@@ -541,7 +727,9 @@ async fn run_coll_job<S: Sync, A: DapLeader<S>>(
         format!("tasks/{}/aggregate_shares", task_id.to_base64url())
     };
 
-    // Send AggregateShareReq and receive AggregateShareResp.
+    // The Helper counts the AggregateShareReq against the query limit of the batch and, once the
+    // limit is reached, rejects the request even if it is a retry. Failures from here on are
+    // therefore not retried.
     let resp = leader_send_http_request(
         aggregator,
         task_id,
@@ -558,9 +746,19 @@ async fn run_coll_job<S: Sync, A: DapLeader<S>>(
             taskprov,
         },
     )
-    .await?;
+    .await
+    .map_err(not_retriable)?;
     let agg_share_resp = AggregateShare::get_decoded(&resp.payload)
         .map_err(|e| DapAbort::from_codec_error(e, *task_id))?;
+    aggregator.audit_log().on_aggregate_share(
+        aggregator.host(),
+        task_id,
+        task_config,
+        batch_sel,
+        leader_agg_share.report_count,
+        &leader_agg_share.checksum,
+    );
+
     // In the latest draft, the Collection message includes the smallest quantized time
     // interval containing all reports in the batch.
     let draft09_interval = match task_config.version {
@@ -589,17 +787,37 @@ async fn run_coll_job<S: Sync, A: DapLeader<S>>(
     };
     aggregator
         .finish_collect_job(task_id, coll_job_id, &collection)
-        .await?;
+        .await
+        .map_err(not_retriable)?;
 
     // Mark reports as collected.
     aggregator
-        .mark_collected(task_id, &agg_share_req.batch_sel)
-        .await?;
+        .mark_collected(
+            task_id,
+            &agg_share_req.batch_sel,
+            &DapCollectionQuery::CollectionJob(*coll_job_id),
+        )
+        .await
+        .map_err(not_retriable)?;
 
-    metrics.report_inc_by("collected", agg_share_req.report_count);
+    metrics.report_inc_by(
+        "collected",
+        agg_share_req.report_count,
+        TaskLabels::new(task_id, task_config),
+    );
     Ok(agg_share_req.report_count)
 }
 
+/// Turn a transient error into one that is not retried.
+fn not_retriable(e: DapError) -> DapError {
+    match e {
+        DapError::Transient(e) => {
+            fatal_error!(err = %e, "failed after the request may have been counted")
+        }
+        e => e,
+    }
+}
+
 /// Drain a number of items from the work queue and process them.
 ///
 /// Aggregation jobs are handled in parallel, subject to the restriction that all aggregation jobs
@@ -607,17 +825,27 @@ async fn run_coll_job<S: Sync, A: DapLeader<S>>(
 ///
 /// Collection jobs are processed in order. If a collection job is still pending once processed, it
 /// is pushed to the back of the work queue.
+///
+/// If a work item fails with a transient error, then it is pushed to the back of the work queue to
+/// be retried after a backoff period determined by the retry policy. Collection jobs for a task
+/// with a failed aggregation job are deferred until the aggregation job is done. Work items that
+/// fail with any other error, or that fail too many times, are dead-lettered.
 pub async fn process<S: Sync, A: DapLeader<S>>(
     aggregator: &A,
     host: &str,
     num_items: usize,
 ) -> Result<DapLeaderProcessTelemetry, DapError> {
     let mut telem = DapLeaderProcessTelemetry::default();
+    let global_config = aggregator.get_global_config();
+    let max_concurrent_agg_jobs_per_task = global_config
+        .agg_job_policy
+        .max_concurrent_agg_jobs_per_task;
 
     tracing::debug!("RUNNING read_work_stream");
 
     let mut agg_jobs = HashMap::new();
     let mut pending_coll_jobs = Vec::new();
+    let mut failed = Vec::new();
     for work_item in aggregator.dequeue_work(num_items).await? {
         match work_item {
             WorkItem::AggregationJob {
@@ -625,31 +853,55 @@ pub async fn process<S: Sync, A: DapLeader<S>>(
                 part_batch_sel,
                 agg_param,
                 reports,
+                agg_job_id,
+                retry,
             } => {
                 telem.reports_processed += u64::try_from(reports.len()).unwrap();
                 let agg_jobs_per_task: &mut Vec<_> = agg_jobs.entry(task_id).or_default();
                 agg_jobs_per_task.push(async move {
-                    let task_config = aggregator
-                        .get_task_config_for(&task_id)
-                        .await?
-                        .ok_or(DapAbort::UnrecognizedTask)?;
-
-                    if reports.is_empty() {
-                        return Ok(0);
+                    let mut agg_job_id = agg_job_id;
+                    let result = async {
+                        let task_config = aggregator
+                            .get_task_config_for(&task_id)
+                            .await?
+                            .ok_or(DapAbort::UnrecognizedTask)?;
+
+                        if reports.is_empty() {
+                            return Ok(0);
+                        }
+
+                        // Reuse the aggregation job ID if this is a retry.
+                        let agg_job_id = *agg_job_id.get_or_insert_with(|| {
+                            MetaAggregationJobId::gen_for_version(task_config.as_ref().version)
+                        });
+
+                        tracing::debug!(
+                            "RUNNING run_agg_job FOR TID {task_id} AND {part_batch_sel:?} AND {host}"
+                        );
+                        run_agg_job(
+                            aggregator,
+                            &task_id,
+                            task_config.as_ref(),
+                            &agg_job_id,
+                            &part_batch_sel,
+                            &agg_param,
+                            &reports,
+                        )
+                        .await
                     }
-
-                    tracing::debug!(
-                        "RUNNING run_agg_job FOR TID {task_id} AND {part_batch_sel:?} AND {host}"
-                    );
-                    run_agg_job(
-                        aggregator,
-                        &task_id,
-                        task_config.as_ref(),
-                        &part_batch_sel,
-                        &agg_param,
-                        reports,
-                    )
-                    .await
+                    .await;
+
+                    result.map_err(|e| {
+                        let work_item = WorkItem::AggregationJob {
+                            task_id,
+                            part_batch_sel,
+                            agg_param,
+                            reports,
+                            agg_job_id,
+                            retry,
+                        };
+                        (work_item, e)
+                    })
                 });
             }
             WorkItem::CollectionJob {
@@ -657,63 +909,153 @@ pub async fn process<S: Sync, A: DapLeader<S>>(
                 coll_job_id,
                 batch_sel,
                 agg_param,
+                retry,
             } => {
                 // Wait for all pending aggregation jobs for this task to complete before
                 // processing the next collection job. This is to prevent a race condition
                 // involving an aggregate share computed during a collection job and any output
                 // shares computed during an aggregation job.
                 if let Some(agg_jobs_per_task) = agg_jobs.get_mut(&task_id) {
-                    telem.reports_aggregated +=
-                        try_join_all(agg_jobs_per_task.drain(0..agg_jobs_per_task.len()))
-                            .await?
-                            .into_iter()
-                            .sum::<u64>();
+                    let results = run_agg_jobs_per_task(
+                        agg_jobs_per_task.drain(0..agg_jobs_per_task.len()),
+                        max_concurrent_agg_jobs_per_task,
+                    )
+                    .await;
+                    telem.reports_aggregated += collect_failures(results, &mut failed);
                 }
 
-                let task_config = aggregator
-                    .get_task_config_for(&task_id)
-                    .await?
-                    .ok_or(DapAbort::UnrecognizedTask)?;
-
-                tracing::debug!("RUNNING run_collect_job FOR TID {task_id} AND {coll_job_id} AND {batch_sel:?} AND {agg_param:?} AND {host}");
-                let collected = run_coll_job(
-                    aggregator,
-                    &task_id,
-                    task_config.as_ref(),
-                    &coll_job_id,
-                    &batch_sel,
-                    &agg_param,
-                )
-                .await?;
-
-                if collected > 0 {
-                    telem.reports_collected += collected;
+                let result = if failed.iter().any(|(item, _)| {
+                    matches!(item, WorkItem::AggregationJob { .. }) && item.task_id() == &task_id
+                }) {
+                    // An aggregation job for this task failed, so the batch may be missing
+                    // reports. Defer the collection job until the aggregation job is done.
+                    Ok(0)
                 } else {
-                    pending_coll_jobs.push(WorkItem::CollectionJob {
-                        task_id,
-                        coll_job_id,
-                        batch_sel,
-                        agg_param,
-                    });
+                    async {
+                        let task_config = aggregator
+                            .get_task_config_for(&task_id)
+                            .await?
+                            .ok_or(DapAbort::UnrecognizedTask)?;
+
+                        tracing::debug!("RUNNING run_collect_job FOR TID {task_id} AND {coll_job_id} AND {batch_sel:?} AND {agg_param:?} AND {host}");
+                        run_coll_job(
+                            aggregator,
+                            &task_id,
+                            task_config.as_ref(),
+                            &coll_job_id,
+                            &batch_sel,
+                            &agg_param,
+                        )
+                        .await
+                    }
+                    .await
+                };
+
+                let work_item = WorkItem::CollectionJob {
+                    task_id,
+                    coll_job_id,
+                    batch_sel,
+                    agg_param,
+                    retry,
+                };
+                match result {
+                    Ok(collected) if collected > 0 => telem.reports_collected += collected,
+                    Ok(_) => pending_coll_jobs.push(work_item),
+                    Err(e) => failed.push((work_item, e)),
                 }
             }
         }
     }
 
-    for (_task_id, mut agg_jobs_per_task) in agg_jobs {
-        telem.reports_aggregated +=
-            try_join_all(agg_jobs_per_task.drain(0..agg_jobs_per_task.len()))
-                .await?
-                .into_iter()
-                .sum::<u64>();
+    for (_task_id, agg_jobs_per_task) in agg_jobs {
+        let results =
+            run_agg_jobs_per_task(agg_jobs_per_task, max_concurrent_agg_jobs_per_task).await;
+        telem.reports_aggregated += collect_failures(results, &mut failed);
+    }
+
+    // Decide which of the failed work items to retry. Retried work items are put back in the
+    // queue ahead of the pending collection jobs.
+    let retry_policy = &global_config.retry_policy;
+    let now = aggregator.get_current_time();
+    let mut requeued = Vec::with_capacity(failed.len() + pending_coll_jobs.len());
+    for (mut work_item, e) in failed {
+        let task_id = *work_item.task_id();
+        let retry = work_item.retry_mut();
+        retry.failed_attempts += 1;
+        if e.is_transient() && retry.failed_attempts < retry_policy.max_attempts {
+            let backoff = retry_policy.backoff(retry.failed_attempts);
+            let jitter = rand::thread_rng().gen_range(0..=backoff / 2);
+            retry.not_before = now.saturating_add(backoff).saturating_add(jitter);
+            tracing::warn!(
+                error = ?e,
+                %task_id,
+                failed_attempts = retry.failed_attempts,
+                not_before = retry.not_before,
+                "work item failed, will retry"
+            );
+            telem.work_items_retried += 1;
+            requeued.push(work_item);
+        } else {
+            tracing::error!(
+                error = ?e,
+                %task_id,
+                failed_attempts = retry.failed_attempts,
+                "work item failed, dead-lettering"
+            );
+            telem.work_items_dead_lettered += 1;
+            // Keep going so that the remaining work items are still put back in the queue.
+            if let Err(dead_letter_error) = aggregator.dead_letter_work(work_item, &e).await {
+                tracing::error!(
+                    error = ?dead_letter_error,
+                    %task_id,
+                    "failed to dead-letter work item"
+                );
+            }
+        }
     }
 
-    // Put all pending collection jobs back in the queue.
-    aggregator.enqueue_work(pending_coll_jobs).await?;
+    // Put all retried work items and pending collection jobs back in the queue.
+    requeued.extend(pending_coll_jobs);
+    aggregator.enqueue_work(requeued).await?;
 
     Ok(telem)
 }
 
+/// Run a set of aggregation jobs for the same task, at most `max_concurrent` at a time. Return the
+/// result of each aggregation job, in the order in which they completed.
+async fn run_agg_jobs_per_task<F, T>(
+    agg_jobs: impl IntoIterator<Item = F>,
+    max_concurrent: Option<usize>,
+) -> Vec<T>
+where
+    F: Future<Output = T>,
+{
+    let Some(max_concurrent) = max_concurrent else {
+        return join_all(agg_jobs).await;
+    };
+
+    stream::iter(agg_jobs)
+        .buffer_unordered(max_concurrent.max(1))
+        .collect()
+        .await
+}
+
+/// Move the failed aggregation jobs to `failed` and return the total number of reports that were
+/// aggregated by the rest.
+fn collect_failures(
+    results: Vec<Result<u64, (WorkItem, DapError)>>,
+    failed: &mut Vec<(WorkItem, DapError)>,
+) -> u64 {
+    let mut total = 0;
+    for result in results {
+        match result {
+            Ok(count) => total += count,
+            Err(failure) => failed.push(failure),
+        }
+    }
+    total
+}
+
 fn check_response_content_type(resp: &DapResponse, expected: DapMediaType) -> Result<(), DapError> {
     let want_str = expected
         .as_str_for_version(resp.version)
@@ -736,3 +1078,49 @@ fn check_response_content_type(resp: &DapResponse, expected: DapMediaType) -> Re
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::run_agg_jobs_per_task;
+    use crate::DapRetryPolicy;
+
+    #[test]
+    fn retry_policy_backoff() {
+        let policy = DapRetryPolicy {
+            max_attempts: 10,
+            initial_backoff: 2,
+            max_backoff: 20,
+        };
+        assert_eq!(
+            (1..=6).map(|n| policy.backoff(n)).collect::<Vec<_>>(),
+            [2, 4, 8, 16, 20, 20]
+        );
+        assert_eq!(policy.backoff(u32::MAX), 20);
+    }
+
+    #[tokio::test]
+    async fn run_agg_jobs_per_task_concurrency_limit() {
+        for (max_concurrent, want) in [(Some(3), 3), (None, 10)] {
+            let running = AtomicUsize::new(0);
+            let max_running = AtomicUsize::new(0);
+            let agg_jobs = (0..10).map(|_| async {
+                let n = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_running.fetch_max(n, Ordering::SeqCst);
+                tokio::task::yield_now().await;
+                running.fetch_sub(1, Ordering::SeqCst);
+                1
+            });
+
+            assert_eq!(
+                run_agg_jobs_per_task(agg_jobs, max_concurrent)
+                    .await
+                    .into_iter()
+                    .sum::<u64>(),
+                10
+            );
+            assert_eq!(max_running.load(Ordering::SeqCst), want);
+        }
+    }
+}
@@ -4,8 +4,10 @@
 //! Trait definitions for Daphne backends.
 
 pub mod aggregator;
+pub mod diagnostics;
 pub mod helper;
 pub mod leader;
+pub mod report_extensions;
 
 use crate::{
     constants::DapMediaType,
@@ -83,10 +85,19 @@ async fn check_batch<S: Sync>(
         _ => return Err(DapAbort::query_mismatch(task_id, &task_config.query, query).into()),
     };
 
-    // Check that the batch does not overlap with any previously collected batch.
+    // Check that the batch has not been collected too many times. Batches are allowed to overlap
+    // previously collected batches as long as none of the buckets they span has been collected
+    // `max_batch_query_count` times.
     if let Some(batch_sel) = query.clone().into_batch_sel() {
-        if agg.is_batch_overlapping(task_id, &batch_sel).await? {
-            return Err(DapAbort::batch_overlap(task_id, query).into());
+        if agg.get_batch_query_count(task_id, &batch_sel).await?
+            >= task_config.max_batch_query_count
+        {
+            return Err(DapAbort::batch_queried_too_many_times(
+                task_id,
+                query,
+                task_config.max_batch_query_count,
+            )
+            .into());
         }
     }
 
@@ -145,38 +156,44 @@ async fn resolve_taskprov<S: Sync>(
         }));
     }
 
-    agg.taskprov_put(req, task_config).await?;
+    agg.taskprov_put(req, task_config.clone()).await?;
+    agg.audit_log()
+        .on_taskprov_provision(agg.host(), task_id, &task_config);
     Ok(())
 }
 
 #[cfg(test)]
 mod test {
-    use super::{aggregator, helper, leader, DapAuthorizedSender, DapLeader};
+    use super::{
+        aggregator, diagnostics, helper, leader, DapAggregator, DapAuthorizedSender, DapHelper,
+        DapLeader,
+    };
     use crate::{
         assert_metrics_include, async_test_version, async_test_versions,
         auth::BearerToken,
         constants::DapMediaType,
         hpke::{HpkeDecrypter, HpkeKemId, HpkeReceiverConfig},
         messages::{
-            AggregateShareReq, AggregationJobContinueReq, AggregationJobInitReq,
+            AggregateShareReq, AggregationJobContinueReq, AggregationJobId, AggregationJobInitReq,
             AggregationJobResp, Base64Encode, BatchId, BatchSelector, Collection, CollectionJobId,
             CollectionReq, Extension, HpkeCiphertext, Interval, PartialBatchSelector, Query,
             Report, ReportId, ReportMetadata, TaskId, Time, Transition, TransitionFailure,
             TransitionVar,
         },
-        roles::leader::WorkItem,
+        roles::leader::{in_memory_leader::InMemoryLeaderState, WorkItem},
         test_versions,
-        testing::InMemoryAggregator,
+        testing::{InMemoryAggregator, MockClock},
         vdaf::{mastic::MasticWeight, MasticWeightConfig, Prio3Config, VdafConfig},
-        DapAbort, DapAggregationJobState, DapAggregationParam, DapBatchBucket, DapCollectionJob,
-        DapError, DapGlobalConfig, DapLeaderAggregationJobTransition, DapMeasurement,
-        DapQueryConfig, DapRequest, DapResource, DapTaskConfig, DapTaskParameters, DapVersion,
+        DapAbort, DapAggregateShare, DapAggregationJobPolicy, DapAggregationJobState,
+        DapAggregationParam, DapBatchBucket, DapCollectionJob, DapCollectionQuery, DapError,
+        DapGlobalConfig, DapLeaderAggregationJobTransition, DapMeasurement, DapQueryConfig,
+        DapRequest, DapResource, DapResponseStatus, DapTaskConfig, DapTaskParameters, DapVersion,
         MetaAggregationJobId,
     };
     use assert_matches::assert_matches;
     use matchit::Router;
     use prio::{
-        codec::{Decode, Encode, ParameterizedEncode},
+        codec::{Decode, Encode, ParameterizedDecode, ParameterizedEncode},
         idpf::IdpfInput,
         vdaf::poplar1::Poplar1AggregationParam,
     };
@@ -262,6 +279,9 @@ mod test {
                 max_batch_interval_end: 259_200,
                 supported_hpke_kems: vec![HpkeKemId::X25519HkdfSha256],
                 allow_taskprov: true,
+                helper_async_agg_jobs: false,
+                agg_job_policy: Default::default(),
+                retry_policy: Default::default(),
             };
 
             // Task Parameters that the Leader and Helper must agree on.
@@ -288,8 +308,9 @@ mod test {
                     expiration: now + Self::TASK_TIME_PRECISION,
                     min_batch_size: 1,
                     query: DapQueryConfig::TimeInterval,
+                    max_batch_query_count: 1,
                     vdaf: vdaf_config,
-                    vdaf_verify_key: vdaf_config.gen_verify_key(),
+                    vdaf_verify_key: vdaf_config.gen_verify_key().unwrap(),
                     method: Default::default(),
                 },
             );
@@ -306,8 +327,9 @@ mod test {
                     query: DapQueryConfig::FixedSize {
                         max_batch_size: Some(2),
                     },
+                    max_batch_query_count: 1,
                     vdaf: vdaf_config,
-                    vdaf_verify_key: vdaf_config.gen_verify_key(),
+                    vdaf_verify_key: vdaf_config.gen_verify_key().unwrap(),
                     method: Default::default(),
                 },
             );
@@ -322,8 +344,9 @@ mod test {
                     expiration: now, // Expires this second
                     min_batch_size: 1,
                     query: DapQueryConfig::TimeInterval,
+                    max_batch_query_count: 1,
                     vdaf: vdaf_config,
-                    vdaf_verify_key: vdaf_config.gen_verify_key(),
+                    vdaf_verify_key: vdaf_config.gen_verify_key().unwrap(),
                     method: Default::default(),
                 },
             );
@@ -343,8 +366,9 @@ mod test {
                     expiration: now + Self::TASK_TIME_PRECISION,
                     min_batch_size: 10,
                     query: DapQueryConfig::TimeInterval,
+                    max_batch_query_count: 1,
                     vdaf: mastic,
-                    vdaf_verify_key: mastic.gen_verify_key(),
+                    vdaf_verify_key: mastic.gen_verify_key().unwrap(),
                     method: Default::default(),
                 },
             );
@@ -411,7 +435,8 @@ mod test {
                     expiration: self.now + Self::TASK_TIME_PRECISION,
                     min_batch_size: 1,
                     query: DapQueryConfig::TimeInterval,
-                    vdaf_verify_key: vdaf.gen_verify_key(),
+                    max_batch_query_count: 1,
+                    vdaf_verify_key: vdaf.gen_verify_key().unwrap(),
                     vdaf,
                     method: Default::default(),
                 },
@@ -558,11 +583,12 @@ mod test {
                     .produce_agg_job_init_req(
                         self.leader.as_ref(),
                         self.leader.as_ref(),
+                        self.leader.report_extension_handlers(),
                         task_id,
                         &agg_job_id,
                         &part_batch_sel,
                         &agg_param,
-                        reports,
+                        &reports,
                         &self.leader.metrics,
                     )
                     .await
@@ -1166,7 +1192,7 @@ mod test {
             let mut agg_store = t.helper.agg_store.lock().unwrap();
             agg_store
                 .for_collection(task_id, &bucket, &DapAggregationParam::Empty)
-                .collected = true;
+                .collection_count = 1;
         }
 
         let query = task_config.query_for_current_batch_window(t.now);
@@ -1190,27 +1216,56 @@ mod test {
 
     async_test_versions! { handle_agg_job_req_failure_batch_collected }
 
-    #[tokio::test]
-    async fn handle_agg_job_req_abort_helper_state_overwritten_draft02() {
-        let t = Test::new(DapVersion::Draft02);
+    async fn handle_agg_job_init_req_retried(version: DapVersion) {
+        let t = Test::new(version);
         let task_id = &t.time_interval_task_id;
 
         let report = t.gen_test_report(task_id).await;
         let (_, req) = t
-            .gen_test_agg_job_init_req(
-                task_id,
-                DapVersion::Draft02,
-                DapAggregationParam::Empty,
-                vec![report],
-            )
+            .gen_test_agg_job_init_req(task_id, version, DapAggregationParam::Empty, vec![report])
             .await;
 
         // Send aggregate request.
-        let _ = helper::handle_agg_job_req(&*t.helper, &req).await;
+        let resp = helper::handle_agg_job_req(&*t.helper, &req).await.unwrap();
+        assert_eq!(t.helper.audit_log.invocations(), 1);
 
+        // Expect the Helper to send the same response if the Leader retries the request, e.g.,
+        // because it didn't get the response.
+        let retried_resp = helper::handle_agg_job_req(&*t.helper, &req).await.unwrap();
+        assert_eq!(retried_resp.payload, resp.payload);
         assert_eq!(t.helper.audit_log.invocations(), 1);
+        if version != DapVersion::Draft02 {
+            // The report is aggregated once and not marked as replayed.
+            assert_metrics_include!(t.helper_registry, {
+                r#"report_counter{env="test_helper",host="helper.org",status="aggregated"}"#: 1,
+                r#"aggregation_job_counter{env="test_helper",host="helper.org",status="started"}"#: 1,
+            });
+        }
+    }
+
+    async_test_versions! { handle_agg_job_init_req_retried }
 
-        // Send another aggregate request.
+    #[tokio::test]
+    async fn handle_agg_job_init_req_conflicting() {
+        let version = DapVersion::Latest;
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+
+        let report = t.gen_test_report(task_id).await;
+        let (_, req) = t
+            .gen_test_agg_job_init_req(task_id, version, DapAggregationParam::Empty, vec![report])
+            .await;
+        let DapResource::AggregationJob(agg_job_id) = req.resource else {
+            panic!("unexpected resource");
+        };
+        helper::handle_agg_job_req(&*t.helper, &req).await.unwrap();
+
+        // Send a different request for the same aggregation job.
+        let report = t.gen_test_report(task_id).await;
+        let (_, mut req) = t
+            .gen_test_agg_job_init_req(task_id, version, DapAggregationParam::Empty, vec![report])
+            .await;
+        req.resource = DapResource::AggregationJob(agg_job_id);
         let err = helper::handle_agg_job_req(&*t.helper, &req)
             .await
             .unwrap_err();
@@ -1251,6 +1306,365 @@ mod test {
 
     async_test_versions! { handle_agg_job_req_fail_send_cont_req }
 
+    #[tokio::test]
+    async fn handle_agg_job_cont_req_round_skew_recovery() {
+        let version = DapVersion::Latest;
+        let t = Test::new(version);
+        let task_id = &t.heavy_hitters_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+        let agg_param = DapAggregationParam::Mastic(
+            Poplar1AggregationParam::try_from_prefixes(vec![IdpfInput::from_bytes(&[0])]).unwrap(),
+        );
+
+        let report = t
+            .gen_test_report_for_measurement(
+                task_id,
+                DapMeasurement::Mastic {
+                    input: vec![0],
+                    weight: MasticWeight::Bool(true),
+                },
+            )
+            .await;
+        let (leader_state, init_req) = t
+            .gen_test_agg_job_init_req(task_id, version, agg_param, vec![report])
+            .await;
+        let DapResource::AggregationJob(agg_job_id) = init_req.resource else {
+            panic!("unexpected resource");
+        };
+        let agg_job_id = MetaAggregationJobId::Draft09(agg_job_id);
+
+        // Mastic takes two rounds, so the Helper waits for the Leader after the first.
+        let agg_job_resp = AggregationJobResp::get_decoded(
+            &helper::handle_agg_job_req(&*t.helper, &init_req)
+                .await
+                .unwrap()
+                .payload,
+        )
+        .unwrap();
+        let DapLeaderAggregationJobTransition::Uncommitted(_, agg_job_cont_req) = task_config
+            .handle_agg_job_resp(
+                task_id,
+                &agg_job_id,
+                leader_state,
+                agg_job_resp,
+                &t.leader.metrics,
+            )
+            .unwrap()
+        else {
+            panic!("unexpected transition");
+        };
+        assert_eq!(agg_job_cont_req.round, Some(1));
+
+        let req = t
+            .gen_test_agg_job_cont_req_with_round(
+                task_id,
+                &agg_job_id,
+                agg_job_cont_req.transitions.clone(),
+                Some(1),
+            )
+            .await;
+        let resp = helper::handle_agg_job_req(&*t.helper, &req).await.unwrap();
+
+        // The Leader retries the round, e.g., because it didn't get the response. Expect the
+        // Helper to send the same response without aggregating the report again.
+        let retried_resp = helper::handle_agg_job_req(&*t.helper, &req).await.unwrap();
+        assert_eq!(retried_resp.payload, resp.payload);
+        assert_metrics_include!(t.helper_registry, {
+            r#"report_counter{env="test_helper",host="helper.org",status="aggregated"}"#: 1,
+        });
+
+        // Expect the Helper to abort if the Leader retries the initialization request after the
+        // aggregation job was continued.
+        assert_matches!(
+            helper::handle_agg_job_req(&*t.helper, &init_req).await,
+            Err(DapError::Abort(DapAbort::RoundMismatch { .. }))
+        );
+
+        // Expect the Helper to abort if the Leader skips ahead.
+        let req = t
+            .gen_test_agg_job_cont_req_with_round(
+                task_id,
+                &agg_job_id,
+                agg_job_cont_req.transitions,
+                Some(3),
+            )
+            .await;
+        assert_matches!(
+            helper::handle_agg_job_req(&*t.helper, &req).await,
+            Err(DapError::Abort(DapAbort::RoundMismatch { .. }))
+        );
+    }
+
+    #[tokio::test]
+    async fn handle_agg_job_init_req_async() {
+        let version = DapVersion::Latest;
+        let mut data = TestData::new(version);
+        data.global_config.helper_async_agg_jobs = true;
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+
+        let report = t.gen_test_report(task_id).await;
+        let (_, req) = t
+            .gen_test_agg_job_init_req(task_id, version, DapAggregationParam::Empty, vec![report])
+            .await;
+        let DapResource::AggregationJob(agg_job_id) = req.resource else {
+            panic!("unexpected resource");
+        };
+        let poll_req = DapRequest {
+            version,
+            media_type: Some(DapMediaType::AggregationJobInitReq),
+            task_id: Some(*task_id),
+            resource: DapResource::AggregationJob(agg_job_id),
+            sender_auth: req.sender_auth.clone(),
+            ..Default::default()
+        };
+
+        // Expect the Helper to accept the aggregation job without processing it.
+        let resp = helper::handle_agg_job_req(&*t.helper, &req).await.unwrap();
+        assert_eq!(resp.status, DapResponseStatus::Processing);
+
+        // Expect the Helper to respond the same way if the Leader retries the request.
+        let resp = helper::handle_agg_job_req(&*t.helper, &req).await.unwrap();
+        assert_eq!(resp.status, DapResponseStatus::Processing);
+
+        let resp = helper::handle_agg_job_poll_req(&*t.helper, &poll_req)
+            .await
+            .unwrap();
+        assert_eq!(resp.status, DapResponseStatus::Processing);
+
+        // Process the aggregation job in the background. Processing it again is a no-op.
+        let agg_job_id = MetaAggregationJobId::Draft09(agg_job_id);
+        helper::process_agg_job(&*t.helper, task_id, agg_job_id)
+            .await
+            .unwrap();
+        helper::process_agg_job(&*t.helper, task_id, agg_job_id)
+            .await
+            .unwrap();
+
+        // Expect the response to be ready.
+        let resp = helper::handle_agg_job_poll_req(&*t.helper, &poll_req)
+            .await
+            .unwrap();
+        assert_eq!(resp.status, DapResponseStatus::Done);
+        let agg_job_resp = AggregationJobResp::get_decoded(&resp.payload).unwrap();
+        assert_eq!(agg_job_resp.transitions.len(), 1);
+        assert_matches!(agg_job_resp.transitions[0].var, TransitionVar::Continued(_));
+        assert_metrics_include!(t.helper_registry, {
+            r#"report_counter{env="test_helper",host="helper.org",status="aggregated"}"#: 1,
+            r#"aggregation_job_counter{env="test_helper",host="helper.org",status="started"}"#: 1,
+            r#"aggregation_job_counter{env="test_helper",host="helper.org",status="completed"}"#: 1,
+        });
+    }
+
+    #[tokio::test]
+    async fn handle_agg_job_poll_req_after_lease_expired() {
+        let version = DapVersion::Latest;
+        let mut data = TestData::new(version);
+        data.global_config.helper_async_agg_jobs = true;
+        let helper = Arc::into_inner(data.new_helper()).unwrap();
+        let clock = MockClock::new(helper.get_current_time());
+        let t = data.with_leader(Arc::new(helper.with_clock(clock.clone())));
+        let task_id = &t.time_interval_task_id;
+
+        let report = t.gen_test_report(task_id).await;
+        let (_, req) = t
+            .gen_test_agg_job_init_req(task_id, version, DapAggregationParam::Empty, vec![report])
+            .await;
+        let DapResource::AggregationJob(agg_job_id) = req.resource else {
+            panic!("unexpected resource");
+        };
+        let poll_req = DapRequest {
+            version,
+            media_type: Some(DapMediaType::AggregationJobInitReq),
+            task_id: Some(*task_id),
+            resource: DapResource::AggregationJob(agg_job_id),
+            sender_auth: req.sender_auth.clone(),
+            ..Default::default()
+        };
+
+        // The aggregation job is accepted, but the worker that was supposed to process it never
+        // does, e.g., because it crashed.
+        let resp = helper::handle_agg_job_req(&*t.helper, &req).await.unwrap();
+        assert_eq!(resp.status, DapResponseStatus::Processing);
+        let resp = helper::handle_agg_job_poll_req(&*t.helper, &poll_req)
+            .await
+            .unwrap();
+        assert_eq!(resp.status, DapResponseStatus::Processing);
+
+        // Expect the aggregation job to be processed once the worker's lease has expired.
+        clock.advance(60);
+        let resp = helper::handle_agg_job_poll_req(&*t.helper, &poll_req)
+            .await
+            .unwrap();
+        assert_eq!(resp.status, DapResponseStatus::Done);
+        let agg_job_resp = AggregationJobResp::get_decoded(&resp.payload).unwrap();
+        assert_eq!(agg_job_resp.transitions.len(), 1);
+        assert_matches!(agg_job_resp.transitions[0].var, TransitionVar::Continued(_));
+        assert_metrics_include!(t.helper_registry, {
+            r#"report_counter{env="test_helper",host="helper.org",status="aggregated"}"#: 1,
+        });
+    }
+
+    #[tokio::test]
+    async fn handle_agg_job_poll_req_failed() {
+        let version = DapVersion::Latest;
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let agg_job_id = AggregationJobId(thread_rng().gen());
+
+        // Accept an aggregation job that can't be processed.
+        let state = DapAggregationJobState::pending(
+            PartialBatchSelector::TimeInterval,
+            b"not an AggregationJobInitReq".to_vec(),
+            [0; 32],
+            0, // not held by any worker
+        );
+        t.helper
+            .put_helper_state(task_id, agg_job_id, &state)
+            .await
+            .unwrap();
+
+        // Expect the failure to be sent to the Leader each time it polls.
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+        let poll_req = DapRequest {
+            version,
+            media_type: Some(DapMediaType::AggregationJobInitReq),
+            task_id: Some(*task_id),
+            resource: DapResource::AggregationJob(agg_job_id),
+            sender_auth: Some(
+                t.leader
+                    .authorize(
+                        task_id,
+                        &task_config,
+                        &DapMediaType::AggregationJobInitReq,
+                        &[],
+                    )
+                    .await
+                    .unwrap(),
+            ),
+            ..Default::default()
+        };
+        for _ in 0..2 {
+            assert_matches!(
+                helper::handle_agg_job_poll_req(&*t.helper, &poll_req).await,
+                Err(DapError::Abort(DapAbort::InvalidMessage { .. }))
+            );
+        }
+    }
+
+    async fn e2e_async_helper(version: DapVersion) {
+        let mut data = TestData::new(version);
+        data.global_config.helper_async_agg_jobs = true;
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+
+        // Mastic takes two rounds, so the Leader also needs to continue the aggregation job after
+        // polling for the response to the first round.
+        let (task_id, measurement, agg_param, report_count) = if version == DapVersion::Draft02 {
+            (
+                &t.time_interval_task_id,
+                DapMeasurement::U64(1),
+                DapAggregationParam::Empty,
+                1,
+            )
+        } else {
+            (
+                &t.heavy_hitters_task_id,
+                DapMeasurement::Mastic {
+                    input: vec![0],
+                    weight: MasticWeight::Bool(true),
+                },
+                DapAggregationParam::Mastic(
+                    Poplar1AggregationParam::try_from_prefixes(vec![IdpfInput::from_bytes(&[0])])
+                        .unwrap(),
+                ),
+                10, // minimum batch size
+            )
+        };
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        for _ in 0..report_count {
+            let report = t
+                .gen_test_report_for_measurement(task_id, measurement.clone())
+                .await;
+            leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+                .await
+                .unwrap();
+        }
+
+        let query = task_config.query_for_current_batch_window(t.now);
+        leader::handle_coll_job_req(
+            &*t.leader,
+            &t.gen_test_coll_job_req_for_agg_param(query, agg_param, task_id)
+                .await,
+        )
+        .await
+        .unwrap();
+
+        leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+
+        assert_metrics_include!(t.helper_registry, {
+            r#"report_counter{env="test_helper",host="helper.org",status="aggregated"}"#: report_count,
+            r#"report_counter{env="test_helper",host="helper.org",status="collected"}"#: report_count,
+            r#"aggregation_job_counter{env="test_helper",host="helper.org",status="started"}"#: 1,
+            r#"aggregation_job_counter{env="test_helper",host="helper.org",status="completed"}"#: 1,
+        });
+        assert_metrics_include!(t.leader_registry, {
+            r#"report_counter{env="test_leader",host="leader.com",status="aggregated"}"#: report_count,
+            r#"report_counter{env="test_leader",host="leader.com",status="collected"}"#: report_count,
+        });
+    }
+
+    async_test_versions! { e2e_async_helper }
+
+    #[tokio::test]
+    async fn process_retry_agg_job_after_polling_too_many_times() {
+        let version = DapVersion::Latest;
+        let mut data = TestData::new(version);
+        data.global_config.helper_async_agg_jobs = true;
+        data.global_config.retry_policy.initial_backoff = 0;
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        let report = t.gen_test_report(task_id).await;
+        leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+            .await
+            .unwrap();
+        let query = task_config.query_for_current_batch_window(t.now);
+        leader::handle_coll_job_req(&*t.leader, &t.gen_test_coll_job_req(query, task_id).await)
+            .await
+            .unwrap();
+
+        // Expect the aggregation job to be retried if the Helper doesn't finish it in time.
+        t.leader.stall_peer_agg_jobs(true);
+        let telem = leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        assert_eq!(telem.reports_aggregated, 0);
+        assert_eq!(telem.reports_collected, 0);
+        assert_eq!(telem.work_items_retried, 1);
+        assert_eq!(telem.work_items_dead_lettered, 0);
+
+        // Expect the Leader to resume polling for the same aggregation job once the Helper
+        // catches up.
+        t.leader.stall_peer_agg_jobs(false);
+        let telem = leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        assert_eq!(telem.reports_aggregated, 1);
+        assert_eq!(telem.reports_collected, 1);
+        assert_eq!(telem.work_items_retried, 0);
+        assert_metrics_include!(t.helper_registry, {
+            r#"aggregation_job_counter{env="test_helper",host="helper.org",status="started"}"#: 1,
+            r#"aggregation_job_counter{env="test_helper",host="helper.org",status="completed"}"#: 1,
+        });
+    }
+
     async fn handle_upload_req_fail_send_invalid_report(version: DapVersion) {
         let t = Test::new(version);
         let task_id = &t.time_interval_task_id;
@@ -1328,6 +1742,8 @@ mod test {
             part_batch_sel: _,
             agg_param: _,
             reports,
+            agg_job_id: _,
+            retry: _,
         } = work_items.pop().unwrap()
         else {
             panic!("unexpected work item type");
@@ -1343,6 +1759,7 @@ mod test {
             coll_job_id: _,
             batch_sel: _,
             agg_param: _,
+            retry: _,
         } = work_items.pop().unwrap()
         else {
             panic!("unexpected work item type");
@@ -1392,6 +1809,7 @@ mod test {
             coll_job_id,
             batch_sel: _,
             agg_param: _,
+            retry: _,
         } = t.leader.dequeue_work(1).await.unwrap().pop().unwrap()
         else {
             panic!("unexpected work item type")
@@ -1582,53 +2000,137 @@ mod test {
             .await
             .unwrap();
 
-        // Repeat the request. Expect failure due to overlapping batch.
+        // Repeat the request. Expect failure since the batch was already collected the maximum
+        // number of times.
         assert_matches!(
             leader::handle_coll_job_req(&*t.leader, &req)
                 .await
                 .unwrap_err(),
-            DapError::Abort(DapAbort::BatchOverlap { .. })
+            DapError::Abort(DapAbort::BatchQueriedTooManyTimes { .. })
         );
     }
 
     async_test_versions! { handle_coll_job_req_fail_overlapping_batch_interval }
 
-    async fn handle_coll_job_req_fail_unrecongized_batch(version: DapVersion) {
-        let t = Test::new(version);
-        let task_id = &t.fixed_size_task_id;
+    async fn handle_coll_job_req_max_batch_query_count(version: DapVersion) {
+        let mut data = TestData::new(version);
+        let task_id = data.insert_task(version, VdafConfig::Prio3(Prio3Config::Count));
+        data.tasks.get_mut(&task_id).unwrap().max_batch_query_count = 2;
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_config = t.leader.unchecked_get_task_config(&task_id).await;
 
-        let req = t
-            .gen_test_coll_job_req(
-                Query::FixedSizeByBatchId {
-                    batch_id: BatchId(thread_rng().gen()),
-                },
-                task_id,
-            )
-            .await;
+        let report = t.gen_test_report(&task_id).await;
+        let req = t.gen_test_upload_req(report, &task_id).await;
+        leader::handle_upload_req(&*t.leader, &req).await.unwrap();
 
-        // Expect failure due to unrecognized batch
+        // Collect the same batch up to the maximum number of times.
+        let query = task_config.query_for_current_batch_window(t.now);
+        for _ in 0..2 {
+            let req = t.gen_test_coll_job_req(query.clone(), &task_id).await;
+            leader::handle_coll_job_req(&*t.leader, &req).await.unwrap();
+            leader::process(&*t.leader, "leader.com", 100)
+                .await
+                .unwrap();
+        }
+
+        assert_metrics_include!(t.helper_registry, {
+            r#"inbound_request_counter{env="test_helper",host="helper.org",type="collect"}"#: 2,
+            r#"report_counter{env="test_helper",host="helper.org",status="collected"}"#: 2,
+        });
+        assert_metrics_include!(t.leader_registry, {
+            r#"report_counter{env="test_leader",host="leader.com",status="collected"}"#: 2,
+        });
+
+        // Expect the batch to be rejected once it has been collected the maximum number of times.
+        let req = t.gen_test_coll_job_req(query, &task_id).await;
         assert_matches!(
             leader::handle_coll_job_req(&*t.leader, &req)
                 .await
                 .unwrap_err(),
-            DapError::Abort(DapAbort::BatchInvalid { .. })
+            DapError::Abort(DapAbort::BatchQueriedTooManyTimes { .. })
         );
     }
 
-    async_test_versions! { handle_coll_job_req_fail_unrecongized_batch }
+    async_test_versions! { handle_coll_job_req_max_batch_query_count }
 
-    // Test a successful collect request submission.
-    // This checks that the Leader reponds with the collect ID with the ID associated to the request.
-    async fn handle_coll_job_req_success(version: DapVersion) {
+    async fn mark_collected_idempotent(version: DapVersion) {
         let t = Test::new(version);
         let task_id = &t.time_interval_task_id;
         let task_config = t.leader.unchecked_get_task_config(task_id).await;
+        let batch_sel = task_config
+            .query_for_current_batch_window(t.now)
+            .into_batch_sel()
+            .unwrap();
 
-        // Collector: Create a CollectReq.
-        let collector_collect_req = CollectionReq {
-            draft02_task_id: task_id.for_request_payload(&version),
-            query: task_config.query_for_current_batch_window(t.now),
-            agg_param: Vec::default(),
+        // Marking the batch again for the same query doesn't count against its query limit.
+        let query = DapCollectionQuery::CollectionJob(CollectionJobId([1; 16]));
+        for _ in 0..2 {
+            t.leader
+                .mark_collected(task_id, &batch_sel, &query)
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            t.leader
+                .get_batch_query_count(task_id, &batch_sel)
+                .await
+                .unwrap(),
+            1
+        );
+
+        let query = DapCollectionQuery::CollectionJob(CollectionJobId([2; 16]));
+        t.leader
+            .mark_collected(task_id, &batch_sel, &query)
+            .await
+            .unwrap();
+        assert_eq!(
+            t.leader
+                .get_batch_query_count(task_id, &batch_sel)
+                .await
+                .unwrap(),
+            2
+        );
+    }
+
+    async_test_versions! { mark_collected_idempotent }
+
+    async fn handle_coll_job_req_fail_unrecongized_batch(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.fixed_size_task_id;
+
+        let req = t
+            .gen_test_coll_job_req(
+                Query::FixedSizeByBatchId {
+                    batch_id: BatchId(thread_rng().gen()),
+                },
+                task_id,
+            )
+            .await;
+
+        // Expect failure due to unrecognized batch
+        assert_matches!(
+            leader::handle_coll_job_req(&*t.leader, &req)
+                .await
+                .unwrap_err(),
+            DapError::Abort(DapAbort::BatchInvalid { .. })
+        );
+    }
+
+    async_test_versions! { handle_coll_job_req_fail_unrecongized_batch }
+
+    // Test a successful collect request submission.
+    // This checks that the Leader reponds with the collect ID with the ID associated to the request.
+    async fn handle_coll_job_req_success(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        // Collector: Create a CollectReq.
+        let collector_collect_req = CollectionReq {
+            draft02_task_id: task_id.for_request_payload(&version),
+            query: task_config.query_for_current_batch_window(t.now),
+            agg_param: Vec::default(),
         };
         let req = t.collector_authorized_req(
             task_id,
@@ -1644,6 +2146,7 @@ mod test {
             coll_job_id: leader_collect_id,
             batch_sel: leader_batch_sel,
             agg_param: leader_agg_param,
+            retry: _,
         } = t.leader.dequeue_work(1).await.unwrap().pop().unwrap()
         else {
             panic!("unexpected work item type");
@@ -1779,10 +2282,477 @@ mod test {
             r#"report_counter{env="test_leader",host="leader.com",status="aggregated"}"#: 1,
             r#"report_counter{env="test_leader",host="leader.com",status="collected"}"#: 1,
         });
+        assert_eq!(t.leader.audit_log.upload_invocations(), 1);
+        assert_eq!(t.leader.audit_log.coll_job_invocations(), 1);
+        assert_eq!(t.leader.audit_log.agg_share_invocations(), 1);
+        assert_eq!(t.helper.audit_log.agg_share_invocations(), 1);
     }
 
     async_test_versions! { e2e_time_interval }
 
+    async fn agg_job_policy_max_reports(version: DapVersion) {
+        let mut data = TestData::new(version);
+        data.global_config.agg_job_policy.max_reports = 2;
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        for _ in 0..5 {
+            let report = t.gen_test_report(task_id).await;
+            leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+                .await
+                .unwrap();
+        }
+
+        // Expect the reports not to be aggregated until a collection job is initialized.
+        let telem = leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        assert_eq!(telem.reports_aggregated, 0);
+
+        let query = task_config.query_for_current_batch_window(t.now);
+        leader::handle_coll_job_req(&*t.leader, &t.gen_test_coll_job_req(query, task_id).await)
+            .await
+            .unwrap();
+        let telem = leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        assert_eq!(telem.reports_aggregated, 5);
+        assert_eq!(telem.reports_collected, 5);
+
+        // Expect the reports to be split into aggregation jobs of at most two reports.
+        assert_metrics_include!(t.helper_registry, {
+            r#"report_counter{env="test_helper",host="helper.org",status="aggregated"}"#: 5,
+            r#"aggregation_job_counter{env="test_helper",host="helper.org",status="started"}"#: 3,
+        });
+    }
+
+    async_test_versions! { agg_job_policy_max_reports }
+
+    async fn agg_job_policy_eager_aggregation(version: DapVersion) {
+        let mut data = TestData::new(version);
+        data.global_config.agg_job_policy.eager_aggregation = true;
+        data.global_config.agg_job_policy.min_reports = 2;
+        data.global_config.agg_job_policy.max_reports = 2;
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        for _ in 0..5 {
+            let report = t.gen_test_report(task_id).await;
+            leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+                .await
+                .unwrap();
+        }
+
+        // Expect full aggregation jobs to be run before a collection job is initialized.
+        let telem = leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        assert_eq!(telem.reports_aggregated, 4);
+
+        // The remaining report is aggregated when the collection job is initialized.
+        let query = task_config.query_for_current_batch_window(t.now);
+        leader::handle_coll_job_req(&*t.leader, &t.gen_test_coll_job_req(query, task_id).await)
+            .await
+            .unwrap();
+        let telem = leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        assert_eq!(telem.reports_aggregated, 1);
+        assert_eq!(telem.reports_collected, 5);
+    }
+
+    async_test_versions! { agg_job_policy_eager_aggregation }
+
+    #[tokio::test]
+    async fn agg_job_policy_max_report_age() {
+        let version = DapVersion::Latest;
+        let mut data = TestData::new(version);
+        data.global_config.agg_job_policy = DapAggregationJobPolicy {
+            min_reports: 2,
+            max_reports: 100,
+            max_report_age: 60,
+            eager_aggregation: true,
+            max_concurrent_agg_jobs_per_task: Some(1),
+        };
+        let helper = data.new_helper();
+        let mut t = data.with_leader(helper);
+        let clock = MockClock::new(t.leader.get_current_time());
+        t.leader = Arc::new(Arc::into_inner(t.leader).unwrap().with_clock(clock.clone()));
+        let task_id = &t.time_interval_task_id;
+
+        // Expect a partial aggregation job to be run once there are enough reports.
+        let report = t.gen_test_report(task_id).await;
+        leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+            .await
+            .unwrap();
+        let telem = leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        assert_eq!(telem.reports_aggregated, 0);
+
+        let report = t.gen_test_report(task_id).await;
+        leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+            .await
+            .unwrap();
+        let telem = leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        assert_eq!(telem.reports_aggregated, 2);
+
+        // Expect fewer than the minimum number of reports to be aggregated once the oldest of them
+        // is too old.
+        let report = t.gen_test_report(task_id).await;
+        leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+            .await
+            .unwrap();
+        let telem = leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        assert_eq!(telem.reports_aggregated, 0);
+
+        clock.advance(60);
+        let telem = leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        assert_eq!(telem.reports_aggregated, 1);
+
+        // Expect reports to be held until the collection job if the VDAF takes an aggregation
+        // parameter.
+        let task_id = &t.heavy_hitters_task_id;
+        for _ in 0..2 {
+            let report = t
+                .gen_test_report_for_measurement(
+                    task_id,
+                    DapMeasurement::Mastic {
+                        input: vec![0],
+                        weight: MasticWeight::Bool(true),
+                    },
+                )
+                .await;
+            leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+                .await
+                .unwrap();
+        }
+        let telem = leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        assert_eq!(telem.reports_aggregated, 0);
+    }
+
+    async fn process_retry_transient_failure(version: DapVersion) {
+        let mut data = TestData::new(version);
+        data.global_config.retry_policy.initial_backoff = 0;
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        let report = t.gen_test_report(task_id).await;
+        leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+            .await
+            .unwrap();
+        let query = task_config.query_for_current_batch_window(t.now);
+        leader::handle_coll_job_req(&*t.leader, &t.gen_test_coll_job_req(query, task_id).await)
+            .await
+            .unwrap();
+
+        // Expect the aggregation job to be put back in the queue and the collection job to be
+        // deferred.
+        t.leader.fail_next_peer_requests(1);
+        let telem = leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        assert_eq!(telem.reports_aggregated, 0);
+        assert_eq!(telem.reports_collected, 0);
+        assert_eq!(telem.work_items_retried, 1);
+        {
+            let leader_state = t.leader.leader_state_store.lock().unwrap();
+            let work_queue = leader_state.work_queue();
+            assert_eq!(work_queue.len(), 2);
+            assert_matches!(work_queue[0], WorkItem::AggregationJob { .. });
+            assert_eq!(work_queue[0].retry().failed_attempts, 1);
+            assert_matches!(work_queue[1], WorkItem::CollectionJob { .. });
+        }
+
+        // Expect the retry to succeed.
+        let telem = leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        assert_eq!(telem.reports_aggregated, 1);
+        assert_eq!(telem.reports_collected, 1);
+        assert_eq!(telem.work_items_retried, 0);
+        assert!(t
+            .leader
+            .leader_state_store
+            .lock()
+            .unwrap()
+            .work_queue()
+            .is_empty());
+    }
+
+    async_test_versions! { process_retry_transient_failure }
+
+    #[tokio::test]
+    async fn process_requeue_after_dead_letter_failure() {
+        let version = DapVersion::Latest;
+        let mut data = TestData::new(version);
+        data.global_config.retry_policy.max_attempts = 1;
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        let report = t.gen_test_report(task_id).await;
+        leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+            .await
+            .unwrap();
+        let query = task_config.query_for_current_batch_window(t.now);
+        leader::handle_coll_job_req(&*t.leader, &t.gen_test_coll_job_req(query, task_id).await)
+            .await
+            .unwrap();
+
+        // Expect the deferred collection job to be put back in the queue even though the failed
+        // aggregation job could not be dead-lettered.
+        t.leader.fail_next_peer_requests(1);
+        t.leader.fail_next_dead_letters(1);
+        let telem = leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        assert_eq!(telem.work_items_dead_lettered, 1);
+
+        let leader_state = t.leader.leader_state_store.lock().unwrap();
+        assert!(leader_state.dead_letters().is_empty());
+        let work_queue = leader_state.work_queue();
+        assert_eq!(work_queue.len(), 1);
+        assert_matches!(work_queue[0], WorkItem::CollectionJob { .. });
+    }
+
+    #[tokio::test]
+    async fn process_dead_letter_after_max_attempts() {
+        let version = DapVersion::Latest;
+        let mut data = TestData::new(version);
+        data.global_config.retry_policy.max_attempts = 2;
+        data.global_config.retry_policy.initial_backoff = 0;
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        let report = t.gen_test_report(task_id).await;
+        leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+            .await
+            .unwrap();
+        let query = task_config.query_for_current_batch_window(t.now);
+        leader::handle_coll_job_req(&*t.leader, &t.gen_test_coll_job_req(query, task_id).await)
+            .await
+            .unwrap();
+
+        t.leader.fail_next_peer_requests(2);
+        let telem = leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        assert_eq!(telem.work_items_retried, 1);
+        assert_eq!(telem.work_items_dead_lettered, 0);
+
+        // Expect the aggregation job to be dead-lettered once it has failed too many times.
+        let telem = leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        assert_eq!(telem.work_items_retried, 0);
+        assert_eq!(telem.work_items_dead_lettered, 1);
+
+        let leader_state = t.leader.leader_state_store.lock().unwrap();
+        let dead_letters = leader_state.dead_letters();
+        assert_eq!(dead_letters.len(), 1);
+        assert_matches!(
+            &dead_letters[0],
+            (WorkItem::AggregationJob { reports, .. }, error) if reports.len() == 1 && error.starts_with("transient error")
+        );
+        assert_eq!(dead_letters[0].0.retry().failed_attempts, 2);
+
+        // The collection job is still pending.
+        let work_queue = leader_state.work_queue();
+        assert_eq!(work_queue.len(), 1);
+        assert_matches!(work_queue[0], WorkItem::CollectionJob { .. });
+        drop(leader_state);
+
+        // Expect the collection job to be dead-lettered rather than to complete without the
+        // reports of the dead-lettered aggregation job.
+        let telem = leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        assert_eq!(telem.reports_collected, 0);
+        assert_eq!(telem.work_items_retried, 0);
+        assert_eq!(telem.work_items_dead_lettered, 1);
+        let leader_state = t.leader.leader_state_store.lock().unwrap();
+        let dead_letters = leader_state.dead_letters();
+        assert_eq!(dead_letters.len(), 2);
+        assert_matches!(dead_letters[1].0, WorkItem::CollectionJob { .. });
+        assert!(leader_state.work_queue().is_empty());
+    }
+
+    async fn process_no_retry_after_agg_share_req(version: DapVersion) {
+        let mut data = TestData::new(version);
+        data.global_config.agg_job_policy.eager_aggregation = true;
+        data.global_config.agg_job_policy.max_reports = 1;
+        data.global_config.retry_policy.initial_backoff = 0;
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        let report = t.gen_test_report(task_id).await;
+        leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+            .await
+            .unwrap();
+        let telem = leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        assert_eq!(telem.reports_aggregated, 1);
+
+        // The Helper may have counted the AggregateShareReq against the query limit of the batch,
+        // so expect the collection job to be dead-lettered rather than retried.
+        let query = task_config.query_for_current_batch_window(t.now);
+        leader::handle_coll_job_req(&*t.leader, &t.gen_test_coll_job_req(query, task_id).await)
+            .await
+            .unwrap();
+        t.leader.fail_next_peer_requests(1);
+        let telem = leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        assert_eq!(telem.reports_collected, 0);
+        assert_eq!(telem.work_items_retried, 0);
+        assert_eq!(telem.work_items_dead_lettered, 1);
+        let leader_state = t.leader.leader_state_store.lock().unwrap();
+        let dead_letters = leader_state.dead_letters();
+        assert_eq!(dead_letters.len(), 1);
+        assert_matches!(dead_letters[0].0, WorkItem::CollectionJob { .. });
+    }
+
+    async_test_versions! { process_no_retry_after_agg_share_req }
+
+    async fn diagnose_batch_mismatch_lost_helper_state(version: DapVersion) {
+        let mut data = TestData::new(version);
+        data.global_config.agg_job_policy.eager_aggregation = true;
+        data.global_config.agg_job_policy.max_reports = 1;
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        let mut report_ids = Vec::new();
+        for _ in 0..2 {
+            let report = t.gen_test_report(task_id).await;
+            report_ids.push(report.report_metadata.id);
+            leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+                .await
+                .unwrap();
+        }
+        let telem = leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        assert_eq!(telem.reports_aggregated, 2);
+
+        let batch_window = task_config.quantized_time_lower_bound(t.now);
+        let batch_sel = BatchSelector::TimeInterval {
+            batch_interval: Interval {
+                start: batch_window,
+                duration: task_config.time_precision,
+            },
+        };
+        let bucket = DapBatchBucket::TimeInterval { batch_window };
+
+        // Simulate the Helper losing track of one of the reports.
+        t.helper
+            .agg_store
+            .lock()
+            .unwrap()
+            .for_collection(task_id, &bucket, &DapAggregationParam::Empty)
+            .reports
+            .remove(&report_ids[1]);
+
+        let leader_report_ids = diagnostics::get_batch_report_ids(&*t.leader, task_id, &batch_sel)
+            .await
+            .unwrap();
+        let helper_report_ids = diagnostics::get_batch_report_ids(&*t.helper, task_id, &batch_sel)
+            .await
+            .unwrap();
+        assert_eq!(
+            diagnostics::diagnose_batch_mismatch(&leader_report_ids, &helper_report_ids),
+            vec![diagnostics::DapBucketMismatch {
+                bucket,
+                missing_from_helper: vec![report_ids[1]],
+                missing_from_leader: vec![],
+            }]
+        );
+    }
+
+    async_test_versions! { diagnose_batch_mismatch_lost_helper_state }
+
+    async fn process_dead_letter_on_peer_abort(version: DapVersion) {
+        let mut data = TestData::new(version);
+        data.global_config.agg_job_policy.eager_aggregation = true;
+        data.global_config.agg_job_policy.max_reports = 1;
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        let report = t.gen_test_report(task_id).await;
+        leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+            .await
+            .unwrap();
+        let telem = leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        assert_eq!(telem.reports_aggregated, 1);
+
+        // Simulate the Helper losing its aggregate share.
+        let batch_window = task_config.quantized_time_lower_bound(t.now);
+        t.helper
+            .agg_store
+            .lock()
+            .unwrap()
+            .for_collection(
+                task_id,
+                &DapBatchBucket::TimeInterval { batch_window },
+                &DapAggregationParam::Empty,
+            )
+            .agg_share = DapAggregateShare::default();
+
+        // Expect the Helper to abort the aggregate share request and the Leader to dead-letter the
+        // collection job rather than retry it.
+        let query = task_config.query_for_current_batch_window(t.now);
+        leader::handle_coll_job_req(&*t.leader, &t.gen_test_coll_job_req(query, task_id).await)
+            .await
+            .unwrap();
+        let telem = leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        assert_eq!(telem.reports_collected, 0);
+        assert_eq!(telem.work_items_retried, 0);
+        assert_eq!(telem.work_items_dead_lettered, 1);
+        {
+            let leader_state = t.leader.leader_state_store.lock().unwrap();
+            let dead_letters = leader_state.dead_letters();
+            assert_eq!(dead_letters.len(), 1);
+            assert_matches!(
+                &dead_letters[0],
+                (WorkItem::CollectionJob { .. }, error) if error == "peer abort: batchMismatch"
+            );
+        }
+
+        assert_metrics_include!(t.leader_registry, {
+            r#"peer_abort_counter{env="test_leader",host="leader.com",type="batchMismatch"}"#: 1,
+        });
+    }
+
+    async_test_versions! { process_dead_letter_on_peer_abort }
+
     async fn e2e_fixed_size(version: DapVersion) {
         let t = Test::new(version);
         let task_id = &t.fixed_size_task_id;
@@ -1823,10 +2793,140 @@ mod test {
             r#"report_counter{env="test_leader",host="leader.com",status="aggregated"}"#: 1,
             r#"report_counter{env="test_leader",host="leader.com",status="collected"}"#: 1,
         });
+        assert_eq!(t.leader.audit_log.upload_invocations(), 1);
+        assert_eq!(t.leader.audit_log.coll_job_invocations(), 1);
+        assert_eq!(t.leader.audit_log.agg_share_invocations(), 1);
+        assert_eq!(t.helper.audit_log.agg_share_invocations(), 1);
     }
 
     async_test_versions! { e2e_fixed_size }
 
+    async fn leader_fixed_size_batch_lifecycle(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.fixed_size_task_id;
+        let mut task_config = t.leader.unchecked_get_task_config(task_id).await;
+        task_config.min_batch_size = 2;
+        task_config.query = DapQueryConfig::FixedSize {
+            max_batch_size: Some(3),
+        };
+
+        let mut leader_state = InMemoryLeaderState::default();
+        let put_report = |leader_state: &mut InMemoryLeaderState, report| {
+            leader_state
+                .put_report(
+                    task_id,
+                    &task_config,
+                    report,
+                    &DapAggregationJobPolicy::default(),
+                    t.now,
+                )
+                .unwrap();
+        };
+
+        // The batch is not ready until it has `min_batch_size` reports.
+        put_report(&mut leader_state, t.gen_test_report(task_id).await);
+        assert_matches!(
+            leader_state.current_batch(task_id, &task_config),
+            Err(DapError::Abort(DapAbort::InvalidBatchSize { .. }))
+        );
+        put_report(&mut leader_state, t.gen_test_report(task_id).await);
+        let first_batch_id = leader_state.current_batch(task_id, &task_config).unwrap();
+
+        // The batch is filled up to `max_batch_size` reports, after which a new batch is opened.
+        // The current batch remains the oldest one until it is collected.
+        for _ in 0..3 {
+            put_report(&mut leader_state, t.gen_test_report(task_id).await);
+        }
+        assert_eq!(
+            leader_state.current_batch(task_id, &task_config).unwrap(),
+            first_batch_id
+        );
+        leader_state
+            .init_collect_job(
+                task_id,
+                &task_config,
+                &Some(CollectionJobId(thread_rng().gen())),
+                BatchSelector::FixedSizeByBatchId {
+                    batch_id: first_batch_id,
+                },
+                DapAggregationParam::Empty,
+                &DapAggregationJobPolicy::default(),
+            )
+            .unwrap();
+        let reports_per_batch = |leader_state: &InMemoryLeaderState| {
+            let mut reports_per_batch = HashMap::<_, usize>::new();
+            for work_item in leader_state.work_queue() {
+                if let WorkItem::AggregationJob {
+                    part_batch_sel: PartialBatchSelector::FixedSizeByBatchId { batch_id },
+                    reports,
+                    ..
+                } = work_item
+                {
+                    *reports_per_batch.entry(*batch_id).or_default() += reports.len();
+                }
+            }
+            reports_per_batch
+        };
+        assert_eq!(
+            reports_per_batch(&leader_state),
+            HashMap::from([(first_batch_id, 3)])
+        );
+
+        // The overflow went into the next batch, which is now ready.
+        let second_batch_id = leader_state.current_batch(task_id, &task_config).unwrap();
+        assert_ne!(second_batch_id, first_batch_id);
+    }
+
+    async_test_versions! { leader_fixed_size_batch_lifecycle }
+
+    async fn helper_rejects_reports_beyond_max_batch_size(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.fixed_size_task_id;
+
+        // Fill the batch up to its maximum size.
+        let reports = vec![
+            t.gen_test_report(task_id).await,
+            t.gen_test_report(task_id).await,
+        ];
+        let (_, req) = t
+            .gen_test_agg_job_init_req(task_id, version, DapAggregationParam::Empty, reports)
+            .await;
+        let part_batch_sel = AggregationJobInitReq::get_decoded_with_param(&version, &req.payload)
+            .unwrap()
+            .part_batch_sel;
+        let resp = helper::handle_agg_job_req(&*t.helper, &req).await.unwrap();
+        let agg_job_resp = AggregationJobResp::get_decoded(&resp.payload).unwrap();
+        assert!(agg_job_resp
+            .transitions
+            .iter()
+            .all(|transition| matches!(transition.var, TransitionVar::Continued(..))));
+
+        // Try to aggregate one more report into the same batch.
+        let report = t.gen_test_report(task_id).await;
+        let (_, mut req) = t
+            .gen_test_agg_job_init_req(task_id, version, DapAggregationParam::Empty, vec![report])
+            .await;
+        let mut agg_job_init_req =
+            AggregationJobInitReq::get_decoded_with_param(&version, &req.payload).unwrap();
+        agg_job_init_req.part_batch_sel = part_batch_sel;
+        req.payload = agg_job_init_req.get_encoded_with_param(&version).unwrap();
+        let resp = helper::handle_agg_job_req(&*t.helper, &req).await.unwrap();
+        let agg_job_resp = AggregationJobResp::get_decoded(&resp.payload).unwrap();
+        assert_eq!(agg_job_resp.transitions.len(), 1);
+        assert_matches!(
+            agg_job_resp.transitions[0].var,
+            TransitionVar::Failed(TransitionFailure::BatchSaturated)
+        );
+
+        assert_metrics_include!(t.helper_registry, {
+            r#"report_counter{env="test_helper",host="helper.org",status="aggregated"}"#: 2,
+            r#"report_counter{env="test_helper",host="helper.org",status="rejected_batch_saturated"}"#: 1,
+        });
+    }
+
+    async_test_version! { helper_rejects_reports_beyond_max_batch_size, Draft09 }
+    async_test_version! { helper_rejects_reports_beyond_max_batch_size, Latest }
+
     async fn e2e_taskprov(
         version: DapVersion,
         vdaf_config: VdafConfig,
@@ -1924,6 +3024,12 @@ mod test {
             r#"report_counter{env="test_leader",host="leader.com",status="aggregated"}"#: 1,
             r#"report_counter{env="test_leader",host="leader.com",status="collected"}"#: 1,
         });
+        assert_eq!(t.leader.audit_log.upload_invocations(), 1);
+        assert_eq!(t.leader.audit_log.coll_job_invocations(), 1);
+        assert_eq!(t.leader.audit_log.agg_share_invocations(), 1);
+        assert_eq!(t.helper.audit_log.agg_share_invocations(), 1);
+        assert_eq!(t.leader.audit_log.taskprov_provision_invocations(), 1);
+        assert_eq!(t.helper.audit_log.taskprov_provision_invocations(), 1);
     }
 
     async fn e2e_taskprov_prio2(version: DapVersion) {
@@ -2146,8 +3252,9 @@ mod test {
             .await
             .unwrap();
 
+        // Mastic takes two rounds, so the aggregation job is initialized and continued once.
         assert_metrics_include!(t.helper_registry, {
-            r#"inbound_request_counter{env="test_helper",host="helper.org",type="aggregate"}"#: 1,
+            r#"inbound_request_counter{env="test_helper",host="helper.org",type="aggregate"}"#: 2,
             r#"inbound_request_counter{env="test_helper",host="helper.org",type="collect"}"#: 1,
             r#"report_counter{env="test_helper",host="helper.org",status="aggregated"}"#: 10,
             r#"report_counter{env="test_helper",host="helper.org",status="collected"}"#: 10,
@@ -0,0 +1,102 @@
+// Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Extension point for report extensions that are not built into Daphne.
+//!
+//! By default, an Aggregator rejects reports carrying an extension it does not recognize. To
+//! accept a custom extension, e.g., a device attestation token, register a
+//! [`DapReportExtensionHandler`] for its type and return the handlers from
+//! [`DapAggregator::report_extension_handlers`].
+
+use std::{collections::BTreeMap, sync::Arc};
+
+use crate::{
+    fatal_error,
+    messages::{ReportMetadata, TaskId, TransitionFailure, EXTENSION_TASKPROV},
+    DapError, DapTaskConfig,
+};
+
+#[cfg(doc)]
+use super::DapAggregator;
+
+/// The outcome of processing a report extension.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DapReportExtensionOutcome {
+    /// Accept the extension.
+    Accept,
+
+    /// Accept the extension and attach the given data to the report. The data is made available
+    /// to the [`DapReportInitializer`](super::DapReportInitializer) along with the report.
+    AcceptWithData(Vec<u8>),
+
+    /// Reject the report.
+    Reject(TransitionFailure),
+}
+
+/// A handler for a custom report extension type.
+pub trait DapReportExtensionHandler: Send + Sync {
+    /// The extension type handled.
+    fn extension_type(&self) -> u16;
+
+    /// Process the extension payload of a report. This is called once the Aggregator has decrypted
+    /// its input share and before VDAF preparation.
+    fn handle(
+        &self,
+        is_leader: bool,
+        task_id: &TaskId,
+        task_config: &DapTaskConfig,
+        metadata: &ReportMetadata,
+        payload: &[u8],
+    ) -> DapReportExtensionOutcome;
+}
+
+/// The set of report extension handlers of an Aggregator, keyed by extension type.
+#[derive(Clone, Default)]
+pub struct DapReportExtensionHandlers {
+    handlers: BTreeMap<u16, Arc<dyn DapReportExtensionHandler>>,
+}
+
+static NO_HANDLERS: DapReportExtensionHandlers = DapReportExtensionHandlers {
+    handlers: BTreeMap::new(),
+};
+
+impl DapReportExtensionHandlers {
+    /// The empty set of handlers.
+    pub fn none() -> &'static Self {
+        &NO_HANDLERS
+    }
+
+    /// Register a handler. Each extension type may only have one handler. Extensions that are built
+    /// into Daphne, i.e., taskprov, cannot be handled.
+    pub fn register(
+        &mut self,
+        handler: Arc<dyn DapReportExtensionHandler>,
+    ) -> Result<(), DapError> {
+        let typ = handler.extension_type();
+        if typ == EXTENSION_TASKPROV {
+            return Err(fatal_error!(
+                err = format!("cannot register handler for extension type {typ:#06x}: reserved")
+            ));
+        }
+        if self.handlers.contains_key(&typ) {
+            return Err(fatal_error!(
+                err = format!(
+                    "cannot register handler for extension type {typ:#06x}: already registered"
+                )
+            ));
+        }
+        self.handlers.insert(typ, handler);
+        Ok(())
+    }
+
+    /// Look up the handler for the given extension type.
+    pub fn get(&self, typ: u16) -> Option<&dyn DapReportExtensionHandler> {
+        self.handlers.get(&typ).map(AsRef::as_ref)
+    }
+}
+
+impl std::fmt::Debug for DapReportExtensionHandlers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.handlers.keys()).finish()
+    }
+}
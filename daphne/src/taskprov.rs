@@ -13,7 +13,7 @@ use crate::{
         taskprov::{QueryConfigVar, TaskConfig, VdafTypeVar},
         Extension, ReportMetadata, TaskId,
     },
-    vdaf::VdafVerifyKey,
+    vdaf::{registered_vdaf, VdafVerifyKey},
     DapAbort, DapError, DapQueryConfig, DapRequest, DapTaskConfig, DapTaskConfigMethod, DapVersion,
     Prio3Config, VdafConfig,
 };
@@ -60,14 +60,18 @@ pub(crate) fn extract_prk_from_verify_key_init(
 }
 
 impl VdafConfig {
-    fn expand_into_taskprov_verify_key(&self, prk: &Prk, task_id: &TaskId) -> VdafVerifyKey {
-        let mut verify_key = self.uninitialized_verify_key();
+    fn expand_into_taskprov_verify_key(
+        &self,
+        prk: &Prk,
+        task_id: &TaskId,
+    ) -> Result<VdafVerifyKey, DapError> {
+        let mut verify_key = self.uninitialized_verify_key()?;
         let info = [task_id.as_ref()];
         // This expand(), and the associated fill() below can only fail if the length is wrong,
         // and it won't be, so we unwrap().
         let okm = prk.expand(&info, verify_key.clone()).unwrap();
         okm.fill(verify_key.as_mut()).unwrap();
-        verify_key
+        Ok(verify_key)
     }
 }
 
@@ -83,7 +87,7 @@ fn compute_vdaf_verify_key(
     verify_key_init: &[u8; 32],
     task_id: &TaskId,
     vdaf_config: &VdafConfig,
-) -> VdafVerifyKey {
+) -> Result<VdafVerifyKey, DapError> {
     vdaf_config.expand_into_taskprov_verify_key(
         &extract_prk_from_verify_key_init(version, verify_key_init),
         task_id,
@@ -264,6 +268,16 @@ impl VdafConfig {
                     },
                 ))
             }
+            (_, VdafTypeVar::NotImplemented { typ, param }) if registered_vdaf(typ).is_some() => {
+                // Registered VDAFs are not parameterized.
+                if !param.is_empty() {
+                    return Err(DapAbort::InvalidTask {
+                        detail: format!("unexpected parameters for registered VDAF ({typ})"),
+                        task_id: *task_id,
+                    });
+                }
+                Ok(VdafConfig::Registered { codepoint: typ })
+            }
             (_, VdafTypeVar::NotImplemented { typ, .. }) => Err(DapAbort::InvalidTask {
                 detail: format!("unimplemented VDAF type ({typ})"),
                 task_id: *task_id,
@@ -295,20 +309,21 @@ impl DapTaskConfig {
             });
         }
 
-        // Only one query per batch is currently supported.
-        if task_config.query_config.max_batch_query_count != 1 {
+        if task_config.query_config.max_batch_query_count == 0 {
             return Err(DapAbort::InvalidTask {
-                detail: format!(
-                    "unsupported max batch query count {}",
-                    task_config.query_config.max_batch_query_count
-                ),
+                detail: "max batch query count must be positive".into(),
                 task_id: *task_id,
             });
         }
 
         let vdaf = VdafConfig::try_from_taskprov(task_id, version, task_config.vdaf_config.var)?;
         let vdaf_verify_key =
-            compute_vdaf_verify_key(version, vdaf_verify_key_init, task_id, &vdaf);
+            compute_vdaf_verify_key(version, vdaf_verify_key_init, task_id, &vdaf).map_err(
+                |e| DapAbort::InvalidTask {
+                    detail: e.to_string(),
+                    task_id: *task_id,
+                },
+            )?;
         Ok(DapTaskConfig {
             version,
             leader_url: url_from_bytes(task_id, &task_config.leader_url.bytes)?,
@@ -317,6 +332,7 @@ impl DapTaskConfig {
             expiration: task_config.task_expiration,
             min_batch_size: task_config.query_config.min_batch_size.into(),
             query: DapQueryConfig::try_from_taskprov(task_id, task_config.query_config.var)?,
+            max_batch_query_count: task_config.query_config.max_batch_query_count.into(),
             vdaf,
             vdaf_verify_key,
             collector_hpke_config: collector_hpke_config.clone(),
@@ -379,6 +395,10 @@ impl TryFrom<&VdafConfig> for messages::taskprov::VdafTypeVar {
             VdafConfig::Mastic { .. } => Err(fatal_error!(
                 err = format!("{vdaf_config} is not currently supported for taskprov")
             )),
+            VdafConfig::Registered { codepoint } => Ok(Self::NotImplemented {
+                typ: *codepoint,
+                param: Vec::new(),
+            }),
         }
     }
 }
@@ -407,7 +427,9 @@ impl TryFrom<&DapTaskConfig> for messages::taskprov::TaskConfig {
                 min_batch_size: task_config.min_batch_size.try_into().map_err(|_| {
                     fatal_error!(err = "task min batch size is too large for taskprov")
                 })?,
-                max_batch_query_count: 1,
+                max_batch_query_count: task_config.max_batch_query_count.try_into().map_err(
+                    |_| fatal_error!(err = "task max batch query count is too large for taskprov"),
+                )?,
                 var: (&task_config.query).try_into()?,
             },
             task_expiration: task_config.expiration,
@@ -460,7 +482,7 @@ mod test {
             },
             query_config: messages::taskprov::QueryConfig {
                 time_precision: 3600,
-                max_batch_query_count: 1,
+                max_batch_query_count: 3,
                 min_batch_size: 1,
                 var: messages::taskprov::QueryConfigVar::FixedSize { max_batch_size: 2 },
             },
@@ -487,6 +509,7 @@ mod test {
         )
         .unwrap();
 
+        assert_eq!(task_config.max_batch_query_count, 3);
         assert_eq!(
             messages::taskprov::TaskConfig::try_from(&task_config).unwrap(),
             taskprov_config
@@ -512,7 +535,8 @@ mod test {
             &verify_key_init,
             &task_id,
             &VdafConfig::Prio2 { dimension: 10 },
-        );
+        )
+        .unwrap();
         let expected: [u8; 32] = [
             251, 209, 125, 181, 57, 15, 148, 158, 227, 45, 38, 52, 220, 73, 159, 91, 145, 40, 123,
             204, 49, 124, 7, 97, 221, 4, 232, 53, 194, 171, 19, 51,
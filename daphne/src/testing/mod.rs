@@ -3,6 +3,8 @@
 
 //! Mock backend functionality to test DAP protocol.
 
+pub mod simulation;
+
 use crate::{
     audit_log::{AggregationJobAuditAction, AuditLog},
     auth::{BearerToken, BearerTokenProvider},
@@ -11,8 +13,8 @@ use crate::{
     hpke::{HpkeConfig, HpkeDecrypter, HpkeKemId, HpkeReceiverConfig},
     messages::{
         AggregationJobContinueReq, AggregationJobInitReq, AggregationJobResp, BatchId,
-        BatchSelector, Collection, CollectionJobId, HpkeCiphertext, Interval, PartialBatchSelector,
-        Report, ReportId, TaskId, Time, TransitionFailure,
+        BatchSelector, Collection, CollectionJobId, Duration, HpkeCiphertext, Interval,
+        PartialBatchSelector, Report, ReportId, TaskId, Time, TransitionFailure,
     },
     metrics::{prometheus::DaphnePromMetrics, DaphneMetrics},
     protocol::aggregator::{EarlyReportStateConsumed, EarlyReportStateInitialized},
@@ -20,25 +22,27 @@ use crate::{
         aggregator::MergeAggShareError,
         helper,
         leader::{in_memory_leader::InMemoryLeaderState, WorkItem},
+        report_extensions::DapReportExtensionHandlers,
         DapAggregator, DapAuthorizedSender, DapHelper, DapLeader, DapReportInitializer,
     },
     DapAbort, DapAggregateResult, DapAggregateShare, DapAggregateSpan, DapAggregationJobState,
-    DapAggregationJobUncommitted, DapAggregationParam, DapBatchBucket, DapCollectionJob, DapError,
-    DapGlobalConfig, DapHelperAggregationJobTransition, DapLeaderAggregationJobTransition,
-    DapMeasurement, DapQueryConfig, DapRequest, DapResponse, DapTaskConfig, DapVersion,
-    MetaAggregationJobId, VdafConfig,
+    DapAggregationJobUncommitted, DapAggregationParam, DapBatchBucket, DapCollectionJob,
+    DapCollectionQuery, DapError, DapGlobalConfig, DapHelperAggregationJobTransition,
+    DapLeaderAggregationJobTransition, DapMeasurement, DapQueryConfig, DapRequest, DapResource,
+    DapResponse, DapResponseStatus, DapTaskConfig, DapVersion, MetaAggregationJobId, VdafConfig,
 };
 use async_trait::async_trait;
 use deepsize::DeepSizeOf;
 use prio::codec::Encode;
 use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
+use simulation::SimulatedNetwork;
 use std::{
     collections::{HashMap, HashSet},
     hash::Hash,
     ops::DerefMut,
     sync::{
-        atomic::{AtomicU32, Ordering},
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
         Arc, Mutex,
     },
     time::SystemTime,
@@ -114,7 +118,7 @@ impl AggregationJobTest {
             .as_secs();
         let task_id = TaskId(rng.gen());
         let agg_job_id = MetaAggregationJobId::gen_for_version(version);
-        let vdaf_verify_key = vdaf.gen_verify_key();
+        let vdaf_verify_key = vdaf.gen_verify_key().unwrap();
         let leader_hpke_receiver_config = HpkeReceiverConfig::gen(rng.gen(), kem_id).unwrap();
         let helper_hpke_receiver_config = HpkeReceiverConfig::gen(rng.gen(), kem_id).unwrap();
         let collector_hpke_receiver_config = HpkeReceiverConfig::gen(rng.gen(), kem_id).unwrap();
@@ -156,6 +160,7 @@ impl AggregationJobTest {
                 expiration: now + 500,
                 min_batch_size: 10,
                 query: DapQueryConfig::TimeInterval,
+                max_batch_query_count: 1,
                 vdaf: *vdaf,
                 vdaf_verify_key,
                 collector_hpke_config,
@@ -203,11 +208,12 @@ impl AggregationJobTest {
             .produce_agg_job_init_req(
                 &self.leader_hpke_receiver_config,
                 self,
+                DapReportExtensionHandlers::none(),
                 &self.task_id,
                 &self.agg_job_id,
                 &PartialBatchSelector::TimeInterval,
                 agg_param,
-                reports,
+                &reports,
                 &self.leader_metrics,
             )
             .await
@@ -223,6 +229,7 @@ impl AggregationJobTest {
     ) -> DapHelperAggregationJobTransition<AggregationJobResp> {
         self.task_config
             .handle_agg_job_init_req(
+                &self.task_id,
                 &HashMap::default(),
                 &agg_job_init_req.part_batch_sel.clone(),
                 &self
@@ -230,8 +237,9 @@ impl AggregationJobTest {
                     .helper_initialize_reports(
                         &self.helper_hpke_receiver_config,
                         self,
+                        DapReportExtensionHandlers::none(),
                         &self.task_id,
-                        agg_job_init_req,
+                        (&agg_job_init_req).into(),
                     )
                     .await
                     .unwrap(),
@@ -240,7 +248,7 @@ impl AggregationJobTest {
             .unwrap()
     }
 
-    /// Leader: Handle first `AggregationJobResp`, produce `AggregationJobContinueReq`.
+    /// Leader: Handle `AggregationJobResp`, produce the next `AggregationJobContinueReq`.
     ///
     /// Panics if the Leader aborts.
     pub fn handle_agg_job_resp(
@@ -277,14 +285,14 @@ impl AggregationJobTest {
             .expect_err("handle_agg_job_resp() succeeded; expected failure")
     }
 
-    /// Helper: Handle `AggregationJobContinueReq`, produce second `AggregationJobResp`.
+    /// Helper: Handle `AggregationJobContinueReq`, produce the next `AggregationJobResp`.
     ///
     /// Panics if the Helper aborts.
     pub fn handle_agg_job_cont_req(
         &self,
         helper_state: &DapAggregationJobState,
         agg_job_cont_req: &AggregationJobContinueReq,
-    ) -> (DapAggregateSpan<DapAggregateShare>, AggregationJobResp) {
+    ) -> DapHelperAggregationJobTransition<AggregationJobResp> {
         self.task_config
             .handle_agg_job_cont_req(
                 &self.task_id,
@@ -323,7 +331,7 @@ impl AggregationJobTest {
     ) -> DapAggregateSpan<DapAggregateShare> {
         let metrics = &self.leader_metrics;
         self.task_config
-            .handle_final_agg_job_resp(leader_uncommitted, agg_job_resp, metrics)
+            .handle_final_agg_job_resp(&self.task_id, leader_uncommitted, agg_job_resp, metrics)
             .unwrap()
     }
 
@@ -411,8 +419,11 @@ impl AggregationJobTest {
             panic!("unexpected transition");
         };
 
-        let (leader_agg_span, helper_agg_span) =
-            match self.handle_agg_job_init_req(agg_job_init_req).await {
+        // Run as many rounds as the VDAF needs.
+        let mut leader_state = leader_state;
+        let mut helper_transition = self.handle_agg_job_init_req(agg_job_init_req).await;
+        let (leader_agg_span, helper_agg_span) = loop {
+            match helper_transition {
                 DapHelperAggregationJobTransition::Continued(helper_state, agg_job_resp) => {
                     let got = DapAggregationJobState::get_decoded(
                         &self.task_config.vdaf,
@@ -424,15 +435,28 @@ impl AggregationJobTest {
                         helper_state.get_encoded().unwrap()
                     );
 
-                    let DapLeaderAggregationJobTransition::Uncommitted(uncommitted, agg_cont) =
-                        self.handle_agg_job_resp(leader_state, agg_job_resp)
-                    else {
-                        panic!("unexpected transition");
-                    };
-                    let (helper_agg_span, transitions) =
-                        self.handle_agg_job_cont_req(&helper_state, &agg_cont);
-                    let leader_agg_span = self.handle_final_agg_job_resp(uncommitted, transitions);
-                    (leader_agg_span, helper_agg_span)
+                    match self.handle_agg_job_resp(leader_state, agg_job_resp) {
+                        DapLeaderAggregationJobTransition::Continued(next_state, agg_cont) => {
+                            leader_state = next_state;
+                            helper_transition =
+                                self.handle_agg_job_cont_req(&helper_state, &agg_cont);
+                        }
+                        DapLeaderAggregationJobTransition::Uncommitted(uncommitted, agg_cont) => {
+                            let DapHelperAggregationJobTransition::Finished(
+                                helper_agg_span,
+                                agg_job_resp,
+                            ) = self.handle_agg_job_cont_req(&helper_state, &agg_cont)
+                            else {
+                                panic!("unexpected transition");
+                            };
+                            let leader_agg_span =
+                                self.handle_final_agg_job_resp(uncommitted, agg_job_resp);
+                            break (leader_agg_span, helper_agg_span);
+                        }
+                        DapLeaderAggregationJobTransition::Finished(..) => {
+                            panic!("unexpected transition");
+                        }
+                    }
                 }
                 DapHelperAggregationJobTransition::Finished(helper_agg_span, agg_job_resp) => {
                     let DapLeaderAggregationJobTransition::Finished(leader_agg_span) =
@@ -440,9 +464,10 @@ impl AggregationJobTest {
                     else {
                         panic!("unexpected transition");
                     };
-                    (leader_agg_span, helper_agg_span)
+                    break (leader_agg_span, helper_agg_span);
                 }
-            };
+            }
+        };
 
         let report_count = u64::try_from(leader_agg_span.report_count()).unwrap();
 
@@ -533,23 +558,41 @@ macro_rules! async_test_versions {
     };
 }
 
-impl From<DapBatchBucket> for PartialBatchSelector {
-    fn from(bucket: DapBatchBucket) -> Self {
-        match bucket {
-            DapBatchBucket::FixedSize { batch_id } => Self::FixedSizeByBatchId { batch_id },
-            DapBatchBucket::TimeInterval { .. } => Self::TimeInterval,
-        }
-    }
-}
-
 #[derive(Default)]
 #[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
-pub struct MockAuditLog(AtomicU32);
+pub struct MockAuditLog {
+    agg_jobs: AtomicU32,
+    uploads: AtomicU32,
+    coll_jobs: AtomicU32,
+    agg_shares: AtomicU32,
+    taskprov_provisions: AtomicU32,
+}
 
 impl MockAuditLog {
+    /// Number of aggregation job events.
     #[allow(dead_code)]
     pub(crate) fn invocations(&self) -> u32 {
-        self.0.load(Ordering::Relaxed)
+        self.agg_jobs.load(Ordering::Relaxed)
+    }
+
+    #[allow(dead_code)]
+    pub(crate) fn upload_invocations(&self) -> u32 {
+        self.uploads.load(Ordering::Relaxed)
+    }
+
+    #[allow(dead_code)]
+    pub(crate) fn coll_job_invocations(&self) -> u32 {
+        self.coll_jobs.load(Ordering::Relaxed)
+    }
+
+    #[allow(dead_code)]
+    pub(crate) fn agg_share_invocations(&self) -> u32 {
+        self.agg_shares.load(Ordering::Relaxed)
+    }
+
+    #[allow(dead_code)]
+    pub(crate) fn taskprov_provision_invocations(&self) -> u32 {
+        self.taskprov_provisions.load(Ordering::Relaxed)
     }
 }
 
@@ -562,17 +605,110 @@ impl AuditLog for MockAuditLog {
         _report_count: u64,
         _action: AggregationJobAuditAction,
     ) {
-        self.0.fetch_add(1, Ordering::Relaxed);
+        self.agg_jobs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_upload(
+        &self,
+        _host: &str,
+        _task_id: &TaskId,
+        _task_config: &DapTaskConfig,
+        _report_id: &ReportId,
+    ) {
+        self.uploads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_collection_job(
+        &self,
+        _host: &str,
+        _task_id: &TaskId,
+        _task_config: &DapTaskConfig,
+        _coll_job_id: Option<&CollectionJobId>,
+        _batch_sel: &BatchSelector,
+    ) {
+        self.coll_jobs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_aggregate_share(
+        &self,
+        _host: &str,
+        _task_id: &TaskId,
+        _task_config: &DapTaskConfig,
+        _batch_sel: &BatchSelector,
+        _report_count: u64,
+        _checksum: &[u8; 32],
+    ) {
+        self.agg_shares.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn on_taskprov_provision(&self, _host: &str, _task_id: &TaskId, _task_config: &DapTaskConfig) {
+        self.taskprov_provisions.fetch_add(1, Ordering::Relaxed);
     }
 }
 
+/// A clock controlled by the test. Clones of a clock share the same time.
+#[derive(Clone, Debug, Default)]
+pub struct MockClock(Arc<AtomicU64>);
+
+impl MockClock {
+    pub fn new(now: Time) -> Self {
+        Self(Arc::new(AtomicU64::new(now)))
+    }
+
+    /// Get the current time.
+    pub fn now(&self) -> Time {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Move the clock forward by `duration` seconds.
+    pub fn advance(&self, duration: Duration) {
+        self.0.fetch_add(duration, Ordering::SeqCst);
+    }
+}
+
+/// The HTTP method of a request sent by the Leader to the Helper.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerMethod {
+    Get,
+    Post,
+    Put,
+}
+
+/// Have the Helper handle a request sent by the Leader. If the Helper aborts, then the Leader gets
+/// the abort as it would have been conveyed in the problem details document of the response.
+pub(crate) async fn handle_peer_request(
+    helper: &InMemoryAggregator,
+    method: PeerMethod,
+    req: &DapRequest<BearerToken>,
+) -> Result<DapResponse, DapError> {
+    let result = match (method, req.media_type) {
+        (
+            PeerMethod::Post | PeerMethod::Put,
+            Some(DapMediaType::AggregationJobInitReq | DapMediaType::AggregationJobContinueReq),
+        ) => helper::handle_agg_job_req(helper, req).await,
+        (PeerMethod::Post, Some(DapMediaType::AggregateShareReq)) => {
+            helper::handle_agg_share_req(helper, req).await
+        }
+        (PeerMethod::Get, Some(DapMediaType::AggregationJobInitReq)) => {
+            helper::handle_agg_job_poll_req(helper, req).await
+        }
+        _ => unreachable!("unhandled request: {method:?} {:?}", req.media_type),
+    };
+    result.map_err(|e| match e {
+        DapError::Abort(abort) => DapError::from_problem_details(abort.into_problem_details()),
+        e => e,
+    })
+}
+
 #[derive(Default)]
 #[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
 pub(crate) struct AggregateStoreForCollection {
     /// The aggregate share.
     pub(crate) agg_share: DapAggregateShare,
-    /// Indication of whether the aggregate share has been collected.
-    pub(crate) collected: bool,
+    /// The number of times the aggregate share has been collected.
+    pub(crate) collection_count: u64,
+    /// The queries by which the aggregate share has been collected.
+    pub(crate) collected_by: HashSet<DapCollectionQuery>,
     /// The reports included in the aggregate share.
     pub(crate) reports: HashSet<ReportId>,
 }
@@ -614,6 +750,7 @@ pub struct InMemoryAggregator {
     pub collector_hpke_config: HpkeConfig,
     pub metrics: DaphnePromMetrics,
     pub(crate) audit_log: MockAuditLog,
+    pub report_extension_handlers: DapReportExtensionHandlers,
 
     // taskprov
     pub taskprov_vdaf_verify_key_init: [u8; 32],
@@ -623,6 +760,29 @@ pub struct InMemoryAggregator {
     // Leader: Reference to peer. Used to simulate HTTP requests from Leader to Helper, i.e.,
     // implement `DapLeader::send_http_post()` for `InMemoryAggregator`. Not set by the Helper.
     pub peer: Option<Arc<InMemoryAggregator>>,
+
+    // Leader: Aggregation jobs that the peer accepted for asynchronous processing, but has not yet
+    // processed. These are processed by the peer when the Leader waits to poll for them.
+    pending_agg_jobs: Arc<Mutex<Vec<(TaskId, MetaAggregationJobId)>>>,
+
+    // Leader: If set, the peer does not process pending aggregation jobs while the Leader waits to
+    // poll for them. Used to simulate a Helper that is slow to process aggregation jobs.
+    stall_peer_agg_jobs: AtomicBool,
+
+    // Leader: Number of subsequent HTTP requests to the peer that fail with a transient error
+    // before reaching the peer. Used to simulate an unavailable Helper.
+    peer_transient_failures: Arc<AtomicU32>,
+    agg_share_merge_failures: AtomicU32,
+
+    // Leader: Number of subsequent attempts to dead-letter a work item that fail.
+    dead_letter_failures: AtomicU32,
+
+    // Leader: If set, requests to the peer are delivered by a simulated network, which may inject
+    // faults. The network also takes the place of `peer`.
+    network: Option<Arc<SimulatedNetwork>>,
+
+    // The current time, if controlled by the test. Otherwise the system time is used.
+    clock: Option<MockClock>,
 }
 
 impl DeepSizeOf for InMemoryAggregator {
@@ -672,10 +832,18 @@ impl InMemoryAggregator {
             collector_hpke_config,
             metrics: DaphnePromMetrics::register(registry).unwrap(),
             audit_log: MockAuditLog::default(),
+            report_extension_handlers: Default::default(),
             taskprov_vdaf_verify_key_init,
             taskprov_leader_token,
             taskprov_collector_token: None,
             peer: None,
+            pending_agg_jobs: Default::default(),
+            stall_peer_agg_jobs: Default::default(),
+            peer_transient_failures: Default::default(),
+            agg_share_merge_failures: Default::default(),
+            dead_letter_failures: Default::default(),
+            network: None,
+            clock: None,
         }
     }
 
@@ -705,15 +873,123 @@ impl InMemoryAggregator {
             collector_hpke_config,
             metrics: DaphnePromMetrics::register(registry).unwrap(),
             audit_log: MockAuditLog::default(),
+            report_extension_handlers: Default::default(),
             taskprov_vdaf_verify_key_init,
             taskprov_leader_token,
             taskprov_collector_token: taskprov_collector_token.into(),
             peer: peer.into(),
+            pending_agg_jobs: Default::default(),
+            stall_peer_agg_jobs: Default::default(),
+            peer_transient_failures: Default::default(),
+            agg_share_merge_failures: Default::default(),
+            dead_letter_failures: Default::default(),
+            network: None,
+            clock: None,
         }
     }
 
+    /// Use `clock` as the source of the current time.
+    #[must_use]
+    pub fn with_clock(mut self, clock: MockClock) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Leader: Send requests to the Helper via `network`.
+    #[must_use]
+    pub(crate) fn with_network(mut self, network: Arc<SimulatedNetwork>) -> Self {
+        self.network = Some(network);
+        self
+    }
+
+    /// Simulate a restart of the aggregator: Return a new instance that has the same
+    /// configuration and storage, but that registers its metrics with `registry`.
+    pub(crate) fn restart(&self, registry: &prometheus::Registry) -> Self {
+        Self {
+            global_config: self.global_config.clone(),
+            tasks: self.tasks.clone(),
+            hpke_receiver_config_list: self.hpke_receiver_config_list.clone(),
+            leader_token: self.leader_token.clone(),
+            collector_token: self.collector_token.clone(),
+            leader_state_store: self.leader_state_store.clone(),
+            helper_state_store: self.helper_state_store.clone(),
+            agg_store: self.agg_store.clone(),
+            collector_hpke_config: self.collector_hpke_config.clone(),
+            metrics: DaphnePromMetrics::register(registry).unwrap(),
+            audit_log: MockAuditLog::default(),
+            report_extension_handlers: Default::default(),
+            taskprov_vdaf_verify_key_init: self.taskprov_vdaf_verify_key_init,
+            taskprov_leader_token: self.taskprov_leader_token.clone(),
+            taskprov_collector_token: self.taskprov_collector_token.clone(),
+            peer: self.peer.clone(),
+            // The aggregation jobs are pending at the Helper, so they outlive the Leader.
+            pending_agg_jobs: self.pending_agg_jobs.clone(),
+            stall_peer_agg_jobs: Default::default(),
+            peer_transient_failures: Default::default(),
+            agg_share_merge_failures: Default::default(),
+            dead_letter_failures: Default::default(),
+            network: self.network.clone(),
+            clock: self.clock.clone(),
+        }
+    }
+
+    /// Cause the next `count` HTTP requests to the peer to fail with a transient error.
+    pub fn fail_next_peer_requests(&self, count: u32) {
+        self.peer_transient_failures.store(count, Ordering::SeqCst);
+    }
+
+    /// Helper: Cause the next `count` attempts to merge an aggregate share span to fail with a
+    /// transient error.
+    pub fn fail_next_agg_share_merges(&self, count: u32) {
+        self.agg_share_merge_failures.store(count, Ordering::SeqCst);
+    }
+
+    /// Leader: Stop (or resume) the peer processing aggregation jobs asynchronously.
+    pub fn stall_peer_agg_jobs(&self, stall: bool) {
+        self.stall_peer_agg_jobs.store(stall, Ordering::SeqCst);
+    }
+
+    /// Leader: Cause the next `count` attempts to dead-letter a work item to fail.
+    pub fn fail_next_dead_letters(&self, count: u32) {
+        self.dead_letter_failures.store(count, Ordering::SeqCst);
+    }
+
+    fn check_peer_transient_failure(&self) -> Result<(), DapError> {
+        if self
+            .peer_transient_failures
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                count.checked_sub(1)
+            })
+            .is_ok()
+        {
+            return Err(DapError::Transient("peer unavailable (injected)".into()));
+        }
+        Ok(())
+    }
+
     fn is_leader(&self) -> bool {
-        self.peer.is_some()
+        self.peer.is_some() || self.network.is_some()
+    }
+
+    /// Leader: The Helper, as currently reachable.
+    fn current_peer(&self) -> Arc<InMemoryAggregator> {
+        match &self.network {
+            Some(network) => network.helper(),
+            None => self.peer.clone().expect("peer not configured"),
+        }
+    }
+
+    /// Leader: Send a request to the Helper.
+    async fn send_to_peer(
+        &self,
+        method: PeerMethod,
+        req: DapRequest<BearerToken>,
+    ) -> Result<DapResponse, DapError> {
+        self.check_peer_transient_failure()?;
+        match &self.network {
+            Some(network) => network.send(method, req).await,
+            None => handle_peer_request(&self.current_peer(), method, &req).await,
+        }
     }
 
     fn get_hpke_receiver_config_for(&self, hpke_config_id: u8) -> Option<&HpkeReceiverConfig> {
@@ -857,6 +1133,10 @@ impl DapAggregator<BearerToken> for InMemoryAggregator {
         &self.global_config
     }
 
+    fn report_extension_handlers(&self) -> &DapReportExtensionHandlers {
+        &self.report_extension_handlers
+    }
+
     fn taskprov_vdaf_verify_key_init(&self) -> Option<&[u8; 32]> {
         Some(&self.taskprov_vdaf_verify_key_init)
     }
@@ -893,17 +1173,20 @@ impl DapAggregator<BearerToken> for InMemoryAggregator {
     }
 
     fn get_current_time(&self) -> Time {
+        if let Some(clock) = &self.clock {
+            return clock.now();
+        }
         SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap()
             .as_secs()
     }
 
-    async fn is_batch_overlapping(
+    async fn get_batch_query_count(
         &self,
         task_id: &TaskId,
         batch_sel: &BatchSelector,
-    ) -> Result<bool, DapError> {
+    ) -> Result<u64, DapError> {
         let task_config = self
             .get_task_config_for(task_id)
             .await?
@@ -912,16 +1195,16 @@ impl DapAggregator<BearerToken> for InMemoryAggregator {
         // TODO heavy hitters: Replace this with the agg param specified by the Collector.
         let agg_param = DapAggregationParam::Empty;
 
+        let mut query_count = 0;
         for bucket in task_config.batch_span_for_sel(batch_sel)? {
-            if agg_store
-                .for_collection(task_id, &bucket, &agg_param)
-                .collected
-            {
-                return Ok(true);
-            }
+            query_count = query_count.max(
+                agg_store
+                    .for_collection(task_id, &bucket, &agg_param)
+                    .collection_count,
+            );
         }
 
-        Ok(false)
+        Ok(query_count)
     }
 
     async fn batch_exists(&self, task_id: &TaskId, batch_id: &BatchId) -> Result<bool, DapError> {
@@ -953,9 +1236,29 @@ impl DapAggregator<BearerToken> for InMemoryAggregator {
     async fn try_put_agg_share_span(
         &self,
         task_id: &TaskId,
-        _task_config: &DapTaskConfig,
+        task_config: &DapTaskConfig,
+        _agg_job_id: &MetaAggregationJobId,
         agg_agg_span: DapAggregateSpan<DapAggregateShare>,
     ) -> DapAggregateSpan<Result<(), MergeAggShareError>> {
+        if self
+            .agg_share_merge_failures
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                count.checked_sub(1)
+            })
+            .is_ok()
+        {
+            return agg_agg_span
+                .into_iter()
+                .map(|(bucket, (_agg_share_delta, report_metadatas))| {
+                    let err = DapError::Transient("aggregate store unavailable (injected)".into());
+                    (
+                        bucket,
+                        (Err(MergeAggShareError::Other(err)), report_metadatas),
+                    )
+                })
+                .collect();
+        }
+
         let mut agg_store = self.agg_store.lock().unwrap();
         // TODO heavy hitters: Replace this with the agg param specified by the Collector.
         let agg_param = DapAggregationParam::Empty;
@@ -972,12 +1275,21 @@ impl DapAggregator<BearerToken> for InMemoryAggregator {
                     .filter(|id| agg_store_for_collection.reports.contains(id))
                     .collect::<HashSet<_>>();
 
-                let result = if replayed.is_empty() {
+                let saturated = task_config.query.max_batch_size().is_some_and(|max| {
+                    agg_store_for_collection.agg_share.report_count + agg_share_delta.report_count
+                        > max
+                });
+
+                let result = if !replayed.is_empty() {
+                    Err(MergeAggShareError::ReplaysDetected(replayed))
+                } else if saturated && agg_store_for_collection.collection_count == 0 {
+                    Err(MergeAggShareError::BatchSaturated)
+                } else {
                     agg_store_for_collection
                         .reports
                         .extend(report_metadatas.iter().map(|(id, _)| *id));
                     // Add to aggregate share.
-                    if agg_store_for_collection.collected {
+                    if agg_store_for_collection.collection_count > 0 {
                         Err(MergeAggShareError::AlreadyCollected)
                     } else {
                         agg_store_for_collection
@@ -985,8 +1297,6 @@ impl DapAggregator<BearerToken> for InMemoryAggregator {
                             .merge(agg_share_delta.clone())
                             .map_err(MergeAggShareError::Other)
                     }
-                } else {
-                    Err(MergeAggShareError::ReplaysDetected(replayed))
                 };
                 (bucket, (result, report_metadatas))
             })
@@ -1011,8 +1321,12 @@ impl DapAggregator<BearerToken> for InMemoryAggregator {
         let mut agg_share = DapAggregateShare::default();
         for bucket in task_config.batch_span_for_sel(batch_sel)? {
             let agg_store_for_collection = agg_store.for_collection(task_id, &bucket, &agg_param);
-            if agg_store_for_collection.collected {
-                return Err(DapError::Abort(DapAbort::batch_overlap(task_id, batch_sel)));
+            if agg_store_for_collection.collection_count >= task_config.max_batch_query_count {
+                return Err(DapError::Abort(DapAbort::batch_queried_too_many_times(
+                    task_id,
+                    batch_sel,
+                    task_config.max_batch_query_count,
+                )));
             }
             agg_share.merge(agg_store_for_collection.agg_share.clone())?;
         }
@@ -1020,10 +1334,34 @@ impl DapAggregator<BearerToken> for InMemoryAggregator {
         Ok(agg_share)
     }
 
+    async fn get_agg_report_ids(
+        &self,
+        task_id: &TaskId,
+        batch_sel: &BatchSelector,
+    ) -> Result<HashMap<DapBatchBucket, HashSet<ReportId>>, DapError> {
+        let task_config = self.unchecked_get_task_config(task_id).await;
+        let mut agg_store = self.agg_store.lock().map_err(|e| fatal_error!(err = ?e))?;
+        // TODO heavy hitters: Replace this with the agg param specified by the Collector.
+        let agg_param = DapAggregationParam::Empty;
+
+        Ok(task_config
+            .batch_span_for_sel(batch_sel)?
+            .into_iter()
+            .map(|bucket| {
+                let reports = agg_store
+                    .for_collection(task_id, &bucket, &agg_param)
+                    .reports
+                    .clone();
+                (bucket, reports)
+            })
+            .collect())
+    }
+
     async fn mark_collected(
         &self,
         task_id: &TaskId,
         batch_sel: &BatchSelector,
+        query: &DapCollectionQuery,
     ) -> Result<(), DapError> {
         let task_config = self.unchecked_get_task_config(task_id).await;
         let mut agg_store = self.agg_store.lock().map_err(|e| fatal_error!(err = ?e))?;
@@ -1031,9 +1369,10 @@ impl DapAggregator<BearerToken> for InMemoryAggregator {
         let agg_param = DapAggregationParam::Empty;
 
         for bucket in task_config.batch_span_for_sel(batch_sel)? {
-            agg_store
-                .for_collection(task_id, &bucket, &agg_param)
-                .collected = true;
+            let agg_store_for_collection = agg_store.for_collection(task_id, &bucket, &agg_param);
+            if agg_store_for_collection.collected_by.insert(query.clone()) {
+                agg_store_for_collection.collection_count += 1;
+            }
         }
 
         Ok(())
@@ -1077,8 +1416,59 @@ impl DapHelper<BearerToken> for InMemoryAggregator {
             return Ok(false);
         }
 
-        // NOTE: This code is only correct for VDAFs with exactly one round of preparation.
-        // For VDAFs with more rounds, the helper state blob will need to be updated here.
+        helper_state_store.insert(helper_state_info, helper_state.clone());
+
+        Ok(true)
+    }
+
+    async fn put_helper_state<Id>(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: Id,
+        helper_state: &DapAggregationJobState,
+    ) -> Result<(), DapError>
+    where
+        Id: Into<MetaAggregationJobId> + Send,
+    {
+        let helper_state_info = HelperStateInfo {
+            task_id: *task_id,
+            agg_job_id_owned: agg_job_id.into(),
+        };
+
+        self.helper_state_store
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?
+            .insert(helper_state_info, helper_state.clone());
+
+        Ok(())
+    }
+
+    async fn put_helper_state_if_unchanged<Id>(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: Id,
+        current: &DapAggregationJobState,
+        helper_state: &DapAggregationJobState,
+    ) -> Result<bool, DapError>
+    where
+        Id: Into<MetaAggregationJobId> + Send,
+    {
+        let helper_state_info = HelperStateInfo {
+            task_id: *task_id,
+            agg_job_id_owned: agg_job_id.into(),
+        };
+
+        let mut helper_state_store = self
+            .helper_state_store
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?;
+
+        let current = current.get_encoded().map_err(DapError::encoding)?;
+        match helper_state_store.get(&helper_state_info) {
+            Some(state) if state.get_encoded().map_err(DapError::encoding)? == current => (),
+            _ => return Ok(false),
+        }
+
         helper_state_store.insert(helper_state_info, helper_state.clone());
 
         Ok(true)
@@ -1102,8 +1492,6 @@ impl DapHelper<BearerToken> for InMemoryAggregator {
             .lock()
             .map_err(|e| fatal_error!(err = ?e))?;
 
-        // NOTE: This code is only correct for VDAFs with exactly one round of preparation.
-        // For VDAFs with more rounds, the helper state blob will need to be updated here.
         Ok(helper_state_store.get(&helper_state_info).cloned())
     }
 }
@@ -1119,7 +1507,13 @@ impl DapLeader<BearerToken> for InMemoryAggregator {
         self.leader_state_store
             .lock()
             .map_err(|e| fatal_error!(err = ?e))?
-            .put_report(task_id, &task_config, report.clone())
+            .put_report(
+                task_id,
+                &task_config,
+                report.clone(),
+                &self.global_config.agg_job_policy,
+                self.get_current_time(),
+            )
     }
 
     async fn current_batch(&self, task_id: &TaskId) -> std::result::Result<BatchId, DapError> {
@@ -1138,7 +1532,11 @@ impl DapLeader<BearerToken> for InMemoryAggregator {
         self.leader_state_store
             .lock()
             .map_err(|e| fatal_error!(err = ?e))?
-            .dequeue_work(num_items)
+            .dequeue_work(
+                num_items,
+                &self.global_config.agg_job_policy,
+                self.get_current_time(),
+            )
     }
 
     async fn enqueue_work(&self, work_items: Vec<WorkItem>) -> Result<(), DapError> {
@@ -1153,6 +1551,41 @@ impl DapLeader<BearerToken> for InMemoryAggregator {
         Ok(())
     }
 
+    async fn dead_letter_work(
+        &self,
+        work_item: WorkItem,
+        error: &DapError,
+    ) -> Result<(), DapError> {
+        if self
+            .dead_letter_failures
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                count.checked_sub(1)
+            })
+            .is_ok()
+        {
+            return Err(fatal_error!(
+                err = "failed to dead-letter work item (injected)"
+            ));
+        }
+        self.leader_state_store
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?
+            .dead_letter_work(work_item, error);
+        Ok(())
+    }
+
+    async fn batch_has_dead_lettered_reports(
+        &self,
+        task_id: &TaskId,
+        batch_sel: &BatchSelector,
+    ) -> Result<bool, DapError> {
+        Ok(self
+            .leader_state_store
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?
+            .batch_has_dead_lettered_reports(task_id, batch_sel))
+    }
+
     // Called after receiving a CollectReq from Collector.
     async fn init_collect_job(
         &self,
@@ -1169,7 +1602,14 @@ impl DapLeader<BearerToken> for InMemoryAggregator {
         self.leader_state_store
             .lock()
             .map_err(|e| fatal_error!(err = ?e))?
-            .init_collect_job(task_id, &task_config, coll_job_id, batch_sel, agg_param)
+            .init_collect_job(
+                task_id,
+                &task_config,
+                coll_job_id,
+                batch_sel,
+                agg_param,
+                &self.global_config.agg_job_policy,
+            )
     }
 
     async fn poll_collect_job(
@@ -1200,23 +1640,7 @@ impl DapLeader<BearerToken> for InMemoryAggregator {
         req: DapRequest<BearerToken>,
         _url: Url,
     ) -> Result<DapResponse, DapError> {
-        match req.media_type {
-            Some(DapMediaType::AggregationJobInitReq | DapMediaType::AggregationJobContinueReq) => {
-                Ok(helper::handle_agg_job_req(
-                    &**self.peer.as_ref().expect("peer not configured"),
-                    &req,
-                )
-                .await
-                .expect("peer aborted unexpectedly"))
-            }
-            Some(DapMediaType::AggregateShareReq) => Ok(helper::handle_agg_share_req(
-                &**self.peer.as_ref().expect("peer not configured"),
-                &req,
-            )
-            .await
-            .expect("peer aborted unexpectedly")),
-            _ => unreachable!("unhandled media type: {:?}", req.media_type),
-        }
+        self.send_to_peer(PeerMethod::Post, req).await
     }
 
     async fn send_http_put(
@@ -1224,15 +1648,40 @@ impl DapLeader<BearerToken> for InMemoryAggregator {
         req: DapRequest<BearerToken>,
         _url: Url,
     ) -> Result<DapResponse, DapError> {
-        if req.media_type == Some(DapMediaType::AggregationJobInitReq) {
-            Ok(helper::handle_agg_job_req(
-                &**self.peer.as_ref().expect("peer not configured"),
-                &req,
-            )
-            .await
-            .expect("peer aborted unexpectedly"))
-        } else {
-            unreachable!("unhandled media type: {:?}", req.media_type)
+        let task_id = *req.task_id()?;
+        let resource = req.resource.clone();
+        let resp = self.send_to_peer(PeerMethod::Put, req).await?;
+        if resp.status == DapResponseStatus::Processing {
+            let DapResource::AggregationJob(agg_job_id) = resource else {
+                unreachable!("unhandled resource: {resource:?}")
+            };
+            self.pending_agg_jobs
+                .lock()
+                .unwrap()
+                .push((task_id, MetaAggregationJobId::Draft09(agg_job_id)));
+        }
+        Ok(resp)
+    }
+
+    async fn send_http_get(
+        &self,
+        req: DapRequest<BearerToken>,
+        _url: Url,
+    ) -> Result<DapResponse, DapError> {
+        self.send_to_peer(PeerMethod::Get, req).await
+    }
+
+    async fn wait_before_agg_job_poll(&self, _attempt: u32) {
+        // Simulate the peer processing the aggregation jobs in the background.
+        if self.stall_peer_agg_jobs.load(Ordering::SeqCst) {
+            return;
+        }
+        let pending_agg_jobs = std::mem::take(&mut *self.pending_agg_jobs.lock().unwrap());
+        let peer = self.current_peer();
+        for (task_id, agg_job_id) in pending_agg_jobs {
+            helper::process_agg_job(&*peer, &task_id, agg_job_id)
+                .await
+                .expect("peer aborted unexpectedly");
         }
     }
 }
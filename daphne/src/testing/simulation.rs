@@ -0,0 +1,843 @@
+// Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Simulation of a Leader and a Helper that communicate over an unreliable network.
+//!
+//! A [`Simulation`] uploads reports, has the Leader aggregate and collect them, and then checks
+//! that the outcome is consistent. Meanwhile, it drops, duplicates, delays and reorders the
+//! requests from the Leader to the Helper (and their responses), restarts the aggregators, and
+//! moves the clock forward. Each of these choices is made by a random number generator seeded by
+//! the caller, so the schedule of a failing run can be replayed from its seed.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+    sync::{Arc, Mutex},
+};
+
+use prio::codec::ParameterizedEncode;
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use url::Url;
+
+use crate::{
+    auth::BearerToken,
+    constants::DapMediaType,
+    hpke::{HpkeDecrypter, HpkeKemId, HpkeReceiverConfig},
+    messages::{
+        Base64Encode, BatchSelector, Collection, CollectionJobId, CollectionReq, Duration,
+        Interval, Query, ReportId, TaskId, Time,
+    },
+    roles::{leader, DapLeader},
+    vdaf::{Prio3Config, VdafConfig},
+    DapAggregateResult, DapAggregationJobPolicy, DapAggregationParam, DapCollectionJob, DapError,
+    DapGlobalConfig, DapMeasurement, DapQueryConfig, DapRequest, DapResource, DapResponse,
+    DapRetryPolicy, DapTaskConfig, DapVersion,
+};
+
+use super::{handle_peer_request, InMemoryAggregator, MockClock, PeerMethod};
+
+/// A fault injected into a request from the Leader to the Helper.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// The request is lost before it reaches the Helper.
+    DropRequest,
+
+    /// The Helper handles the request, but the response is lost.
+    DropResponse,
+
+    /// The request is delivered to the Helper twice. The Leader gets the second response.
+    Duplicate,
+
+    /// The request is held up in the network and the Leader gives up waiting for the response.
+    /// The request is delivered to the Helper later on, possibly after requests that were sent
+    /// after it.
+    Delay,
+}
+
+/// The probability with which each [`Fault`] is injected into a request.
+#[derive(Clone, Debug)]
+pub struct FaultRates {
+    pub drop_request: f64,
+    pub drop_response: f64,
+    pub duplicate: f64,
+    pub delay: f64,
+}
+
+impl FaultRates {
+    /// Deliver every request reliably.
+    pub fn none() -> Self {
+        Self {
+            drop_request: 0.0,
+            drop_response: 0.0,
+            duplicate: 0.0,
+            delay: 0.0,
+        }
+    }
+
+    fn choose(&self, rng: &mut impl Rng) -> Option<Fault> {
+        let x = rng.gen::<f64>();
+        let mut bound = 0.0;
+        for (fault, rate) in [
+            (Fault::DropRequest, self.drop_request),
+            (Fault::DropResponse, self.drop_response),
+            (Fault::Duplicate, self.duplicate),
+            (Fault::Delay, self.delay),
+        ] {
+            bound += rate;
+            if x < bound {
+                return Some(fault);
+            }
+        }
+        None
+    }
+}
+
+impl Default for FaultRates {
+    fn default() -> Self {
+        Self {
+            drop_request: 0.1,
+            drop_response: 0.1,
+            duplicate: 0.1,
+            delay: 0.1,
+        }
+    }
+}
+
+/// An event of a simulation run. The events are recorded so that a failing run can be debugged.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SimulationEvent {
+    /// The Client uploaded a number of reports to the Leader.
+    Upload { count: usize },
+
+    /// The Leader processed items from its work queue.
+    Process { num_items: usize },
+
+    /// The clock was moved forward.
+    AdvanceClock { duration: Duration },
+
+    /// The Leader was restarted.
+    RestartLeader,
+
+    /// The Helper was restarted. Requests that had not yet been delivered to it are lost.
+    RestartHelper,
+
+    /// The Leader sent a request to the Helper, with the given fault, if any.
+    Send {
+        method: PeerMethod,
+        media_type: Option<DapMediaType>,
+        fault: Option<Fault>,
+    },
+
+    /// A request that was delayed earlier was delivered to the Helper.
+    DeliverDelayed {
+        method: PeerMethod,
+        media_type: Option<DapMediaType>,
+    },
+
+    /// The network stopped injecting faults.
+    Heal,
+
+    /// The Collector asked the Leader to collect the batch.
+    Collect,
+}
+
+/// Parameters of a simulation run.
+#[derive(Clone, Debug)]
+pub struct SimulationConfig {
+    /// The DAP version of the task.
+    pub version: DapVersion,
+
+    /// Number of reports uploaded.
+    pub num_reports: usize,
+
+    /// Number of steps taken while faults are injected. Each step is an upload, a run of the
+    /// Leader's work queue, a move of the clock, or the delivery of delayed requests.
+    pub num_steps: usize,
+
+    /// The rate at which faults are injected into requests from the Leader to the Helper.
+    pub faults: FaultRates,
+
+    /// The probability that an aggregator is restarted after a step.
+    pub restart_rate: f64,
+
+    /// The maximum amount of time (in seconds) by which the clock is moved forward in a step.
+    pub max_clock_advance: Duration,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            version: DapVersion::Draft09,
+            num_reports: 50,
+            num_steps: 100,
+            faults: FaultRates::default(),
+            restart_rate: 0.02,
+            max_clock_advance: 60,
+        }
+    }
+}
+
+/// Number of times the Leader's work queue is processed after the network is healed. This bounds
+/// the run if the Leader keeps failing.
+const MAX_DRAIN_ITERATIONS: usize = 100;
+
+/// The state of the simulated network.
+struct NetworkState {
+    rng: StdRng,
+    faults: FaultRates,
+    /// Requests that are held up in the network.
+    delayed: Vec<(PeerMethod, DapRequest<BearerToken>)>,
+    events: Vec<SimulationEvent>,
+}
+
+/// The network between the Leader and the Helper. It delivers each request sent by the Leader to
+/// the Helper, subject to the faults it is configured to inject.
+pub(crate) struct SimulatedNetwork {
+    helper: Mutex<Arc<InMemoryAggregator>>,
+    state: Mutex<NetworkState>,
+}
+
+impl SimulatedNetwork {
+    fn new(helper: Arc<InMemoryAggregator>, faults: FaultRates, seed: u64) -> Self {
+        Self {
+            helper: Mutex::new(helper),
+            state: Mutex::new(NetworkState {
+                rng: StdRng::seed_from_u64(seed),
+                faults,
+                delayed: Vec::new(),
+                events: Vec::new(),
+            }),
+        }
+    }
+
+    /// The Helper, as currently running.
+    pub(crate) fn helper(&self) -> Arc<InMemoryAggregator> {
+        self.helper.lock().unwrap().clone()
+    }
+
+    fn record(&self, event: SimulationEvent) {
+        self.state.lock().unwrap().events.push(event);
+    }
+
+    /// Send a request to the Helper and wait for the response.
+    pub(crate) async fn send(
+        &self,
+        method: PeerMethod,
+        req: DapRequest<BearerToken>,
+    ) -> Result<DapResponse, DapError> {
+        // Requests that were delayed earlier may arrive before this one.
+        self.deliver_delayed().await;
+
+        let fault = {
+            let mut state = self.state.lock().unwrap();
+            let fault = {
+                let NetworkState { rng, faults, .. } = &mut *state;
+                faults.choose(rng)
+            };
+            state.events.push(SimulationEvent::Send {
+                method,
+                media_type: req.media_type,
+                fault,
+            });
+            fault
+        };
+
+        let helper = self.helper();
+        let result = match fault {
+            None => handle_peer_request(&helper, method, &req).await,
+            Some(Fault::DropRequest) => {
+                Err(DapError::Transient("request dropped (simulated)".into()))
+            }
+            Some(Fault::DropResponse) => {
+                let _ = handle_peer_request(&helper, method, &req).await;
+                Err(DapError::Transient("response dropped (simulated)".into()))
+            }
+            Some(Fault::Duplicate) => {
+                let _ = handle_peer_request(&helper, method, &req).await;
+                handle_peer_request(&helper, method, &req).await
+            }
+            Some(Fault::Delay) => {
+                self.state.lock().unwrap().delayed.push((method, req));
+                Err(DapError::Transient("request timed out (simulated)".into()))
+            }
+        };
+
+        // ... or after it.
+        self.deliver_delayed().await;
+        result
+    }
+
+    /// Deliver each delayed request with probability 1/2, in random order. The responses are
+    /// discarded, since the Leader has given up on them.
+    async fn deliver_delayed(&self) {
+        let ready = {
+            let mut state = self.state.lock().unwrap();
+            let NetworkState { rng, delayed, .. } = &mut *state;
+            let (mut ready, held): (Vec<_>, Vec<_>) =
+                delayed.drain(..).partition(|_| rng.gen_bool(0.5));
+            ready.shuffle(rng);
+            *delayed = held;
+            ready
+        };
+        self.deliver(ready).await;
+    }
+
+    async fn deliver(&self, requests: Vec<(PeerMethod, DapRequest<BearerToken>)>) {
+        for (method, req) in requests {
+            self.record(SimulationEvent::DeliverDelayed {
+                method,
+                media_type: req.media_type,
+            });
+            let _ = handle_peer_request(&self.helper(), method, &req).await;
+        }
+    }
+
+    /// Stop injecting faults and deliver all of the delayed requests.
+    async fn heal(&self) {
+        let delayed = {
+            let mut state = self.state.lock().unwrap();
+            state.faults = FaultRates::none();
+            state.events.push(SimulationEvent::Heal);
+            let NetworkState { rng, delayed, .. } = &mut *state;
+            let mut delayed = std::mem::take(delayed);
+            delayed.shuffle(rng);
+            delayed
+        };
+        self.deliver(delayed).await;
+    }
+
+    /// Replace the Helper with `helper`. Requests that have not yet been delivered are lost.
+    fn restart_helper(&self, helper: InMemoryAggregator) {
+        *self.helper.lock().unwrap() = Arc::new(helper);
+        let mut state = self.state.lock().unwrap();
+        state.delayed.clear();
+        state.events.push(SimulationEvent::RestartHelper);
+    }
+}
+
+/// A Leader and a Helper running a task while the network between them is unreliable. See the
+/// [module documentation](self).
+pub struct Simulation {
+    seed: u64,
+    config: SimulationConfig,
+    rng: StdRng,
+    clock: MockClock,
+    task_id: TaskId,
+    task_config: DapTaskConfig,
+    collector_hpke_receiver_config: HpkeReceiverConfig,
+    collector_token: BearerToken,
+    leader: InMemoryAggregator,
+    network: Arc<SimulatedNetwork>,
+
+    /// The measurement of each uploaded report.
+    measurements: HashMap<ReportId, u64>,
+
+    /// The start of the earliest batch window.
+    start: Time,
+
+    /// The batch selector and result of the collection job, once it's done.
+    collection: Option<(BatchSelector, Collection)>,
+}
+
+impl Simulation {
+    /// The VDAF of the simulated task.
+    const VDAF: VdafConfig = VdafConfig::Prio3(Prio3Config::Sum { bits: 8 });
+
+    /// Time precision of the simulated task.
+    const TIME_PRECISION: Duration = 3600;
+
+    pub fn new(seed: u64, config: SimulationConfig) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let now = 1_700_000_000;
+        let clock = MockClock::new(now);
+
+        let global_config = DapGlobalConfig {
+            max_batch_duration: 604_800,
+            min_batch_interval_start: 604_800,
+            max_batch_interval_end: 604_800,
+            supported_hpke_kems: vec![HpkeKemId::X25519HkdfSha256],
+            allow_taskprov: false,
+            helper_async_agg_jobs: false,
+            agg_job_policy: DapAggregationJobPolicy {
+                min_reports: 1,
+                max_reports: 4,
+                max_report_age: 120,
+                eager_aggregation: true,
+                max_concurrent_agg_jobs_per_task: None,
+            },
+            // Work items are retried as soon as they fail, which keeps the run independent of
+            // the random jitter added to the backoff.
+            retry_policy: DapRetryPolicy {
+                max_attempts: 20,
+                initial_backoff: 0,
+                max_backoff: 0,
+            },
+        };
+
+        let collector_hpke_receiver_config =
+            HpkeReceiverConfig::gen(rng.gen(), HpkeKemId::X25519HkdfSha256)
+                .expect("failed to generate HPKE receiver config");
+        let task_id = TaskId(rng.gen());
+        let task_config = DapTaskConfig {
+            version: config.version,
+            leader_url: Url::parse("https://leader.example.com/").unwrap(),
+            helper_url: Url::parse("https://helper.example.com/").unwrap(),
+            time_precision: Self::TIME_PRECISION,
+            expiration: now + 604_800,
+            min_batch_size: 1,
+            query: DapQueryConfig::TimeInterval,
+            max_batch_query_count: 1,
+            vdaf: Self::VDAF,
+            vdaf_verify_key: Self::VDAF.gen_verify_key().unwrap(),
+            collector_hpke_config: collector_hpke_receiver_config.config.clone(),
+            method: Default::default(),
+        };
+        let tasks = [(task_id, task_config.clone())];
+        let leader_token = BearerToken::from("leader_token");
+        let collector_token = BearerToken::from("collector_token");
+        let taskprov_vdaf_verify_key_init = rng.gen();
+
+        let helper = InMemoryAggregator::new_helper(
+            tasks.clone(),
+            global_config
+                .gen_hpke_receiver_config_list(rng.gen())
+                .expect("failed to generate HPKE receiver config"),
+            global_config.clone(),
+            leader_token.clone(),
+            collector_hpke_receiver_config.config.clone(),
+            &prometheus::Registry::new(),
+            taskprov_vdaf_verify_key_init,
+            leader_token.clone(),
+        )
+        .with_clock(clock.clone());
+        let network = Arc::new(SimulatedNetwork::new(
+            Arc::new(helper),
+            config.faults.clone(),
+            rng.gen(),
+        ));
+        let leader = InMemoryAggregator::new_leader(
+            tasks,
+            global_config
+                .gen_hpke_receiver_config_list(rng.gen())
+                .expect("failed to generate HPKE receiver config"),
+            global_config.clone(),
+            leader_token.clone(),
+            collector_token.clone(),
+            collector_hpke_receiver_config.config.clone(),
+            &prometheus::Registry::new(),
+            taskprov_vdaf_verify_key_init,
+            leader_token,
+            collector_token.clone(),
+            None,
+        )
+        .with_clock(clock.clone())
+        .with_network(network.clone());
+
+        Self {
+            seed,
+            config,
+            rng,
+            start: task_config.quantized_time_lower_bound(now),
+            clock,
+            task_id,
+            task_config,
+            collector_hpke_receiver_config,
+            collector_token,
+            leader,
+            network,
+            measurements: HashMap::new(),
+            collection: None,
+        }
+    }
+
+    /// The events of the run so far.
+    pub fn events(&self) -> Vec<SimulationEvent> {
+        self.network.state.lock().unwrap().events.clone()
+    }
+
+    /// The aggregate result, if the batch was collected.
+    pub async fn aggregate_result(&self) -> Option<DapAggregateResult> {
+        let (batch_sel, collection) = self.collection.as_ref()?;
+        Some(
+            self.task_config
+                .vdaf
+                .consume_encrypted_agg_shares(
+                    &self.collector_hpke_receiver_config,
+                    &self.task_id,
+                    batch_sel,
+                    collection.report_count,
+                    &DapAggregationParam::Empty,
+                    collection.encrypted_agg_shares.to_vec(),
+                    self.task_config.version,
+                )
+                .await
+                .unwrap_or_else(|e| self.fail(&format!("failed to unshard the result: {e}"))),
+        )
+    }
+
+    /// Run the simulation. While faults are injected, take the configured number of steps, each
+    /// followed by a restart of the Leader or Helper at random. Then heal the network, upload the
+    /// remaining reports, drain the Leader's work queue and collect the batch.
+    ///
+    /// The run does not fail if the batch could not be collected; see
+    /// [`aggregate_result`](Self::aggregate_result).
+    pub async fn run(&mut self) {
+        for _ in 0..self.config.num_steps {
+            match self.rng.gen_range(0..4) {
+                0 => {
+                    let count = self.rng.gen_range(1..=3);
+                    self.upload(count).await;
+                }
+                1 => {
+                    let num_items = self.rng.gen_range(1..=4);
+                    self.process(num_items).await;
+                }
+                2 => {
+                    let duration = self.rng.gen_range(0..=self.config.max_clock_advance);
+                    self.advance_clock(duration);
+                }
+                _ => self.network.deliver_delayed().await,
+            }
+
+            if self.rng.gen_bool(self.config.restart_rate) {
+                if self.rng.gen() {
+                    self.restart_leader();
+                } else {
+                    self.restart_helper();
+                }
+            }
+        }
+
+        self.network.heal().await;
+        self.upload(self.config.num_reports).await;
+
+        // Wait long enough for each pending report to be put in an aggregation job.
+        self.advance_clock(self.leader.global_config.agg_job_policy.max_report_age);
+        self.drain().await;
+
+        self.collect().await;
+        self.drain().await;
+    }
+
+    /// Check that the outcome of the run is consistent:
+    ///
+    /// * Only uploaded reports are aggregated, and each at most once.
+    /// * The Leader and Helper have aggregated the same reports into each batch bucket.
+    /// * If the batch was collected, then the result is the sum of the measurements of the reports
+    ///   aggregated into it.
+    ///
+    /// If not, return a description of each violation.
+    pub async fn check_invariants(&self) -> Result<(), String> {
+        let mut violations = Vec::new();
+
+        let result = self.aggregate_result().await;
+        let helper = self.network.helper();
+        let leader_store = self.leader.agg_store.lock().unwrap();
+        let helper_store = helper.agg_store.lock().unwrap();
+        for (role, store) in [("Leader", &*leader_store), ("Helper", &*helper_store)] {
+            let mut aggregated = HashSet::new();
+            for (name, bucket) in &store.0 {
+                for report_id in &bucket.reports {
+                    if !self.measurements.contains_key(report_id) {
+                        violations.push(format!(
+                            "{role}: {name}: report {report_id} was never uploaded"
+                        ));
+                    }
+                    if !aggregated.insert(*report_id) {
+                        violations.push(format!(
+                            "{role}: report {report_id} was aggregated into more than one bucket"
+                        ));
+                    }
+                }
+
+                // Reports are recorded even if they arrive after the bucket was collected, in
+                // which case they are not aggregated.
+                let report_count = usize::try_from(bucket.agg_share.report_count).unwrap();
+                if report_count > bucket.reports.len()
+                    || (bucket.collection_count == 0 && report_count != bucket.reports.len())
+                {
+                    violations.push(format!(
+                        "{role}: {name}: aggregate share covers {report_count} reports, but {} were aggregated",
+                        bucket.reports.len(),
+                    ));
+                }
+            }
+        }
+
+        let buckets = leader_store
+            .0
+            .keys()
+            .chain(helper_store.0.keys())
+            .collect::<HashSet<_>>();
+        for name in buckets {
+            let (Some(leader_bucket), Some(helper_bucket)) =
+                (leader_store.0.get(name), helper_store.0.get(name))
+            else {
+                violations.push(format!("{name}: bucket is only known to one aggregator"));
+                continue;
+            };
+            if leader_bucket.reports != helper_bucket.reports
+                || leader_bucket.agg_share.report_count != helper_bucket.agg_share.report_count
+                || leader_bucket.agg_share.checksum != helper_bucket.agg_share.checksum
+            {
+                violations.push(format!(
+                    "{name}: the Leader aggregated {} reports and the Helper aggregated {}",
+                    leader_bucket.agg_share.report_count, helper_bucket.agg_share.report_count,
+                ));
+            }
+        }
+
+        if let Some((batch_sel, collection)) = &self.collection {
+            let mut report_count = 0;
+            let mut sum = 0;
+            for bucket in self.task_config.batch_span_for_sel(batch_sel).unwrap() {
+                let name = format!("{}/{bucket}/0", self.task_id);
+                if let Some(bucket) = leader_store.0.get(&name) {
+                    report_count += bucket.agg_share.report_count;
+                    sum += bucket
+                        .reports
+                        .iter()
+                        .filter_map(|report_id| self.measurements.get(report_id))
+                        .sum::<u64>();
+                }
+            }
+
+            if collection.report_count != report_count {
+                violations.push(format!(
+                    "collection covers {} reports, but {report_count} were aggregated",
+                    collection.report_count
+                ));
+            }
+            if result != Some(DapAggregateResult::U128(u128::from(sum))) {
+                violations.push(format!(
+                    "collected {result:?}, but the aggregated measurements sum to {sum}"
+                ));
+            }
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            let mut message = format!("simulation with seed {} failed:\n", self.seed);
+            for violation in violations {
+                writeln!(message, "  {violation}").unwrap();
+            }
+            Err(message)
+        }
+    }
+
+    fn fail(&self, msg: &str) -> ! {
+        panic!(
+            "simulation with seed {} failed: {msg}\nevents: {:#?}",
+            self.seed,
+            self.events()
+        )
+    }
+
+    /// Upload up to `count` reports with random measurements.
+    async fn upload(&mut self, count: usize) {
+        let count = count.min(self.config.num_reports - self.measurements.len());
+        if count == 0 {
+            return;
+        }
+        self.network.record(SimulationEvent::Upload { count });
+
+        let hpke_config_list = [
+            self.leader
+                .get_hpke_config_for(self.task_config.version, Some(&self.task_id))
+                .await
+                .unwrap()
+                .clone(),
+            self.network
+                .helper()
+                .get_hpke_config_for(self.task_config.version, Some(&self.task_id))
+                .await
+                .unwrap()
+                .clone(),
+        ];
+        for _ in 0..count {
+            let measurement = self.rng.gen_range(0..256);
+            let report = self
+                .task_config
+                .vdaf
+                .produce_report(
+                    &hpke_config_list,
+                    self.clock.now(),
+                    &self.task_id,
+                    DapMeasurement::U64(measurement),
+                    self.task_config.version,
+                )
+                .unwrap();
+            self.measurements
+                .insert(report.report_metadata.id, measurement);
+
+            let req = DapRequest {
+                version: self.task_config.version,
+                media_type: Some(DapMediaType::Report),
+                task_id: Some(self.task_id),
+                resource: DapResource::Undefined,
+                payload: report
+                    .get_encoded_with_param(&self.task_config.version)
+                    .unwrap(),
+                sender_auth: None,
+                taskprov: None,
+            };
+            if let Err(e) = leader::handle_upload_req(&self.leader, &req).await {
+                self.fail(&format!("upload failed: {e}"));
+            }
+        }
+    }
+
+    async fn process(&mut self, num_items: usize) {
+        self.network.record(SimulationEvent::Process { num_items });
+        if let Err(e) = leader::process(&self.leader, "leader.example.com", num_items).await {
+            self.fail(&format!("processing failed: {e}"));
+        }
+    }
+
+    fn advance_clock(&mut self, duration: Duration) {
+        self.network
+            .record(SimulationEvent::AdvanceClock { duration });
+        self.clock.advance(duration);
+    }
+
+    fn restart_leader(&mut self) {
+        self.network.record(SimulationEvent::RestartLeader);
+        self.leader = self.leader.restart(&prometheus::Registry::new());
+    }
+
+    fn restart_helper(&mut self) {
+        let helper = self.network.helper().restart(&prometheus::Registry::new());
+        self.network.restart_helper(helper);
+    }
+
+    /// Process the Leader's work queue until it is empty.
+    async fn drain(&mut self) {
+        for _ in 0..MAX_DRAIN_ITERATIONS {
+            let queued = self
+                .leader
+                .leader_state_store
+                .lock()
+                .unwrap()
+                .work_queue()
+                .len();
+            if queued == 0 {
+                break;
+            }
+            self.process(queued).await;
+        }
+    }
+
+    /// Ask the Leader to collect every batch window in which reports were uploaded.
+    async fn collect(&mut self) {
+        self.network.record(SimulationEvent::Collect);
+        let end = self
+            .task_config
+            .quantized_time_upper_bound(self.clock.now());
+        let batch_interval = Interval {
+            start: self.start,
+            duration: end - self.start,
+        };
+        let req = DapRequest {
+            version: self.task_config.version,
+            media_type: Some(DapMediaType::CollectReq),
+            task_id: Some(self.task_id),
+            resource: if self.task_config.version == DapVersion::Draft02 {
+                DapResource::Undefined
+            } else {
+                DapResource::CollectionJob(CollectionJobId(self.rng.gen()))
+            },
+            payload: CollectionReq {
+                draft02_task_id: self.task_id.for_request_payload(&self.task_config.version),
+                query: Query::TimeInterval {
+                    batch_interval: batch_interval.clone(),
+                },
+                agg_param: Vec::new(),
+            }
+            .get_encoded_with_param(&self.task_config.version)
+            .unwrap(),
+            sender_auth: Some(self.collector_token.clone()),
+            taskprov: None,
+        };
+        let coll_job_uri = leader::handle_coll_job_req(&self.leader, &req)
+            .await
+            .unwrap_or_else(|e| self.fail(&format!("collection request failed: {e}")));
+        let coll_job_id = coll_job_uri
+            .path_segments()
+            .and_then(Iterator::last)
+            .and_then(CollectionJobId::try_from_base64url)
+            .unwrap_or_else(|| self.fail("unexpected collection job URI"));
+
+        self.drain().await;
+
+        if let DapCollectionJob::Done(collection) = self
+            .leader
+            .poll_collect_job(&self.task_id, &coll_job_id)
+            .await
+            .unwrap_or_else(|e| self.fail(&format!("polling the collection job failed: {e}")))
+        {
+            self.collection = Some((BatchSelector::TimeInterval { batch_interval }, collection));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{async_test_version, async_test_versions, DapAggregateResult, DapVersion};
+
+    use super::{FaultRates, Simulation, SimulationConfig};
+
+    async fn reliable_network(version: DapVersion) {
+        let mut sim = Simulation::new(
+            0,
+            SimulationConfig {
+                version,
+                faults: FaultRates::none(),
+                restart_rate: 0.0,
+                ..Default::default()
+            },
+        );
+        sim.run().await;
+        sim.check_invariants().await.unwrap();
+
+        let sum = sim.measurements.values().sum::<u64>();
+        assert_eq!(
+            sim.aggregate_result().await,
+            Some(DapAggregateResult::U128(u128::from(sum)))
+        );
+    }
+
+    async_test_versions! { reliable_network }
+
+    async fn faulty_network(version: DapVersion) {
+        for seed in 0..10 {
+            let mut sim = Simulation::new(
+                seed,
+                SimulationConfig {
+                    version,
+                    ..Default::default()
+                },
+            );
+            sim.run().await;
+            if let Err(e) = sim.check_invariants().await {
+                panic!("{e}\nevents: {:#?}", sim.events());
+            }
+        }
+    }
+
+    // In draft02, AggregationJobContinueReq does not carry the round number, so the Helper cannot
+    // tell a retried request from a new one. If the response to a continuation is lost, then the
+    // Helper commits the reports while the Leader abandons the aggregation job.
+    async_test_version! { faulty_network, Draft09 }
+    async_test_version! { faulty_network, Latest }
+
+    #[tokio::test]
+    async fn same_seed_same_faults() {
+        let events = || async {
+            let mut sim = Simulation::new(1337, SimulationConfig::default());
+            sim.run().await;
+            sim.events()
+        };
+        assert_eq!(events().await, events().await);
+    }
+}
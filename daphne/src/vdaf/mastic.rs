@@ -1,25 +1,31 @@
 // Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
-//! Dummy Mastic [[draft-mouris-cfrg-mastic]], a 2-party, 1-round VDAF for (weighted) heavy hitters
-//! and attribute-based metrics. This module implements an insecure, "dummy" version of Mastic
-//! intended for testing and prototyping heavy hitters in daphne. Eventually it will be replaced by
-//! a production-quality implementation.
+//! Dummy Mastic [[draft-mouris-cfrg-mastic]], a 2-party VDAF for (weighted) heavy hitters and
+//! attribute-based metrics. This module implements an insecure, "dummy" version of Mastic intended
+//! for testing and prototyping heavy hitters in daphne. Eventually it will be replaced by a
+//! production-quality implementation.
+//!
+//! Like Poplar1, the dummy takes two rounds of preparation: in the first round the Aggregators
+//! check that they got the same weight; in the second they check that the weight is in range. This
+//! allows us to exercise multi-round aggregation jobs.
 //!
 //! [draft-mouris-cfrg-mastic]: https://datatracker.ietf.org/doc/draft-mouris-cfrg-mastic/
 
 use crate::{fatal_error, DapAggregateResult, DapAggregationParam, DapMeasurement};
 
 use super::{
-    decode_field_vec, VdafAggregateShare, VdafError, VdafPrepMessage, VdafPrepState, VdafVerifyKey,
+    decode_field_vec, DapVdaf, VdafAggregateShare, VdafError, VdafPrepMessage, VdafPrepState,
+    VdafPrepTransition, VdafVerifyKey,
 };
 
 use prio::{
-    codec::Decode,
+    codec::{decode_u32_items, CodecError, Decode, Encode},
     field::{Field64, FieldElement},
     vdaf::AggregateShare,
 };
 use serde::{Deserialize, Serialize};
+use std::io::Cursor;
 
 #[derive(
     Clone,
@@ -55,7 +61,7 @@ pub enum MasticWeight {
     Bool(bool),
 }
 
-pub(crate) fn mastic_shard(
+fn mastic_shard(
     input_size: usize,
     weight_config: MasticWeightConfig,
     measurement: DapMeasurement,
@@ -78,7 +84,7 @@ pub(crate) fn mastic_shard(
     }
 }
 
-pub(crate) fn mastic_prep_init(
+fn mastic_prep_init(
     input_size: usize,
     weight_config: MasticWeightConfig,
     verify_key: &VdafVerifyKey,
@@ -134,7 +140,10 @@ pub(crate) fn mastic_prep_init(
                 .collect::<Result<Vec<Field64>, _>>()?;
 
             Ok((
-                VdafPrepState::Mastic { out_share },
+                VdafPrepState::Mastic {
+                    round: 0,
+                    out_share,
+                },
                 VdafPrepMessage::MasticShare(weight),
             ))
         }
@@ -144,20 +153,20 @@ pub(crate) fn mastic_prep_init(
     }
 }
 
-pub(crate) fn mastic_prep_finish_from_shares(
+fn mastic_prep_next_from_shares(
     weight_config: MasticWeightConfig,
     host_state: VdafPrepState,
     host_share: VdafPrepMessage,
     peer_share_bytes: &[u8],
-) -> Result<(VdafAggregateShare, Vec<u8>), VdafError> {
-    match (weight_config, host_state, host_share) {
+) -> Result<(VdafPrepTransition, Vec<u8>), VdafError> {
+    let prep_msg = match (weight_config, &host_state, host_share) {
         (
             MasticWeightConfig::Count,
-            VdafPrepState::Mastic { out_share },
+            VdafPrepState::Mastic { round: 0, .. },
             VdafPrepMessage::MasticShare(host_weight),
         ) => {
-            // Simulate Mastic. Check that both Aggregators got the same weight, and the weight is
-            // valid. This is not secure because the weight is revealed to the caller.
+            // Simulate Mastic. Check that both Aggregators got the same weight. This is not secure
+            // because the weight is revealed to the caller.
             let peer_weight = Field64::get_decoded(peer_share_bytes)?;
             if peer_weight != host_weight {
                 return Err(VdafError::Vdaf(prio::vdaf::VdafError::Uncategorized(
@@ -165,30 +174,62 @@ pub(crate) fn mastic_prep_finish_from_shares(
                 )));
             }
 
-            if peer_weight != Field64::one() && peer_weight != Field64::zero() {
+            // The prep message is the weight.
+            host_weight.get_encoded()?
+        }
+        (
+            MasticWeightConfig::Count,
+            VdafPrepState::Mastic { round: 1, .. },
+            VdafPrepMessage::MasticShare(host_weight),
+        ) => {
+            // Simulate Mastic. Check that the weight is valid.
+            let peer_weight = Field64::get_decoded(peer_share_bytes)?;
+            if peer_weight != host_weight
+                || (peer_weight != Field64::one() && peer_weight != Field64::zero())
+            {
                 return Err(VdafError::Vdaf(prio::vdaf::VdafError::Uncategorized(
                     "mastic: weight is out of range".into(),
                 )));
             }
 
-            Ok((
-                VdafAggregateShare::Field64(AggregateShare::from(out_share)),
-                // Empty prep message for now.
-                Vec::new(),
-            ))
+            // Empty prep message for now.
+            Vec::new()
         }
-        _ => Err(VdafError::Dap(fatal_error!(
-            err = "mastic: unexpected prep state"
-        ))),
-    }
+        _ => {
+            return Err(VdafError::Dap(fatal_error!(
+                err = "mastic: unexpected prep state"
+            )))
+        }
+    };
+
+    let transition = mastic_prep_next(host_state, &prep_msg)?;
+    Ok((transition, prep_msg))
 }
 
-pub(crate) fn mastic_prep_finish(
+fn mastic_prep_next(
     host_state: VdafPrepState,
     peer_message_bytes: &[u8],
-) -> Result<VdafAggregateShare, VdafError> {
+) -> Result<VdafPrepTransition, VdafError> {
     match host_state {
-        VdafPrepState::Mastic { out_share } => {
+        VdafPrepState::Mastic {
+            round: 0,
+            out_share,
+        } => {
+            // Simulate Mastic: The prep message is the weight the Aggregators agreed on. Move on
+            // to the range check.
+            let weight = Field64::get_decoded(peer_message_bytes)?;
+            Ok(VdafPrepTransition::Continue(
+                VdafPrepState::Mastic {
+                    round: 1,
+                    out_share,
+                },
+                VdafPrepMessage::MasticShare(weight),
+            ))
+        }
+        VdafPrepState::Mastic {
+            round: 1,
+            out_share,
+        } => {
             // Simulate Mastic: If the prep message is empty, then accept the output share.
             if !peer_message_bytes.is_empty() {
                 return Err(VdafError::Vdaf(prio::vdaf::VdafError::Uncategorized(
@@ -196,7 +237,9 @@ pub(crate) fn mastic_prep_finish(
                 )));
             }
 
-            Ok(VdafAggregateShare::Field64(AggregateShare::from(out_share)))
+            Ok(VdafPrepTransition::Finish(VdafAggregateShare::Field64(
+                AggregateShare::from(out_share),
+            )))
         }
         _ => Err(VdafError::Dap(fatal_error!(
             err = "mastic: unexpected prep state"
@@ -204,7 +247,7 @@ pub(crate) fn mastic_prep_finish(
     }
 }
 
-pub(crate) fn mastic_unshard<M: IntoIterator<Item = Vec<u8>>>(
+fn mastic_unshard<M: IntoIterator<Item = Vec<u8>>>(
     weight_config: MasticWeightConfig,
     agg_param: &DapAggregationParam,
     agg_share_bytes: M,
@@ -237,6 +280,102 @@ pub(crate) fn mastic_unshard<M: IntoIterator<Item = Vec<u8>>>(
     }
 }
 
+/// The dummy Mastic VDAF.
+pub(crate) struct MasticVdaf {
+    pub(crate) input_size: usize,
+    pub(crate) weight_config: MasticWeightConfig,
+}
+
+impl DapVdaf for MasticVdaf {
+    fn name(&self) -> &'static str {
+        "mastic"
+    }
+
+    fn codepoint(&self) -> Option<u32> {
+        // The dummy is not advertised in taskprov.
+        None
+    }
+
+    fn verify_key_len(&self) -> usize {
+        16
+    }
+
+    fn is_valid_agg_param(&self, _agg_param: &[u8]) -> bool {
+        // TODO(cjpatton) Implement agg param validation once we know what we need. In the
+        // meantime, permit everything.
+        true
+    }
+
+    fn uses_agg_param(&self) -> bool {
+        true
+    }
+
+    fn shard(
+        &self,
+        measurement: DapMeasurement,
+        _nonce: &[u8; 16],
+    ) -> Result<(Vec<u8>, Vec<Vec<u8>>), VdafError> {
+        mastic_shard(self.input_size, self.weight_config, measurement)
+    }
+
+    fn prep_init(
+        &self,
+        verify_key: &VdafVerifyKey,
+        _agg_id: usize,
+        agg_param: &DapAggregationParam,
+        _nonce: &[u8; 16],
+        public_share_data: &[u8],
+        input_share_data: &[u8],
+    ) -> Result<(VdafPrepState, VdafPrepMessage), VdafError> {
+        mastic_prep_init(
+            self.input_size,
+            self.weight_config,
+            verify_key,
+            agg_param,
+            public_share_data,
+            input_share_data,
+        )
+    }
+
+    fn prep_next_from_shares(
+        &self,
+        _agg_id: usize,
+        host_state: VdafPrepState,
+        host_share: VdafPrepMessage,
+        peer_share_data: &[u8],
+    ) -> Result<(VdafPrepTransition, Vec<u8>), VdafError> {
+        mastic_prep_next_from_shares(self.weight_config, host_state, host_share, peer_share_data)
+    }
+
+    fn prep_next(
+        &self,
+        host_state: VdafPrepState,
+        peer_message_data: &[u8],
+    ) -> Result<VdafPrepTransition, VdafError> {
+        mastic_prep_next(host_state, peer_message_data)
+    }
+
+    fn decode_prep_state(
+        &self,
+        _agg_id: usize,
+        bytes: &mut Cursor<&[u8]>,
+    ) -> Result<VdafPrepState, CodecError> {
+        Ok(VdafPrepState::Mastic {
+            round: u8::decode(bytes)?,
+            out_share: decode_u32_items(&(), bytes)?,
+        })
+    }
+
+    fn unshard(
+        &self,
+        agg_param: &DapAggregationParam,
+        _num_measurements: usize,
+        agg_shares: Vec<Vec<u8>>,
+    ) -> Result<DapAggregateResult, VdafError> {
+        mastic_unshard(self.weight_config, agg_param, agg_shares)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use prio::{idpf::IdpfInput, vdaf::poplar1::Poplar1AggregationParam};
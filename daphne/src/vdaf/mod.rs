@@ -8,16 +8,15 @@
 pub(crate) mod mastic;
 pub(crate) mod prio2;
 pub(crate) mod prio3;
+mod registry;
 
-use crate::{
-    error::DapAbort,
-    vdaf::{prio2::prio2_decode_prep_state, prio3::prio3_decode_prep_state},
-    DapError,
-};
 #[cfg(any(test, feature = "test-utils"))]
-use prio::field::FieldElement;
+use crate::vdaf::mastic::MasticVdaf;
+use crate::{error::DapAbort, fatal_error, vdaf::prio2::Prio2Vdaf, DapError};
+#[cfg(any(test, feature = "test-utils"))]
+use prio::{codec::Decode, field::FieldElement};
 use prio::{
-    codec::{CodecError, Encode, ParameterizedDecode},
+    codec::{encode_u32_items, CodecError, Encode, ParameterizedDecode},
     field::{Field128, Field64, FieldPrio2},
     vdaf::{
         prio2::{Prio2PrepareShare, Prio2PrepareState},
@@ -27,14 +26,18 @@ use prio::{
 use rand::prelude::*;
 use ring::hkdf::KeyType;
 use serde::{Deserialize, Serialize};
-#[cfg(any(test, feature = "test-utils"))]
-use std::io::Read;
+use std::{io::Read, sync::Arc};
 
 #[cfg(any(test, feature = "test-utils"))]
-pub use self::mastic::MasticWeightConfig;
+pub use self::mastic::{MasticWeight, MasticWeightConfig};
+pub use self::registry::{register_vdaf, registered_vdaf, registered_vdaf_by_name, DapVdaf};
 
+/// An error encountered while executing a VDAF.
+///
+/// Codec and VDAF errors cause the report to be rejected; [`VdafError::Dap`] indicates that the
+/// VDAF is misconfigured.
 #[derive(Debug, thiserror::Error)]
-pub(crate) enum VdafError {
+pub enum VdafError {
     #[error("{0}")]
     Codec(#[from] CodecError),
     #[error("{0}")]
@@ -60,13 +63,18 @@ pub enum VdafConfig {
         /// The type of each weight.
         weight_config: MasticWeightConfig,
     },
+    /// A VDAF provided by the application and registered with [`register_vdaf`].
+    Registered {
+        /// The VDAF's taskprov codepoint.
+        codepoint: u32,
+    },
 }
 
 impl std::str::FromStr for VdafConfig {
     type Err = serde_json::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        serde_json::from_str(s)
+        serde_json::from_str(s).or_else(|e| Self::from_registered_name(s).ok_or(e))
     }
 }
 
@@ -80,6 +88,10 @@ impl std::fmt::Display for VdafConfig {
                 input_size,
                 weight_config,
             } => write!(f, "Mastic({input_size}, {weight_config})"),
+            VdafConfig::Registered { codepoint } => match registered_vdaf(*codepoint) {
+                Some(vdaf) => write!(f, "Registered({})", vdaf.name()),
+                None => write!(f, "Registered({codepoint:#010x})"),
+            },
         }
     }
 }
@@ -195,8 +207,11 @@ pub enum VdafPrepState {
     Prio3Field128(Prio3PrepareState<Field128, 16>),
     #[cfg(any(test, feature = "test-utils"))]
     Mastic {
+        round: u8,
         out_share: Vec<Field64>,
     },
+    /// The encoded state of a VDAF implemented outside of this crate.
+    Opaque(Vec<u8>),
 }
 
 #[cfg(any(test, feature = "test-utils"))]
@@ -213,6 +228,7 @@ impl deepsize::DeepSizeOf for VdafPrepState {
             | Self::Prio3Field128(_) => 0,
             #[cfg(any(test, feature = "test-utils"))]
             Self::Mastic { .. } => 0,
+            Self::Opaque(state) => state.len(),
         }
     }
 }
@@ -225,7 +241,11 @@ impl Encode for VdafPrepState {
             Self::Prio3Field128(state) => state.encode(bytes),
             Self::Prio2(state) => state.encode(bytes),
             #[cfg(any(test, feature = "test-utils"))]
-            Self::Mastic { .. } => todo!("mastic: decoding of prep state is not implemented"),
+            Self::Mastic { round, out_share } => {
+                round.encode(bytes)?;
+                encode_u32_items(bytes, &(), out_share)
+            }
+            Self::Opaque(state) => encode_u32_items(bytes, &(), state),
         }
     }
 }
@@ -236,18 +256,10 @@ impl<'a> ParameterizedDecode<(&'a VdafConfig, bool /* is_leader */)> for VdafPre
         bytes: &mut std::io::Cursor<&[u8]>,
     ) -> Result<Self, CodecError> {
         let agg_id = usize::from(!is_leader);
-        match vdaf_config {
-            VdafConfig::Prio3(ref prio3_config) => {
-                Ok(prio3_decode_prep_state(prio3_config, agg_id, bytes)
-                    .map_err(|e| CodecError::Other(Box::new(e)))?)
-            }
-            VdafConfig::Prio2 { dimension } => {
-                Ok(prio2_decode_prep_state(*dimension, agg_id, bytes)
-                    .map_err(|e| CodecError::Other(Box::new(e)))?)
-            }
-            #[cfg(any(test, feature = "test-utils"))]
-            VdafConfig::Mastic { .. } => todo!("mastic: decoding of prep state is not implemented"),
-        }
+        vdaf_config
+            .dap_vdaf()
+            .map_err(|e| CodecError::Other(Box::new(e)))?
+            .decode_prep_state(agg_id, bytes)
     }
 }
 
@@ -261,6 +273,8 @@ pub enum VdafPrepMessage {
     Prio3ShareField128(Prio3PrepareShare<Field128, 16>),
     #[cfg(any(test, feature = "test-utils"))]
     MasticShare(Field64),
+    /// The encoded prep share of a VDAF implemented outside of this crate.
+    Opaque(Vec<u8>),
 }
 
 #[cfg(any(test, feature = "test-utils"))]
@@ -279,6 +293,7 @@ impl deepsize::DeepSizeOf for VdafPrepMessage {
             | Self::Prio3ShareField128(..) => 0,
             #[cfg(any(test, feature = "test-utils"))]
             Self::MasticShare(..) => 0,
+            Self::Opaque(share) => share.len(),
         }
     }
 }
@@ -292,6 +307,10 @@ impl Encode for VdafPrepMessage {
             Self::Prio2Share(share) => share.encode(bytes),
             #[cfg(any(test, feature = "test-utils"))]
             Self::MasticShare(weight) => weight.encode(bytes),
+            Self::Opaque(share) => {
+                bytes.extend_from_slice(share);
+                Ok(())
+            }
         }
     }
 }
@@ -318,12 +337,27 @@ impl ParameterizedDecode<VdafPrepState> for VdafPrepMessage {
             )),
             #[cfg(any(test, feature = "test-utils"))]
             VdafPrepState::Mastic { .. } => {
-                todo!("mastic: decoding of prep messages is not implemented")
+                Ok(VdafPrepMessage::MasticShare(Field64::decode(bytes)?))
+            }
+            VdafPrepState::Opaque(..) => {
+                let mut share = Vec::new();
+                bytes.read_to_end(&mut share)?;
+                Ok(VdafPrepMessage::Opaque(share))
             }
         }
     }
 }
 
+/// The outcome of a step of VDAF preparation.
+#[cfg_attr(any(test, feature = "test-utils"), derive(Debug))]
+pub enum VdafPrepTransition {
+    /// Preparation continues with the given prep state and prep share for the next round.
+    Continue(VdafPrepState, VdafPrepMessage),
+
+    /// Preparation is complete. This is the Aggregator's output share.
+    Finish(VdafAggregateShare),
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum VdafAggregateShare {
@@ -354,31 +388,56 @@ impl Encode for VdafAggregateShare {
 }
 
 impl VdafConfig {
-    pub(crate) fn uninitialized_verify_key(&self) -> VdafVerifyKey {
+    /// Resolve the implementation of this VDAF.
+    ///
+    /// This fails if the VDAF is [`VdafConfig::Registered`] but no VDAF with its codepoint has
+    /// been registered.
+    pub fn dap_vdaf(&self) -> Result<Arc<dyn DapVdaf>, DapError> {
         match self {
-            Self::Prio3(Prio3Config::SumVecField64MultiproofHmacSha256Aes128 { .. })
-            | Self::Prio2 { .. } => VdafVerifyKey::L32([0; 32]),
-            Self::Prio3(..) => VdafVerifyKey::L16([0; 16]),
+            Self::Prio3(prio3_config) => Ok(Arc::new(*prio3_config)),
+            Self::Prio2 { dimension } => Ok(Arc::new(Prio2Vdaf {
+                dimension: *dimension,
+            })),
             #[cfg(any(test, feature = "test-utils"))]
-            Self::Mastic { .. } => VdafVerifyKey::L16([0; 16]),
+            Self::Mastic {
+                input_size,
+                weight_config,
+            } => Ok(Arc::new(MasticVdaf {
+                input_size: *input_size,
+                weight_config: *weight_config,
+            })),
+            Self::Registered { codepoint } => registered_vdaf(*codepoint).ok_or_else(|| {
+                fatal_error!(err = format!("VDAF {codepoint:#010x} is not registered"))
+            }),
+        }
+    }
+
+    /// Look up a registered VDAF by name and return the config that refers to it.
+    pub fn from_registered_name(name: &str) -> Option<Self> {
+        let codepoint = registered_vdaf_by_name(name)?.codepoint()?;
+        Some(Self::Registered { codepoint })
+    }
+
+    pub(crate) fn uninitialized_verify_key(&self) -> Result<VdafVerifyKey, DapError> {
+        match self.dap_vdaf()?.verify_key_len() {
+            16 => Ok(VdafVerifyKey::L16([0; 16])),
+            32 => Ok(VdafVerifyKey::L32([0; 32])),
+            len => Err(fatal_error!(
+                err = format!("{self}: unsupported verify key length {len}")
+            )),
         }
     }
 
     /// Parse a verification key from raw bytes.
     pub fn get_decoded_verify_key(&self, bytes: &[u8]) -> Result<VdafVerifyKey, DapError> {
-        match self {
-            Self::Prio3(Prio3Config::SumVecField64MultiproofHmacSha256Aes128 { .. })
-            | Self::Prio2 { .. } => {
-                Ok(VdafVerifyKey::L32(<[u8; 32]>::try_from(bytes).map_err(
+        match self.uninitialized_verify_key()? {
+            VdafVerifyKey::L16(..) => {
+                Ok(VdafVerifyKey::L16(<[u8; 16]>::try_from(bytes).map_err(
                     |e| DapAbort::from_codec_error(CodecError::Other(Box::new(e)), None),
                 )?))
             }
-            Self::Prio3(..) => Ok(VdafVerifyKey::L16(<[u8; 16]>::try_from(bytes).map_err(
-                |e| DapAbort::from_codec_error(CodecError::Other(Box::new(e)), None),
-            )?)),
-            #[cfg(any(test, feature = "test-utils"))]
-            Self::Mastic { .. } => {
-                Ok(VdafVerifyKey::L16(<[u8; 16]>::try_from(bytes).map_err(
+            VdafVerifyKey::L32(..) => {
+                Ok(VdafVerifyKey::L32(<[u8; 32]>::try_from(bytes).map_err(
                     |e| DapAbort::from_codec_error(CodecError::Other(Box::new(e)), None),
                 )?))
             }
@@ -386,23 +445,49 @@ impl VdafConfig {
     }
 
     /// Generate the Aggregators' shared verification parameters.
-    pub fn gen_verify_key(&self) -> VdafVerifyKey {
+    pub fn gen_verify_key(&self) -> Result<VdafVerifyKey, DapError> {
         let mut rng = thread_rng();
-        let mut verify_key = self.uninitialized_verify_key();
+        let mut verify_key = self.uninitialized_verify_key()?;
         rng.fill(verify_key.as_mut());
-        verify_key
+        Ok(verify_key)
+    }
+
+    /// Combine the host's prep share with its peer's to compute the prep message for the current
+    /// round, then consume the prep message to advance preparation. The outputs are the host's
+    /// transition and the encoded prep message, which is sent to the peer.
+    pub(crate) fn prep_next_from_shares(
+        &self,
+        agg_id: usize,
+        host_state: VdafPrepState,
+        host_share: VdafPrepMessage,
+        peer_share_data: &[u8],
+    ) -> Result<(VdafPrepTransition, Vec<u8>), VdafError> {
+        self.dap_vdaf()
+            .map_err(VdafError::Dap)?
+            .prep_next_from_shares(agg_id, host_state, host_share, peer_share_data)
+    }
+
+    /// Consume the prep message computed by the peer to advance preparation.
+    pub(crate) fn prep_next(
+        &self,
+        host_state: VdafPrepState,
+        peer_message_data: &[u8],
+    ) -> Result<VdafPrepTransition, VdafError> {
+        self.dap_vdaf()
+            .map_err(VdafError::Dap)?
+            .prep_next(host_state, peer_message_data)
     }
 
     /// Checks if the provided aggregation parameter is valid for the underling VDAF being
     /// executed.
     pub fn is_valid_agg_param(&self, agg_param: &[u8]) -> bool {
-        match self {
-            Self::Prio3(..) | Self::Prio2 { .. } => agg_param.is_empty(),
-            #[cfg(any(test, feature = "test-utils"))]
-            // TODO(cjpatton) Implement agg param validation once we know what we need. In the
-            // meantime, permit everything.
-            Self::Mastic { .. } => true,
-        }
+        self.dap_vdaf()
+            .is_ok_and(|vdaf| vdaf.is_valid_agg_param(agg_param))
+    }
+
+    /// Indicates if the VDAF takes a (non-empty) aggregation parameter.
+    pub fn uses_agg_param(&self) -> bool {
+        self.dap_vdaf().map_or(true, |vdaf| vdaf.uses_agg_param())
     }
 }
 
@@ -5,11 +5,14 @@
 //! [VDAF](https://datatracker.ietf.org/doc/draft-patton-cfrg-vdaf/).
 
 use crate::{
-    fatal_error, vdaf::VdafError, DapAggregateResult, DapMeasurement, VdafAggregateShare,
-    VdafPrepMessage, VdafPrepState, VdafVerifyKey,
+    fatal_error,
+    messages::taskprov::VDAF_TYPE_PRIO2,
+    vdaf::{DapVdaf, VdafError, VdafPrepTransition},
+    DapAggregateResult, DapAggregationParam, DapMeasurement, VdafAggregateShare, VdafPrepMessage,
+    VdafPrepState, VdafVerifyKey,
 };
 use prio::{
-    codec::{Decode, Encode, ParameterizedDecode},
+    codec::{CodecError, Decode, Encode, ParameterizedDecode},
     field::FieldPrio2,
     vdaf::{
         prio2::{Prio2, Prio2PrepareShare, Prio2PrepareState},
@@ -156,6 +159,90 @@ pub(crate) fn prio2_unshard<M: IntoIterator<Item = Vec<u8>>>(
     Ok(DapAggregateResult::U32Vec(agg_res))
 }
 
+/// Prio2 with the given dimension.
+pub(crate) struct Prio2Vdaf {
+    pub(crate) dimension: usize,
+}
+
+impl DapVdaf for Prio2Vdaf {
+    fn name(&self) -> &'static str {
+        "prio2"
+    }
+
+    fn codepoint(&self) -> Option<u32> {
+        Some(VDAF_TYPE_PRIO2)
+    }
+
+    fn verify_key_len(&self) -> usize {
+        32
+    }
+
+    fn shard(
+        &self,
+        measurement: DapMeasurement,
+        nonce: &[u8; 16],
+    ) -> Result<(Vec<u8>, Vec<Vec<u8>>), VdafError> {
+        prio2_shard(self.dimension, measurement, nonce)
+    }
+
+    fn prep_init(
+        &self,
+        verify_key: &VdafVerifyKey,
+        agg_id: usize,
+        _agg_param: &DapAggregationParam,
+        nonce: &[u8; 16],
+        public_share_data: &[u8],
+        input_share_data: &[u8],
+    ) -> Result<(VdafPrepState, VdafPrepMessage), VdafError> {
+        prio2_prep_init(
+            self.dimension,
+            verify_key,
+            agg_id,
+            nonce,
+            public_share_data,
+            input_share_data,
+        )
+    }
+
+    fn prep_next_from_shares(
+        &self,
+        _agg_id: usize,
+        host_state: VdafPrepState,
+        host_share: VdafPrepMessage,
+        peer_share_data: &[u8],
+    ) -> Result<(VdafPrepTransition, Vec<u8>), VdafError> {
+        prio2_prep_finish_from_shares(self.dimension, host_state, host_share, peer_share_data)
+            .map(|(agg_share, prep_msg)| (VdafPrepTransition::Finish(agg_share), prep_msg))
+    }
+
+    fn prep_next(
+        &self,
+        host_state: VdafPrepState,
+        peer_message_data: &[u8],
+    ) -> Result<VdafPrepTransition, VdafError> {
+        prio2_prep_finish(self.dimension, host_state, peer_message_data)
+            .map(VdafPrepTransition::Finish)
+    }
+
+    fn decode_prep_state(
+        &self,
+        agg_id: usize,
+        bytes: &mut Cursor<&[u8]>,
+    ) -> Result<VdafPrepState, CodecError> {
+        prio2_decode_prep_state(self.dimension, agg_id, bytes)
+            .map_err(|e| CodecError::Other(Box::new(e)))
+    }
+
+    fn unshard(
+        &self,
+        _agg_param: &DapAggregationParam,
+        num_measurements: usize,
+        agg_shares: Vec<Vec<u8>>,
+    ) -> Result<DapAggregateResult, VdafError> {
+        prio2_unshard(self.dimension, num_measurements, agg_shares)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::{
@@ -6,12 +6,12 @@
 use crate::{
     fatal_error,
     messages::taskprov::VDAF_TYPE_PRIO3_SUM_VEC_FIELD64_MULTIPROOF_HMAC_SHA256_AES128,
-    vdaf::{VdafError, VdafVerifyKey},
-    DapAggregateResult, DapMeasurement, Prio3Config, VdafAggregateShare, VdafPrepMessage,
-    VdafPrepState,
+    vdaf::{DapVdaf, VdafError, VdafPrepTransition, VdafVerifyKey},
+    DapAggregateResult, DapAggregationParam, DapMeasurement, Prio3Config, VdafAggregateShare,
+    VdafPrepMessage, VdafPrepState,
 };
 use prio::{
-    codec::{Encode, ParameterizedDecode},
+    codec::{CodecError, Encode, ParameterizedDecode},
     field::Field64,
     flp::{
         gadgets::{Mul, ParallelSum},
@@ -652,6 +652,102 @@ pub(crate) fn prio3_unshard<M: IntoIterator<Item = Vec<u8>>>(
     }
 }
 
+impl DapVdaf for Prio3Config {
+    fn name(&self) -> &'static str {
+        match self {
+            Prio3Config::Count => "prio3_count",
+            Prio3Config::Sum { .. } => "prio3_sum",
+            Prio3Config::Histogram { .. } => "prio3_histogram",
+            Prio3Config::SumVec { .. } => "prio3_sum_vec",
+            Prio3Config::SumVecField64MultiproofHmacSha256Aes128 { .. } => {
+                "prio3_sum_vec_field64_multiproof_hmac_sha256_aes128"
+            }
+        }
+    }
+
+    fn codepoint(&self) -> Option<u32> {
+        Some(match self {
+            Prio3Config::Count => 0x0000_0000,
+            Prio3Config::Sum { .. } => 0x0000_0001,
+            Prio3Config::SumVec { .. } => 0x0000_0002,
+            Prio3Config::Histogram { .. } => 0x0000_0003,
+            Prio3Config::SumVecField64MultiproofHmacSha256Aes128 { .. } => {
+                VDAF_TYPE_PRIO3_SUM_VEC_FIELD64_MULTIPROOF_HMAC_SHA256_AES128
+            }
+        })
+    }
+
+    fn verify_key_len(&self) -> usize {
+        match self {
+            Prio3Config::SumVecField64MultiproofHmacSha256Aes128 { .. } => 32,
+            _ => 16,
+        }
+    }
+
+    fn shard(
+        &self,
+        measurement: DapMeasurement,
+        nonce: &[u8; 16],
+    ) -> Result<(Vec<u8>, Vec<Vec<u8>>), VdafError> {
+        prio3_shard(self, measurement, nonce)
+    }
+
+    fn prep_init(
+        &self,
+        verify_key: &VdafVerifyKey,
+        agg_id: usize,
+        _agg_param: &DapAggregationParam,
+        nonce: &[u8; 16],
+        public_share_data: &[u8],
+        input_share_data: &[u8],
+    ) -> Result<(VdafPrepState, VdafPrepMessage), VdafError> {
+        prio3_prep_init(
+            self,
+            verify_key,
+            agg_id,
+            nonce,
+            public_share_data,
+            input_share_data,
+        )
+    }
+
+    fn prep_next_from_shares(
+        &self,
+        agg_id: usize,
+        host_state: VdafPrepState,
+        host_share: VdafPrepMessage,
+        peer_share_data: &[u8],
+    ) -> Result<(VdafPrepTransition, Vec<u8>), VdafError> {
+        prio3_prep_finish_from_shares(self, agg_id, host_state, host_share, peer_share_data)
+            .map(|(agg_share, prep_msg)| (VdafPrepTransition::Finish(agg_share), prep_msg))
+    }
+
+    fn prep_next(
+        &self,
+        host_state: VdafPrepState,
+        peer_message_data: &[u8],
+    ) -> Result<VdafPrepTransition, VdafError> {
+        prio3_prep_finish(self, host_state, peer_message_data).map(VdafPrepTransition::Finish)
+    }
+
+    fn decode_prep_state(
+        &self,
+        agg_id: usize,
+        bytes: &mut Cursor<&[u8]>,
+    ) -> Result<VdafPrepState, CodecError> {
+        prio3_decode_prep_state(self, agg_id, bytes).map_err(|e| CodecError::Other(Box::new(e)))
+    }
+
+    fn unshard(
+        &self,
+        _agg_param: &DapAggregationParam,
+        num_measurements: usize,
+        agg_shares: Vec<Vec<u8>>,
+    ) -> Result<DapAggregateResult, VdafError> {
+        prio3_unshard(self, num_measurements, agg_shares)
+    }
+}
+
 #[cfg(test)]
 mod test {
 
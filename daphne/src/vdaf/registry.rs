@@ -0,0 +1,432 @@
+// Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Extension point for VDAFs that are not built into Daphne.
+//!
+//! Every VDAF operation performed by the Client, Aggregators and Collector is dispatched through
+//! the [`DapVdaf`] trait. The built-in VDAFs implement it directly; other VDAFs are made available
+//! to tasks by calling [`register_vdaf`] at startup and configuring the task with
+//! [`VdafConfig::Registered`].
+
+use crate::{
+    fatal_error,
+    messages::taskprov::{
+        VDAF_TYPE_PRIO2, VDAF_TYPE_PRIO3_SUM_VEC_FIELD64_MULTIPROOF_HMAC_SHA256_AES128,
+    },
+    vdaf::{VdafError, VdafPrepMessage, VdafPrepState, VdafPrepTransition, VdafVerifyKey},
+    DapAggregateResult, DapAggregationParam, DapError, DapMeasurement,
+};
+use prio::codec::{decode_u32_items, CodecError};
+use std::{
+    collections::BTreeMap,
+    io::Cursor,
+    sync::{Arc, RwLock},
+};
+
+#[cfg(doc)]
+use crate::vdaf::{VdafAggregateShare, VdafConfig};
+
+/// A VDAF that can be executed by Daphne.
+///
+/// Preparation state and prep shares are carried between steps as [`VdafPrepState`] and
+/// [`VdafPrepMessage`]. Implementations outside of this crate should use the `Opaque` variants of
+/// these types. Output shares must be one of the variants of [`VdafAggregateShare`] so that the
+/// aggregate store can merge them without knowing which VDAF produced them.
+pub trait DapVdaf: Send + Sync {
+    /// Name of the VDAF. This is used as the metrics label and to look up registered VDAFs.
+    fn name(&self) -> &'static str;
+
+    /// Codepoint identifying the VDAF in the taskprov extension, if one has been assigned.
+    fn codepoint(&self) -> Option<u32>;
+
+    /// Length of the verification key in bytes. Must be either 16 or 32.
+    fn verify_key_len(&self) -> usize;
+
+    /// Check that the aggregation parameter is valid for this VDAF.
+    fn is_valid_agg_param(&self, agg_param: &[u8]) -> bool {
+        agg_param.is_empty()
+    }
+
+    /// Indicates if the VDAF takes a (non-empty) aggregation parameter. If not, then reports can be
+    /// aggregated before the Collector requests the aggregate result.
+    fn uses_agg_param(&self) -> bool {
+        false
+    }
+
+    /// Split a measurement into the public share and a sequence of input shares, one for each
+    /// Aggregator.
+    fn shard(
+        &self,
+        measurement: DapMeasurement,
+        nonce: &[u8; 16],
+    ) -> Result<(Vec<u8>, Vec<Vec<u8>>), VdafError>;
+
+    /// Consume an input share and begin preparation. The outputs are the Aggregator's prep state
+    /// and the prep share for the first round.
+    fn prep_init(
+        &self,
+        verify_key: &VdafVerifyKey,
+        agg_id: usize,
+        agg_param: &DapAggregationParam,
+        nonce: &[u8; 16],
+        public_share_data: &[u8],
+        input_share_data: &[u8],
+    ) -> Result<(VdafPrepState, VdafPrepMessage), VdafError>;
+
+    /// Combine the host's prep share with its peer's to compute the prep message for the current
+    /// round, then consume the prep message to advance preparation. The outputs are the host's
+    /// transition and the encoded prep message.
+    fn prep_next_from_shares(
+        &self,
+        agg_id: usize,
+        host_state: VdafPrepState,
+        host_share: VdafPrepMessage,
+        peer_share_data: &[u8],
+    ) -> Result<(VdafPrepTransition, Vec<u8>), VdafError>;
+
+    /// Consume the prep message computed by the peer to advance preparation.
+    fn prep_next(
+        &self,
+        host_state: VdafPrepState,
+        peer_message_data: &[u8],
+    ) -> Result<VdafPrepTransition, VdafError>;
+
+    /// Decode a prep state previously encoded with [`VdafPrepState`]'s `Encode` implementation.
+    ///
+    /// The default implementation decodes [`VdafPrepState::Opaque`].
+    fn decode_prep_state(
+        &self,
+        agg_id: usize,
+        bytes: &mut Cursor<&[u8]>,
+    ) -> Result<VdafPrepState, CodecError> {
+        let _ = agg_id;
+        Ok(VdafPrepState::Opaque(decode_u32_items(&(), bytes)?))
+    }
+
+    /// Combine the Aggregators' encoded aggregate shares into the aggregate result.
+    fn unshard(
+        &self,
+        agg_param: &DapAggregationParam,
+        num_measurements: usize,
+        agg_shares: Vec<Vec<u8>>,
+    ) -> Result<DapAggregateResult, VdafError>;
+}
+
+/// VDAFs registered by the application, keyed by codepoint.
+static REGISTRY: RwLock<BTreeMap<u32, Arc<dyn DapVdaf>>> = RwLock::new(BTreeMap::new());
+
+/// Register a VDAF so that it can be used by tasks configured with [`VdafConfig::Registered`].
+///
+/// The VDAF must have a codepoint and a name that are not used by any other registered VDAF. The
+/// codepoints of the VDAFs that Daphne decodes natively in taskprov are reserved.
+pub fn register_vdaf(vdaf: Arc<dyn DapVdaf>) -> Result<(), DapError> {
+    let name = vdaf.name();
+    let Some(codepoint) = vdaf.codepoint() else {
+        return Err(fatal_error!(
+            err = format!("cannot register VDAF {name}: no codepoint assigned")
+        ));
+    };
+    if matches!(
+        codepoint,
+        VDAF_TYPE_PRIO2 | VDAF_TYPE_PRIO3_SUM_VEC_FIELD64_MULTIPROOF_HMAC_SHA256_AES128
+    ) {
+        return Err(fatal_error!(
+            err = format!("cannot register VDAF {name}: codepoint {codepoint:#010x} is reserved")
+        ));
+    }
+    if !matches!(vdaf.verify_key_len(), 16 | 32) {
+        return Err(fatal_error!(
+            err = format!(
+                "cannot register VDAF {name}: unsupported verify key length {}",
+                vdaf.verify_key_len()
+            )
+        ));
+    }
+
+    let mut registry = REGISTRY
+        .write()
+        .map_err(|e| fatal_error!(err = ?e, "VDAF registry is poisoned"))?;
+    if let Some(existing) = registry.get(&codepoint) {
+        return Err(fatal_error!(
+            err = format!(
+                "cannot register VDAF {name}: codepoint {codepoint:#010x} is already used by {}",
+                existing.name()
+            )
+        ));
+    }
+    if registry.values().any(|existing| existing.name() == name) {
+        return Err(fatal_error!(
+            err = format!("cannot register VDAF {name}: name is already registered")
+        ));
+    }
+    registry.insert(codepoint, vdaf);
+    Ok(())
+}
+
+/// Look up a registered VDAF by its codepoint.
+pub fn registered_vdaf(codepoint: u32) -> Option<Arc<dyn DapVdaf>> {
+    REGISTRY.read().ok()?.get(&codepoint).cloned()
+}
+
+/// Look up a registered VDAF by its name.
+pub fn registered_vdaf_by_name(name: &str) -> Option<Arc<dyn DapVdaf>> {
+    REGISTRY
+        .read()
+        .ok()?
+        .values()
+        .find(|vdaf| vdaf.name() == name)
+        .cloned()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        async_test_versions,
+        hpke::HpkeKemId,
+        messages::taskprov::VdafTypeVar,
+        testing::AggregationJobTest,
+        vdaf::{VdafAggregateShare, VdafConfig},
+        DapVersion,
+    };
+    use prio::{
+        codec::{Decode, Encode},
+        field::{Field64, FieldElement},
+        vdaf::AggregateShare,
+    };
+    use rand::prelude::*;
+    use std::sync::OnceLock;
+
+    const TOY_SUM_CODEPOINT: u32 = 0xFFFF_F001;
+
+    /// An insecure VDAF for summing 64-bit integers that is implemented outside of the built-in
+    /// VDAFs, as a downstream crate would.
+    struct ToySum;
+
+    impl ToySum {
+        fn decode_share(bytes: &[u8]) -> Result<Field64, VdafError> {
+            Ok(Field64::get_decoded(bytes)?)
+        }
+    }
+
+    impl DapVdaf for ToySum {
+        fn name(&self) -> &'static str {
+            "toy_sum"
+        }
+
+        fn codepoint(&self) -> Option<u32> {
+            Some(TOY_SUM_CODEPOINT)
+        }
+
+        fn verify_key_len(&self) -> usize {
+            16
+        }
+
+        fn shard(
+            &self,
+            measurement: DapMeasurement,
+            _nonce: &[u8; 16],
+        ) -> Result<(Vec<u8>, Vec<Vec<u8>>), VdafError> {
+            let DapMeasurement::U64(measurement) = measurement else {
+                return Err(VdafError::Dap(fatal_error!(
+                    err = "toy_sum: unexpected measurement type"
+                )));
+            };
+            let leader_share = Field64::from(thread_rng().gen::<u64>());
+            let helper_share = Field64::from(measurement) - leader_share;
+            Ok((
+                Vec::new(),
+                vec![leader_share.get_encoded()?, helper_share.get_encoded()?],
+            ))
+        }
+
+        fn prep_init(
+            &self,
+            _verify_key: &VdafVerifyKey,
+            _agg_id: usize,
+            _agg_param: &DapAggregationParam,
+            _nonce: &[u8; 16],
+            _public_share_data: &[u8],
+            input_share_data: &[u8],
+        ) -> Result<(VdafPrepState, VdafPrepMessage), VdafError> {
+            Self::decode_share(input_share_data)?;
+            Ok((
+                VdafPrepState::Opaque(input_share_data.to_vec()),
+                VdafPrepMessage::Opaque(Vec::new()),
+            ))
+        }
+
+        fn prep_next_from_shares(
+            &self,
+            _agg_id: usize,
+            host_state: VdafPrepState,
+            _host_share: VdafPrepMessage,
+            peer_share_data: &[u8],
+        ) -> Result<(VdafPrepTransition, Vec<u8>), VdafError> {
+            if !peer_share_data.is_empty() {
+                return Err(VdafError::Codec(CodecError::UnexpectedValue));
+            }
+            Ok((self.prep_next(host_state, &[])?, Vec::new()))
+        }
+
+        fn prep_next(
+            &self,
+            host_state: VdafPrepState,
+            peer_message_data: &[u8],
+        ) -> Result<VdafPrepTransition, VdafError> {
+            let VdafPrepState::Opaque(state) = host_state else {
+                return Err(VdafError::Dap(fatal_error!(
+                    err = "toy_sum: unexpected prep state"
+                )));
+            };
+            if !peer_message_data.is_empty() {
+                return Err(VdafError::Codec(CodecError::UnexpectedValue));
+            }
+            Ok(VdafPrepTransition::Finish(VdafAggregateShare::Field64(
+                AggregateShare::from(vec![Self::decode_share(&state)?]),
+            )))
+        }
+
+        fn unshard(
+            &self,
+            _agg_param: &DapAggregationParam,
+            _num_measurements: usize,
+            agg_shares: Vec<Vec<u8>>,
+        ) -> Result<DapAggregateResult, VdafError> {
+            let mut agg = Field64::zero();
+            for agg_share in agg_shares {
+                agg += Self::decode_share(&agg_share)?;
+            }
+            Ok(DapAggregateResult::U64(u64::from(agg)))
+        }
+    }
+
+    fn toy_sum() -> VdafConfig {
+        static REGISTERED: OnceLock<()> = OnceLock::new();
+        REGISTERED.get_or_init(|| register_vdaf(Arc::new(ToySum)).unwrap());
+        VdafConfig::Registered {
+            codepoint: TOY_SUM_CODEPOINT,
+        }
+    }
+
+    async fn roundtrip_registered(version: DapVersion) {
+        let mut t = AggregationJobTest::new(&toy_sum(), HpkeKemId::X25519HkdfSha256, version);
+        let got = t
+            .roundtrip(
+                DapAggregationParam::Empty,
+                vec![
+                    DapMeasurement::U64(1),
+                    DapMeasurement::U64(1337),
+                    DapMeasurement::U64(0),
+                ],
+            )
+            .await;
+        assert_eq!(got, DapAggregateResult::U64(1338));
+    }
+
+    async_test_versions! { roundtrip_registered }
+
+    #[test]
+    fn lookup() {
+        let vdaf = toy_sum();
+        assert_eq!(vdaf.dap_vdaf().unwrap().name(), "toy_sum");
+        assert_eq!(vdaf.to_string(), "Registered(toy_sum)");
+        assert_eq!("toy_sum".parse::<VdafConfig>().unwrap(), vdaf);
+        assert_eq!(
+            serde_json::from_str::<VdafConfig>(&serde_json::to_string(&vdaf).unwrap()).unwrap(),
+            vdaf
+        );
+        assert!(registered_vdaf_by_name("toy_sum").is_some());
+        assert_eq!(
+            VdafTypeVar::try_from(&vdaf).unwrap(),
+            VdafTypeVar::NotImplemented {
+                typ: TOY_SUM_CODEPOINT,
+                param: Vec::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn unregistered() {
+        let vdaf = VdafConfig::Registered {
+            codepoint: 0xFFFF_F0FF,
+        };
+        assert!(vdaf.dap_vdaf().is_err());
+        assert!(vdaf.gen_verify_key().is_err());
+        assert!(!vdaf.is_valid_agg_param(&[]));
+        assert_eq!(vdaf.to_string(), "Registered(0xfffff0ff)");
+    }
+
+    #[test]
+    fn register_rejects_conflicts() {
+        toy_sum();
+
+        // Codepoint is already registered.
+        assert!(register_vdaf(Arc::new(ToySum)).is_err());
+
+        // Codepoint is reserved for a built-in VDAF.
+        struct Reserved;
+        impl DapVdaf for Reserved {
+            fn name(&self) -> &'static str {
+                "reserved"
+            }
+            fn codepoint(&self) -> Option<u32> {
+                Some(VDAF_TYPE_PRIO2)
+            }
+            fn verify_key_len(&self) -> usize {
+                32
+            }
+            fn shard(
+                &self,
+                measurement: DapMeasurement,
+                nonce: &[u8; 16],
+            ) -> Result<(Vec<u8>, Vec<Vec<u8>>), VdafError> {
+                ToySum.shard(measurement, nonce)
+            }
+            fn prep_init(
+                &self,
+                verify_key: &VdafVerifyKey,
+                agg_id: usize,
+                agg_param: &DapAggregationParam,
+                nonce: &[u8; 16],
+                public_share_data: &[u8],
+                input_share_data: &[u8],
+            ) -> Result<(VdafPrepState, VdafPrepMessage), VdafError> {
+                ToySum.prep_init(
+                    verify_key,
+                    agg_id,
+                    agg_param,
+                    nonce,
+                    public_share_data,
+                    input_share_data,
+                )
+            }
+            fn prep_next_from_shares(
+                &self,
+                agg_id: usize,
+                host_state: VdafPrepState,
+                host_share: VdafPrepMessage,
+                peer_share_data: &[u8],
+            ) -> Result<(VdafPrepTransition, Vec<u8>), VdafError> {
+                ToySum.prep_next_from_shares(agg_id, host_state, host_share, peer_share_data)
+            }
+            fn prep_next(
+                &self,
+                host_state: VdafPrepState,
+                peer_message_data: &[u8],
+            ) -> Result<VdafPrepTransition, VdafError> {
+                ToySum.prep_next(host_state, peer_message_data)
+            }
+            fn unshard(
+                &self,
+                agg_param: &DapAggregationParam,
+                num_measurements: usize,
+                agg_shares: Vec<Vec<u8>>,
+            ) -> Result<DapAggregateResult, VdafError> {
+                ToySum.unshard(agg_param, num_measurements, agg_shares)
+            }
+        }
+        assert!(register_vdaf(Arc::new(Reserved)).is_err());
+        assert!(registered_vdaf(VDAF_TYPE_PRIO2).is_none());
+    }
+}
@@ -4,6 +4,7 @@
 use std::path::PathBuf;
 
 use clap::Parser;
+use daphne::{audit_log::JsonLinesAuditLog, metrics::TaskLabelPolicy};
 use daphne_server::{router, App, StorageProxyConfig};
 use daphne_service_utils::{
     config::DaphneServiceConfig, metrics::DaphnePromServiceMetrics, DapRole,
@@ -17,6 +18,11 @@ struct Config {
     service: DaphneServiceConfig,
     port: u16,
     storage_proxy: StorageProxyConfig,
+    /// Which tasks, if any, get their own label in the exported metrics.
+    #[serde(default)]
+    metrics_task_labels: TaskLabelPolicy,
+    /// If set, append the audit log to this file as JSON lines.
+    audit_log_path: Option<PathBuf>,
 }
 
 impl TryFrom<Args> for Config {
@@ -97,11 +103,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
 
     // Create a new prometheus registry where metrics will be registered and measured
     let registry = prometheus::Registry::new();
-    let daphne_service_metrics = DaphnePromServiceMetrics::register(&registry)?;
+    let daphne_service_metrics =
+        DaphnePromServiceMetrics::register_with_task_labels(&registry, config.metrics_task_labels)?;
 
     let role = config.service.role;
     // Configure the application
-    let app = App::new(config.storage_proxy, daphne_service_metrics, config.service)?;
+    let mut app = App::new(config.storage_proxy, daphne_service_metrics, config.service)?;
+    if let Some(path) = config.audit_log_path {
+        app = app.with_audit_log(JsonLinesAuditLog::open(path)?);
+    }
 
     // create the router that will handle the protocol's http requests
     let router = router::new(role, app);
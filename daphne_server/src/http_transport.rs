@@ -0,0 +1,80 @@
+// Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use std::sync::Arc;
+
+use axum::async_trait;
+use url::Url;
+
+/// Sends the HTTP requests made by the service, i.e., the requests to the storage proxy and, for
+/// the Leader, the requests to the Helper. By default requests are sent over the network with a
+/// [`reqwest::Client`]. See [`App::with_http_transport`](crate::App::with_http_transport).
+#[async_trait]
+pub trait HttpTransport: Send + Sync {
+    /// Send `request` and return the response.
+    async fn execute(&self, request: reqwest::Request) -> reqwest::Result<reqwest::Response>;
+}
+
+#[async_trait]
+impl HttpTransport for reqwest::Client {
+    async fn execute(&self, request: reqwest::Request) -> reqwest::Result<reqwest::Response> {
+        reqwest::Client::execute(self, request).await
+    }
+}
+
+#[async_trait]
+impl<T: HttpTransport + ?Sized> HttpTransport for Arc<T> {
+    async fn execute(&self, request: reqwest::Request) -> reqwest::Result<reqwest::Response> {
+        T::execute(self, request).await
+    }
+}
+
+/// Builds requests with a [`reqwest::Client`] and sends them with an [`HttpTransport`].
+pub(crate) struct HttpClient {
+    client: reqwest::Client,
+    transport: Box<dyn HttpTransport>,
+}
+
+impl Default for HttpClient {
+    fn default() -> Self {
+        let client = reqwest::Client::new();
+        Self {
+            transport: Box::new(client.clone()),
+            client,
+        }
+    }
+}
+
+impl HttpClient {
+    pub fn with_transport(self, transport: Box<dyn HttpTransport>) -> Self {
+        Self { transport, ..self }
+    }
+
+    pub fn request(&self, method: reqwest::Method, url: Url) -> reqwest::RequestBuilder {
+        self.client.request(method, url)
+    }
+
+    pub fn get(&self, url: Url) -> reqwest::RequestBuilder {
+        self.client.get(url)
+    }
+
+    pub fn post(&self, url: Url) -> reqwest::RequestBuilder {
+        self.client.post(url)
+    }
+
+    pub fn put(&self, url: Url) -> reqwest::RequestBuilder {
+        self.client.put(url)
+    }
+
+    pub fn delete(&self, url: Url) -> reqwest::RequestBuilder {
+        self.client.delete(url)
+    }
+
+    /// Send a request built with this client.
+    pub async fn send(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> reqwest::Result<reqwest::Response> {
+        self.transport.execute(request.build()?).await
+    }
+}
@@ -3,17 +3,32 @@
 
 use std::sync::Arc;
 
-use daphne::{auth::BearerToken, roles::leader::in_memory_leader::InMemoryLeaderState, DapError};
+use daphne::{
+    audit_log::{AuditLog, NoopAuditLog},
+    auth::BearerToken,
+    roles::{
+        leader::in_memory_leader::InMemoryLeaderState,
+        report_extensions::DapReportExtensionHandlers,
+    },
+    DapError,
+};
 use daphne_service_utils::{config::DaphneServiceConfig, metrics::DaphneServiceMetrics};
 use futures::lock::Mutex;
+use http_transport::HttpClient;
 use serde::{Deserialize, Serialize};
 use storage_proxy_connection::{kv, Do, Kv};
+
+pub use http_transport::HttpTransport;
+pub use storage_proxy_connection::kv::CacheConfig as KvCacheConfig;
 use tokio::sync::RwLock;
 use url::Url;
 
+mod http_transport;
 mod roles;
 pub mod router;
 mod storage_proxy_connection;
+#[cfg(feature = "test-utils")]
+pub mod testing;
 
 /// Entrypoint to the server implementation. This struct implements
 /// [`DapLeader`](daphne::roles::DapLeader) and [`DapHelper`](daphne::roles::DapHelper) and can be
@@ -37,6 +52,7 @@ mod storage_proxy_connection;
 /// let storage_proxy_settings = StorageProxyConfig {
 ///     url: Url::parse("http://example.com").unwrap(),
 ///     auth_token: "some-token".into(),
+///     kv_cache: Default::default(),
 /// };
 /// let registry = prometheus::Registry::new();
 /// let daphne_service_metrics = DaphnePromServiceMetrics::register(&registry).unwrap();
@@ -46,6 +62,9 @@ mod storage_proxy_connection;
 ///     max_batch_interval_end: 259_200,
 ///     supported_hpke_kems: vec![HpkeKemId::X25519HkdfSha256],
 ///     allow_taskprov: true,
+///     helper_async_agg_jobs: false,
+///     agg_job_policy: Default::default(),
+///     retry_policy: Default::default(),
 /// };
 /// let service_config = DaphneServiceConfig {
 ///     env: "some-machine-identifier".into(),
@@ -57,6 +76,8 @@ mod storage_proxy_connection;
 ///     report_storage_epoch_duration: 300,
 ///     report_storage_max_future_time_skew: 300,
 ///     signing_key: None,
+///     admin_token: None,
+///     aggregate_store: Default::default(),
 /// };
 /// let app = App::new(storage_proxy_settings, daphne_service_metrics, service_config)?;
 ///
@@ -68,10 +89,12 @@ mod storage_proxy_connection;
 /// ```
 pub struct App {
     storage_proxy_config: StorageProxyConfig,
-    http: reqwest::Client,
+    http: HttpClient,
     cache: RwLock<kv::Cache>,
     metrics: Box<dyn DaphneServiceMetrics>,
+    audit_log: Box<dyn AuditLog + Send + Sync>,
     service_config: DaphneServiceConfig,
+    report_extension_handlers: DapReportExtensionHandlers,
 
     /// Volatile memory for the Leader, including the work queue, pending reports, and pending
     /// colleciton requests. Note that in a production Leader, it is necessary to store this state
@@ -83,6 +106,9 @@ pub struct App {
 pub struct StorageProxyConfig {
     pub url: Url,
     pub auth_token: BearerToken,
+    /// Caching of values read from KV.
+    #[serde(default)]
+    pub kv_cache: KvCacheConfig,
 }
 
 impl router::DaphneService for App {
@@ -106,15 +132,46 @@ impl App {
         M: DaphneServiceMetrics + 'static,
     {
         Ok(Self {
+            cache: RwLock::new(kv::Cache::new(storage_proxy_config.kv_cache.clone())),
             storage_proxy_config,
-            http: reqwest::Client::new(),
-            cache: Default::default(),
+            http: HttpClient::default(),
             metrics: Box::new(daphne_service_metrics),
+            audit_log: Box::new(NoopAuditLog),
             service_config,
+            report_extension_handlers: Default::default(),
             test_leader_state: Default::default(),
         })
     }
 
+    /// Record auditable events to `audit_log`. By default, events are discarded.
+    #[must_use]
+    pub fn with_audit_log<L>(mut self, audit_log: L) -> Self
+    where
+        L: AuditLog + Send + Sync + 'static,
+    {
+        self.audit_log = Box::new(audit_log);
+        self
+    }
+
+    /// Handle custom report extensions with `handlers`. By default, reports carrying an extension
+    /// that is not built into Daphne are rejected.
+    #[must_use]
+    pub fn with_report_extension_handlers(mut self, handlers: DapReportExtensionHandlers) -> Self {
+        self.report_extension_handlers = handlers;
+        self
+    }
+
+    /// Send the requests to the storage proxy and to the Helper with `transport`. By default,
+    /// requests are sent over the network.
+    #[must_use]
+    pub fn with_http_transport<T>(mut self, transport: T) -> Self
+    where
+        T: HttpTransport + 'static,
+    {
+        self.http = self.http.with_transport(Box::new(transport));
+        self
+    }
+
     pub(crate) fn durable(&self) -> Do<'_> {
         Do::new(&self.storage_proxy_config, &self.http)
     }
@@ -1,27 +1,37 @@
 // Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
-use std::{borrow::Cow, time::SystemTime};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    ops::Range,
+    time::SystemTime,
+};
 
 use axum::async_trait;
 use daphne::{
-    audit_log::{AuditLog, NoopAuditLog},
+    audit_log::AuditLog,
     auth::{BearerToken, BearerTokenProvider},
     error::DapAbort,
     fatal_error,
     hpke::{HpkeConfig, HpkeDecrypter},
-    messages::{BatchId, BatchSelector, HpkeCiphertext, TaskId, Time, TransitionFailure},
+    messages::{BatchId, BatchSelector, HpkeCiphertext, ReportId, TaskId, Time, TransitionFailure},
     metrics::DaphneMetrics,
-    roles::{aggregator::MergeAggShareError, DapAggregator, DapReportInitializer},
-    DapAggregateShare, DapAggregateSpan, DapAggregationParam, DapBatchBucket, DapError,
-    DapGlobalConfig, DapRequest, DapSender, DapTaskConfig, DapVersion, EarlyReportState,
-    EarlyReportStateConsumed, EarlyReportStateInitialized,
+    roles::{
+        aggregator::MergeAggShareError, report_extensions::DapReportExtensionHandlers,
+        DapAggregator, DapReportInitializer,
+    },
+    DapAggregateShare, DapAggregateSpan, DapAggregationParam, DapBatchBucket, DapCollectionQuery,
+    DapError, DapGlobalConfig, DapRequest, DapSender, DapTaskConfig, DapVersion, EarlyReportState,
+    EarlyReportStateConsumed, EarlyReportStateInitialized, MetaAggregationJobId,
 };
 use daphne_service_utils::{
     auth::DaphneAuth,
-    durable_requests::bindings::{self, AggregateStoreMergeReq, AggregateStoreMergeResp},
+    durable_requests::bindings::{
+        self, AggregateStoreMergeReq, AggregateStoreMergeResp, AggregateStoreReserveReq,
+        AggregateStoreReserveResp,
+    },
 };
-use futures::{future::try_join_all, StreamExt};
 use rayon::prelude::{IntoParallelIterator, ParallelIterator};
 
 use crate::storage_proxy_connection::kv;
@@ -32,40 +42,156 @@ impl DapAggregator<DaphneAuth> for crate::App {
         &self,
         task_id: &TaskId,
         task_config: &DapTaskConfig,
+        agg_job_id: &MetaAggregationJobId,
         agg_share_span: DapAggregateSpan<DapAggregateShare>,
     ) -> DapAggregateSpan<Result<(), MergeAggShareError>> {
         let task_id_hex = task_id.to_hex();
         let durable = self.durable();
+        let shard_count = match self.aggregate_store_shard_count(task_id, task_config).await {
+            Ok(shard_count) => shard_count,
+            Err(e) => {
+                return agg_share_span
+                    .into_iter()
+                    .map(|(bucket, (_agg_share, report_metadatas))| {
+                        let result = Err(MergeAggShareError::Other(fatal_error!(err = ?e)));
+                        (bucket, (result, report_metadatas))
+                    })
+                    .collect();
+            }
+        };
+        let shard = bindings::AggregateStore::shard_for_agg_job(agg_job_id, shard_count);
+        let mut result_span = DapAggregateSpan::default();
+
+        // Each shard only detects replays of the reports that were merged into it, and two
+        // aggregation jobs that include the same report may be assigned to different shards.
+        // Reserve each report at the shard that owns it before merging, so that replays are
+        // detected atomically by a single shard regardless of where the report is merged.
+        let agg_share_span = agg_share_span.into_iter().collect::<Vec<_>>();
+        let agg_share_span = if shard_count > 1 {
+            let mut owners = Vec::new();
+            let mut requests = Vec::new();
+            for (i, (bucket, (_agg_share, report_metadatas))) in agg_share_span.iter().enumerate() {
+                let mut report_ids_by_shard = HashMap::<_, Vec<_>>::new();
+                for (id, _) in report_metadatas {
+                    report_ids_by_shard
+                        .entry(bindings::AggregateStore::shard_for_report(id, shard_count))
+                        .or_default()
+                        .push(*id);
+                }
+                for (owner, report_ids) in report_ids_by_shard {
+                    owners.push(i);
+                    requests.push(
+                        durable
+                            .request(
+                                bindings::AggregateStore::Reserve,
+                                (task_config.version, &task_id_hex, bucket, owner),
+                            )
+                            .encode_bincode(AggregateStoreReserveReq {
+                                agg_job_id: *agg_job_id,
+                                report_ids,
+                            }),
+                    );
+                }
+            }
+            let reserved = match durable
+                .send_batch::<_, _, AggregateStoreReserveResp>(requests)
+                .await
+            {
+                Ok(reserved) => reserved,
+                Err(e) => {
+                    return agg_share_span
+                        .into_iter()
+                        .map(|(bucket, (_agg_share, report_metadatas))| {
+                            let result = Err(MergeAggShareError::Other(fatal_error!(err = ?e)));
+                            (bucket, (result, report_metadatas))
+                        })
+                        .collect();
+                }
+            };
+
+            // Reservations are idempotent for an aggregation job, so the reports reserved for a
+            // bucket that is rejected here may be merged when the bucket is retried.
+            let mut replays = vec![HashSet::new(); agg_share_span.len()];
+            let mut errors = (0..agg_share_span.len()).map(|_| None).collect::<Vec<_>>();
+            for (i, reserved) in owners.into_iter().zip(reserved) {
+                match reserved {
+                    Ok(AggregateStoreReserveResp::Ok) => (),
+                    Ok(AggregateStoreReserveResp::ReplaysDetected(ids)) => replays[i].extend(ids),
+                    Err(e) => errors[i] = Some(e),
+                }
+            }
+
+            let mut unreplayed = Vec::with_capacity(agg_share_span.len());
+            for ((bucket, (agg_share, report_metadatas)), (replays, error)) in agg_share_span
+                .into_iter()
+                .zip(replays.into_iter().zip(errors))
+            {
+                if let Some(e) = error {
+                    let result = Err(MergeAggShareError::Other(fatal_error!(err = ?e)));
+                    result_span.extend([(bucket, (result, report_metadatas))]);
+                } else if !replays.is_empty() {
+                    let result = Err(MergeAggShareError::ReplaysDetected(replays));
+                    result_span.extend([(bucket, (result, report_metadatas))]);
+                } else {
+                    unreplayed.push((bucket, (agg_share, report_metadatas)));
+                }
+            }
+            unreplayed
+        } else {
+            agg_share_span
+        };
 
-        futures::stream::iter(agg_share_span)
-            .map(|(bucket, (agg_share, report_metadatas))| async {
-                let result = durable
+        let (buckets, requests): (Vec<_>, Vec<_>) = agg_share_span
+            .into_iter()
+            .map(|(bucket, (agg_share, report_metadatas))| {
+                let request = durable
                     .request(
                         bindings::AggregateStore::Merge,
-                        (task_config.version, &task_id_hex, &bucket),
+                        (task_config.version, &task_id_hex, &bucket, shard),
                     )
                     .encode_bincode(AggregateStoreMergeReq {
                         contained_reports: report_metadatas.iter().map(|(id, _)| *id).collect(),
                         agg_share_delta: agg_share,
-                    })
-                    .send::<AggregateStoreMergeResp>()
-                    .await
-                    .map_err(|e| fatal_error!(err = ?e));
+                        max_report_count: task_config.query.max_batch_size(),
+                    });
+                ((bucket, report_metadatas), request)
+            })
+            .unzip();
+
+        // Merge every bucket in a single round trip to the storage proxy.
+        let results = match durable
+            .send_batch::<_, _, AggregateStoreMergeResp>(requests)
+            .await
+        {
+            Ok(results) => results,
+            Err(e) => {
+                result_span.extend(buckets.into_iter().map(|(bucket, report_metadatas)| {
+                    let result = Err(MergeAggShareError::Other(fatal_error!(err = ?e)));
+                    (bucket, (result, report_metadatas))
+                }));
+                return result_span;
+            }
+        };
+
+        result_span.extend(buckets.into_iter().zip(results).map(
+            |((bucket, report_metadatas), result)| {
                 let result = match result {
                     Ok(AggregateStoreMergeResp::Ok) => Ok(()),
                     Ok(AggregateStoreMergeResp::AlreadyCollected) => {
                         Err(MergeAggShareError::AlreadyCollected)
                     }
+                    Ok(AggregateStoreMergeResp::BatchSaturated) => {
+                        Err(MergeAggShareError::BatchSaturated)
+                    }
                     Ok(AggregateStoreMergeResp::ReplaysDetected(replays)) => {
                         Err(MergeAggShareError::ReplaysDetected(replays))
                     }
-                    Err(e) => Err(MergeAggShareError::Other(e)),
+                    Err(e) => Err(MergeAggShareError::Other(fatal_error!(err = ?e))),
                 };
                 (bucket, (result, report_metadatas))
-            })
-            .buffer_unordered(usize::MAX)
-            .collect()
-            .await
+            },
+        ));
+        result_span
     }
 
     async fn get_agg_share(
@@ -79,19 +205,26 @@ impl DapAggregator<DaphneAuth> for crate::App {
             .ok_or(DapError::Abort(DapAbort::UnrecognizedTask))?;
 
         let durable = self.durable();
-        let mut requests = Vec::new();
-        for bucket in task_config.as_ref().batch_span_for_sel(batch_sel)? {
-            requests.push(
-                durable
-                    .request(
-                        bindings::AggregateStore::Get,
-                        (task_config.as_ref().version, &task_id.to_hex(), &bucket),
-                    )
-                    .send(),
-            );
-        }
-        let responses: Vec<DapAggregateShare> = try_join_all(requests)
+        let task_id_hex = task_id.to_hex();
+        let buckets = task_config.as_ref().batch_span_for_sel(batch_sel)?;
+        let shards = self
+            .aggregate_store_shards(task_id, task_config.as_ref())
+            .await?;
+        let requests = buckets
+            .iter()
+            .flat_map(|bucket| shards.clone().map(move |shard| (bucket, shard)))
+            .map(|(bucket, shard)| {
+                durable.request(
+                    bindings::AggregateStore::Get,
+                    (task_config.as_ref().version, &task_id_hex, bucket, shard),
+                )
+            });
+        let responses = durable
+            .send_batch::<_, _, DapAggregateShare>(requests)
             .await
+            .map_err(|e| fatal_error!(err = ?e))?
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
             .map_err(|e| fatal_error!(err = ?e))?;
         let mut agg_share = DapAggregateShare::default();
         for agg_share_delta in responses {
@@ -101,10 +234,54 @@ impl DapAggregator<DaphneAuth> for crate::App {
         Ok(agg_share)
     }
 
+    async fn get_agg_report_ids(
+        &self,
+        task_id: &TaskId,
+        batch_sel: &BatchSelector,
+    ) -> Result<HashMap<DapBatchBucket, HashSet<ReportId>>, DapError> {
+        let task_config = self
+            .get_task_config_for(task_id)
+            .await?
+            .ok_or(DapError::Abort(DapAbort::UnrecognizedTask))?;
+
+        let durable = self.durable();
+        let task_id_hex = task_id.to_hex();
+        let buckets = task_config.as_ref().batch_span_for_sel(batch_sel)?;
+        let shards = self
+            .aggregate_store_shards(task_id, task_config.as_ref())
+            .await?;
+        let requests = buckets
+            .iter()
+            .flat_map(|bucket| shards.clone().map(move |shard| (bucket, shard)))
+            .map(|(bucket, shard)| {
+                durable.request(
+                    bindings::AggregateStore::GetMerged,
+                    (task_config.as_ref().version, &task_id_hex, bucket, shard),
+                )
+            });
+        let mut responses = durable
+            .send_batch::<_, _, HashSet<ReportId>>(requests)
+            .await
+            .map_err(|e| fatal_error!(err = ?e))?
+            .into_iter();
+
+        // The responses are in the same order as the requests, i.e., one per shard of each bucket.
+        let mut report_ids = HashMap::with_capacity(buckets.len());
+        for bucket in buckets {
+            let mut report_ids_for_bucket = HashSet::new();
+            for merged in responses.by_ref().take(shards.len()) {
+                report_ids_for_bucket.extend(merged.map_err(|e| fatal_error!(err = ?e))?);
+            }
+            report_ids.insert(bucket, report_ids_for_bucket);
+        }
+        Ok(report_ids)
+    }
+
     async fn mark_collected(
         &self,
         task_id: &TaskId,
         batch_sel: &BatchSelector,
+        query: &DapCollectionQuery,
     ) -> Result<(), DapError> {
         let task_config = self
             .get_task_config_for(task_id)
@@ -112,25 +289,34 @@ impl DapAggregator<DaphneAuth> for crate::App {
             .ok_or(DapError::Abort(DapAbort::UnrecognizedTask))?;
 
         let durable = self.durable();
-        let mut requests = Vec::new();
-        for bucket in task_config.as_ref().batch_span_for_sel(batch_sel)? {
-            requests.push(
+        let task_id_hex = task_id.to_hex();
+        let buckets = task_config.as_ref().batch_span_for_sel(batch_sel)?;
+        let shards = self
+            .aggregate_store_shards(task_id, task_config.as_ref())
+            .await?;
+        let requests = buckets
+            .iter()
+            .flat_map(|bucket| shards.clone().map(move |shard| (bucket, shard)))
+            .map(|(bucket, shard)| {
                 durable
                     .request(
                         bindings::AggregateStore::MarkCollected,
-                        (task_config.as_ref().version, &task_id.to_hex(), &bucket),
+                        (task_config.as_ref().version, &task_id_hex, bucket, shard),
                     )
-                    .send::<()>(),
-            );
-        }
-
-        try_join_all(requests)
+                    .encode_bincode(query)
+            });
+        durable
+            .send_batch::<_, _, ()>(requests)
             .await
+            .map_err(|e| fatal_error!(err = ?e))?
+            .into_iter()
+            .collect::<Result<Vec<()>, _>>()
             .map_err(|e| fatal_error!(err = ?e))?;
         Ok(())
     }
 
-    type WrappedDapTaskConfig<'a> = DapTaskConfig
+    type WrappedDapTaskConfig<'a>
+        = DapTaskConfig
     where
         Self: 'a;
 
@@ -216,6 +402,10 @@ impl DapAggregator<DaphneAuth> for crate::App {
         &self.service_config.global
     }
 
+    fn report_extension_handlers(&self) -> &DapReportExtensionHandlers {
+        &self.report_extension_handlers
+    }
+
     fn taskprov_vdaf_verify_key_init(&self) -> Option<&[u8; 32]> {
         self.service_config
             .taskprov
@@ -275,43 +465,42 @@ impl DapAggregator<DaphneAuth> for crate::App {
             .as_secs()
     }
 
-    async fn is_batch_overlapping(
+    async fn get_batch_query_count(
         &self,
         task_id: &TaskId,
         batch_sel: &BatchSelector,
-    ) -> Result<bool, DapError> {
+    ) -> Result<u64, DapError> {
         let task_config = self
             .get_task_config_for(task_id)
             .await?
             .ok_or(DapError::Abort(DapAbort::UnrecognizedTask))?;
 
-        // Check whether the request overlaps with previous requests. This is done by
-        // checking the AggregateStore and seeing whether it requests for aggregate
-        // shares that have already been marked collected.
+        // Every shard of a bucket is marked collected at the same time, so the number of times
+        // the batch has been collected is the largest collection count of any shard of any bucket.
         let durable = self.durable();
-        let mut requests = Vec::new();
-        for bucket in task_config.as_ref().batch_span_for_sel(batch_sel)? {
-            requests.push(
-                durable
-                    .request(
-                        bindings::AggregateStore::CheckCollected,
-                        (task_config.as_ref().version, &task_id.to_hex(), &bucket),
-                    )
-                    .send(),
-            );
-        }
-
-        let responses: Vec<bool> = try_join_all(requests)
+        let shards = self
+            .aggregate_store_shards(task_id, task_config.as_ref())
+            .await?;
+        let task_id_hex = task_id.to_hex();
+        let buckets = task_config.as_ref().batch_span_for_sel(batch_sel)?;
+        let requests = buckets
+            .iter()
+            .flat_map(|bucket| shards.clone().map(move |shard| (bucket, shard)))
+            .map(|(bucket, shard)| {
+                durable.request(
+                    bindings::AggregateStore::GetCollectionCount,
+                    (task_config.as_ref().version, &task_id_hex, bucket, shard),
+                )
+            });
+        let responses = durable
+            .send_batch::<_, _, u64>(requests)
             .await
+            .map_err(|e| fatal_error!(err = ?e))?
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
             .map_err(|e| fatal_error!(err = ?e))?;
 
-        for collected in responses {
-            if collected {
-                return Ok(true);
-            }
-        }
-
-        Ok(false)
+        Ok(responses.into_iter().max().unwrap_or_default())
     }
 
     async fn batch_exists(&self, task_id: &TaskId, batch_id: &BatchId) -> Result<bool, DapError> {
@@ -320,23 +509,29 @@ impl DapAggregator<DaphneAuth> for crate::App {
             .await?
             .ok_or(DapError::Abort(DapAbort::UnrecognizedTask))?;
 
-        let agg_share: DapAggregateShare = self
-            .durable()
-            .request(
-                bindings::AggregateStore::Get,
-                (
-                    task_config.as_ref().version,
-                    &task_id.to_hex(),
-                    &DapBatchBucket::FixedSize {
-                        batch_id: *batch_id,
-                    },
-                ),
-            )
-            .send()
+        let durable = self.durable();
+        let task_id_hex = task_id.to_hex();
+        let bucket = DapBatchBucket::FixedSize {
+            batch_id: *batch_id,
+        };
+        let requests = self
+            .aggregate_store_shards(task_id, task_config.as_ref())
+            .await?
+            .map(|shard| {
+                durable.request(
+                    bindings::AggregateStore::Get,
+                    (task_config.as_ref().version, &task_id_hex, &bucket, shard),
+                )
+            });
+        let agg_shares = durable
+            .send_batch::<_, _, DapAggregateShare>(requests)
             .await
+            .map_err(|e| fatal_error!(err = ?e))?
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
             .map_err(|e| fatal_error!(err = ?e))?;
 
-        Ok(!agg_share.empty())
+        Ok(agg_shares.iter().any(|agg_share| !agg_share.empty()))
     }
 
     fn metrics(&self) -> &dyn DaphneMetrics {
@@ -344,7 +539,7 @@ impl DapAggregator<DaphneAuth> for crate::App {
     }
 
     fn audit_log(&self) -> &dyn AuditLog {
-        &NoopAuditLog
+        &*self.audit_log
     }
 
     fn host(&self) -> &str {
@@ -352,6 +547,71 @@ impl DapAggregator<DaphneAuth> for crate::App {
     }
 }
 
+impl crate::App {
+    /// The number of shards of the aggregate store used for a task.
+    ///
+    /// A report is owned by the shard determined by the shard count, so changing it would let a
+    /// report aggregated before the change be replayed after it. The shard count is therefore
+    /// fixed the first time the aggregate store is used for the task.
+    async fn aggregate_store_shard_count(
+        &self,
+        task_id: &TaskId,
+        task_config: &DapTaskConfig,
+    ) -> Result<u16, DapError> {
+        // The maximum batch size, if any, is enforced by the instance a bucket is merged into,
+        // which only counts the reports merged into it. Merge every aggregation job into the same
+        // instance so that the check is atomic.
+        if task_config.query.max_batch_size().is_some() {
+            return Ok(1);
+        }
+
+        let configured = self.service_config.aggregate_store.shard_count_for(task_id);
+        let pinned = match self
+            .kv()
+            .get::<kv::prefix::AggregateStoreShardCount>(task_id)
+            .await
+            .map_err(|e| fatal_error!(err = ?e))?
+        {
+            Some(pinned) => pinned,
+            None => match self
+                .kv()
+                .put_if_not_exists::<kv::prefix::AggregateStoreShardCount>(task_id, configured)
+                .await
+                .map_err(|e| fatal_error!(err = ?e))?
+            {
+                None => configured,
+                // Another request fixed the shard count first.
+                Some(_) => self
+                    .kv()
+                    .get::<kv::prefix::AggregateStoreShardCount>(task_id)
+                    .await
+                    .map_err(|e| fatal_error!(err = ?e))?
+                    .ok_or_else(|| fatal_error!(err = "shard count of task not found"))?,
+            },
+        };
+        if pinned != configured {
+            tracing::warn!(
+                %task_id,
+                pinned,
+                configured,
+                "ignoring change to the shard count of the aggregate store for an existing task"
+            );
+        }
+        Ok(pinned)
+    }
+
+    /// The shards of the aggregate store that hold the aggregate share of each bucket of a task.
+    async fn aggregate_store_shards(
+        &self,
+        task_id: &TaskId,
+        task_config: &DapTaskConfig,
+    ) -> Result<Range<u16>, DapError> {
+        Ok(0..self
+            .aggregate_store_shard_count(task_id, task_config)
+            .await?)
+    }
+}
+
 #[async_trait]
 impl DapReportInitializer for crate::App {
     async fn initialize_reports(
@@ -403,7 +663,8 @@ impl DapReportInitializer for crate::App {
 
 #[async_trait]
 impl HpkeDecrypter for crate::App {
-    type WrappedHpkeConfig<'a> = HpkeConfig
+    type WrappedHpkeConfig<'a>
+        = HpkeConfig
     where
         Self: 'a;
 
@@ -480,8 +741,10 @@ impl HpkeDecrypter for crate::App {
 
 #[async_trait]
 impl BearerTokenProvider for crate::App {
-    type WrappedBearerToken<'a> = Cow<'a,  BearerToken>
-        where Self: 'a;
+    type WrappedBearerToken<'a>
+        = Cow<'a, BearerToken>
+    where
+        Self: 'a;
 
     async fn get_leader_bearer_token_for<'s>(
         &'s self,
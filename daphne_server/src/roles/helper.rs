@@ -41,6 +41,62 @@ impl DapHelper<DaphneAuth> for crate::App {
             .map_err(|e| fatal_error!(err = ?e))?)
     }
 
+    async fn put_helper_state<Id>(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: Id,
+        helper_state: &DapAggregationJobState,
+    ) -> Result<(), DapError>
+    where
+        Id: Into<MetaAggregationJobId> + Send,
+    {
+        let task_config = self
+            .get_task_config_for(task_id)
+            .await?
+            .ok_or(DapError::Abort(DapAbort::UnrecognizedTask))?;
+        let helper_state_hex = hex::encode(helper_state.get_encoded().map_err(DapError::encoding)?);
+        self.durable()
+            .with_retry()
+            .request(
+                bindings::HelperState::Put,
+                (task_config.as_ref().version, task_id, &agg_job_id.into()),
+            )
+            .encode_bincode(helper_state_hex)
+            .send()
+            .await
+            .map_err(|e| fatal_error!(err = ?e))
+    }
+
+    async fn put_helper_state_if_unchanged<Id>(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: Id,
+        current: &DapAggregationJobState,
+        helper_state: &DapAggregationJobState,
+    ) -> Result<bool, DapError>
+    where
+        Id: Into<MetaAggregationJobId> + Send,
+    {
+        let task_config = self
+            .get_task_config_for(task_id)
+            .await?
+            .ok_or(DapError::Abort(DapAbort::UnrecognizedTask))?;
+        let current_hex = hex::encode(current.get_encoded().map_err(DapError::encoding)?);
+        let helper_state_hex = hex::encode(helper_state.get_encoded().map_err(DapError::encoding)?);
+        // Not retried: If the state was replaced but the response was lost, then a retry would
+        // report failure.
+        Ok(self
+            .durable()
+            .request(
+                bindings::HelperState::PutIfUnchanged,
+                (task_config.as_ref().version, task_id, &agg_job_id.into()),
+            )
+            .encode_bincode((current_hex, helper_state_hex))
+            .send()
+            .await
+            .map_err(|e| fatal_error!(err = ?e))?)
+    }
+
     async fn get_helper_state<Id>(
         &self,
         task_id: &TaskId,
@@ -53,8 +109,8 @@ impl DapHelper<DaphneAuth> for crate::App {
             .get_task_config_for(task_id)
             .await?
             .ok_or(DapError::Abort(DapAbort::UnrecognizedTask))?;
-        // TODO(cjpatton) Figure out if retry is safe, since the request is not actually
-        // idempotent. (It removes the helper's state from storage if it exists.)
+        // Retrying is safe: reading the state does not remove it from storage. The Helper relies
+        // on this to respond to retried aggregation job requests.
         let res: Option<String> = self
             .durable()
             .with_retry()
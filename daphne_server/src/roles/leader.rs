@@ -3,17 +3,18 @@
 
 #![allow(unused_variables)]
 
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use axum::{async_trait, http::Method};
 use daphne::{
     auth::BearerTokenProvider,
-    constants::DapMediaType,
-    error::DapAbort,
+    constants::{DapMediaType, DAP_AGG_JOB_STATUS_HEADER, DAP_AGG_JOB_STATUS_PROCESSING},
+    error::{aborts::ProblemDetails, DapAbort},
     fatal_error,
     messages::{BatchId, BatchSelector, Collection, CollectionJobId, Report, TaskId},
     roles::{leader::WorkItem, DapAggregator, DapAuthorizedSender, DapLeader},
-    DapAggregationParam, DapCollectionJob, DapError, DapRequest, DapResponse, DapTaskConfig,
+    DapAggregationParam, DapCollectionJob, DapError, DapRequest, DapResponse, DapResponseStatus,
+    DapTaskConfig,
 };
 use daphne_service_utils::auth::DaphneAuth;
 use tracing::{error, info};
@@ -51,10 +52,13 @@ impl DapLeader<DaphneAuth> for crate::App {
             .await?
             .ok_or(DapAbort::UnrecognizedTask)?;
 
-        self.test_leader_state
-            .lock()
-            .await
-            .put_report(task_id, &task_config, report.clone())
+        self.test_leader_state.lock().await.put_report(
+            task_id,
+            &task_config,
+            report.clone(),
+            &self.get_global_config().agg_job_policy,
+            self.get_current_time(),
+        )
     }
 
     async fn current_batch(&self, task_id: &TaskId) -> Result<BatchId, DapError> {
@@ -87,6 +91,7 @@ impl DapLeader<DaphneAuth> for crate::App {
             coll_job_id,
             batch_sel,
             agg_param,
+            &self.get_global_config().agg_job_policy,
         )
     }
 
@@ -114,13 +119,37 @@ impl DapLeader<DaphneAuth> for crate::App {
     }
 
     async fn dequeue_work(&self, num_items: usize) -> Result<Vec<WorkItem>, DapError> {
-        self.test_leader_state.lock().await.dequeue_work(num_items)
+        self.test_leader_state.lock().await.dequeue_work(
+            num_items,
+            &self.get_global_config().agg_job_policy,
+            self.get_current_time(),
+        )
     }
 
     async fn enqueue_work(&self, items: Vec<WorkItem>) -> Result<(), DapError> {
         self.test_leader_state.lock().await.enqueue_work(items)
     }
 
+    async fn dead_letter_work(&self, item: WorkItem, error: &DapError) -> Result<(), DapError> {
+        self.test_leader_state
+            .lock()
+            .await
+            .dead_letter_work(item, error);
+        Ok(())
+    }
+
+    async fn batch_has_dead_lettered_reports(
+        &self,
+        task_id: &TaskId,
+        batch_sel: &BatchSelector,
+    ) -> Result<bool, DapError> {
+        Ok(self
+            .test_leader_state
+            .lock()
+            .await
+            .batch_has_dead_lettered_reports(task_id, batch_sel))
+    }
+
     async fn send_http_post(
         &self,
         req: DapRequest<DaphneAuth>,
@@ -136,6 +165,20 @@ impl DapLeader<DaphneAuth> for crate::App {
     ) -> Result<DapResponse, DapError> {
         self.send_http(req, Method::PUT, url).await
     }
+
+    async fn send_http_get(
+        &self,
+        req: DapRequest<DaphneAuth>,
+        url: Url,
+    ) -> Result<DapResponse, DapError> {
+        self.send_http(req, Method::GET, url).await
+    }
+
+    async fn wait_before_agg_job_poll(&self, attempt: u32) {
+        // Back off exponentially, waiting at most 16 seconds between polls.
+        let wait = Duration::from_millis(500 << attempt.min(5));
+        tokio::time::sleep(wait).await;
+    }
 }
 
 impl crate::App {
@@ -192,10 +235,14 @@ impl crate::App {
             .headers(headers);
 
         let start = Instant::now();
-        let reqwest_resp = req_builder
-            .send()
-            .await
-            .map_err(|e| fatal_error!(err = ?e))?;
+        let reqwest_resp = self.http.send(req_builder).await.map_err(|e| {
+            // The request may succeed if retried later.
+            if e.is_timeout() || e.is_connect() {
+                DapError::Transient(format!("request to {url} failed: {e}"))
+            } else {
+                fatal_error!(err = ?e)
+            }
+        })?;
         info!("request to {} completed in {:?}", url, start.elapsed());
         let status = reqwest_resp.status();
 
@@ -212,6 +259,17 @@ impl crate::App {
                 .find_map(|h| DapMediaType::from_str_for_version(req.version, h))
                 .ok_or_else(|| fatal_error!(err = INT_ERR_PEER_RESP_MISSING_MEDIA_TYPE))?;
 
+            // The Helper accepted the aggregation job but hasn't finished processing it.
+            let status = if reqwest_resp
+                .headers()
+                .get(DAP_AGG_JOB_STATUS_HEADER)
+                .is_some_and(|status| status == DAP_AGG_JOB_STATUS_PROCESSING)
+            {
+                DapResponseStatus::Processing
+            } else {
+                DapResponseStatus::Done
+            };
+
             let payload = reqwest_resp
                 .bytes()
                 .await
@@ -222,21 +280,33 @@ impl crate::App {
                 version: req.version,
                 payload,
                 media_type,
+                status,
             })
         } else {
             error!("{}: request failed: {:?}", url, reqwest_resp);
+            if status.is_server_error()
+                || status == reqwest::StatusCode::REQUEST_TIMEOUT
+                || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            {
+                // The peer is temporarily unable to handle the request.
+                return Err(DapError::Transient(format!(
+                    "request to {url} failed with status {status}"
+                )));
+            }
             if status == 400 {
                 if let Some(content_type) =
                     reqwest_resp.headers().get(reqwest::header::CONTENT_TYPE)
                 {
                     if content_type == "application/problem+json" {
-                        error!(
-                            "Problem details: {}",
-                            reqwest_resp
-                                .text()
-                                .await
-                                .map_err(|e| fatal_error!(err = ?e))?
-                        );
+                        let text = reqwest_resp
+                            .text()
+                            .await
+                            .map_err(|e| fatal_error!(err = ?e))?;
+                        error!("Problem details: {}", text);
+                        let problem: ProblemDetails = serde_json::from_str(&text).map_err(
+                            |e| fatal_error!(err = ?e, "failed to parse problem details"),
+                        )?;
+                        return Err(DapError::from_problem_details(problem));
                     }
                 }
             }
@@ -44,17 +44,19 @@ mod test_utils {
             self.test_leader_state.lock().await.delete_all();
 
             use daphne_service_utils::durable_requests::PURGE_STORAGE;
-            *self.cache.write().await = Default::default();
+            self.cache.write().await.clear();
 
-            self.http
+            let req = self
+                .http
                 .delete(self.storage_proxy_config.url.join(PURGE_STORAGE).unwrap())
                 .header(
                     DAP_STORAGE_AUTH_TOKEN,
                     self.storage_proxy_config
                         .auth_token
                         .to_standard_header_value(),
-                )
-                .send()
+                );
+            self.http
+                .send(req)
                 .await
                 .map_err(|e| fatal_error!(err = ?e))?
                 .error_for_status()
@@ -65,15 +67,17 @@ mod test_utils {
 
         pub(crate) async fn storage_ready_check(&self) -> Result<(), DapError> {
             use daphne_service_utils::durable_requests::STORAGE_READY;
-            self.http
+            let req = self
+                .http
                 .get(self.storage_proxy_config.url.join(STORAGE_READY).unwrap())
                 .header(
                     DAP_STORAGE_AUTH_TOKEN,
                     self.storage_proxy_config
                         .auth_token
                         .to_standard_header_value(),
-                )
-                .send()
+                );
+            self.http
+                .send(req)
                 .await
                 .map_err(|e| fatal_error!(err = ?e))?
                 .error_for_status()
@@ -127,6 +131,8 @@ mod test_utils {
                         chunk_length: chunk_length.parse().map_err(|e| fatal_error!(err = ?e))?,
                     })
                 }
+                (typ, None, None, None) => VdafConfig::from_registered_name(typ)
+                    .ok_or_else(|| fatal_error!(err = "command failed: unrecognized VDAF"))?,
                 _ => return Err(fatal_error!(err = "command failed: unrecognized VDAF")),
             };
 
@@ -220,6 +226,7 @@ mod test_utils {
                         expiration: cmd.task_expiration,
                         min_batch_size: cmd.min_batch_size,
                         query,
+                        max_batch_query_count: cmd.max_batch_query_count.unwrap_or(1),
                         vdaf,
                         vdaf_verify_key,
                         collector_hpke_config,
@@ -0,0 +1,160 @@
+// Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use std::sync::Arc;
+
+use axum::{
+    body::HttpBody,
+    extract::State,
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::post,
+    Json,
+};
+use daphne::{
+    auth::BearerToken,
+    messages::{BatchSelector, TaskId},
+    roles::diagnostics::{self, DapBatchReportIds},
+    DapError,
+};
+use serde::Deserialize;
+
+use crate::App;
+
+/// Add the administrative routes. Requests are rejected unless they carry the admin token.
+pub(super) fn add_admin_routes<B>(router: super::Router<App, B>) -> super::Router<App, B>
+where
+    B: Send + HttpBody + 'static,
+    B::Data: Send,
+    B::Error: Send + Sync + Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    router
+        .route(
+            "/internal/admin/kv_cache/invalidate",
+            post(invalidate_kv_cache),
+        )
+        .route(
+            "/internal/admin/diagnostics/report_ids",
+            post(batch_report_ids),
+        )
+        .route(
+            "/internal/admin/diagnostics/batch_mismatch",
+            post(batch_mismatch),
+        )
+}
+
+/// Check that the request carries the configured admin token as "Authorization: Bearer <token>".
+fn is_authorized(app: &App, headers: &HeaderMap) -> bool {
+    let Some(admin_token) = &app.service_config.admin_token else {
+        return false;
+    };
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|token| BearerToken::from(token) == *admin_token)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InvalidateKvCache {
+    /// The KV prefix, e.g. "bearer_token/leader/task". If not set, the entire cache is dropped.
+    #[serde(default)]
+    prefix: Option<String>,
+    /// The key within the prefix. If not set, every entry under the prefix is dropped.
+    #[serde(default)]
+    key: Option<String>,
+}
+
+#[tracing::instrument(skip(app, headers))]
+async fn invalidate_kv_cache(
+    State(app): State<Arc<App>>,
+    headers: HeaderMap,
+    Json(cmd): Json<InvalidateKvCache>,
+) -> Response {
+    if !is_authorized(&app, &headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    if cmd.prefix.is_none() && cmd.key.is_some() {
+        return (StatusCode::BAD_REQUEST, "key requires a prefix").into_response();
+    }
+
+    let invalidated = app
+        .kv()
+        .invalidate_cache(cmd.prefix.as_deref(), cmd.key.as_deref())
+        .await;
+    tracing::info!(invalidated, "invalidated kv cache entries");
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({ "invalidated": invalidated })),
+    )
+        .into_response()
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BatchReportIds {
+    /// The task ID, hex-encoded.
+    task_id: TaskId,
+    batch_sel: BatchSelector,
+}
+
+/// Return the IDs of the reports this Aggregator aggregated into a batch.
+#[tracing::instrument(skip(app, headers))]
+async fn batch_report_ids(
+    State(app): State<Arc<App>>,
+    headers: HeaderMap,
+    Json(cmd): Json<BatchReportIds>,
+) -> Response {
+    if !is_authorized(&app, &headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    match diagnostics::get_batch_report_ids(&*app, &cmd.task_id, &cmd.batch_sel).await {
+        Ok(report_ids) => (StatusCode::OK, Json(report_ids)).into_response(),
+        Err(e) => diagnostics_error(&e),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BatchMismatch {
+    /// The task ID, hex-encoded.
+    task_id: TaskId,
+    batch_sel: BatchSelector,
+    /// The response of the Helper's "/internal/admin/diagnostics/report_ids" endpoint for the same
+    /// batch.
+    helper_report_ids: DapBatchReportIds,
+}
+
+/// Leader: Compare the reports the Leader aggregated into a batch with those the Helper
+/// aggregated and return the buckets in which they differ.
+#[tracing::instrument(skip(app, headers, cmd), fields(task_id = %cmd.task_id))]
+async fn batch_mismatch(
+    State(app): State<Arc<App>>,
+    headers: HeaderMap,
+    Json(cmd): Json<BatchMismatch>,
+) -> Response {
+    if !is_authorized(&app, &headers) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    match diagnostics::get_batch_report_ids(&*app, &cmd.task_id, &cmd.batch_sel).await {
+        Ok(leader_report_ids) => {
+            let mismatches =
+                diagnostics::diagnose_batch_mismatch(&leader_report_ids, &cmd.helper_report_ids);
+            tracing::info!(buckets = mismatches.len(), "diagnosed batch mismatch");
+            (StatusCode::OK, Json(mismatches)).into_response()
+        }
+        Err(e) => diagnostics_error(&e),
+    }
+}
+
+fn diagnostics_error(e: &DapError) -> Response {
+    let status = match e {
+        DapError::Abort(_) => StatusCode::BAD_REQUEST,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    tracing::error!(error = ?e, "diagnostics request failed");
+    (status, e.to_string()).into_response()
+}
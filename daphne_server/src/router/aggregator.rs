@@ -91,7 +91,8 @@ mod test {
     };
     use daphne::messages::{Base64Encode, TaskId};
     use daphne::{
-        constants::DapMediaType, messages::decode_base64url_vec, DapResponse, DapVersion,
+        constants::DapMediaType, messages::decode_base64url_vec, DapResponse, DapResponseStatus,
+        DapVersion,
     };
     use p256::pkcs8::EncodePrivateKey;
     use rand::{thread_rng, Rng};
@@ -171,6 +172,7 @@ mod test {
         let resp = DapResponse {
             version: DapVersion::default(),
             media_type: DapMediaType::HpkeConfigList,
+            status: DapResponseStatus::Done,
             payload: PAYLOAD.to_vec(),
         };
 
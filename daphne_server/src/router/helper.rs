@@ -7,7 +7,9 @@ use axum::{body::HttpBody, extract::State, routing::post};
 use daphne::{
     constants::DapMediaType,
     error::DapAbort,
+    messages::TaskId,
     roles::{helper, DapHelper},
+    DapResource, DapResponseStatus, MetaAggregationJobId,
 };
 use daphne_service_utils::auth::DaphneAuth;
 use http::StatusCode;
@@ -28,7 +30,7 @@ where
         .route("/:version/aggregate_share", post(agg_share))
         .route(
             "/:version/tasks/:task_id/aggregation_jobs/:agg_job_id",
-            post(agg_job).put(agg_job),
+            post(agg_job).put(agg_job).get(agg_job_poll),
         )
         .route("/:version/tasks/:task_id/aggregate_shares", post(agg_share))
 }
@@ -46,17 +48,30 @@ async fn agg_job<A>(
     DapRequestExtractor(req): DapRequestExtractor,
 ) -> AxumDapResponse
 where
-    A: DapHelper<DaphneAuth> + DaphneService + Send + Sync,
+    A: DapHelper<DaphneAuth> + DaphneService + Send + Sync + 'static,
 {
     match req.media_type {
         Some(DapMediaType::AggregationJobInitReq) => {
             let resp = helper::handle_agg_job_init_req(&*app, &req).await;
+            let processing = resp
+                .as_ref()
+                .is_ok_and(|resp| resp.status == DapResponseStatus::Processing);
+            if let (true, Ok(task_id), DapResource::AggregationJob(agg_job_id)) =
+                (processing, req.task_id(), &req.resource)
+            {
+                spawn_agg_job(
+                    Arc::clone(&app),
+                    *task_id,
+                    MetaAggregationJobId::Draft09(*agg_job_id),
+                );
+            }
             AxumDapResponse::from_result_with_success_code(
                 resp,
                 app.server_metrics(),
-                match req.version {
-                    daphne::DapVersion::Draft02 => StatusCode::OK,
-                    daphne::DapVersion::Draft09 | daphne::DapVersion::Latest => StatusCode::CREATED,
+                if req.version == daphne::DapVersion::Draft02 {
+                    StatusCode::OK
+                } else {
+                    StatusCode::CREATED
                 },
             )
         }
@@ -71,6 +86,49 @@ where
     }
 }
 
+/// Process an aggregation job that was accepted for asynchronous processing in the background. If
+/// this fails, then the aggregation job is processed again when the Leader polls for it.
+fn spawn_agg_job<A>(app: Arc<A>, task_id: TaskId, agg_job_id: MetaAggregationJobId)
+where
+    A: DapHelper<DaphneAuth> + DaphneService + Send + Sync + 'static,
+{
+    tokio::spawn(async move {
+        if let Err(error) = helper::process_agg_job(&*app, &task_id, agg_job_id).await {
+            tracing::error!(?error, %task_id, "failed to process aggregation job");
+        }
+    });
+}
+
+#[tracing::instrument(
+    skip_all,
+    fields(
+        media_type = ?req.media_type,
+        task_id = ?req.task_id().ok(),
+        version = ?req.version
+    )
+)]
+async fn agg_job_poll<A>(
+    State(app): State<Arc<A>>,
+    DapRequestExtractor(req): DapRequestExtractor,
+) -> AxumDapResponse
+where
+    A: DapHelper<DaphneAuth> + DaphneService + Send + Sync,
+{
+    let resp = helper::handle_agg_job_poll_req(&*app, &req).await;
+    let processing = resp
+        .as_ref()
+        .is_ok_and(|resp| resp.status == DapResponseStatus::Processing);
+    AxumDapResponse::from_result_with_success_code(
+        resp,
+        app.server_metrics(),
+        if processing {
+            StatusCode::ACCEPTED
+        } else {
+            StatusCode::OK
+        },
+    )
+}
+
 #[tracing::instrument(
     skip_all,
     fields(
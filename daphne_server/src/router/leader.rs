@@ -142,6 +142,7 @@ where
             daphne::DapResponse {
                 version: req.version,
                 media_type: DapMediaType::Collection,
+                status: daphne::DapResponseStatus::Done,
                 payload: match collect_resp.get_encoded_with_param(&req.version) {
                     Ok(payload) => payload,
                     Err(e) => {
@@ -1,6 +1,7 @@
 // Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
+mod admin;
 mod aggregator;
 mod helper;
 mod leader;
@@ -20,11 +21,11 @@ use axum::{
 };
 use daphne::{
     auth::BearerToken,
-    constants::DapMediaType,
-    error::DapAbort,
+    constants::{DapMediaType, DAP_AGG_JOB_STATUS_HEADER, DAP_AGG_JOB_STATUS_PROCESSING},
+    error::{aborts::ProblemDetails, DapAbort},
     fatal_error,
     messages::{AggregationJobId, CollectionJobId, TaskId},
-    DapError, DapRequest, DapResource, DapResponse, DapVersion,
+    DapError, DapRequest, DapResource, DapResponse, DapResponseStatus, DapVersion,
 };
 use daphne_service_utils::{
     auth::{DaphneAuth, TlsClientAuth},
@@ -77,6 +78,12 @@ where
         DapRole::Helper => helper::add_helper_routes(router),
     };
 
+    let router = if aggregator.service_config.admin_token.is_some() {
+        admin::add_admin_routes(router)
+    } else {
+        router
+    };
+
     #[cfg(feature = "test-utils")]
     let router = test_routes::add_test_routes(router, role);
 
@@ -102,15 +109,35 @@ where
         resp
     }
 
+    /// Set the `instance` of the problem details document of an error response to the path of the
+    /// request that caused it.
+    async fn problem_details_instance<B>(
+        req: Request<B>,
+        next: Next<B>,
+    ) -> axum::response::Response {
+        let path = req.uri().path().to_owned();
+        let mut resp = next.run(req).await;
+        match resp.extensions_mut().remove::<ProblemDetails>() {
+            Some(problem_details) => {
+                let (parts, _body) = resp.into_parts();
+                let body = Json(problem_details.with_instance(path))
+                    .into_response()
+                    .into_body();
+                axum::response::Response::from_parts(parts, body)
+            }
+            None => resp,
+        }
+    }
+
     let app = Arc::new(aggregator);
-    router
-        .with_state(app.clone())
-        .layer(
-            tower::ServiceBuilder::new().layer(axum::middleware::from_fn_with_state(
+    router.with_state(app.clone()).layer(
+        tower::ServiceBuilder::new()
+            .layer(axum::middleware::from_fn_with_state(
                 app.clone(),
                 request_metrics,
-            )),
-        )
+            ))
+            .layer(axum::middleware::from_fn(problem_details_instance)),
+    )
 }
 
 struct AxumDapResponse(axum::response::Response);
@@ -147,7 +174,14 @@ impl AxumDapResponse {
 
         let headers = [(CONTENT_TYPE, media_type)];
 
-        Self((status_code, headers, response.payload).into_response())
+        let mut http_response = (status_code, headers, response.payload).into_response();
+        if response.status == DapResponseStatus::Processing {
+            http_response.headers_mut().insert(
+                DAP_AGG_JOB_STATUS_HEADER,
+                HeaderValue::from_static(DAP_AGG_JOB_STATUS_PROCESSING),
+            );
+        }
+        Self(http_response)
     }
 
     pub fn new_error<E: Into<DapError>>(error: E, metrics: &dyn DaphneServiceMetrics) -> Self {
@@ -156,6 +190,21 @@ impl AxumDapResponse {
             DapError::Transition(failure) => DapAbort::report_rejected(failure),
             DapError::Fatal(e) => Err(e),
             DapError::Abort(abort) => Ok(abort),
+            DapError::Transient(e) => {
+                let DapError::Fatal(fatal) = fatal_error!(err = %e, "transient error") else {
+                    unreachable!("fatal_error! should always create a DapError::Fatal");
+                };
+                Err(fatal)
+            }
+            // An abort by our peer is not the fault of whoever sent us the request.
+            DapError::PeerAbort { abort, instance } => {
+                let DapError::Fatal(fatal) =
+                    fatal_error!(err = %abort, ?instance, "request aborted by peer")
+                else {
+                    unreachable!("fatal_error! should always create a DapError::Fatal");
+                };
+                Err(fatal)
+            }
         };
         let status = if let Err(_e) = &error {
             // TODO(mendess) uncomment the line below
@@ -179,7 +228,11 @@ impl AxumDapResponse {
         metrics.abort_count_inc(&problem_details.title);
         let headers = [(CONTENT_TYPE, "application/problem+json")];
 
-        Self((status, headers, Json(problem_details)).into_response())
+        // The request path isn't known here, so the document is also attached to the response for
+        // `problem_details_instance` to fill it in.
+        let mut response = (status, headers, Json(&problem_details)).into_response();
+        response.extensions_mut().insert(problem_details);
+        Self(response)
     }
 
     pub fn from_result<E>(
@@ -305,8 +358,10 @@ where
 
         let taskprov = extract_header_as_string("dap-taskprov");
 
-        // TODO(mendess): this is very eager, we could redesign DapResponse later to allow for
-        // streaming of data.
+        // TODO(mendess): this is very eager, we could redesign DapRequest later to allow for
+        // streaming of data. The Helper decodes aggregation job requests without copying the
+        // reports out of the payload, but it still only starts preparing them once the whole
+        // body has arrived.
         let payload = hyper::body::to_bytes(body).await;
 
         let Ok(payload) = payload else {
@@ -383,8 +438,9 @@ mod test {
     };
     use daphne::{
         async_test_version, async_test_versions,
+        constants::{DapMediaType, DAP_AGG_JOB_STATUS_HEADER, DAP_AGG_JOB_STATUS_PROCESSING},
         messages::{AggregationJobId, Base64Encode, TaskId},
-        DapRequest, DapResource, DapVersion,
+        DapRequest, DapResource, DapResponse, DapResponseStatus, DapVersion,
     };
     use daphne_service_utils::{auth::DaphneAuth, metrics::DaphnePromServiceMetrics};
     use futures::future::BoxFuture;
@@ -392,7 +448,7 @@ mod test {
     use tokio::sync::mpsc::{self, Sender};
     use tower::ServiceExt;
 
-    use super::DapRequestExtractor;
+    use super::{AxumDapResponse, DapRequestExtractor};
 
     /// Return a function that will parse a request using the [`DapRequestExtractor`] and return
     /// the parsed request.
@@ -528,4 +584,33 @@ mod test {
 
     async_test_version! { parse_agg_job_id, Draft09 }
     async_test_version! { parse_agg_job_id, Latest }
+
+    #[test]
+    fn agg_job_processing_response() {
+        let metrics = DaphnePromServiceMetrics::register(&prometheus::Registry::new()).unwrap();
+        let response = |status| {
+            AxumDapResponse::new_success_with_code(
+                DapResponse {
+                    version: DapVersion::Draft09,
+                    media_type: DapMediaType::AggregationJobResp,
+                    status,
+                    payload: Vec::new(),
+                },
+                &metrics,
+                StatusCode::CREATED,
+            )
+            .into_response()
+        };
+
+        let resp = response(DapResponseStatus::Processing);
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(
+            resp.headers().get(DAP_AGG_JOB_STATUS_HEADER).unwrap(),
+            DAP_AGG_JOB_STATUS_PROCESSING
+        );
+
+        let resp = response(DapResponseStatus::Done);
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert!(resp.headers().get(DAP_AGG_JOB_STATUS_HEADER).is_none());
+    }
 }
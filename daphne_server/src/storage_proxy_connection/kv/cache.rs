@@ -1,36 +1,118 @@
 // Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
-use std::{any::Any, collections::HashMap};
+use std::{
+    any::Any,
+    collections::{BTreeMap, HashMap},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
 
 use super::KvPrefix;
 
+/// Configuration of the cache of KV values.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// Number of seconds a value is cached for, unless overridden by `prefix_ttl_secs`. A value
+    /// of 0 disables caching.
+    pub ttl_secs: u64,
+
+    /// Number of seconds values are cached for, keyed by KV prefix (e.g. "config/task").
+    pub prefix_ttl_secs: HashMap<String, u64>,
+
+    /// Number of seconds to remember that a key was not found in KV. A value of 0 disables
+    /// negative caching.
+    pub not_found_ttl_secs: u64,
+
+    /// Maximum number of entries in the cache. When the cache is full, the least recently used
+    /// entry is evicted.
+    pub max_entries: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            ttl_secs: 300,
+            prefix_ttl_secs: HashMap::new(),
+            not_found_ttl_secs: 0,
+            max_entries: 10_000,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    /// The cached value, or `None` if the key is known not to exist.
+    value: Option<Box<dyn Any + Send + Sync + 'static>>,
+    expires_at: Instant,
+    /// Value of [`Cache::clock`] when this entry was last read or written.
+    last_used: AtomicU64,
+    /// Key of this entry in [`Cache::lru`].
+    lru_tick: u64,
+}
+
 #[derive(Default, Debug)]
 pub struct Cache {
+    config: CacheConfig,
+
     /// This map follows the same structure of KV queries.
     /// The first key (&'static str) is a KvPrefix::PREFIX
     /// The second key (String) is the key that is associated with this value
-    kv: HashMap<&'static str, HashMap<String, Box<dyn Any + Send + Sync + 'static>>>,
+    kv: HashMap<&'static str, HashMap<String, CacheEntry>>,
+
+    /// Total number of entries across all prefixes.
+    len: usize,
+
+    /// Logical clock used to determine the least recently used entry.
+    clock: AtomicU64,
+
+    /// Every entry keyed by its `last_used` tick at the time it was indexed. Reads only update
+    /// `last_used`, so an entry may have been used since; such entries are re-indexed when they
+    /// come up for eviction.
+    lru: BTreeMap<u64, (&'static str, String)>,
 }
 
 pub enum GetResult<T> {
     NoFound,
+    /// The key is known not to exist in KV.
+    Absent,
     MismatchedType,
     Found(T),
 }
 
 impl Cache {
+    pub fn new(config: CacheConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
     pub fn get<'s, P>(&'s self, key: &str) -> GetResult<&'s P::Value>
     where
         P: KvPrefix,
     {
-        match self.kv.get(P::PREFIX) {
-            Some(cache) => match cache.get(key).map(|t| t.downcast_ref::<P::Value>()) {
-                Some(Some(t)) => GetResult::Found(t),
-                Some(None) => GetResult::MismatchedType,
-                None => GetResult::NoFound,
-            },
-            None => GetResult::NoFound,
+        self.get_at::<P>(key, Instant::now())
+    }
+
+    fn get_at<'s, P>(&'s self, key: &str, now: Instant) -> GetResult<&'s P::Value>
+    where
+        P: KvPrefix,
+    {
+        let Some(entry) = self.kv.get(P::PREFIX).and_then(|cache| cache.get(key)) else {
+            return GetResult::NoFound;
+        };
+        if entry.expires_at <= now {
+            return GetResult::NoFound;
+        }
+        entry.last_used.store(self.tick(), Ordering::Relaxed);
+        match entry.value.as_ref().map(|t| t.downcast_ref::<P::Value>()) {
+            Some(Some(t)) => GetResult::Found(t),
+            Some(None) => GetResult::MismatchedType,
+            None => GetResult::Absent,
         }
     }
 
@@ -38,23 +120,241 @@ impl Cache {
     where
         P: KvPrefix,
     {
-        self.kv
-            .entry(P::PREFIX)
-            .or_default()
-            .insert(key, Box::new(value));
+        self.put_at::<P>(key, Some(Box::new(value)), Instant::now());
+    }
+
+    /// Remember that the key does not exist in KV.
+    pub fn put_not_found<P>(&mut self, key: String)
+    where
+        P: KvPrefix,
+    {
+        self.put_at::<P>(key, None, Instant::now());
+    }
+
+    fn put_at<P>(
+        &mut self,
+        key: String,
+        value: Option<Box<dyn Any + Send + Sync + 'static>>,
+        now: Instant,
+    ) where
+        P: KvPrefix,
+    {
+        let ttl_secs = if value.is_some() {
+            self.config
+                .prefix_ttl_secs
+                .get(P::PREFIX)
+                .copied()
+                .unwrap_or(self.config.ttl_secs)
+        } else {
+            self.config.not_found_ttl_secs
+        };
+        if ttl_secs == 0 || self.config.max_entries == 0 {
+            // Caching is disabled, but make sure we don't keep serving an older value.
+            self.remove(P::PREFIX, &key);
+            return;
+        }
+
+        let tick = self.tick();
+        let entry = CacheEntry {
+            value,
+            expires_at: now + Duration::from_secs(ttl_secs),
+            last_used: AtomicU64::new(tick),
+            lru_tick: tick,
+        };
+        self.lru.insert(tick, (P::PREFIX, key.clone()));
+        if let Some(replaced) = self.kv.entry(P::PREFIX).or_default().insert(key, entry) {
+            self.lru.remove(&replaced.lru_tick);
+        } else {
+            self.len += 1;
+            self.evict();
+        }
     }
 
     pub fn delete<P>(&mut self, key: &str) -> GetResult<P::Value>
     where
         P: KvPrefix,
     {
-        match self.kv.get_mut(P::PREFIX) {
-            Some(cache) => match cache.remove(key).map(|t| t.downcast::<P::Value>().ok()) {
-                Some(Some(t)) => GetResult::Found(*t),
-                Some(None) => GetResult::MismatchedType,
-                None => GetResult::NoFound,
+        match self.remove(P::PREFIX, key).map(|e| e.value) {
+            Some(Some(t)) => match t.downcast::<P::Value>() {
+                Ok(t) => GetResult::Found(*t),
+                Err(_) => GetResult::MismatchedType,
             },
+            Some(None) => GetResult::Absent,
             None => GetResult::NoFound,
         }
     }
+
+    /// Remove entries from the cache. If `prefix` is not set, then every entry is removed; if
+    /// `key` is not set, then every entry under `prefix` is removed. Returns the number of
+    /// entries removed.
+    pub fn invalidate(&mut self, prefix: Option<&str>, key: Option<&str>) -> usize {
+        match (prefix, key) {
+            (None, _) => self.clear(),
+            (Some(prefix), None) => {
+                let Some(cache) = self.kv.remove(prefix) else {
+                    return 0;
+                };
+                for entry in cache.values() {
+                    self.lru.remove(&entry.lru_tick);
+                }
+                self.len -= cache.len();
+                cache.len()
+            }
+            (Some(prefix), Some(key)) => usize::from(self.remove(prefix, key).is_some()),
+        }
+    }
+
+    /// Remove every entry from the cache, keeping its configuration. Returns the number of
+    /// entries removed.
+    pub fn clear(&mut self) -> usize {
+        self.kv.clear();
+        self.lru.clear();
+        std::mem::take(&mut self.len)
+    }
+
+    fn remove(&mut self, prefix: &str, key: &str) -> Option<CacheEntry> {
+        let entry = self.kv.get_mut(prefix)?.remove(key)?;
+        self.lru.remove(&entry.lru_tick);
+        self.len -= 1;
+        Some(entry)
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Drop the least recently used entries until the cache is within its size bound.
+    fn evict(&mut self) {
+        while self.len > self.config.max_entries {
+            let Some((tick, (prefix, key))) = self.lru.pop_first() else {
+                break;
+            };
+            let Some(cache) = self.kv.get_mut(prefix) else {
+                continue;
+            };
+            let Some(entry) = cache.get_mut(&key) else {
+                continue;
+            };
+            let last_used = *entry.last_used.get_mut();
+            if last_used == tick {
+                cache.remove(&key);
+                self.len -= 1;
+            } else {
+                // The entry was read since it was indexed.
+                entry.lru_tick = last_used;
+                self.lru.insert(last_used, (prefix, key));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{Cache, CacheConfig, GetResult};
+    use crate::storage_proxy_connection::kv::prefix::{CollectorBearerToken, LeaderBearerToken};
+    use daphne::auth::BearerToken;
+
+    fn token(s: &str) -> Box<dyn std::any::Any + Send + Sync> {
+        Box::new(BearerToken::from(s))
+    }
+
+    #[test]
+    fn expires_per_prefix() {
+        let now = Instant::now();
+        let mut cache = Cache::new(CacheConfig {
+            ttl_secs: 10,
+            prefix_ttl_secs: [("bearer_token/leader/task".into(), 100)].into(),
+            ..Default::default()
+        });
+        cache.put_at::<LeaderBearerToken>("a".into(), Some(token("leader")), now);
+        cache.put_at::<CollectorBearerToken>("a".into(), Some(token("collector")), now);
+
+        let later = now + Duration::from_secs(50);
+        assert!(matches!(
+            cache.get_at::<LeaderBearerToken>("a", later),
+            GetResult::Found(t) if t.as_str() == "leader"
+        ));
+        assert!(matches!(
+            cache.get_at::<CollectorBearerToken>("a", later),
+            GetResult::NoFound
+        ));
+    }
+
+    #[test]
+    fn caches_not_found() {
+        let now = Instant::now();
+        let mut cache = Cache::new(CacheConfig {
+            not_found_ttl_secs: 5,
+            ..Default::default()
+        });
+        cache.put_at::<LeaderBearerToken>("a".into(), None, now);
+        assert!(matches!(
+            cache.get_at::<LeaderBearerToken>("a", now),
+            GetResult::Absent
+        ));
+        assert!(matches!(
+            cache.get_at::<LeaderBearerToken>("a", now + Duration::from_secs(5)),
+            GetResult::NoFound
+        ));
+
+        // Negative caching is disabled by default.
+        let mut cache = Cache::default();
+        cache.put_at::<LeaderBearerToken>("a".into(), None, now);
+        assert!(matches!(
+            cache.get_at::<LeaderBearerToken>("a", now),
+            GetResult::NoFound
+        ));
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let now = Instant::now();
+        let mut cache = Cache::new(CacheConfig {
+            max_entries: 2,
+            ..Default::default()
+        });
+        cache.put_at::<LeaderBearerToken>("a".into(), Some(token("a")), now);
+        cache.put_at::<LeaderBearerToken>("b".into(), Some(token("b")), now);
+        let _ = cache.get_at::<LeaderBearerToken>("a", now);
+        cache.put_at::<LeaderBearerToken>("c".into(), Some(token("c")), now);
+
+        assert_eq!(cache.len, 2);
+        assert_eq!(cache.lru.len(), 2);
+        assert!(matches!(
+            cache.get_at::<LeaderBearerToken>("a", now),
+            GetResult::Found(_)
+        ));
+        assert!(matches!(
+            cache.get_at::<LeaderBearerToken>("b", now),
+            GetResult::NoFound
+        ));
+        assert!(matches!(
+            cache.get_at::<LeaderBearerToken>("c", now),
+            GetResult::Found(_)
+        ));
+    }
+
+    #[test]
+    fn invalidate() {
+        let now = Instant::now();
+        let mut cache = Cache::default();
+        cache.put_at::<LeaderBearerToken>("a".into(), Some(token("a")), now);
+        cache.put_at::<LeaderBearerToken>("b".into(), Some(token("b")), now);
+        cache.put_at::<CollectorBearerToken>("a".into(), Some(token("a")), now);
+
+        assert_eq!(
+            cache.invalidate(Some("bearer_token/leader/task"), Some("a")),
+            1
+        );
+        assert_eq!(
+            cache.invalidate(Some("bearer_token/leader/task"), Some("a")),
+            0
+        );
+        assert_eq!(cache.invalidate(Some("bearer_token/leader/task"), None), 1);
+        assert_eq!(cache.invalidate(None, None), 1);
+        assert_eq!(cache.len, 0);
+        assert!(cache.lru.is_empty());
+    }
 }
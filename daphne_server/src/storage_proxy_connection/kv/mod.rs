@@ -10,14 +10,15 @@ use daphne_service_utils::durable_requests::KV_PATH_PREFIX;
 use serde::{de::DeserializeOwned, Serialize};
 use tokio::sync::RwLock;
 
-use crate::StorageProxyConfig;
+use crate::{http_transport::HttpClient, StorageProxyConfig};
 
 use super::{status_http_1_0_to_reqwest_0_11, Error};
 pub(crate) use cache::Cache;
+pub use cache::CacheConfig;
 
 pub(crate) struct Kv<'h> {
     config: &'h StorageProxyConfig,
-    http: &'h reqwest::Client,
+    http: &'h HttpClient,
     cache: &'h RwLock<Cache>,
 }
 
@@ -65,12 +66,22 @@ pub mod prefix {
         type Key = TaskId;
         type Value = BearerToken;
     }
+
+    /// The number of shards of the aggregate store used for a task. See
+    /// [`AggregateStoreConfig`](daphne_service_utils::config::AggregateStoreConfig).
+    pub struct AggregateStoreShardCount();
+    impl KvPrefix for AggregateStoreShardCount {
+        const PREFIX: &'static str = "aggregate_store/shard_count/task";
+
+        type Key = TaskId;
+        type Value = u16;
+    }
 }
 
 impl<'h> Kv<'h> {
     pub fn new(
         config: &'h StorageProxyConfig,
-        client: &'h reqwest::Client,
+        client: &'h HttpClient,
         cache: &'h RwLock<Cache>,
     ) -> Self {
         Self {
@@ -98,6 +109,7 @@ impl<'h> Kv<'h> {
         tracing::debug!(key, "GET");
         match self.cache.read().await.get::<P>(&key) {
             cache::GetResult::NoFound => {}
+            cache::GetResult::Absent => return Ok(None),
             cache::GetResult::Found(t) => return Ok(mapper(t)),
             cache::GetResult::MismatchedType => {
                 tracing::warn!(
@@ -106,16 +118,13 @@ impl<'h> Kv<'h> {
                 );
             }
         }
-        let resp = self
-            .http
-            .get(self.config.url.join(&key).unwrap())
-            .header(
-                super::DAP_STORAGE_AUTH_TOKEN,
-                self.config.auth_token.to_standard_header_value(),
-            )
-            .send()
-            .await?;
+        let req = self.http.get(self.config.url.join(&key).unwrap()).header(
+            super::DAP_STORAGE_AUTH_TOKEN,
+            self.config.auth_token.to_standard_header_value(),
+        );
+        let resp = self.http.send(req).await?;
         if resp.status() == status_http_1_0_to_reqwest_0_11(StatusCode::NOT_FOUND) {
+            self.cache.write().await.put_not_found::<P>(key);
             Ok(None)
         } else {
             let resp = resp.error_for_status()?;
@@ -132,16 +141,15 @@ impl<'h> Kv<'h> {
     {
         let key = Self::to_key::<P>(key);
         tracing::debug!(key, "PUT");
-        self.http
+        let req = self
+            .http
             .post(self.config.url.join(&key).unwrap())
             .header(
                 super::DAP_STORAGE_AUTH_TOKEN,
                 self.config.auth_token.to_standard_header_value(),
             )
-            .body(serde_json::to_vec(&value).unwrap())
-            .send()
-            .await?
-            .error_for_status()?;
+            .body(serde_json::to_vec(&value).unwrap());
+        self.http.send(req).await?.error_for_status()?;
         self.cache.write().await.put::<P>(key, value);
         Ok(())
     }
@@ -160,16 +168,15 @@ impl<'h> Kv<'h> {
         let key = Self::to_key::<P>(key);
 
         tracing::debug!(key, "PUT if not exists");
-        let response = self
+        let req = self
             .http
             .put(self.config.url.join(&key).unwrap())
             .header(
                 super::DAP_STORAGE_AUTH_TOKEN,
                 self.config.auth_token.to_standard_header_value(),
             )
-            .body(serde_json::to_vec(&value).unwrap())
-            .send()
-            .await?;
+            .body(serde_json::to_vec(&value).unwrap());
+        let response = self.http.send(req).await?;
 
         if response.status() == status_http_1_0_to_reqwest_0_11(StatusCode::CONFLICT) {
             Ok(Some(value))
@@ -188,6 +195,15 @@ impl<'h> Kv<'h> {
         self.cache.write().await.put::<P>(key, value);
     }
 
+    /// Drop cached values so that they are fetched from KV the next time they are needed. See
+    /// [`Cache::invalidate`] for the meaning of `prefix` and `key`.
+    pub async fn invalidate_cache(&self, prefix: Option<&str>, key: Option<&str>) -> usize {
+        let key = prefix
+            .zip(key)
+            .map(|(prefix, key)| format!("{KV_PATH_PREFIX}/{prefix}/{key}"));
+        self.cache.write().await.invalidate(prefix, key.as_deref())
+    }
+
     fn to_key<P: KvPrefix>(key: &P::Key) -> String {
         format!("{KV_PATH_PREFIX}/{}/{key}", P::PREFIX)
     }
@@ -11,13 +11,15 @@ use std::fmt::Debug;
 
 use axum::http::{Method, StatusCode};
 use daphne_service_utils::durable_requests::{
-    bindings::DurableMethod, DurableRequest, ObjectIdFrom, DO_PATH_PREFIX,
+    batch::{BatchOperation, BatchOperationResponse, BatchRequest, BatchResponse},
+    bindings::DurableMethod,
+    DurableRequest, ObjectIdFrom, BATCH_PATH_PREFIX, DO_PATH_PREFIX,
 };
 use serde::{de::DeserializeOwned, Serialize};
 
 pub(crate) use kv::Kv;
 
-use crate::StorageProxyConfig;
+use crate::{http_transport::HttpClient, StorageProxyConfig};
 
 pub(crate) const DAP_STORAGE_AUTH_TOKEN: &str = "Authorization";
 
@@ -29,17 +31,21 @@ pub(crate) enum Error {
     Reqwest(#[from] reqwest::Error),
     #[error("http error. request returned status code {status} with the body {body}")]
     Http { status: StatusCode, body: String },
+    #[error("batch encoding error: {0}")]
+    Capnp(#[from] capnp::Error),
+    #[error("batch response has {got} responses, expected {expected}")]
+    BatchLength { expected: usize, got: usize },
 }
 
 #[derive(Clone, Copy)]
 pub(crate) struct Do<'h> {
     config: &'h StorageProxyConfig,
-    http: &'h reqwest::Client,
+    http: &'h HttpClient,
     retry: bool,
 }
 
 impl<'h> Do<'h> {
-    pub fn new(config: &'h StorageProxyConfig, client: &'h reqwest::Client) -> Self {
+    pub fn new(config: &'h StorageProxyConfig, client: &'h HttpClient) -> Self {
         Self {
             config,
             http: client,
@@ -77,17 +83,15 @@ impl<'d, B: DurableMethod + Debug, P: AsRef<[u8]>> RequestBuilder<'d, B, P> {
             .url
             .join(&format!("{DO_PATH_PREFIX}{}", self.path.to_uri()))
             .unwrap();
-        let resp = self
-            .durable
-            .http
+        let http = self.durable.http;
+        let req = http
             .post(url)
             .body(self.request.into_bytes())
             .header(
                 DAP_STORAGE_AUTH_TOKEN,
                 self.durable.config.auth_token.to_standard_header_value(),
-            )
-            .send()
-            .await?;
+            );
+        let resp = http.send(req).await?;
 
         if resp.status().is_success() {
             Ok(resp.json().await?)
@@ -100,6 +104,15 @@ impl<'d, B: DurableMethod + Debug, P: AsRef<[u8]>> RequestBuilder<'d, B, P> {
     }
 }
 
+impl<'d, B: DurableMethod, P: AsRef<[u8]>> RequestBuilder<'d, B, P> {
+    fn into_batch_operation(self) -> BatchOperation {
+        BatchOperation {
+            uri: self.path.to_uri().to_owned(),
+            request: self.request.into_bytes(),
+        }
+    }
+}
+
 impl<'d, B: DurableMethod> RequestBuilder<'d, B, [u8; 0]> {
     pub fn encode_bincode<T: Serialize>(self, payload: T) -> RequestBuilder<'d, B, Vec<u8>> {
         self.with_body(bincode::serialize(&payload).unwrap())
@@ -132,6 +145,28 @@ impl<'w> Do<'w> {
         }
     }
 
+    /// Send several requests to the storage proxy in a single round trip. The storage proxy
+    /// executes them concurrently. The responses are returned in the same order as the requests.
+    pub async fn send_batch<'d, B, P, R>(
+        &self,
+        requests: impl IntoIterator<Item = RequestBuilder<'d, B, P>>,
+    ) -> Result<Vec<Result<R, Error>>, Error>
+    where
+        B: DurableMethod + 'd,
+        P: AsRef<[u8]>,
+        R: DeserializeOwned,
+    {
+        let operations = requests
+            .into_iter()
+            .map(RequestBuilder::into_batch_operation)
+            .collect();
+        Ok(send_batch(self.config, self.http, operations)
+            .await?
+            .into_iter()
+            .map(parse_batch_operation_response)
+            .collect())
+    }
+
     pub fn request_with_id<B: DurableMethod + Copy>(
         &self,
         path: B,
@@ -150,6 +185,54 @@ impl<'w> Do<'w> {
     }
 }
 
+/// Send a batch of operations to the storage proxy. Fails only if the batch as a whole could not
+/// be executed; the outcome of each operation is reported in its response.
+async fn send_batch(
+    config: &StorageProxyConfig,
+    http: &HttpClient,
+    operations: Vec<BatchOperation>,
+) -> Result<Vec<BatchOperationResponse>, Error> {
+    let expected = operations.len();
+    tracing::debug!(len = expected, "sending batch");
+    let req = http
+        .post(config.url.join(BATCH_PATH_PREFIX).unwrap())
+        .body(BatchRequest { operations }.into_bytes())
+        .header(
+            DAP_STORAGE_AUTH_TOKEN,
+            config.auth_token.to_standard_header_value(),
+        );
+    let resp = http.send(req).await?;
+
+    if !resp.status().is_success() {
+        return Err(Error::Http {
+            status: status_reqwest_0_11_to_http_1_0(resp.status()),
+            body: resp.text().await?,
+        });
+    }
+
+    let BatchResponse { responses } = BatchResponse::try_from(resp.bytes().await?.as_ref())?;
+    if responses.len() != expected {
+        return Err(Error::BatchLength {
+            expected,
+            got: responses.len(),
+        });
+    }
+    Ok(responses)
+}
+
+fn parse_batch_operation_response<R: DeserializeOwned>(
+    resp: BatchOperationResponse,
+) -> Result<R, Error> {
+    if resp.is_success() {
+        Ok(serde_json::from_slice(&resp.body)?)
+    } else {
+        Err(Error::Http {
+            status: StatusCode::from_u16(resp.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR),
+            body: String::from_utf8_lossy(&resp.body).into_owned(),
+        })
+    }
+}
+
 /// this is needed while [reqwest#2039](https://github.com/seanmonstar/reqwest/issues/2039) isn't
 /// completed.
 ///
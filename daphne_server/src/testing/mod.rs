@@ -0,0 +1,385 @@
+// Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! An in-process deployment of a Leader and a Helper for end-to-end tests. Each aggregator is an
+//! instance of [`router::new`] backed by its own [`InMemoryStorageProxy`]. All HTTP requests,
+//! including those made by the test itself, are delivered by an [`InProcessNetwork`] rather than
+//! over sockets.
+
+mod storage_proxy;
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    async_trait,
+    body::Body,
+    http::{Request, Response, StatusCode},
+};
+use daphne::{fatal_error, DapError};
+use daphne_service_utils::{config::DaphneServiceConfig, metrics::DaphnePromServiceMetrics};
+use tower::ServiceExt;
+use url::Url;
+
+pub use storage_proxy::InMemoryStorageProxy;
+
+use crate::{router, App, HttpTransport, StorageProxyConfig};
+
+/// Token the aggregators of an [`InProcessDeployment`] use to authorize requests to their storage
+/// proxy.
+const STORAGE_PROXY_AUTH_TOKEN: &str = "in-process-storage-proxy-auth-token";
+
+/// A set of in-process HTTP servers, addressed by the host and port of their URL.
+#[derive(Default)]
+pub struct InProcessNetwork {
+    // `axum::Router` is not `Sync`, so the routers are guarded by a `Mutex` rather than a `RwLock`.
+    hosts: Mutex<HashMap<String, axum::Router>>,
+}
+
+impl InProcessNetwork {
+    /// Serve the requests for `url`'s host and port with `router`.
+    pub fn add_host(&self, url: &Url, router: axum::Router) {
+        self.hosts.lock().unwrap().insert(authority(url), router);
+    }
+
+    /// Stop serving requests. The routers are dropped, including any transport they hold to this
+    /// network.
+    pub fn clear(&self) {
+        self.hosts.lock().unwrap().clear();
+    }
+
+    async fn route(&self, request: reqwest::Request) -> Response<Vec<u8>> {
+        let authority = authority(request.url());
+        let Some(router) = self.hosts.lock().unwrap().get(&authority).cloned() else {
+            return error_response(StatusCode::BAD_GATEWAY, format!("unknown host {authority}"));
+        };
+
+        let mut builder = Request::builder()
+            .method(request.method().clone())
+            .uri(request.url().as_str());
+        if let Some(headers) = builder.headers_mut() {
+            *headers = request.headers().clone();
+        }
+        let body = request
+            .body()
+            .and_then(|body| body.as_bytes())
+            .map(<[u8]>::to_vec)
+            .unwrap_or_default();
+        let request = match builder.body(Body::from(body)) {
+            Ok(request) => request,
+            Err(e) => return error_response(StatusCode::BAD_REQUEST, e.to_string()),
+        };
+
+        let response = match router.oneshot(request).await {
+            Ok(response) => response,
+            Err(infallible) => match infallible {},
+        };
+        let (parts, body) = response.into_parts();
+        match hyper::body::to_bytes(body).await {
+            Ok(body) => Response::from_parts(parts, body.to_vec()),
+            Err(e) => error_response(StatusCode::BAD_GATEWAY, e.to_string()),
+        }
+    }
+}
+
+#[async_trait]
+impl HttpTransport for InProcessNetwork {
+    async fn execute(&self, request: reqwest::Request) -> reqwest::Result<reqwest::Response> {
+        Ok(self.route(request).await.into())
+    }
+}
+
+/// A Leader and a Helper connected by an [`InProcessNetwork`].
+pub struct InProcessDeployment {
+    network: Arc<InProcessNetwork>,
+}
+
+impl InProcessDeployment {
+    /// Run a Leader and a Helper with the given configurations. Each aggregator is reachable at
+    /// its `base_url`, which must be set, and stores its data in its own
+    /// [`InMemoryStorageProxy`].
+    pub fn new(
+        leader_config: DaphneServiceConfig,
+        helper_config: DaphneServiceConfig,
+    ) -> Result<Self, DapError> {
+        let deployment = Self {
+            network: Default::default(),
+        };
+        deployment.add_aggregator(leader_config)?;
+        deployment.add_aggregator(helper_config)?;
+        Ok(deployment)
+    }
+
+    fn add_aggregator(&self, service_config: DaphneServiceConfig) -> Result<(), DapError> {
+        let base_url = service_config
+            .base_url
+            .clone()
+            .ok_or_else(|| fatal_error!(err = "base_url not configured"))?;
+        let storage_proxy_url = Url::parse(&format!(
+            "http://storage.{}/",
+            base_url.host_str().unwrap_or_default()
+        ))
+        .map_err(|e| fatal_error!(err = ?e, "failed to construct storage proxy URL"))?;
+
+        let storage_proxy = Arc::new(InMemoryStorageProxy::new(STORAGE_PROXY_AUTH_TOKEN.into()));
+        self.network
+            .add_host(&storage_proxy_url, storage_proxy.router());
+
+        let role = service_config.role;
+        let metrics = DaphnePromServiceMetrics::register(&prometheus::Registry::new())?;
+        let app = App::new(
+            StorageProxyConfig {
+                url: storage_proxy_url,
+                auth_token: STORAGE_PROXY_AUTH_TOKEN.into(),
+                kv_cache: Default::default(),
+            },
+            metrics,
+            service_config,
+        )?
+        .with_http_transport(self.network.clone());
+        self.network.add_host(&base_url, router::new(role, app));
+        Ok(())
+    }
+
+    /// The network connecting the aggregators. Tests send their requests through it.
+    pub fn network(&self) -> &Arc<InProcessNetwork> {
+        &self.network
+    }
+}
+
+impl Drop for InProcessDeployment {
+    fn drop(&mut self) {
+        // The aggregators hold a reference to the network, so break the cycle.
+        self.network.clear();
+    }
+}
+
+fn authority(url: &Url) -> String {
+    format!(
+        "{}:{}",
+        url.host_str().unwrap_or_default(),
+        url.port_or_known_default().unwrap_or_default()
+    )
+}
+
+fn error_response(status: StatusCode, body: String) -> Response<Vec<u8>> {
+    let mut response = Response::new(body.into_bytes());
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use daphne::{
+        auth::BearerToken,
+        hpke::{HpkeKemId, HpkeReceiverConfig},
+        messages::{AggregationJobId, ReportId},
+        roles::{aggregator::MergeAggShareError, DapAggregator},
+        DapAggregateShare, DapAggregateSpan, DapBatchBucket, DapGlobalConfig, DapTaskParameters,
+        DapVersion, MetaAggregationJobId,
+    };
+    use daphne_service_utils::{
+        config::DaphneServiceConfig, durable_requests::KV_PATH_PREFIX,
+        metrics::DaphnePromServiceMetrics, DapRole,
+    };
+    use reqwest::StatusCode;
+    use url::Url;
+
+    use crate::{App, HttpTransport, StorageProxyConfig};
+
+    use super::{
+        InMemoryStorageProxy, InProcessDeployment, InProcessNetwork, STORAGE_PROXY_AUTH_TOKEN,
+    };
+
+    fn service_config(role: DapRole, base_url: &str) -> DaphneServiceConfig {
+        DaphneServiceConfig {
+            env: "in-process".into(),
+            role,
+            global: DapGlobalConfig {
+                max_batch_duration: 360_000,
+                min_batch_interval_start: 259_200,
+                max_batch_interval_end: 259_200,
+                supported_hpke_kems: vec![HpkeKemId::X25519HkdfSha256],
+                allow_taskprov: true,
+                helper_async_agg_jobs: false,
+                agg_job_policy: Default::default(),
+                retry_policy: Default::default(),
+            },
+            base_url: Some(Url::parse(base_url).unwrap()),
+            taskprov: None,
+            default_version: DapVersion::Draft09,
+            report_storage_epoch_duration: 300_000,
+            report_storage_max_future_time_skew: 300,
+            signing_key: None,
+            admin_token: None,
+            aggregate_store: Default::default(),
+        }
+    }
+
+    fn deployment() -> InProcessDeployment {
+        InProcessDeployment::new(
+            service_config(DapRole::Leader, "http://leader:8787/"),
+            service_config(DapRole::Helper, "http://helper:8788/"),
+        )
+        .unwrap()
+    }
+
+    async fn send(
+        deployment: &InProcessDeployment,
+        request: reqwest::RequestBuilder,
+    ) -> reqwest::Response {
+        deployment
+            .network()
+            .execute(request.build().unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn aggregators_reach_their_storage() {
+        let deployment = deployment();
+        let client = reqwest::Client::new();
+        for url in ["http://leader:8787/", "http://helper:8788/"] {
+            let url = Url::parse(url)
+                .unwrap()
+                .join("internal/test/ready")
+                .unwrap();
+            let resp = send(&deployment, client.post(url)).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_host() {
+        let deployment = deployment();
+        let resp = send(
+            &deployment,
+            reqwest::Client::new().post("http://nowhere:8789/internal/test/ready"),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn storage_proxy_kv() {
+        let deployment = deployment();
+        let client = reqwest::Client::new();
+        let url = format!("http://storage.leader{KV_PATH_PREFIX}/some-key");
+        let auth = BearerToken::from(STORAGE_PROXY_AUTH_TOKEN).to_standard_header_value();
+
+        // Requests that aren't authorized are rejected.
+        let resp = send(&deployment, client.get(&url)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let resp = send(&deployment, client.get(&url).bearer_auth("wrong")).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let get = || client.get(&url).header("Authorization", &auth);
+        let resp = send(&deployment, get()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        // PUT only succeeds if the key is not yet set.
+        let put = |value: &'static str| client.put(&url).header("Authorization", &auth).body(value);
+        let resp = send(&deployment, put("first")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = send(&deployment, put("second")).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        let resp = send(&deployment, get()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.text().await.unwrap(), "first");
+
+        // POST overwrites the value.
+        let resp = send(
+            &deployment,
+            client
+                .post(&url)
+                .header("Authorization", &auth)
+                .body("second"),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = send(&deployment, get()).await;
+        assert_eq!(resp.text().await.unwrap(), "second");
+
+        // Each aggregator has its own storage.
+        let resp = send(
+            &deployment,
+            client
+                .get(format!("http://storage.helper{KV_PATH_PREFIX}/some-key"))
+                .header("Authorization", &auth),
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn replay_across_shard_count_change() {
+        let network = Arc::new(InProcessNetwork::default());
+        let storage_proxy_url = Url::parse("http://storage.helper/").unwrap();
+        let storage_proxy = Arc::new(InMemoryStorageProxy::new(STORAGE_PROXY_AUTH_TOKEN.into()));
+        network.add_host(&storage_proxy_url, storage_proxy.router());
+        let helper = |shard_count| {
+            let mut service_config = service_config(DapRole::Helper, "http://helper:8788/");
+            service_config.aggregate_store.shard_count = shard_count;
+            App::new(
+                StorageProxyConfig {
+                    url: storage_proxy_url.clone(),
+                    auth_token: STORAGE_PROXY_AUTH_TOKEN.into(),
+                    kv_cache: Default::default(),
+                },
+                DaphnePromServiceMetrics::register(&prometheus::Registry::new()).unwrap(),
+                service_config,
+            )
+            .unwrap()
+            .with_http_transport(network.clone())
+        };
+
+        let collector_hpke_config = HpkeReceiverConfig::gen(1, HpkeKemId::X25519HkdfSha256)
+            .unwrap()
+            .config;
+        let (task_config, task_id, _, _) = DapTaskParameters {
+            version: DapVersion::Draft09,
+            ..Default::default()
+        }
+        .to_config_with_taskprov(b"cool task".to_vec(), 0, &[0; 32], &collector_hpke_config)
+        .unwrap();
+        let report_id = ReportId([2; 16]);
+        let agg_share_span = || {
+            [(
+                DapBatchBucket::TimeInterval { batch_window: 0 },
+                (DapAggregateShare::default(), vec![(report_id, 0)]),
+            )]
+            .into_iter()
+            .collect::<DapAggregateSpan<_>>()
+        };
+
+        let merged = helper(1)
+            .try_put_agg_share_span(
+                &task_id,
+                &task_config,
+                &MetaAggregationJobId::Draft09(AggregationJobId([0; 16])),
+                agg_share_span(),
+            )
+            .await;
+        assert!(merged.into_iter().all(|(_, (result, _))| result.is_ok()));
+
+        // Expect the replay to be detected even though the shard count was increased, which would
+        // otherwise move the report to another shard.
+        let replayed = helper(4)
+            .try_put_agg_share_span(
+                &task_id,
+                &task_config,
+                &MetaAggregationJobId::Draft09(AggregationJobId([1; 16])),
+                agg_share_span(),
+            )
+            .await;
+        assert!(replayed.into_iter().all(|(_, (result, _))| matches!(
+            result,
+            Err(MergeAggShareError::ReplaysDetected(replays)) if replays.contains(&report_id)
+        )));
+    }
+}
@@ -0,0 +1,354 @@
+// Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! An in-memory implementation of the storage proxy, i.e., of the KV and durable object API
+//! implemented by `daphne_worker::storage_proxy`. Each durable object keeps its state in memory
+//! and implements the same semantics as its counterpart in `daphne_worker::durable`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header::AUTHORIZATION, Method, Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+use daphne::{
+    auth::BearerToken, messages::ReportId, DapAggregateShare, DapCollectionQuery,
+    MetaAggregationJobId,
+};
+use daphne_service_utils::durable_requests::{
+    batch::{BatchOperation, BatchOperationResponse, BatchRequest, BatchResponse},
+    bindings::{
+        self, AggregateStoreMergeReq, AggregateStoreMergeResp, AggregateStoreReserveReq,
+        AggregateStoreReserveResp, DurableMethod,
+    },
+    DurableRequest, ObjectIdFrom, BATCH_PATH_PREFIX, DO_PATH_PREFIX, KV_PATH_PREFIX, PURGE_STORAGE,
+    STORAGE_READY,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// The state of an `AggregateStore` instance.
+#[derive(Default)]
+struct AggregateStore {
+    agg_share: DapAggregateShare,
+    merged_report_ids: HashSet<ReportId>,
+    reserved_reports: HashMap<ReportId, MetaAggregationJobId>,
+    collection_count: u64,
+    collected_by: HashSet<DapCollectionQuery>,
+}
+
+/// Storage proxy that keeps KV and the state of each durable object instance in memory.
+pub struct InMemoryStorageProxy {
+    auth_token: BearerToken,
+    kv: Mutex<HashMap<String, Vec<u8>>>,
+    aggregate_stores: Mutex<HashMap<String, AggregateStore>>,
+    helper_states: Mutex<HashMap<String, String>>,
+}
+
+impl InMemoryStorageProxy {
+    /// Create an empty storage proxy that only accepts requests authorized with `auth_token`.
+    pub fn new(auth_token: BearerToken) -> Self {
+        Self {
+            auth_token,
+            kv: Default::default(),
+            aggregate_stores: Default::default(),
+            helper_states: Default::default(),
+        }
+    }
+
+    /// Serve the storage proxy API.
+    pub fn router(self: Arc<Self>) -> axum::Router {
+        axum::Router::new()
+            .fallback(handle_request)
+            .with_state(self)
+    }
+
+    /// Delete all KV pairs and durable object instances.
+    pub fn purge(&self) {
+        self.kv.lock().unwrap().clear();
+        self.delete_all_objects();
+    }
+
+    fn delete_all_objects(&self) {
+        self.aggregate_stores.lock().unwrap().clear();
+        self.helper_states.lock().unwrap().clear();
+    }
+
+    /// Perform a single KV operation.
+    fn kv_operation(&self, method: &Method, key: &str, body: &[u8]) -> (StatusCode, Vec<u8>) {
+        let mut kv = self.kv.lock().unwrap();
+        match *method {
+            Method::GET => match kv.get(key) {
+                Some(value) => (StatusCode::OK, value.clone()),
+                None => (StatusCode::NOT_FOUND, b"value not found".to_vec()),
+            },
+            Method::POST => {
+                kv.insert(key.to_owned(), body.to_vec());
+                (StatusCode::OK, Vec::new())
+            }
+            Method::PUT => {
+                if kv.contains_key(key) {
+                    (StatusCode::CONFLICT, Vec::new())
+                } else {
+                    kv.insert(key.to_owned(), body.to_vec());
+                    (StatusCode::OK, Vec::new())
+                }
+            }
+            Method::DELETE => {
+                kv.remove(key);
+                (StatusCode::OK, Vec::new())
+            }
+            _ => (StatusCode::METHOD_NOT_ALLOWED, Vec::new()),
+        }
+    }
+
+    /// Perform a single durable object request. `request` is an encoded [`DurableRequest`].
+    fn do_operation(&self, uri: &str, request: &[u8]) -> (StatusCode, Vec<u8>) {
+        let request = match DurableRequest::try_from(request) {
+            Ok(request) => request,
+            Err(e) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    format!("invalid format: {e:?}").into_bytes(),
+                )
+            }
+        };
+        let id = match &request.id {
+            ObjectIdFrom::Name(name) => name,
+            ObjectIdFrom::Hex(hex) => hex,
+        };
+        let result = match request.binding.as_str() {
+            bindings::AggregateStore::BINDING => self.aggregate_store(id, uri, request.body()),
+            bindings::HelperState::BINDING => self.helper_state(id, uri, request.body()),
+            bindings::GarbageCollector::BINDING => self.garbage_collector(uri),
+            binding => Err(format!("unrecognized binding: {binding}")),
+        };
+        match result {
+            Ok(body) => (StatusCode::OK, body),
+            Err(e) => {
+                tracing::error!(
+                    binding = request.binding,
+                    uri,
+                    error = e,
+                    "DO request failed"
+                );
+                (StatusCode::INTERNAL_SERVER_ERROR, e.into_bytes())
+            }
+        }
+    }
+
+    fn aggregate_store(&self, id: &str, uri: &str, body: &[u8]) -> Result<Vec<u8>, String> {
+        let mut aggregate_stores = self.aggregate_stores.lock().unwrap();
+        match bindings::AggregateStore::try_from_uri(uri) {
+            Some(bindings::AggregateStore::GetMerged) => json(
+                &aggregate_stores
+                    .get(id)
+                    .map(|store| store.merged_report_ids.clone())
+                    .unwrap_or_default(),
+            ),
+            Some(bindings::AggregateStore::Get) => json(
+                &aggregate_stores
+                    .get(id)
+                    .map(|store| store.agg_share.clone())
+                    .unwrap_or_default(),
+            ),
+            Some(bindings::AggregateStore::Merge) => {
+                let AggregateStoreMergeReq {
+                    contained_reports,
+                    agg_share_delta,
+                    max_report_count,
+                } = parse(body)?;
+                let store = aggregate_stores.entry(id.to_owned()).or_default();
+
+                if store.collection_count > 0 {
+                    return json(&AggregateStoreMergeResp::AlreadyCollected);
+                }
+                let replays = contained_reports
+                    .iter()
+                    .filter(|id| store.merged_report_ids.contains(id))
+                    .copied()
+                    .collect::<HashSet<_>>();
+                if !replays.is_empty() {
+                    return json(&AggregateStoreMergeResp::ReplaysDetected(replays));
+                }
+                if let Some(max_report_count) = max_report_count {
+                    let report_count = store.merged_report_ids.len() + contained_reports.len();
+                    if report_count as u64 > max_report_count {
+                        return json(&AggregateStoreMergeResp::BatchSaturated);
+                    }
+                }
+
+                store
+                    .agg_share
+                    .merge(agg_share_delta)
+                    .map_err(|e| e.to_string())?;
+                store.merged_report_ids.extend(contained_reports);
+                json(&AggregateStoreMergeResp::Ok)
+            }
+            Some(bindings::AggregateStore::Reserve) => {
+                let AggregateStoreReserveReq {
+                    agg_job_id,
+                    report_ids,
+                } = parse(body)?;
+                let store = aggregate_stores.entry(id.to_owned()).or_default();
+
+                let replays = report_ids
+                    .iter()
+                    .filter(|id| {
+                        store
+                            .reserved_reports
+                            .get(id)
+                            .is_some_and(|reserved_by| *reserved_by != agg_job_id)
+                    })
+                    .copied()
+                    .collect::<HashSet<_>>();
+                if !replays.is_empty() {
+                    return json(&AggregateStoreReserveResp::ReplaysDetected(replays));
+                }
+
+                store
+                    .reserved_reports
+                    .extend(report_ids.into_iter().map(|id| (id, agg_job_id)));
+                json(&AggregateStoreReserveResp::Ok)
+            }
+            Some(bindings::AggregateStore::MarkCollected) => {
+                let query: DapCollectionQuery = parse(body)?;
+                let store = aggregate_stores.entry(id.to_owned()).or_default();
+                if store.collected_by.insert(query) {
+                    store.collection_count += 1;
+                }
+                json(&())
+            }
+            Some(bindings::AggregateStore::GetCollectionCount) => json(
+                &aggregate_stores
+                    .get(id)
+                    .map_or(0, |store| store.collection_count),
+            ),
+            None => Err(format!("AggregateStore: unexpected request: path={uri:?}")),
+        }
+    }
+
+    fn helper_state(&self, id: &str, uri: &str, body: &[u8]) -> Result<Vec<u8>, String> {
+        let mut helper_states = self.helper_states.lock().unwrap();
+        match bindings::HelperState::try_from_uri(uri) {
+            Some(bindings::HelperState::PutIfNotExists) => {
+                let helper_state_hex: String = parse(body)?;
+                let success = !helper_states.contains_key(id);
+                if success {
+                    helper_states.insert(id.to_owned(), helper_state_hex);
+                }
+                json(&success)
+            }
+            Some(bindings::HelperState::PutIfUnchanged) => {
+                let (current_hex, helper_state_hex): (String, String) = parse(body)?;
+                let success = helper_states.get(id) == Some(&current_hex);
+                if success {
+                    helper_states.insert(id.to_owned(), helper_state_hex);
+                }
+                json(&success)
+            }
+            Some(bindings::HelperState::Put) => {
+                let helper_state_hex: String = parse(body)?;
+                helper_states.insert(id.to_owned(), helper_state_hex);
+                json(&())
+            }
+            Some(bindings::HelperState::Get) => json(&helper_states.get(id)),
+            None => Err(format!(
+                "HelperStateStore: unexpected request: path={uri:?}"
+            )),
+        }
+    }
+
+    fn garbage_collector(&self, uri: &str) -> Result<Vec<u8>, String> {
+        match bindings::GarbageCollector::try_from_uri(uri) {
+            // Every instance is tracked by the proxy itself, so there is nothing to schedule.
+            Some(bindings::GarbageCollector::Put) => json(&()),
+            Some(bindings::GarbageCollector::DeleteAll) => {
+                self.delete_all_objects();
+                json(&())
+            }
+            None => Err(format!(
+                "GarbageCollector: unexpected request: path={uri:?}"
+            )),
+        }
+    }
+
+    /// Perform a batch of durable object operations.
+    fn batch_operation(&self, request: &[u8]) -> (StatusCode, Vec<u8>) {
+        let batch = match BatchRequest::try_from(request) {
+            Ok(batch) => batch,
+            Err(e) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    format!("invalid format: {e:?}").into_bytes(),
+                )
+            }
+        };
+        let responses = batch
+            .operations
+            .iter()
+            .map(|op| {
+                let BatchOperation { uri, request } = op;
+                let (status, body) = self.do_operation(uri, request);
+                BatchOperationResponse {
+                    status: status.as_u16(),
+                    body,
+                }
+            })
+            .collect();
+        (StatusCode::OK, BatchResponse { responses }.into_bytes())
+    }
+}
+
+async fn handle_request(
+    State(proxy): State<Arc<InMemoryStorageProxy>>,
+    req: Request<Body>,
+) -> Response {
+    let authorized = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|auth| auth.to_str().ok())
+        .is_some_and(|auth| auth == proxy.auth_token.to_standard_header_value());
+    if !authorized {
+        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    }
+
+    let (parts, body) = req.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(e) => return (StatusCode::BAD_REQUEST, e.to_string()).into_response(),
+    };
+
+    let path = parts.uri.path();
+    if let Some(key) = path
+        .strip_prefix(KV_PATH_PREFIX)
+        .and_then(|s| s.strip_prefix('/'))
+    {
+        proxy
+            .kv_operation(&parts.method, key, &body)
+            .into_response()
+    } else if let Some(uri) = path.strip_prefix(DO_PATH_PREFIX) {
+        proxy.do_operation(uri, &body).into_response()
+    } else if path == BATCH_PATH_PREFIX {
+        proxy.batch_operation(&body).into_response()
+    } else if path == PURGE_STORAGE {
+        proxy.purge();
+        StatusCode::OK.into_response()
+    } else if path == STORAGE_READY {
+        StatusCode::OK.into_response()
+    } else {
+        (StatusCode::BAD_REQUEST, "invalid base path").into_response()
+    }
+}
+
+fn parse<T: DeserializeOwned>(body: &[u8]) -> Result<T, String> {
+    bincode::deserialize(body).map_err(|e| format!("failed to deserialize bincode: {e:?}"))
+}
+
+fn json<T: Serialize>(value: &T) -> Result<Vec<u8>, String> {
+    serde_json::to_vec(value).map_err(|e| e.to_string())
+}
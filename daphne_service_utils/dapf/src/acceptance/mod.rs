@@ -31,7 +31,7 @@ use daphne::{
         BatchSelector, Draft02AggregationJobId, PartialBatchSelector, ReportId, TaskId,
     },
     metrics::{prometheus::DaphnePromMetrics, DaphneMetrics},
-    roles::DapReportInitializer,
+    roles::{report_extensions::DapReportExtensionHandlers, DapReportInitializer},
     vdaf::VdafConfig,
     DapAggregateShare, DapAggregateSpan, DapAggregationParam, DapBatchBucket, DapError,
     DapLeaderAggregationJobTransition, DapMeasurement, DapQueryConfig, DapTaskConfig,
@@ -292,6 +292,7 @@ impl Test {
                 query: DapQueryConfig::FixedSize {
                     max_batch_size: Some(reports_per_batch.try_into().unwrap()),
                 },
+                max_batch_query_count: 1,
                 vdaf: self.vdaf_config,
             }
             .to_config_with_taskprov(
@@ -448,11 +449,12 @@ impl Test {
             .produce_agg_job_init_req(
                 fake_leader_hpke_receiver_config,
                 self,
+                DapReportExtensionHandlers::none(),
                 task_id,
                 &agg_job_id,
                 part_batch_sel,
                 &DapAggregationParam::Empty,
-                reports_for_agg_job,
+                &reports_for_agg_job,
                 self.metrics(),
             )
             .await
@@ -514,7 +516,10 @@ impl Test {
                 serde_json::from_str(&text).with_context(|| {
                     format!("400 Bad Request: failed to parse problem details document: {text:?}")
                 })?;
-            return Err(anyhow!("400 Bad Request: {problem_details:?}"));
+            return Err(anyhow!(
+                "400 Bad Request: {:?}",
+                DapError::from_problem_details(problem_details)
+            ));
         } else if resp.status() == 500 {
             return Err(anyhow::anyhow!(
                 "500 Internal Server Error: {}",
@@ -606,7 +611,10 @@ impl Test {
                     .context("transfering bytes from AggregationJobContinueReq")?,
             )
             .with_context(|| "400 Bad Request: failed to parse problem details document")?;
-            return Err(anyhow!("400 Bad Request: {problem_details:?}"));
+            return Err(anyhow!(
+                "400 Bad Request: {:?}",
+                DapError::from_problem_details(problem_details)
+            ));
         } else if resp.status() == 500 {
             return Err(anyhow::anyhow!(
                 "500 Internal Server Error: {}",
@@ -627,7 +635,7 @@ impl Test {
         )
         .with_context(|| "failed to parse response to AggregateContinueReq")?;
         let out_shares = task_config
-            .handle_final_agg_job_resp(uncommited, agg_resp, self.metrics())
+            .handle_final_agg_job_resp(task_id, uncommited, agg_resp, self.metrics())
             .with_context(|| "error while handling response to AggregateContinueReq")?;
 
         Ok((out_shares, durations))
@@ -750,7 +758,10 @@ impl Test {
                     .context("transfering bytes for AggregateShareReq")?,
             )
             .with_context(|| "400 Bad Request: failed to parse problem details document")?;
-            return Err(anyhow!("400 Bad Request: {problem_details:?}"));
+            return Err(anyhow!(
+                "400 Bad Request: {:?}",
+                DapError::from_problem_details(problem_details)
+            ));
         } else if resp.status() == 500 {
             return Err(anyhow::anyhow!(
                 "500 Internal Server Error: {}",
@@ -5,13 +5,16 @@ pub mod acceptance;
 mod test_durations;
 pub mod test_routes;
 
-use std::{io::Cursor, path::Path};
+use std::{io::Cursor, path::Path, time::SystemTime};
 
 use anyhow::{anyhow, Context};
+use async_trait::async_trait;
 use daphne::{
+    client::http::{DapHttpClient, DapHttpMethod, DapHttpRequest, DapHttpResponse},
+    fatal_error,
     hpke::HpkeConfig,
-    messages::{decode_base64url_vec, HpkeConfigList},
-    DapVersion,
+    messages::{decode_base64url_vec, Duration, HpkeConfigList, Time},
+    DapError, DapVersion,
 };
 use daphne_service_utils::http_headers;
 use prio::codec::Decode;
@@ -78,6 +81,70 @@ impl HttpClientExt for Client {
     }
 }
 
+/// Read the public key used to verify the Aggregators' HPKE config signatures from the given PEM
+/// certificate. The key is SEC1-encoded.
+pub fn load_hpke_config_verification_key(certificate_file: &Path) -> anyhow::Result<Vec<u8>> {
+    let cert = std::fs::read_to_string(certificate_file).context("reading the certificate")?;
+    let (cert_pem, _bytes_read) =
+        Pem::read(Cursor::new(cert.as_bytes())).context("reading PEM certificate")?;
+    let cert = cert_pem
+        .parse_x509()
+        .map_err(|e| anyhow!("{e:?}"))
+        .context("parsing PEM certificate")?;
+    Ok(cert.public_key().subject_public_key.data.to_vec())
+}
+
+/// [`DapHttpClient`] backed by a [`reqwest::Client`].
+pub struct ReqwestDapHttpClient(pub Client);
+
+#[async_trait]
+impl DapHttpClient for ReqwestDapHttpClient {
+    async fn send(&self, req: DapHttpRequest) -> Result<DapHttpResponse, DapError> {
+        let method = match req.method {
+            DapHttpMethod::Get => reqwest::Method::GET,
+            DapHttpMethod::Post => reqwest::Method::POST,
+            DapHttpMethod::Put => reqwest::Method::PUT,
+            DapHttpMethod::Delete => reqwest::Method::DELETE,
+        };
+        let mut builder = self.0.request(method, req.url).body(req.body);
+        for (name, value) in req.headers {
+            builder = builder.header(name, value);
+        }
+
+        let map_err = |e: reqwest::Error| {
+            if e.is_timeout() || e.is_connect() {
+                DapError::Transient(e.to_string())
+            } else {
+                fatal_error!(err = ?e, "request failed")
+            }
+        };
+        let resp = builder.send().await.map_err(map_err)?;
+        let status = resp.status().as_u16();
+        let headers = resp
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect();
+        let body = resp.bytes().await.map_err(map_err)?.to_vec();
+        Ok(DapHttpResponse {
+            status,
+            headers,
+            body,
+        })
+    }
+
+    fn get_current_time(&self) -> Time {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        tokio::time::sleep(std::time::Duration::from_secs(duration)).await;
+    }
+}
+
 pub fn deduce_dap_version_from_url(url: &Url) -> anyhow::Result<DapVersion> {
     url.path_segments()
         .context("no version specified in leader url")?
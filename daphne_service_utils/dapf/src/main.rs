@@ -5,24 +5,26 @@ use anyhow::{anyhow, Context, Result};
 use clap::{builder::PossibleValue, Parser, Subcommand, ValueEnum};
 use dapf::{
     acceptance::{load_testing, TestOptions},
-    deduce_dap_version_from_url, HttpClientExt,
+    deduce_dap_version_from_url, load_hpke_config_verification_key, HttpClientExt,
+    ReqwestDapHttpClient,
 };
 use daphne::{
-    constants::DapMediaType,
-    error::aborts::ProblemDetails,
+    auth::BearerToken,
+    client::{
+        collector::{DapCollector, DapCollectorConfig, DapCollectorJob, DapCollectorTask},
+        DapClient, DapClientConfig, DapClientTask,
+    },
     hpke::{HpkeKemId, HpkeReceiverConfig},
-    messages::{Base64Encode, BatchSelector, Collection, CollectionReq, Query, TaskId},
+    messages::{Base64Encode, BatchSelector, Duration, Query, TaskId},
     vdaf::VdafConfig,
     DapAggregationParam, DapMeasurement, DapVersion,
 };
-use prio::codec::{ParameterizedDecode, ParameterizedEncode};
 use rand::{thread_rng, Rng};
 use reqwest::ClientBuilder;
 use std::{
     io::{stdin, Read},
     path::PathBuf,
     process::Command,
-    time::SystemTime,
 };
 
 use url::Url;
@@ -86,6 +88,11 @@ enum Action {
         /// DAP task ID (base64, URL-safe encoding)
         #[arg(short, long, env, value_parser = parse_id)]
         task_id: TaskId,
+
+        /// The task's time precision (in seconds). The report timestamp is rounded down to a
+        /// multiple of this value.
+        #[arg(long, env, default_value_t = 1)]
+        time_precision: Duration,
     },
     /// Collect an aggregate result from the DAP Leader using the JSON-formatted batch selector
     /// provided on stdin.
@@ -94,6 +101,10 @@ enum Action {
         #[clap(long, env)]
         leader_url: Url,
 
+        /// JSON-formatted VDAF config
+        #[clap(short, long, env)]
+        vdaf_config: VdafConfig,
+
         /// DAP task ID (base64, URL-safe encoding)
         #[clap(short, long, env, value_parser = parse_id)]
         task_id: TaskId,
@@ -206,9 +217,6 @@ async fn main() -> Result<()> {
         .with_writer(std::io::stderr)
         .init();
     let mut rng = thread_rng();
-    let now = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)?
-        .as_secs();
 
     let cli = Cli::parse();
 
@@ -241,6 +249,7 @@ async fn main() -> Result<()> {
             vdaf_config,
             certificate_file,
             task_id,
+            time_precision,
         } => {
             // Read the measurement from stdin.
             let mut buf = String::new();
@@ -251,61 +260,36 @@ async fn main() -> Result<()> {
             let measurement: DapMeasurement =
                 serde_json::from_str(&buf).with_context(|| "failed to parse JSON from stdin")?;
 
-            // Get the Aggregators' HPKE configs.
-            let leader_hpke_config = http_client
-                .get_hpke_config(&leader_url, certificate_file.as_deref())
-                .await
-                .with_context(|| "failed to fetch the Leader's HPKE config")?
-                .hpke_configs
-                .swap_remove(0);
-            let helper_hpke_config = http_client
-                .get_hpke_config(&helper_url, certificate_file.as_deref())
-                .await
-                .with_context(|| "failed to fetch the Helper's HPKE config")?
-                .hpke_configs
-                .swap_remove(0);
-
-            let version = deduce_dap_version_from_url(&leader_url)?;
-            // Generate a report for the measurement.
-            let report = vdaf_config
-                .produce_report(
-                    &[leader_hpke_config, helper_hpke_config],
-                    now,
-                    &task_id,
-                    measurement,
-                    version,
-                )
-                .with_context(|| "failed to produce report")?;
-
-            // Post the report to the Leader.
-            let mut headers = reqwest::header::HeaderMap::new();
-            headers.insert(
-                reqwest::header::CONTENT_TYPE,
-                reqwest::header::HeaderValue::from_str(
-                    DapMediaType::Report
-                        .as_str_for_version(version)
-                        .expect("failed to construct content-type value"),
-                )
-                .expect("failecd to construct content-type header"),
+            let hpke_config_verification_key = certificate_file
+                .as_deref()
+                .map(load_hpke_config_verification_key)
+                .transpose()?;
+            let client = DapClient::new(
+                ReqwestDapHttpClient(http_client),
+                DapClientConfig {
+                    hpke_config_verification_key,
+                    ..Default::default()
+                },
             );
-            let resp = http_client
-                .post(leader_url.join("upload")?)
-                .body(report.get_encoded_with_param(&version)?)
-                .headers(headers)
-                .send()
-                .await?;
-            if resp.status() == 400 {
-                let problem_details: ProblemDetails = serde_json::from_str(&resp.text().await?)
-                    .with_context(|| "unexpected response")?;
-                return Err(anyhow!(serde_json::to_string(&problem_details)?));
-            } else if resp.status() != 200 {
-                return Err(anyhow!("unexpected response: {:?}", resp));
-            }
+            let task = DapClientTask {
+                task_id,
+                version: deduce_dap_version_from_url(&leader_url)?,
+                leader_url,
+                helper_url,
+                vdaf: vdaf_config,
+                time_precision,
+            };
+            client
+                .upload(&task, measurement)
+                .await
+                .map_err(|e| anyhow!("{e:?}"))
+                .with_context(|| "failed to upload report")?;
 
             Ok(())
         }
         Action::Collect {
             leader_url,
+            vdaf_config,
             task_id,
         } => {
             // Read the batch selector from stdin.
@@ -317,58 +301,24 @@ async fn main() -> Result<()> {
             let query: Query =
                 serde_json::from_str(&buf).with_context(|| "failed to parse JSON from stdin")?;
 
-            let version = deduce_dap_version_from_url(&leader_url)?;
-            // Construct collect request.
-            let collect_req = CollectionReq {
-                draft02_task_id: if version == DapVersion::Draft02 {
-                    Some(task_id)
-                } else {
-                    None
-                },
-                query,
-                agg_param: Vec::default(),
+            let task = DapCollectorTask {
+                task_id,
+                version: deduce_dap_version_from_url(&leader_url)?,
+                leader_url,
+                vdaf: vdaf_config,
+                collector_auth_token: leader_bearer_token(),
             };
-
-            let mut headers = reqwest::header::HeaderMap::new();
-            headers.insert(
-                reqwest::header::CONTENT_TYPE,
-                reqwest::header::HeaderValue::from_str(
-                    DapMediaType::CollectReq
-                        .as_str_for_version(version)
-                        .expect("failed to construct content-type value"),
-                )
-                .expect("failed to construct content-type hader"),
+            let collector = DapCollector::new(
+                ReqwestDapHttpClient(http_client),
+                DapCollectorConfig::default(),
             );
-            if let Ok(token) = std::env::var("LEADER_BEARER_TOKEN") {
-                headers.insert(
-                    reqwest::header::HeaderName::from_static("dap-auth-token"),
-                    reqwest::header::HeaderValue::from_str(&token)?,
-                );
-            }
-
-            let resp = http_client
-                .post(leader_url.join("collect")?)
-                .body(collect_req.get_encoded_with_param(&version)?)
-                .headers(headers)
-                .send()
-                .await?;
-            if resp.status() == 400 {
-                let problem_details: ProblemDetails = serde_json::from_str(&resp.text().await?)
-                    .with_context(|| "unexpected response")?;
-                return Err(anyhow!(serde_json::to_string(&problem_details)?));
-            } else if resp.status() != 303 {
-                return Err(anyhow!("unexpected response: {:?}", resp));
-            }
-
-            let uri_str = resp
-                .headers()
-                .get("Location")
-                .ok_or_else(|| anyhow!("response is missing Location header"))?
-                .to_str()?;
-            let uri =
-                Url::parse(uri_str).with_context(|| "Leader did not respond with valid URI")?;
+            let job = collector
+                .start_collection_job(&task, query, DapAggregationParam::Empty)
+                .await
+                .map_err(|e| anyhow!("{e:?}"))
+                .with_context(|| "failed to start collection job")?;
 
-            println!("{uri}");
+            println!("{}", job.uri);
             Ok(())
         }
         Action::CollectPoll {
@@ -386,28 +336,33 @@ async fn main() -> Result<()> {
             let batch_selector: BatchSelector =
                 serde_json::from_str(&buf).with_context(|| "failed to parse JSON from stdin")?;
 
-            let resp = http_client.get(uri.clone()).send().await?;
-            if resp.status() == 202 {
-                return Err(anyhow!("aggregate result not ready"));
-            } else if resp.status() != 200 {
-                return Err(anyhow!("unexpected response: {:?}", resp));
-            }
             let receiver = hpke_receiver.as_ref().ok_or_else(|| {
-                anyhow!("received response, but cannot decrypt without HPKE receiver config")
+                anyhow!("cannot decrypt the aggregate result without HPKE receiver config")
             })?;
-            let version = deduce_dap_version_from_url(&uri)?;
-            let collect_resp = Collection::get_decoded_with_param(&version, &resp.bytes().await?)?;
-            let agg_res = vdaf_config
-                .consume_encrypted_agg_shares(
-                    receiver,
-                    &task_id,
-                    &batch_selector,
-                    collect_resp.report_count,
-                    &DapAggregationParam::Empty,
-                    collect_resp.encrypted_agg_shares.to_vec(),
-                    version,
-                )
-                .await?;
+            let task = DapCollectorTask {
+                task_id,
+                version: deduce_dap_version_from_url(&uri)?,
+                // The Leader's URL is only used to start collection jobs.
+                leader_url: uri.clone(),
+                vdaf: vdaf_config,
+                collector_auth_token: leader_bearer_token(),
+            };
+            let job = DapCollectorJob {
+                uri,
+                query: batch_selector.into(),
+                agg_param: DapAggregationParam::Empty,
+            };
+            let collector = DapCollector::new(
+                ReqwestDapHttpClient(http_client),
+                DapCollectorConfig::default(),
+            );
+            let agg_res = collector
+                .poll_collection_job(&task, &job, receiver)
+                .await
+                .map_err(|e| anyhow!("{e:?}"))
+                .with_context(|| "failed to poll collection job")?
+                .ok_or_else(|| anyhow!("aggregate result not ready"))?
+                .result;
 
             print!("{}", serde_json::to_string(&agg_res)?);
             Ok(())
@@ -590,6 +545,13 @@ async fn main() -> Result<()> {
     }
 }
 
+/// Read the token used to authorize the Collector's requests to the Leader, if any.
+fn leader_bearer_token() -> Option<BearerToken> {
+    std::env::var("LEADER_BEARER_TOKEN")
+        .ok()
+        .map(BearerToken::from)
+}
+
 fn parse_id(id_str: &str) -> Result<TaskId> {
     TaskId::try_from_base64url(id_str)
         .ok_or_else(|| anyhow!("failed to decode ID"))
@@ -1,8 +1,12 @@
 // Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
+use std::collections::HashMap;
+
 use daphne::{
+    auth::BearerToken,
     hpke::{HpkeConfig, HpkeReceiverConfig},
+    messages::TaskId,
     DapGlobalConfig, DapVersion,
 };
 use p256::ecdsa::SigningKey;
@@ -86,6 +90,54 @@ pub struct DaphneServiceConfig {
         skip_serializing
     )]
     pub signing_key: Option<SigningKey>,
+
+    /// Bearer token used to authorize requests to the administrative endpoints. If not set, then
+    /// these endpoints are disabled.
+    #[serde(default, skip_serializing)]
+    pub admin_token: Option<BearerToken>,
+
+    /// Storage of aggregate shares.
+    #[serde(default)]
+    pub aggregate_store: AggregateStoreConfig,
+}
+
+/// Configuration of the durable objects that store aggregate shares.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AggregateStoreConfig {
+    /// Number of objects the aggregate share of each batch bucket is spread across, unless
+    /// overridden by `task_shard_count`. Merges are assigned to a shard by aggregation job ID, so
+    /// that concurrent aggregation jobs for the same bucket don't contend on a single object.
+    /// Tasks with a maximum batch size always merge into the first shard, since the maximum can
+    /// only be enforced atomically by a single object.
+    ///
+    /// Each report is owned by a shard determined by the shard count, which detects replays of
+    /// it. The shard count of a task is therefore fixed the first time its aggregate shares are
+    /// stored or read; changing this value, or `task_shard_count`, only affects new tasks.
+    pub shard_count: u16,
+
+    /// Per-task overrides of `shard_count`.
+    pub task_shard_count: HashMap<TaskId, u16>,
+}
+
+impl Default for AggregateStoreConfig {
+    fn default() -> Self {
+        Self {
+            shard_count: 1,
+            task_shard_count: HashMap::new(),
+        }
+    }
+}
+
+impl AggregateStoreConfig {
+    /// The number of shards used for the given task. This is always at least 1.
+    pub fn shard_count_for(&self, task_id: &TaskId) -> u16 {
+        self.task_shard_count
+            .get(task_id)
+            .copied()
+            .unwrap_or(self.shard_count)
+            .max(1)
+    }
 }
 
 fn default_report_storage_max_future_time_skew() -> daphne::messages::Duration {
@@ -234,3 +286,28 @@ mod from_raw_string {
         serde_json::from_str(&s).map_err(<D::Error as de::Error>::custom)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use daphne::messages::TaskId;
+
+    use super::AggregateStoreConfig;
+
+    #[test]
+    fn aggregate_store_shard_count() {
+        let config: AggregateStoreConfig = serde_json::from_value(serde_json::json!({
+            "shard_count": 4,
+            "task_shard_count": {
+                TaskId([1; 32]).to_hex(): 16,
+                TaskId([2; 32]).to_hex(): 0,
+            },
+        }))
+        .unwrap();
+        assert_eq!(config.shard_count_for(&TaskId([1; 32])), 16);
+        assert_eq!(config.shard_count_for(&TaskId([2; 32])), 1);
+        assert_eq!(config.shard_count_for(&TaskId([3; 32])), 4);
+
+        let config: AggregateStoreConfig = serde_json::from_value(serde_json::json!({})).unwrap();
+        assert_eq!(config.shard_count_for(&TaskId([1; 32])), 1);
+    }
+}
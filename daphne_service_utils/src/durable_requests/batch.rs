@@ -0,0 +1,189 @@
+// Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Batched storage requests.
+//!
+//! A batch is sent as the body of a `POST` request to [`BATCH_PATH_PREFIX`]. The storage proxy
+//! executes every operation concurrently and replies with one [`BatchOperationResponse`] per
+//! operation, in the same order as the operations of the request. The failure of one operation
+//! does not affect the others.
+//!
+//! [`BATCH_PATH_PREFIX`]: super::BATCH_PATH_PREFIX
+
+use std::io;
+
+use crate::durable_request_capnp::{batch_operation_response, batch_request, batch_response};
+
+/// A durable object request that is part of a [`BatchRequest`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchOperation {
+    /// The URI of the durable object method, as returned by
+    /// [`DurableMethod::to_uri`](super::bindings::DurableMethod::to_uri).
+    pub uri: String,
+    /// The encoded [`DurableRequest`](super::DurableRequest), including its body.
+    pub request: Vec<u8>,
+}
+
+/// A set of durable object requests to be executed by the storage proxy in a single round trip.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BatchRequest {
+    pub operations: Vec<BatchOperation>,
+}
+
+/// The result of a single [`BatchOperation`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchOperationResponse {
+    /// The HTTP status code the operation would have had if it had been sent on its own.
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+impl BatchOperationResponse {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+/// The responses to a [`BatchRequest`], in the same order as its operations.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BatchResponse {
+    pub responses: Vec<BatchOperationResponse>,
+}
+
+fn len_u32(len: usize) -> u32 {
+    len.try_into().expect("batch is too large")
+}
+
+impl BatchRequest {
+    pub fn into_bytes(self) -> Vec<u8> {
+        let mut message = capnp::message::Builder::new_default();
+        {
+            let request = message.init_root::<batch_request::Builder>();
+            let mut operations = request.init_operations(len_u32(self.operations.len()));
+            for (i, op) in self.operations.iter().enumerate() {
+                let mut builder = operations.reborrow().get(len_u32(i));
+                builder.set_uri(op.uri.as_str().into());
+                builder.set_request(&op.request);
+            }
+        }
+        let mut vec = Vec::new();
+        capnp::serialize_packed::write_message(&mut vec, &message).unwrap();
+        vec
+    }
+}
+
+impl TryFrom<&[u8]> for BatchRequest {
+    type Error = capnp::Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let message_reader = capnp::serialize_packed::read_message(
+            &mut io::Cursor::new(bytes),
+            capnp::message::ReaderOptions::new(),
+        )?;
+        let request = message_reader.get_root::<batch_request::Reader>()?;
+        let operations = request
+            .get_operations()?
+            .iter()
+            .map(|op| {
+                Ok(BatchOperation {
+                    uri: op.get_uri()?.to_string()?,
+                    request: op.get_request()?.to_vec(),
+                })
+            })
+            .collect::<Result<_, capnp::Error>>()?;
+        Ok(Self { operations })
+    }
+}
+
+impl BatchResponse {
+    pub fn into_bytes(self) -> Vec<u8> {
+        let mut message = capnp::message::Builder::new_default();
+        {
+            let response = message.init_root::<batch_response::Builder>();
+            let mut responses = response.init_responses(len_u32(self.responses.len()));
+            for (i, resp) in self.responses.iter().enumerate() {
+                let mut builder = responses.reborrow().get(len_u32(i));
+                builder.set_status(resp.status);
+                builder.set_body(&resp.body);
+            }
+        }
+        let mut vec = Vec::new();
+        capnp::serialize_packed::write_message(&mut vec, &message).unwrap();
+        vec
+    }
+}
+
+impl TryFrom<&[u8]> for BatchResponse {
+    type Error = capnp::Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Self::Error> {
+        let message_reader = capnp::serialize_packed::read_message(
+            &mut io::Cursor::new(bytes),
+            capnp::message::ReaderOptions::new(),
+        )?;
+        let response = message_reader.get_root::<batch_response::Reader>()?;
+        let responses = response
+            .get_responses()?
+            .iter()
+            .map(|resp: batch_operation_response::Reader<'_>| {
+                Ok(BatchOperationResponse {
+                    status: resp.get_status(),
+                    body: resp.get_body()?.to_vec(),
+                })
+            })
+            .collect::<Result<_, capnp::Error>>()?;
+        Ok(Self { responses })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use daphne::{DapBatchBucket, DapVersion};
+
+    use super::{BatchOperation, BatchOperationResponse, BatchRequest, BatchResponse};
+    use crate::durable_requests::{bindings::AggregateStore, DurableRequest};
+
+    #[test]
+    fn roundtrip_request() {
+        let request = |body: &[u8]| {
+            let (durable_request, uri) = DurableRequest::new(
+                AggregateStore::Merge,
+                (
+                    DapVersion::Draft09,
+                    "some-task-id-hex",
+                    &DapBatchBucket::TimeInterval { batch_window: 0 },
+                    0,
+                ),
+            );
+            BatchOperation {
+                uri: uri.to_string(),
+                request: durable_request.with_body(body).into_bytes(),
+            }
+        };
+        let want = BatchRequest {
+            operations: vec![request(b"cool body"), request(b"")],
+        };
+
+        let bytes = want.clone().into_bytes();
+        assert_eq!(BatchRequest::try_from(bytes.as_slice()).unwrap(), want);
+    }
+
+    #[test]
+    fn roundtrip_response() {
+        let want = BatchResponse {
+            responses: vec![
+                BatchOperationResponse {
+                    status: 200,
+                    body: b"\"Ok\"".to_vec(),
+                },
+                BatchOperationResponse {
+                    status: 404,
+                    body: Vec::new(),
+                },
+            ],
+        };
+
+        let bytes = want.clone().into_bytes();
+        assert_eq!(BatchResponse::try_from(bytes.as_slice()).unwrap(), want);
+    }
+}
@@ -92,19 +92,51 @@ define_do_binding! {
         GetMerged = "/internal/do/aggregate_store/get_merged",
         Get = "/internal/do/aggregate_store/get",
         Merge = "/internal/do/aggregate_store/merge",
+        Reserve = "/internal/do/aggregate_store/reserve",
         MarkCollected = "/internal/do/aggregate_store/mark_collected",
-        CheckCollected = "/internal/do/aggregate_store/check_collected",
+        GetCollectionCount = "/internal/do/aggregate_store/get_collection_count",
     }
 
-    fn name((version, task_id_hex, bucket): (DapVersion, &'n str, &'n DapBatchBucket)) -> ObjectIdFrom {
+    fn name((version, task_id_hex, bucket, shard): (DapVersion, &'n str, &'n DapBatchBucket, u16)) -> ObjectIdFrom {
         fn durable_name_bucket(bucket: &DapBatchBucket) -> String {
             format!("{bucket}")
         }
-        ObjectIdFrom::Name(format!(
-            "{}/{}",
-            durable_name_task(version, task_id_hex),
-            durable_name_bucket(bucket),
-        ))
+        // The first shard keeps the name used before aggregate shares were sharded so that
+        // existing objects remain reachable.
+        if shard == 0 {
+            ObjectIdFrom::Name(format!(
+                "{}/{}",
+                durable_name_task(version, task_id_hex),
+                durable_name_bucket(bucket),
+            ))
+        } else {
+            ObjectIdFrom::Name(format!(
+                "{}/{}/shard/{shard}",
+                durable_name_task(version, task_id_hex),
+                durable_name_bucket(bucket),
+            ))
+        }
+    }
+}
+
+impl AggregateStore {
+    /// Select the shard that the aggregate shares produced by an aggregation job are merged into.
+    pub fn shard_for_agg_job(agg_job_id: &MetaAggregationJobId, shard_count: u16) -> u16 {
+        let id = match agg_job_id {
+            MetaAggregationJobId::Draft02(id) => id.as_ref(),
+            MetaAggregationJobId::Draft09(id) => id.as_ref(),
+        };
+        // Aggregation job IDs are chosen at random by the Leader, so any two bytes of the ID are
+        // uniformly distributed.
+        u16::from_le_bytes([id[0], id[1]]) % shard_count.max(1)
+    }
+
+    /// Select the shard that owns a report ID, i.e., the shard at which the report is reserved
+    /// before its aggregation job merges it into any shard.
+    pub fn shard_for_report(report_id: &ReportId, shard_count: u16) -> u16 {
+        // Report IDs are chosen at random by the Client.
+        let id = report_id.as_ref();
+        u16::from_le_bytes([id[0], id[1]]) % shard_count.max(1)
     }
 }
 
@@ -116,6 +148,22 @@ fn durable_name_task(version: DapVersion, task_id_hex: &str) -> String {
 pub struct AggregateStoreMergeReq {
     pub contained_reports: Vec<ReportId>,
     pub agg_share_delta: DapAggregateShare,
+    /// The maximum number of reports this instance may aggregate into the bucket, if any.
+    pub max_report_count: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AggregateStoreReserveReq {
+    /// The aggregation job reserving the reports. Reserving a report again for the same
+    /// aggregation job succeeds, which makes retrying a reservation safe.
+    pub agg_job_id: MetaAggregationJobId,
+    pub report_ids: Vec<ReportId>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum AggregateStoreReserveResp {
+    Ok,
+    ReplaysDetected(HashSet<ReportId>),
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -123,6 +171,7 @@ pub enum AggregateStoreMergeResp {
     Ok,
     ReplaysDetected(HashSet<ReportId>),
     AlreadyCollected,
+    BatchSaturated,
 }
 
 define_do_binding! {
@@ -145,6 +194,8 @@ define_do_binding! {
     const BINDING = "DAP_HELPER_STATE_STORE";
     enum HelperState {
         PutIfNotExists = "/internal/do/helper_state/put_if_not_exists",
+        PutIfUnchanged = "/internal/do/helper_state/put_if_unchanged",
+        Put = "/internal/do/helper_state/put",
         Get = "/internal/do/helper_state/get",
     }
 
@@ -161,7 +212,13 @@ define_do_binding! {
 
 #[cfg(test)]
 mod tests {
-    use daphne::{messages::BatchId, DapBatchBucket};
+    use daphne::{
+        messages::{AggregationJobId, BatchId, ReportId},
+        DapBatchBucket, DapVersion, MetaAggregationJobId,
+    };
+
+    use super::{AggregateStore, DurableMethod};
+    use crate::durable_requests::ObjectIdFrom;
 
     // We use `std::fmt::Display` for `DapBatchBucket` to format names for DO instances. Ensure
     // that they are formatted the way we expect.
@@ -181,4 +238,40 @@ mod tests {
             format!("{}", DapBatchBucket::TimeInterval { batch_window: 1337 })
         );
     }
+
+    #[test]
+    fn aggregate_store_shard_names() {
+        let bucket = DapBatchBucket::TimeInterval { batch_window: 1337 };
+        let name = |shard| {
+            let ObjectIdFrom::Name(name) =
+                AggregateStore::name((DapVersion::Draft09, "abcd", &bucket, shard))
+            else {
+                unreachable!()
+            };
+            name
+        };
+        assert_eq!(name(0), "v09/task/abcd/window/1337");
+        assert_eq!(name(3), "v09/task/abcd/window/1337/shard/3");
+    }
+
+    #[test]
+    fn aggregate_store_shard_for_agg_job() {
+        let agg_job_id = MetaAggregationJobId::Draft09(AggregationJobId([7; 16]));
+        assert_eq!(AggregateStore::shard_for_agg_job(&agg_job_id, 1), 0);
+        assert_eq!(AggregateStore::shard_for_agg_job(&agg_job_id, 0), 0);
+        assert_eq!(
+            AggregateStore::shard_for_agg_job(&agg_job_id, 16),
+            AggregateStore::shard_for_agg_job(&agg_job_id, 16),
+        );
+        assert!(AggregateStore::shard_for_agg_job(&agg_job_id, 16) < 16);
+    }
+
+    #[test]
+    fn aggregate_store_shard_for_report() {
+        let report_id = ReportId([0x03, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(AggregateStore::shard_for_report(&report_id, 1), 0);
+        assert_eq!(AggregateStore::shard_for_report(&report_id, 0), 0);
+        assert_eq!(AggregateStore::shard_for_report(&report_id, 16), 3);
+        assert_eq!(AggregateStore::shard_for_report(&report_id, 1000), 259);
+    }
 }
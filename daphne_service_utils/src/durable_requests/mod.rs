@@ -52,7 +52,13 @@
 //!    |                                       |              Http Response |         |
 //!    |<--------------------------------------|<---------------------------|<--------+
 //!```
+//!
+//! # Batching
+//!
+//! Several durable object requests can be sent in a single round trip to the storage
+//! proxy. See the [`batch`] module for details.
 
+pub mod batch;
 pub mod bindings;
 
 use std::io;
@@ -68,6 +74,8 @@ use crate::durable_request_capnp;
 pub const KV_PATH_PREFIX: &str = "/v1/kv";
 /// The base of a request path that points to a durable object.
 pub const DO_PATH_PREFIX: &str = "/v1/do";
+/// The path of a batch of durable object requests.
+pub const BATCH_PATH_PREFIX: &str = "/v1/batch";
 #[cfg(feature = "test-utils")]
 /// The path of the purge request, which wipes all storage. This is meant for tests only.
 pub const PURGE_STORAGE: &str = "/v1/purge";
@@ -300,6 +308,7 @@ mod test {
                 DapVersion::Draft02,
                 "some-task-id-hex",
                 &DapBatchBucket::TimeInterval { batch_window: 0 },
+                0,
             ),
         );
 
@@ -317,6 +326,7 @@ mod test {
                 DapVersion::Draft02,
                 "some-task-id-hex",
                 &DapBatchBucket::TimeInterval { batch_window: 0 },
+                0,
             ),
         );
 
@@ -22,27 +22,32 @@ pub enum AuthMethod {
 mod prometheus {
     use super::DaphneServiceMetrics;
     use daphne::{
+        error::DapAbort,
         fatal_error,
-        metrics::{prometheus::DaphnePromMetrics, DaphneMetrics},
+        metrics::{prometheus::DaphnePromMetrics, DaphneMetrics, TaskLabelPolicy, TaskLabels},
         DapError,
     };
     use prometheus::{register_int_counter_vec_with_registry, IntCounterVec, Registry};
 
     impl DaphneMetrics for DaphnePromServiceMetrics {
-        fn report_inc_by(&self, status: &str, val: u64) {
-            self.daphne.report_inc_by(status, val);
+        fn report_inc_by(&self, status: &str, val: u64, task: TaskLabels<'_>) {
+            self.daphne.report_inc_by(status, val, task);
         }
 
-        fn inbound_req_inc(&self, request_type: daphne::metrics::DaphneRequestType) {
-            self.daphne.inbound_req_inc(request_type);
+        fn inbound_req_inc(
+            &self,
+            request_type: daphne::metrics::DaphneRequestType,
+            task: Option<TaskLabels<'_>>,
+        ) {
+            self.daphne.inbound_req_inc(request_type, task);
         }
 
-        fn agg_job_started_inc(&self) {
-            self.daphne.agg_job_started_inc();
+        fn agg_job_started_inc(&self, task: TaskLabels<'_>) {
+            self.daphne.agg_job_started_inc(task);
         }
 
-        fn agg_job_completed_inc(&self) {
-            self.daphne.agg_job_completed_inc();
+        fn agg_job_completed_inc(&self, task: TaskLabels<'_>) {
+            self.daphne.agg_job_completed_inc(task);
         }
 
         fn agg_job_observe_batch_size(&self, val: usize) {
@@ -52,6 +57,10 @@ mod prometheus {
         fn agg_job_put_span_retry_inc(&self) {
             self.daphne.agg_job_put_span_retry_inc();
         }
+
+        fn peer_abort_inc(&self, abort: &DapAbort, task: TaskLabels<'_>) {
+            self.daphne.peer_abort_inc(abort, task);
+        }
     }
 
     impl DaphneServiceMetrics for DaphnePromServiceMetrics {
@@ -95,6 +104,15 @@ mod prometheus {
 
     impl DaphnePromServiceMetrics {
         pub fn register(registry: &Registry) -> Result<Self, DapError> {
+            Self::register_with_task_labels(registry, TaskLabelPolicy::Disabled)
+        }
+
+        /// Like [`register`](Self::register), but label the Daphne metrics by task according to
+        /// `policy`.
+        pub fn register_with_task_labels(
+            registry: &Registry,
+            policy: TaskLabelPolicy,
+        ) -> Result<Self, DapError> {
             let http_status_code_counter = register_int_counter_vec_with_registry!(
                 "http_status_code",
                 "HTTP response status code.",
@@ -119,7 +137,7 @@ mod prometheus {
             )
             .map_err(|e| fatal_error!(err = ?e, "failed to register dap_abort"))?;
 
-            let daphne = DaphnePromMetrics::register(registry)?;
+            let daphne = DaphnePromMetrics::register_with_task_labels(registry, policy)?;
 
             Ok(Self {
                 daphne,
@@ -40,6 +40,8 @@ pub struct InternalTestAddTask {
     pub min_batch_size: u64,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub max_batch_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_batch_query_count: Option<u64>,
     pub time_precision: Duration,
     pub collector_hpke_config: String, // base64url
     pub task_expiration: Time,
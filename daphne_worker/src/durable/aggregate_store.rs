@@ -4,18 +4,19 @@
 use std::{collections::HashSet, io::Cursor, mem::size_of, ops::ControlFlow};
 
 use crate::{
-    durable::{create_span_from_request, state_get_or_default},
+    durable::{create_span_from_request, state_get, state_get_or_default},
     initialize_tracing, int_err,
 };
 use daphne::{
     messages::{ReportId, Time},
     vdaf::VdafAggregateShare,
-    DapAggregateShare,
+    DapAggregateShare, DapCollectionQuery, MetaAggregationJobId,
 };
 use daphne_service_utils::{
     config::DaphneWorkerDeployment,
     durable_requests::bindings::{
-        self, AggregateStoreMergeReq, AggregateStoreMergeResp, DurableMethod,
+        self, AggregateStoreMergeReq, AggregateStoreMergeResp, AggregateStoreReserveReq,
+        AggregateStoreReserveResp, DurableMethod,
     },
 };
 use prio::{
@@ -32,14 +33,19 @@ use worker::{
 
 use super::{req_parse, DapDurableObject, DaphneWorkerDurableConfig, GarbageCollectable};
 
-/// Durable Object (DO) for storing aggregate shares for a bucket of reports.
+/// Durable Object (DO) for storing aggregate shares for a bucket of reports. The aggregate share of
+/// a bucket may be sharded across several instances, in which case each instance stores the
+/// aggregate share and report IDs of the aggregation jobs assigned to it.
 ///
 /// This object defines the following API endpoints:
 ///
 /// - `DURABLE_AGGREGATE_STORE_GET`: Return the current value of the aggregate share.
 /// - `DURABLE_AGGREGATE_STORE_MERGE`: Update the aggregate share.
-/// - `DURABLE_AGGREGATE_STORE_MARK_COLLECTED`: Mark the bucket as having been collected.
-/// - `DURABLE_AGGREGATE_STORE_CHECK_COLLECTED`: Return a boolean indicating if the bucket has been
+/// - `DURABLE_AGGREGATE_STORE_RESERVE`: Reserve report IDs owned by this instance for an
+///   aggregation job, rejecting those that were reserved by another job.
+/// - `DURABLE_AGGREGATE_STORE_MARK_COLLECTED`: Increment the number of times the bucket has been
+///   collected, unless it was already collected by the same query.
+/// - `DURABLE_AGGREGATE_STORE_GET_COLLECTION_COUNT`: Return the number of times the bucket has been
 ///   collected.
 ///
 /// The schema for the data stored by this DO is as follows:
@@ -48,8 +54,13 @@ use super::{req_parse, DapDurableObject, DaphneWorkerDurableConfig, GarbageColle
 /// [Aggregate share]
 ///     meta                -> DapAggregateShareMetadata
 ///     chunk_v2_{000..004} -> slice of VdafAggregateShare
-/// [Collected flag]
-///     collected -> bool
+/// [Reserved reports]
+///     reserved_report_<report_id_hex> -> MetaAggregationJobId
+/// [Collection count]
+///     collection_count -> u64
+///     collected_by_<query> -> u64 (the collection count after the query was counted)
+///     collected        -> bool (deprecated: set if the bucket was collected before collection
+///                                counts were introduced)
 /// ```
 #[durable_object]
 pub struct AggregateStore {
@@ -58,7 +69,7 @@ pub struct AggregateStore {
     env: Env,
     config: DaphneWorkerDurableConfig,
     touched: bool,
-    collected: Option<bool>,
+    collection_count: Option<u64>,
 }
 
 /// Minimum number of chunks needed to store 1Mb of aggregate share data.
@@ -71,12 +82,18 @@ const MAX_REPORT_ID_CHUNK_KEY_COUNT: usize = 2;
 /// [the public docs](https://developers.cloudflare.com/durable-objects/platform/limits/)
 const MAX_CHUNK_SIZE: usize = 128_000;
 
+/// The maximum number of keys that may be read or written in a single storage operation.
+const MAX_KEYS_PER_STORAGE_OP: usize = 128;
+
 /// Key used to store metadata under.
 const METADATA_KEY: &str = "meta";
 
-/// Key used to store where this share has been collected
+/// Key used to store whether this share has been collected. Replaced by `COLLECTION_COUNT_KEY`.
 const COLLECTED_KEY: &str = "collected";
 
+/// Key used to store the number of times this share has been collected.
+const COLLECTION_COUNT_KEY: &str = "collection_count";
+
 #[derive(Debug, Serialize, Deserialize, Clone, Copy)]
 #[serde(rename_all = "snake_case")]
 enum VdafKind {
@@ -235,6 +252,62 @@ impl AggregateStore {
         }
         Ok(ids)
     }
+
+    fn reserved_report_key(report_id: &ReportId) -> String {
+        format!("reserved_report_{}", report_id.to_hex())
+    }
+
+    fn collected_by_key(query: &DapCollectionQuery) -> String {
+        format!("collected_by_{query}")
+    }
+
+    /// Reserve the given reports for an aggregation job. Either every report is reserved or, if
+    /// any of them was reserved by another aggregation job, none of them is.
+    async fn reserve_reports(
+        &self,
+        agg_job_id: MetaAggregationJobId,
+        report_ids: Vec<ReportId>,
+    ) -> Result<AggregateStoreReserveResp> {
+        let mut replays = HashSet::new();
+        for report_ids in report_ids.chunks(MAX_KEYS_PER_STORAGE_OP) {
+            let keys = report_ids
+                .iter()
+                .map(Self::reserved_report_key)
+                .collect::<Vec<_>>();
+            let reserved = self
+                .state
+                .storage()
+                .get_multiple(keys.iter().map(String::as_str).collect())
+                .await?;
+            for (report_id, key) in report_ids.iter().zip(keys) {
+                let key = JsValue::from_str(&key);
+                if reserved.has(&key) {
+                    let reserved_by =
+                        serde_wasm_bindgen::from_value::<MetaAggregationJobId>(reserved.get(&key))?;
+                    if reserved_by != agg_job_id {
+                        replays.insert(*report_id);
+                    }
+                }
+            }
+        }
+        if !replays.is_empty() {
+            return Ok(AggregateStoreReserveResp::ReplaysDetected(replays));
+        }
+
+        let agg_job_id = serde_wasm_bindgen::to_value(&agg_job_id)?;
+        for report_ids in report_ids.chunks(MAX_KEYS_PER_STORAGE_OP) {
+            let reservations = js_sys::Object::default();
+            for report_id in report_ids {
+                js_sys::Reflect::set(
+                    &reservations,
+                    &JsValue::from_str(&Self::reserved_report_key(report_id)),
+                    &agg_job_id,
+                )?;
+            }
+            self.state.storage().put_multiple_raw(reservations).await?;
+        }
+        Ok(AggregateStoreReserveResp::Ok)
+    }
 }
 
 fn shard_bytes_to_object(
@@ -292,7 +365,7 @@ impl DurableObject for AggregateStore {
             env,
             config,
             touched: false,
-            collected: None,
+            collection_count: None,
         }
     }
 
@@ -303,14 +376,17 @@ impl DurableObject for AggregateStore {
 }
 
 impl AggregateStore {
-    async fn is_collected(&mut self) -> Result<bool> {
-        Ok(if let Some(collected) = self.collected {
-            collected
-        } else {
-            let collected = state_get_or_default(&self.state, COLLECTED_KEY).await?;
-            self.collected = Some(collected);
-            collected
-        })
+    async fn collection_count(&mut self) -> Result<u64> {
+        if let Some(collection_count) = self.collection_count {
+            return Ok(collection_count);
+        }
+
+        let collection_count = match state_get(&self.state, COLLECTION_COUNT_KEY).await? {
+            Some(collection_count) => collection_count,
+            None => u64::from(state_get_or_default::<bool>(&self.state, COLLECTED_KEY).await?),
+        };
+        self.collection_count = Some(collection_count);
+        Ok(collection_count)
     }
 
     async fn handle(&mut self, req: Request) -> Result<Response> {
@@ -333,11 +409,12 @@ impl AggregateStore {
                 let AggregateStoreMergeReq {
                     contained_reports,
                     agg_share_delta,
+                    max_report_count,
                 } = req_parse(&mut req).await?;
 
                 let chunks_map = js_sys::Object::default();
 
-                if self.is_collected().await? {
+                if self.collection_count().await? > 0 {
                     return Response::from_json(&AggregateStoreMergeResp::AlreadyCollected);
                 }
 
@@ -354,6 +431,12 @@ impl AggregateStore {
                             repeat_ids,
                         ));
                     }
+                    if let Some(max_report_count) = max_report_count {
+                        let report_count = merged_report_ids.len() + contained_reports.len();
+                        if u64::try_from(report_count).map_err(int_err)? > max_report_count {
+                            return Response::from_json(&AggregateStoreMergeResp::BatchSaturated);
+                        }
+                    }
                     merged_report_ids.extend(contained_reports);
                     let mut as_bytes =
                         Vec::with_capacity(merged_report_ids.len() * size_of::<ReportId>());
@@ -395,6 +478,19 @@ impl AggregateStore {
                 Response::from_json(&AggregateStoreMergeResp::Ok)
             }
 
+            // Reserve the reports owned by this instance for an aggregation job.
+            //
+            // Idempotent for a given aggregation job
+            // Input: `AggregateStoreReserveReq`
+            // Output: `AggregateStoreReserveResp`
+            Some(bindings::AggregateStore::Reserve) => {
+                let AggregateStoreReserveReq {
+                    agg_job_id,
+                    report_ids,
+                } = req_parse(&mut req).await?;
+                Response::from_json(&self.reserve_reports(agg_job_id, report_ids).await?)
+            }
+
             // Get the current aggregate share.
             //
             // Idempotent
@@ -404,22 +500,42 @@ impl AggregateStore {
                 Response::from_json(&agg_share)
             }
 
-            // Mark this bucket as collected.
+            // Mark this bucket as collected, i.e., increment its collection count.
             //
-            // Non-idempotent (do not retry)
+            // Idempotent for a given query
+            // Input: `DapCollectionQuery`
             // Output: `()`
             Some(bindings::AggregateStore::MarkCollected) => {
-                self.state.storage().put(COLLECTED_KEY, true).await?;
-                self.collected = Some(true);
+                let query: DapCollectionQuery = req_parse(&mut req).await?;
+                let collected_by_key = Self::collected_by_key(&query);
+                if state_get::<u64>(&self.state, &collected_by_key)
+                    .await?
+                    .is_some()
+                {
+                    return Response::from_json(&());
+                }
+
+                // Record the query along with the new count so that both are written atomically.
+                let collection_count = self.collection_count().await? + 1;
+                let values = js_sys::Object::default();
+                for key in [COLLECTION_COUNT_KEY, &collected_by_key] {
+                    js_sys::Reflect::set(
+                        &values,
+                        &JsValue::from_str(key),
+                        &serde_wasm_bindgen::to_value(&collection_count)?,
+                    )?;
+                }
+                self.state.storage().put_multiple_raw(values).await?;
+                self.collection_count = Some(collection_count);
                 Response::from_json(&())
             }
 
-            // Get the value of the flag indicating whether this bucket has been collected.
+            // Get the number of times this bucket has been collected.
             //
             // Idempotent
-            // Output: `bool`
-            Some(bindings::AggregateStore::CheckCollected) => {
-                Response::from_json(&self.is_collected().await?)
+            // Output: `u64`
+            Some(bindings::AggregateStore::GetCollectionCount) => {
+                Response::from_json(&self.collection_count().await?)
             }
 
             _ => Err(int_err(format!(
@@ -2,7 +2,7 @@
 // SPDX-License-Identifier: BSD-3-Clause
 
 use crate::{
-    durable::{create_span_from_request, state_get, state_set_if_not_exists},
+    durable::{create_span_from_request, state_get},
     initialize_tracing, int_err,
 };
 use daphne_service_utils::{
@@ -11,8 +11,8 @@ use daphne_service_utils::{
 };
 use tracing::{trace, Instrument};
 use worker::{
-    async_trait, durable_object, js_sys, wasm_bindgen, wasm_bindgen_futures, worker_sys, Env,
-    Request, Response, Result, State,
+    async_trait, durable_object, js_sys, wasm_bindgen, wasm_bindgen::JsValue, wasm_bindgen_futures,
+    worker_sys, Env, Request, Response, Result, State,
 };
 
 use super::{req_parse, Alarmed, DapDurableObject, DaphneWorkerDurableConfig};
@@ -23,9 +23,25 @@ use super::{req_parse, Alarmed, DapDurableObject, DaphneWorkerDurableConfig};
 ///
 /// - `DURABLE_HELPER_STATE_PUT_IF_NOT_EXISTS`: Stores Helper's hex-encoded state unless the state
 ///    already exists. Returns a boolean indicating whether the operation succeeded.
-/// - `DURABLE_HELPER_STATE_GET`: Drains the Helper's hex-encoded state.
+/// - `DURABLE_HELPER_STATE_PUT_IF_UNCHANGED`: Stores the Helper's hex-encoded state if the current
+///    state is the one given. Returns a boolean indicating whether the operation succeeded.
+/// - `DURABLE_HELPER_STATE_PUT`: Stores the Helper's hex-encoded state, overwriting the state from
+///    the previous round of the aggregation job.
+/// - `DURABLE_HELPER_STATE_GET`: Returns the Helper's hex-encoded state.
 ///
-/// The state blob is stored in `helper_state`.
+/// The state blob may be larger than a single value can hold, e.g., when it includes an
+/// aggregation job initialization request that is pending asynchronous processing, so it is split
+/// into chunks. The schema for the data stored by this DO is as follows:
+///
+/// ```text
+/// helper_state_chunk_count -> usize
+/// helper_state_{00000..}   -> slice of the hex-encoded state
+/// helper_state             -> hex-encoded state (deprecated: set if the state was stored before
+///                             it was split into chunks)
+/// ```
+///
+/// Chunks beyond the chunk count are left over from a larger state that has since been replaced.
+/// They are deleted when the instance is garbage collected.
 #[durable_object]
 pub struct HelperStateStore {
     state: State,
@@ -33,6 +49,19 @@ pub struct HelperStateStore {
     alarmed: bool,
 }
 
+/// Key used to store the number of chunks of the state.
+const CHUNK_COUNT_KEY: &str = "helper_state_chunk_count";
+
+/// Key used to store the state before it was split into chunks. Replaced by `CHUNK_COUNT_KEY`.
+const LEGACY_HELPER_STATE_KEY: &str = "helper_state";
+
+/// The maximum chunk size as documented in
+/// [the public docs](https://developers.cloudflare.com/durable-objects/platform/limits/)
+const MAX_CHUNK_SIZE: usize = 128_000;
+
+/// The maximum number of keys that may be read or written in a single storage operation.
+const MAX_KEYS_PER_STORAGE_OP: usize = 128;
+
 #[durable_object]
 impl DurableObject for HelperStateStore {
     fn new(state: State, env: Env) -> Self {
@@ -80,19 +109,44 @@ impl HelperStateStore {
             // Output: `bool`
             Some(bindings::HelperState::PutIfNotExists) => {
                 let helper_state_hex: String = req_parse(&mut req).await?;
-                let success =
-                    state_set_if_not_exists(&self.state, "helper_state", &helper_state_hex)
-                        .await?
-                        .is_none();
+                let success = self.get_helper_state().await?.is_none();
+                if success {
+                    self.put_helper_state(&helper_state_hex).await?;
+                }
                 Response::from_json(&success)
             }
 
+            // Replace the Helper's state if it hasn't changed.
+            //
+            // Non-idempotent
+            // Input: `(current_hex, helper_state_hex): (String, String)` (hex-encoded states)
+            // Output: `bool`
+            Some(bindings::HelperState::PutIfUnchanged) => {
+                let (current_hex, helper_state_hex): (String, String) = req_parse(&mut req).await?;
+                let success = self.get_helper_state().await? == Some(current_hex);
+                if success {
+                    self.put_helper_state(&helper_state_hex).await?;
+                }
+                Response::from_json(&success)
+            }
+
+            // Overwrite the Helper's state.
+            //
+            // Idempotent
+            // Input: `helper_state_hex: String` (hex-encoded state)
+            // Output: `()`
+            Some(bindings::HelperState::Put) => {
+                let helper_state_hex: String = req_parse(&mut req).await?;
+                self.put_helper_state(&helper_state_hex).await?;
+                Response::from_json(&())
+            }
+
             // Get the Helper's state.
             //
             // Idempotent
             // Output: `String` (hex-encoded state)
             Some(bindings::HelperState::Get) => {
-                let helper_state: Option<String> = state_get(&self.state, "helper_state").await?;
+                let helper_state = self.get_helper_state().await?;
                 Response::from_json(&helper_state)
             }
 
@@ -103,6 +157,54 @@ impl HelperStateStore {
             ))),
         }
     }
+
+    fn chunk_key(n: usize) -> String {
+        format!("helper_state_{n:05}")
+    }
+
+    async fn get_helper_state(&self) -> Result<Option<String>> {
+        let Some(chunk_count) = state_get::<usize>(&self.state, CHUNK_COUNT_KEY).await? else {
+            return state_get(&self.state, LEGACY_HELPER_STATE_KEY).await;
+        };
+
+        let keys = (0..chunk_count).map(Self::chunk_key).collect::<Vec<_>>();
+        let mut helper_state_hex = String::new();
+        for keys in keys.chunks(MAX_KEYS_PER_STORAGE_OP) {
+            let chunks = self.state.storage().get_multiple(keys.to_vec()).await?;
+            for key in keys {
+                let chunk = chunks.get(&JsValue::from_str(key));
+                helper_state_hex.push_str(&serde_wasm_bindgen::from_value::<String>(chunk)?);
+            }
+        }
+        Ok(Some(helper_state_hex))
+    }
+
+    async fn put_helper_state(&self, helper_state_hex: &str) -> Result<()> {
+        let chunks = helper_state_hex
+            .as_bytes()
+            .chunks(MAX_CHUNK_SIZE)
+            .map(std::str::from_utf8)
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| int_err(format!("HelperStateStore: state is not hex-encoded: {e}")))?;
+
+        for (i, chunks) in chunks.chunks(MAX_KEYS_PER_STORAGE_OP).enumerate() {
+            let values = js_sys::Object::default();
+            for (j, chunk) in chunks.iter().enumerate() {
+                js_sys::Reflect::set(
+                    &values,
+                    &JsValue::from_str(&Self::chunk_key(i * MAX_KEYS_PER_STORAGE_OP + j)),
+                    &JsValue::from_str(chunk),
+                )?;
+            }
+            self.state.storage().put_multiple_raw(values).await?;
+        }
+        self.state
+            .storage()
+            .put(CHUNK_COUNT_KEY, chunks.len())
+            .await?;
+        self.state.storage().delete(LEGACY_HELPER_STATE_KEY).await?;
+        Ok(())
+    }
 }
 
 impl DapDurableObject for HelperStateStore {
@@ -51,7 +51,8 @@
 //!     (
 //!         daphne::DapVersion::Draft09,
 //!         "some-task-id-in-hex",
-//!         &daphne::DapBatchBucket::TimeInterval { batch_window: 50 }
+//!         &daphne::DapBatchBucket::TimeInterval { batch_window: 50 },
+//!         0,
 //!     ),
 //! );
 //!
@@ -67,17 +68,25 @@
 //!     .send();
 //! ```
 //!
+//! # Batches
+//!
+//! Several durable object requests can be sent at once by making a `POST` request to
+//! [`BATCH_PATH_PREFIX`] whose body is an encoded [`BatchRequest`]. The operations are executed
+//! concurrently and the response body is an encoded [`BatchResponse`] containing the status and
+//! body of each operation, in request order.
+//!
 //! [to_uri]: daphne_service_utils::durable_requests::bindings::DurableMethod::to_uri
 
 use std::{sync::OnceLock, time::Duration};
 
 use daphne::auth::BearerToken;
 use daphne_service_utils::durable_requests::{
-    DurableRequest, ObjectIdFrom, DO_PATH_PREFIX, KV_PATH_PREFIX,
+    batch::{BatchOperation, BatchOperationResponse, BatchRequest, BatchResponse},
+    DurableRequest, ObjectIdFrom, BATCH_PATH_PREFIX, DO_PATH_PREFIX, KV_PATH_PREFIX,
 };
 use tracing::warn;
 use url::Url;
-use worker::{js_sys::Uint8Array, Delay, Env, Request, RequestInit, Response};
+use worker::{js_sys::Uint8Array, Delay, Env, Headers, Request, RequestInit, Response};
 
 const KV_BINDING_DAP_CONFIG: &str = "DAP_CONFIG";
 
@@ -132,6 +141,8 @@ pub async fn handle_request(
         handle_kv_request(req, env, uri).await
     } else if let Some(uri) = path.strip_prefix(DO_PATH_PREFIX) {
         handle_do_request(req, env, uri).await
+    } else if path == BATCH_PATH_PREFIX {
+        handle_batch_request(req, env).await
     } else {
         #[cfg(feature = "test-utils")]
         if let Some("") = path.strip_prefix(daphne_service_utils::durable_requests::PURGE_STORAGE) {
@@ -226,6 +237,20 @@ async fn handle_kv_request(mut req: Request, env: Env, key: &str) -> worker::Res
 
 /// Handle a durable object request
 async fn handle_do_request(mut req: Request, env: Env, uri: &str) -> worker::Result<Response> {
+    let buf = req.bytes().await.map_err(|e| {
+        tracing::error!(error = ?e, "failed to get bytes");
+        e
+    })?;
+    do_operation(&env, req.headers(), uri, &buf).await
+}
+
+/// Perform a single durable object request. `buf` is an encoded [`DurableRequest`].
+async fn do_operation(
+    env: &Env,
+    headers: &Headers,
+    uri: &str,
+    buf: &[u8],
+) -> worker::Result<Response> {
     const RETRY_DELAYS: &[Duration] = &[
         Duration::from_millis(100),
         Duration::from_millis(500),
@@ -233,12 +258,8 @@ async fn handle_do_request(mut req: Request, env: Env, uri: &str) -> worker::Res
         Duration::from_millis(3_000),
     ];
 
-    let buf = req.bytes().await.map_err(|e| {
-        tracing::error!(error = ?e, "failed to get bytes");
-        e
-    })?;
     tracing::debug!(len = buf.len(), "deserializing do request");
-    let parsed_req = DurableRequest::try_from(&buf)
+    let parsed_req = DurableRequest::try_from(buf)
         .map_err(|e| worker::Error::RustError(format!("invalid format: {e:?}")))?;
 
     let binding = env.durable_object(&parsed_req.binding)?;
@@ -249,7 +270,7 @@ async fn handle_do_request(mut req: Request, env: Env, uri: &str) -> worker::Res
 
     let mut do_req = RequestInit::new();
     do_req.with_method(worker::Method::Post);
-    do_req.with_headers(req.headers().clone());
+    do_req.with_headers(headers.clone());
     if let body @ [_a, ..] = parsed_req.body() {
         let buffer =
             Uint8Array::new_with_length(body.len().try_into().map_err(|_| {
@@ -290,3 +311,34 @@ async fn handle_do_request(mut req: Request, env: Env, uri: &str) -> worker::Res
         }
     }
 }
+
+/// Handle a batch of durable object requests.
+async fn handle_batch_request(mut req: Request, env: Env) -> worker::Result<Response> {
+    let buf = req.bytes().await?;
+    let batch = BatchRequest::try_from(buf.as_slice())
+        .map_err(|e| worker::Error::RustError(format!("invalid format: {e:?}")))?;
+    tracing::debug!(len = batch.operations.len(), "handling batch request");
+
+    let env = &env;
+    let headers = req.headers();
+    let responses = futures::future::join_all(batch.operations.iter().map(|op| async move {
+        let BatchOperation { uri, request } = op;
+        let result = match do_operation(env, headers, uri, request).await {
+            Ok(mut resp) => resp.bytes().await.map(|body| BatchOperationResponse {
+                status: resp.status_code(),
+                body,
+            }),
+            Err(e) => Err(e),
+        };
+        result.unwrap_or_else(|error| {
+            tracing::error!(?error, "batched storage operation failed");
+            BatchOperationResponse {
+                status: 500,
+                body: error.to_string().into_bytes(),
+            }
+        })
+    }))
+    .await;
+
+    Response::from_bytes(BatchResponse { responses }.into_bytes())
+}
@@ -298,7 +298,7 @@ async fn leader_upload(version: DapVersion) {
         DapVersion::Draft02 => client.post(url.as_str()),
         DapVersion::Draft09 | DapVersion::Latest => client.put(url.as_str()),
     };
-    let resp = builder
+    let builder = builder
         .body(
             Report {
                 draft02_task_id: t.task_id.for_request_payload(&version),
@@ -327,10 +327,8 @@ async fn leader_upload(version: DapVersion) {
             .get_encoded_with_param(&version)
             .unwrap(),
         )
-        .headers(headers)
-        .send()
-        .await
-        .expect("request failed");
+        .headers(headers);
+    let resp = t.send(builder).await.expect("request failed");
     assert_eq!(
         200,
         resp.status(),
@@ -1012,7 +1010,7 @@ async fn leader_collect_abort_overlapping_batch_interval(version: DapVersion) {
             DapMediaType::CollectReq,
             collect_req.get_encoded_with_param(&t.version).unwrap(),
             400,
-            "batchOverlap",
+            "batchQueriedTooManyTimes",
         )
         .await;
     } else {
@@ -1023,7 +1021,7 @@ async fn leader_collect_abort_overlapping_batch_interval(version: DapVersion) {
             DapMediaType::CollectReq,
             collect_req.get_encoded_with_param(&t.version).unwrap(),
             400,
-            "batchOverlap",
+            "batchQueriedTooManyTimes",
         )
         .await;
     }
@@ -1136,7 +1134,7 @@ async fn fixed_size() {
         collection.get_encoded_with_param(&t.version).unwrap()
     );
 
-    // Clients: Upload reports.
+    // Clients: Upload fewer reports than the minimum batch size.
     for _ in 0..2 {
         t.leader_put_expect_ok(
             &client,
@@ -1159,6 +1157,49 @@ async fn fixed_size() {
         .await;
     }
 
+    // Collector: Expect the Leader to refuse to collect the current batch, since it is not ready.
+    if t.version != DapVersion::Draft02 {
+        t.leader_put_expect_abort(
+            &client,
+            Some(&t.collector_bearer_token),
+            &t.collect_path_for_task(&t.task_id),
+            DapMediaType::CollectReq,
+            CollectionReq {
+                draft02_task_id: t.collect_task_id_field(),
+                query: Query::FixedSizeCurrentBatch,
+                agg_param: Vec::new(),
+            }
+            .get_encoded_with_param(&t.version)
+            .unwrap(),
+            400,
+            "invalidBatchSize",
+        )
+        .await;
+    }
+
+    // Clients: Upload the rest of the batch.
+    for _ in 2..t.task_config.min_batch_size {
+        t.leader_put_expect_ok(
+            &client,
+            &path,
+            DapMediaType::Report,
+            None,
+            t.task_config
+                .vdaf
+                .produce_report(
+                    &hpke_config_list,
+                    t.now,
+                    &t.task_id,
+                    DapMeasurement::U64(1),
+                    version,
+                )
+                .unwrap()
+                .get_encoded_with_param(&version)
+                .unwrap(),
+        )
+        .await;
+    }
+
     // Get the oldest, not-yet-collected batch ID. This should be different than the one we got
     // before, since that batch was collected.
     let prev_batch_id = batch_id;
@@ -1185,9 +1226,18 @@ async fn fixed_size() {
 
     // Aggregators run processing loop.
     let agg_telem = t.internal_process(&client).await;
-    assert_eq!(agg_telem.reports_processed, 2, "reports processed");
-    assert_eq!(agg_telem.reports_aggregated, 2, "reports aggregated");
-    assert_eq!(agg_telem.reports_collected, 0, "reports collected");
+    assert_eq!(
+        agg_telem.reports_processed, t.task_config.min_batch_size,
+        "reports processed"
+    );
+    assert_eq!(
+        agg_telem.reports_aggregated, t.task_config.min_batch_size,
+        "reports aggregated"
+    );
+    assert_eq!(
+        agg_telem.reports_collected, t.task_config.min_batch_size,
+        "reports collected"
+    );
 
     // Collector: Try CollectReq with out-dated batch ID.
     if t.version == DapVersion::Draft02 {
@@ -1206,7 +1256,7 @@ async fn fixed_size() {
             .get_encoded_with_param(&t.version)
             .unwrap(),
             400,
-            "batchOverlap",
+            "batchQueriedTooManyTimes",
         )
         .await;
     } else {
@@ -1225,7 +1275,7 @@ async fn fixed_size() {
             .get_encoded_with_param(&t.version)
             .unwrap(),
             400,
-            "batchOverlap",
+            "batchQueriedTooManyTimes",
         )
         .await;
     }
@@ -1371,7 +1421,7 @@ async fn helper_hpke_config_signature(version: DapVersion) {
     let t = TestRunner::default_with_version(version).await;
     let url = t.helper_url.join("hpke_config").unwrap();
     let req = TestRunner::http_client().get(url.as_str());
-    let resp = req.send().await.unwrap();
+    let resp = t.send(req).await.unwrap();
     let signature = resp
         .headers()
         .get(http_headers::HPKE_SIGNATURE)
@@ -1379,7 +1429,7 @@ async fn helper_hpke_config_signature(version: DapVersion) {
         .clone();
     let hpke_config_bytes = resp.bytes().await.unwrap();
 
-    let test_certificate = std::env::var("E2E_TEST_HPKE_SIGNING_CERTIFICATE").unwrap();
+    let test_certificate = t.hpke_signing_certificate();
 
     let signature_bytes = decode_base64url_vec(signature.as_bytes()).unwrap();
     let (cert_pem, _bytes_read) = Pem::read(Cursor::new(test_certificate.as_bytes())).unwrap();
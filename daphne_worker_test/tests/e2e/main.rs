@@ -1,4 +1,2 @@
-#[cfg(feature = "test_e2e")]
 mod e2e;
-#[cfg(feature = "test_e2e")]
 mod test_runner;
@@ -14,9 +14,17 @@ use daphne::{
     vdaf::{Prio3Config, VdafConfig},
     DapGlobalConfig, DapLeaderProcessTelemetry, DapQueryConfig, DapTaskConfig, DapVersion,
 };
+use daphne_server::{testing::InProcessDeployment, HttpTransport};
+use daphne_service_utils::{
+    auth::DaphneWorkerAuthMethod,
+    config::{DaphneServiceConfig, TaskprovConfig},
+    DapRole,
+};
 use hpke_rs::{HpkePrivateKey, HpkePublicKey};
+use p256::{ecdsa::SigningKey, pkcs8::EncodePrivateKey};
 use prio::codec::{Decode, Encode};
 use rand::prelude::*;
+use rcgen::CertificateParams;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::time::SystemTime;
@@ -51,6 +59,13 @@ pub struct TestRunner {
     pub taskprov_vdaf_verify_key_init: [u8; 32],
     pub taskprov_collector_hpke_receiver: HpkeReceiverConfig,
     pub version: DapVersion,
+
+    /// The Leader and the Helper, if they run in-process. When the `test_e2e` feature is enabled,
+    /// the tests are run against the deployment at `leader_url` and `helper_url` instead.
+    deployment: Option<InProcessDeployment>,
+
+    /// PEM-encoded certificate of the key the Helper signs its HPKE configurations with.
+    hpke_signing_certificate: Option<String>,
 }
 
 impl TestRunner {
@@ -102,8 +117,9 @@ impl TestRunner {
             time_precision: TIME_PRECISION,
             min_batch_size: MIN_BATCH_SIZE,
             query: query_config.clone(),
+            max_batch_query_count: 1,
             vdaf: *VDAF_CONFIG,
-            vdaf_verify_key: VDAF_CONFIG.gen_verify_key(),
+            vdaf_verify_key: VDAF_CONFIG.gen_verify_key().unwrap(),
             collector_hpke_config: collector_hpke_receiver.config.clone(),
             method: Default::default(),
         };
@@ -115,6 +131,9 @@ impl TestRunner {
             max_batch_interval_end: 259_200,
             supported_hpke_kems: vec![HpkeKemId::X25519HkdfSha256],
             allow_taskprov: true,
+            helper_async_agg_jobs: false,
+            agg_job_policy: Default::default(),
+            retry_policy: Default::default(),
         };
         let taskprov_vdaf_verify_key_init =
             hex::decode("b029a72fa327931a5cb643dcadcaafa098fcbfac07d990cb9e7c9a8675fafb18")
@@ -132,6 +151,17 @@ impl TestRunner {
             HpkePrivateKey::from(hex::decode("9ce9851512df3ea674b108b305c3f8c424955a94d93fd53ecf3c3f17f7d1df9e").unwrap())
         )).expect("bad hpke configuration");
 
+        let (deployment, hpke_signing_certificate) = if cfg!(feature = "test_e2e") {
+            (None, None)
+        } else {
+            let (deployment, hpke_signing_certificate) = in_process_deployment(
+                &global_config,
+                taskprov_vdaf_verify_key_init,
+                &taskprov_collector_hpke_receiver,
+            );
+            (Some(deployment), Some(hpke_signing_certificate))
+        };
+
         let leader_bearer_token = hex::encode(rng.gen::<[u8; 16]>());
         let collector_bearer_token = hex::encode(rng.gen::<[u8; 16]>());
         let t = Self {
@@ -147,6 +177,8 @@ impl TestRunner {
             taskprov_vdaf_verify_key_init,
             taskprov_collector_hpke_receiver,
             version,
+            deployment,
+            hpke_signing_certificate,
         };
 
         let vdaf_verify_key_base64url = encode_base64url(t.task_config.vdaf_verify_key.as_ref());
@@ -258,6 +290,24 @@ impl TestRunner {
         t
     }
 
+    /// Send a request to the Leader or the Helper.
+    pub async fn send(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> reqwest::Result<reqwest::Response> {
+        match &self.deployment {
+            Some(deployment) => deployment.network().execute(request.build()?).await,
+            None => request.send().await,
+        }
+    }
+
+    /// The PEM-encoded certificate of the key the Helper signs its HPKE configurations with.
+    pub fn hpke_signing_certificate(&self) -> String {
+        self.hpke_signing_certificate
+            .clone()
+            .unwrap_or_else(|| std::env::var("E2E_TEST_HPKE_SIGNING_CERTIFICATE").unwrap())
+    }
+
     pub fn http_client() -> reqwest::Client {
         reqwest::ClientBuilder::new()
             .redirect(reqwest::redirect::Policy::none())
@@ -311,11 +361,25 @@ impl TestRunner {
     }
 
     pub async fn leader_get_raw_hpke_config(&self, client: &reqwest::Client) -> Vec<u8> {
-        get_raw_hpke_config(client, self.task_id.as_ref(), &self.leader_url, "leader").await
+        get_raw_hpke_config(
+            self,
+            client,
+            self.task_id.as_ref(),
+            &self.leader_url,
+            "leader",
+        )
+        .await
     }
 
     pub async fn helper_get_raw_hpke_config(&self, client: &reqwest::Client) -> Vec<u8> {
-        get_raw_hpke_config(client, self.task_id.as_ref(), &self.helper_url, "helper").await
+        get_raw_hpke_config(
+            self,
+            client,
+            self.task_id.as_ref(),
+            &self.helper_url,
+            "helper",
+        )
+        .await
     }
 
     pub async fn leader_post_expect_ok(
@@ -335,11 +399,8 @@ impl TestRunner {
                 .parse()
                 .unwrap(),
         );
-        let resp = client
-            .post(url.as_str())
-            .body(data)
-            .headers(headers)
-            .send()
+        let resp = self
+            .send(client.post(url.as_str()).body(data).headers(headers))
             .await
             .expect("request failed");
 
@@ -380,11 +441,8 @@ impl TestRunner {
             );
         }
 
-        let resp = client
-            .post(url.as_str())
-            .body(data)
-            .headers(headers)
-            .send()
+        let resp = self
+            .send(client.post(url.as_str()).body(data).headers(headers))
             .await
             .expect("request failed");
 
@@ -440,11 +498,8 @@ impl TestRunner {
             );
         }
 
-        let resp = client
-            .put(url.as_str())
-            .body(data)
-            .headers(headers)
-            .send()
+        let resp = self
+            .send(client.put(url.as_str()).body(data).headers(headers))
             .await
             .expect("request failed");
 
@@ -501,11 +556,8 @@ impl TestRunner {
             );
         }
 
-        let resp = client
-            .put(url.as_str())
-            .body(data)
-            .headers(headers)
-            .send()
+        let resp = self
+            .send(client.put(url.as_str()).body(data).headers(headers))
             .await
             .expect("request failed");
 
@@ -565,10 +617,8 @@ impl TestRunner {
         } else {
             client.put(url.as_str())
         };
-        let resp = builder
-            .body(data)
-            .headers(headers)
-            .send()
+        let resp = self
+            .send(builder.body(data).headers(headers))
             .await
             .expect("request failed");
 
@@ -602,9 +652,8 @@ impl TestRunner {
         let mut url = self.leader_url.clone();
         url.set_path("internal/process");
 
-        let resp = client
-            .post(url.as_str())
-            .send()
+        let resp = self
+            .send(client.post(url.as_str()))
             .await
             .expect("request failed");
         assert_eq!(
@@ -629,10 +678,8 @@ impl TestRunner {
             self.helper_url.clone()
         };
         url.set_path(path); // Overwrites the version path (i.e., "/v09")
-        let resp = client
-            .post(url.clone())
-            .json(data)
-            .send()
+        let resp = self
+            .send(client.post(url.clone()).json(data))
             .await
             .expect("request failed");
         assert_eq!(
@@ -679,8 +726,8 @@ impl TestRunner {
 
     pub async fn internal_delete_all(&self, batch_interval: &Interval) {
         let client = Self::http_client();
-        post_internal_delete_all(&client, &self.leader_url, batch_interval).await;
-        post_internal_delete_all(&client, &self.helper_url, batch_interval).await;
+        post_internal_delete_all(self, &client, &self.leader_url, batch_interval).await;
+        post_internal_delete_all(self, &client, &self.helper_url, batch_interval).await;
     }
 
     pub async fn internal_current_batch(&self, task_id: &TaskId) -> BatchId {
@@ -690,9 +737,8 @@ impl TestRunner {
             "internal/current_batch/task/{}",
             task_id.to_base64url()
         ));
-        let resp = client
-            .get(url.clone())
-            .send()
+        let resp = self
+            .send(client.get(url.clone()))
             .await
             .expect("request failed");
         if resp.status() == 200 {
@@ -751,7 +797,7 @@ impl TestRunner {
             )
             .unwrap(),
         );
-        builder.headers(headers).send().await.unwrap()
+        self.send(builder.headers(headers)).await.unwrap()
     }
 
     pub fn collect_task_id_field(&self) -> Option<TaskId> {
@@ -764,6 +810,7 @@ impl TestRunner {
 }
 
 async fn get_raw_hpke_config(
+    t: &TestRunner,
     client: &reqwest::Client,
     task_id: &[u8],
     base_url: &Url,
@@ -776,7 +823,7 @@ async fn get_raw_hpke_config(
     let query = [("task_id", encode_base64url(task_id))];
     while elapsed_time < max_time_to_wait {
         let req = client.get(url.as_str()).query(&query);
-        match req.send().await {
+        match t.send(req).await {
             Ok(resp) => {
                 if resp.status() == 200 {
                     println!("{svc} is up.");
@@ -803,6 +850,7 @@ async fn get_raw_hpke_config(
 }
 
 async fn post_internal_delete_all(
+    t: &TestRunner,
     client: &reqwest::Client,
     base_url: &Url,
     batch_interval: &Interval,
@@ -814,7 +862,7 @@ async fn post_internal_delete_all(
     let req = client
         .post(url.as_str())
         .body(serde_json::to_string(batch_interval).unwrap());
-    let resp = req.send().await.expect("request failed");
+    let resp = t.send(req).await.expect("request failed");
     assert_eq!(
         200,
         resp.status(),
@@ -822,3 +870,59 @@ async fn post_internal_delete_all(
         resp.text().await.unwrap()
     );
 }
+
+/// Run the Leader and the Helper in-process, configured like the example services in
+/// `daphne_server/examples`. Returns the deployment and the PEM-encoded certificate of the key the
+/// Helper signs its HPKE configurations with.
+fn in_process_deployment(
+    global_config: &DapGlobalConfig,
+    taskprov_vdaf_verify_key_init: [u8; 32],
+    taskprov_collector_hpke_receiver: &HpkeReceiverConfig,
+) -> (InProcessDeployment, String) {
+    let signing_key = SigningKey::from(p256::SecretKey::random(&mut rand::rngs::OsRng));
+    let certificate = {
+        let mut params = CertificateParams::new(["helper".to_string()]);
+        params.key_pair = Some(
+            rcgen::KeyPair::from_der(signing_key.to_pkcs8_der().unwrap().to_bytes().as_ref())
+                .unwrap(),
+        );
+        rcgen::Certificate::from_params(params)
+            .unwrap()
+            .serialize_pem()
+            .unwrap()
+    };
+
+    let service_config = |role, base_url: &str| DaphneServiceConfig {
+        env: "in-process".into(),
+        role,
+        global: global_config.clone(),
+        base_url: Some(Url::parse(base_url).unwrap()),
+        taskprov: Some(TaskprovConfig {
+            hpke_collector_config: taskprov_collector_hpke_receiver.config.clone(),
+            vdaf_verify_key_init: taskprov_vdaf_verify_key_init,
+            leader_auth: DaphneWorkerAuthMethod {
+                bearer_token: Some("I am the leader!".into()),
+                cf_tls_client_auth: None,
+            },
+            collector_auth: Some(DaphneWorkerAuthMethod {
+                bearer_token: Some("I am the collector!".into()),
+                cf_tls_client_auth: None,
+            }),
+        }),
+        default_version: DapVersion::Draft09,
+        report_storage_epoch_duration: 300_000,
+        report_storage_max_future_time_skew: 300,
+        signing_key: None,
+        admin_token: None,
+        aggregate_store: Default::default(),
+    };
+    let leader_config = service_config(DapRole::Leader, "http://leader:8787/");
+    let helper_config = DaphneServiceConfig {
+        signing_key: Some(signing_key),
+        ..service_config(DapRole::Helper, "http://helper:8788/")
+    };
+
+    let deployment = InProcessDeployment::new(leader_config, helper_config)
+        .expect("failed to start in-process deployment");
+    (deployment, certificate)
+}